@@ -1,3 +1,4 @@
+use crate::client::OutputFormat;
 use clap::{command, Parser};
 use std::net::Ipv4Addr;
 
@@ -19,4 +20,12 @@ pub struct Args {
     /// Directory to save tracing logs from client
     #[arg(short, long, default_value = "./logs")]
     pub logs_dir: String,
+
+    /// Format of received messages, `json` writes one JSON object per line
+    #[arg(long, value_enum, default_value_t = OutputFormat::Pretty)]
+    pub output_format: OutputFormat,
+
+    /// Prefix received messages with the local time they were sent, e.g. `[14:03:12] alice: hi`
+    #[arg(long)]
+    pub show_timestamps: bool,
 }
@@ -1,7 +1,7 @@
 use crate::{
     client_error::ClientError,
     command::Command,
-    utils::{save_file, write_to_output},
+    utils::{format_timestamp, save_file, write_to_output},
 };
 use anyhow::Result;
 use chrono::Utc;
@@ -30,6 +30,8 @@ impl Client {
         host: Ipv4Addr,
         port: u32,
         output_dir: &str,
+        output_format: OutputFormat,
+        show_timestamps: bool,
     ) -> Result<(
         ClientSender<OwnedWriteHalf>,
         ClientReceiver<OwnedReadHalf, T>,
@@ -56,7 +58,13 @@ impl Client {
         write_to_output(&mut writer, b"Connected. You can now send messages.\n").await?;
 
         // Create both ends of the client. I split it to two structs to make it easier to test.
-        let receiver = ClientReceiver::new(read_half, writer, output_dir);
+        let receiver = ClientReceiver::new(
+            read_half,
+            writer,
+            output_dir,
+            output_format,
+            show_timestamps,
+        );
         let sender = ClientSender::new(write_half);
 
         Ok((sender, receiver))
@@ -140,11 +148,24 @@ where
     }
 }
 
+/// How the received messages are written to the output.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    /// Human-readable lines.
+    #[default]
+    Pretty,
+    /// One JSON object per line, see `Message::to_json_line`.
+    Json,
+}
+
 /// The client receiver. It is responsible for receiving messages from the server and handling them.
 pub struct ClientReceiver<T, U> {
     stream: T,
     writer: U,
     output_dir: String,
+    output_format: OutputFormat,
+    /// Prefix pretty printed messages with the time they were sent.
+    show_timestamps: bool,
 }
 
 impl<T, U> ClientReceiver<T, U>
@@ -152,11 +173,19 @@ where
     T: AsyncRead + Unpin,
     U: AsyncWrite + Unpin,
 {
-    fn new(stream: T, writer: U, output_dir: &str) -> Self {
+    fn new(
+        stream: T,
+        writer: U,
+        output_dir: &str,
+        output_format: OutputFormat,
+        show_timestamps: bool,
+    ) -> Self {
         Self {
             stream,
             writer,
             output_dir: output_dir.to_string(),
+            output_format,
+            show_timestamps,
         }
     }
 
@@ -165,7 +194,14 @@ where
 
         while let Ok(message) = Message::receive_msg(&mut self.stream).await {
             tracing::debug!("received msg");
-            if let Err(e) = Self::handle_message(message, &mut self.writer, &self.output_dir).await
+            if let Err(e) = Self::handle_message(
+                message,
+                &mut self.writer,
+                &self.output_dir,
+                self.output_format,
+                self.show_timestamps,
+            )
+            .await
             {
                 tracing::error!("Error while handling message. {e}");
                 eprintln!("Error while handling message. {e}");
@@ -177,14 +213,25 @@ where
     }
 
     /// Handles the received message. It writes the message to the `writer`. If message ista if it is an image or a file.
+    /// In the JSON format only the JSON lines are written, so the output can be parsed line by line.
+    /// With `show_timestamps` the pretty lines start with the local time, messages without a valid timestamp are written without it.
     #[tracing::instrument(name = "Handling message", skip_all)]
     async fn handle_message(
         message: Message,
         writer: &mut U,
         output_dir: &str,
+        output_format: OutputFormat,
+        show_timestamps: bool,
     ) -> Result<(), ClientError> {
-        write_to_output(writer, message.to_string().as_bytes()).await?;
-        Self::store_data(message.data, writer, output_dir).await?;
+        let text = match output_format {
+            OutputFormat::Pretty => match format_timestamp(message.timestamp) {
+                Some(time) if show_timestamps => format!("[{time}] {message}"),
+                _ => message.to_string(),
+            },
+            OutputFormat::Json => message.to_json_line(),
+        };
+        write_to_output(writer, text.as_bytes()).await?;
+        Self::store_data(message.data, writer, output_dir, output_format).await?;
         Ok(())
     }
 
@@ -193,26 +240,25 @@ where
         message: MessagePayload,
         writer: &mut U,
         output_dir: &str,
+        output_format: OutputFormat,
     ) -> Result<(), ClientError> {
-        match message {
+        let saved = match message {
             MessagePayload::Image(data) => {
                 let now = Utc::now();
                 let timestamp = now.timestamp();
                 let file_path = format!("{}/images/{}.png", output_dir, timestamp);
                 save_file(&file_path, &data).await?;
-                write_to_output(
-                    writer,
-                    format!("Image saved to: {}\n", file_path).as_bytes(),
-                )
-                .await?;
+                format!("Image saved to: {}\n", file_path)
             }
             MessagePayload::File(file_name, data) => {
                 let file_path = format!("{}/files/{}", output_dir, file_name);
                 save_file(&file_path, &data).await?;
-                write_to_output(writer, format!("File saved to: {}\n", file_path).as_bytes())
-                    .await?;
+                format!("File saved to: {}\n", file_path)
             }
-            _ => {}
+            _ => return Ok(()),
+        };
+        if output_format == OutputFormat::Pretty {
+            write_to_output(writer, saved.as_bytes()).await?;
         }
         Ok(())
     }
@@ -274,6 +320,8 @@ mod tests {
             stream,
             writer: test_writer,
             output_dir: "./".to_string(),
+            output_format: Default::default(),
+            show_timestamps: false,
         };
 
         let payload = MessagePayload::Text("Hello world!".to_string());
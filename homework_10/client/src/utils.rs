@@ -1,4 +1,5 @@
 use crate::client_error::ClientError;
+use chrono::{Local, TimeZone};
 use image::io::Reader as ImageReader;
 use std::{
     ffi::OsStr,
@@ -89,9 +90,39 @@ where
     Ok(())
 }
 
+/// Formats the unix timestamp of a message as local time, e.g. `14:03:12`.
+/// None for zero, negative or out of range timestamps, e.g. from messages that didn't set it.
+pub fn format_timestamp(timestamp: i64) -> Option<String> {
+    if timestamp <= 0 {
+        return None;
+    }
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|time| time.format("%H:%M:%S").to_string())
+}
+
 #[cfg(test)]
 mod tests {
 
+    #[test]
+    fn format_timestamp() {
+        let formatted = super::format_timestamp(1701720000).unwrap();
+        assert_eq!(formatted.len(), 8);
+        assert!(formatted
+            .chars()
+            .enumerate()
+            .all(|(i, c)| if i == 2 || i == 5 {
+                c == ':'
+            } else {
+                c.is_ascii_digit()
+            }));
+
+        assert_eq!(super::format_timestamp(0), None);
+        assert_eq!(super::format_timestamp(-1), None);
+        assert_eq!(super::format_timestamp(i64::MAX), None);
+    }
+
     #[tokio::test]
     async fn get_file() {
         let (file_name, bytes) = super::get_file("Cargo.toml").await.unwrap();
@@ -0,0 +1,5 @@
+-- `timestamp` is when the user sent the message, `received_at` when the server stored it.
+-- The timestamps stored so far were taken by the server.
+ALTER TABLE messages ADD COLUMN received_at timestamptz;
+UPDATE messages SET received_at = timestamp;
+ALTER TABLE messages ALTER COLUMN received_at SET NOT NULL;
@@ -1,14 +1,21 @@
 use actix_cors::Cors;
-use actix_web::http::header::ContentType;
-use actix_web::{dev::Server, web, App, HttpServer};
+use actix_web::http::header::{ContentType, AUTHORIZATION};
+use actix_web::{dev::Payload, dev::Server, web, App, FromRequest, HttpRequest, HttpServer};
 use actix_web::{HttpResponse, Responder};
-use serde::Deserialize;
+use chrono::Utc;
+use futures::future::{ready, Ready};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use ring::{constant_time::verify_slices_are_equal, digest};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
 use std::net::TcpListener;
 use std::ops::Deref;
+use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 use uuid::Uuid;
 
 use crate::server_error::ServerError;
+use crate::user::User;
 use crate::{
     configuration::Settings,
     db::{ChatDb, ChatPostgresDb},
@@ -31,11 +38,29 @@ impl Api {
 
         let listener = TcpListener::bind(address).map_err(ServerError::Bind)?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(listener, db)?;
+        let jwt = JwtSettings {
+            secret: config.application.jwt_secret,
+            expiry_seconds: config.application.jwt_expiry_seconds,
+        };
+        let server = run(
+            listener,
+            db,
+            AdminToken(config.application.admin_token),
+            jwt,
+        )?;
 
         Ok(Self { port, server })
     }
 
+    /// Builds the api only if it is enabled in the configuration, otherwise returns None.
+    pub fn build_if_enabled(config: Settings) -> Result<Option<Self>, ServerError> {
+        if !config.application.enable_api {
+            tracing::info!("Api is disabled.");
+            return Ok(None);
+        }
+        Self::build(config).map(Some)
+    }
+
     pub fn port(&self) -> u16 {
         self.port
     }
@@ -45,21 +70,39 @@ impl Api {
     }
 }
 
-fn run(listener: std::net::TcpListener, db_pool: ChatPostgresDb) -> Result<Server, ServerError> {
+fn run(
+    listener: std::net::TcpListener,
+    db_pool: ChatPostgresDb,
+    admin_token: AdminToken,
+    jwt: JwtSettings,
+) -> Result<Server, ServerError> {
     let db_pool = web::Data::new(db_pool);
+    let admin_token = web::Data::new(admin_token);
+    let jwt = web::Data::new(jwt);
 
     let server = HttpServer::new(move || {
         App::new()
             .wrap(Cors::permissive())
             .wrap(TracingLogger::default())
             .route("/health", web::get().to(health_check))
+            .route(
+                "/health/ready",
+                web::get().to(readiness_check::<ChatPostgresDb>),
+            )
+            .route("/login", web::post().to(login::<ChatPostgresDb>))
             .route("/messages", web::get().to(get_messages::<ChatPostgresDb>))
             .route(
                 "/user/{id}",
                 web::delete().to(delete_user::<ChatPostgresDb>),
             )
             .route("/users", web::get().to(get_users::<ChatPostgresDb>))
+            .route(
+                "/users/{id}/messages",
+                web::get().to(get_user_messages::<ChatPostgresDb>),
+            )
             .app_data(db_pool.clone())
+            .app_data(admin_token.clone())
+            .app_data(jwt.clone())
     })
     .listen(listener)
     .map_err(ServerError::StartApi)?
@@ -68,25 +111,186 @@ fn run(listener: std::net::TcpListener, db_pool: ChatPostgresDb) -> Result<Serve
     Ok(server)
 }
 
+/// Liveness check, the api is running.
 async fn health_check() -> impl Responder {
     HttpResponse::Ok().finish()
 }
 
+/// How long the readiness check waits for the database.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Readiness check, the api can serve requests only when the database is reachable.
+/// Returns 503 when the database fails or doesn't answer in `READY_TIMEOUT`.
+#[tracing::instrument(skip(db))]
+async fn readiness_check<T>(db: web::Data<T>) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    match tokio::time::timeout(READY_TIMEOUT, db.ping()).await {
+        Ok(Ok(())) => HttpResponse::Ok().finish(),
+        Ok(Err(e)) => {
+            tracing::warn!("Api is not ready. {e}");
+            HttpResponse::ServiceUnavailable().finish()
+        }
+        Err(_) => {
+            tracing::warn!("Api is not ready, database didn't answer in time.");
+            HttpResponse::ServiceUnavailable().finish()
+        }
+    }
+}
+
+/// Secret and lifetime of the tokens issued by `login`. Without the secret nobody can log in.
+struct JwtSettings {
+    secret: Option<Secret<String>>,
+    expiry_seconds: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct LoginRequest {
+    username: String,
+    password: Secret<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    /// Id of the user.
+    sub: Uuid,
+    name: String,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+    expires_at: i64,
+}
+
+/// Verifies the credentials and returns a JWT signed with HS256. 401 for unknown users and wrong passwords alike,
+/// so the response doesn't tell which usernames exist.
+#[tracing::instrument(skip(db, jwt, request), fields(username = %request.username))]
+async fn login<T>(
+    db: web::Data<T>,
+    jwt: web::Data<JwtSettings>,
+    request: web::Json<LoginRequest>,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    let Some(secret) = &jwt.secret else {
+        tracing::warn!("Login requested, but no jwt secret is configured.");
+        return HttpResponse::ServiceUnavailable().finish();
+    };
+    let user = match db.get_user(&request.username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            User::verify_unknown_user_password(request.password.expose_secret().as_bytes());
+            return HttpResponse::Unauthorized().finish();
+        }
+        Err(e) => {
+            tracing::error!("Error while getting user from db. {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    match user.verify_user_password(request.password.expose_secret().as_bytes()) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => {
+            tracing::error!("Error while verifying password. {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        sub: user.id,
+        name: user.username,
+        iat: now,
+        exp: now.saturating_add_unsigned(jwt.expiry_seconds),
+    };
+    let key = EncodingKey::from_secret(secret.expose_secret().as_bytes());
+    match encode(&Header::default(), &claims, &key) {
+        Ok(token) => HttpResponse::Ok().json(LoginResponse {
+            token,
+            expires_at: claims.exp,
+        }),
+        Err(e) => {
+            tracing::error!("Error while signing token. {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Messages returned when the query has no limit.
+const DEFAULT_MESSAGES_LIMIT: i64 = 50;
+/// Larger limits are lowered to this.
+const MAX_MESSAGES_LIMIT: i64 = 500;
+
+/// limit: how many messages are returned, it is clamped to 1..=500.
+/// before_id: id of a message, only older messages are returned. It is the id of the last message of the previous page.
+/// An id of a message that doesn't exist, e.g. a deleted one, gets 400.
+/// search: only messages containing the text are returned, the case is ignored.
 #[derive(Deserialize, Debug)]
 struct MessageQuery {
     username: Option<String>,
+    limit: Option<i64>,
+    before_id: Option<Uuid>,
+    search: Option<String>,
+}
+
+impl MessageQuery {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_MESSAGES_LIMIT)
+            .clamp(1, MAX_MESSAGES_LIMIT)
+    }
 }
 
 #[tracing::instrument(skip(db))]
 async fn get_messages<T>(db: web::Data<T>, query: web::Query<MessageQuery>) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    let username = query.username.as_deref().unwrap_or("");
+    let messages = match &query.search {
+        Some(search) => {
+            db.search_messages(username, search, query.limit(), query.before_id)
+                .await
+        }
+        None => {
+            db.get_messages(username, query.limit(), query.before_id)
+                .await
+        }
+    };
+    match messages {
+        Ok(Some(messages)) => {
+            let Ok(body) = serde_json::to_string(&messages) else {
+                tracing::error!("Error while serializing messages.");
+                return HttpResponse::InternalServerError().finish();
+            };
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(body)
+        }
+        Ok(None) => HttpResponse::BadRequest().body("No message with the before_id exists."),
+        Err(e) => {
+            tracing::error!("Error while getting messages from db. {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Messages of the user with the id, the newest first. 404 when the user doesn't exist.
+#[tracing::instrument(skip(db))]
+async fn get_user_messages<T>(db: web::Data<T>, path: web::Path<Uuid>) -> impl Responder
 where
     T: ChatDb + Sync + Send,
 {
     match db
-        .get_messages(query.username.as_deref().unwrap_or(""))
+        .get_messages_by_user_id(path.deref(), DEFAULT_MESSAGES_LIMIT)
         .await
     {
-        Ok(messages) => {
+        Ok(Some(messages)) => {
             let Ok(body) = serde_json::to_string(&messages) else {
                 tracing::error!("Error while serializing messages.");
                 return HttpResponse::InternalServerError().finish();
@@ -95,8 +299,9 @@ where
                 .content_type(ContentType::json())
                 .body(body)
         }
+        Ok(None) => HttpResponse::NotFound().finish(),
         Err(e) => {
-            tracing::error!("Error while getting messages from db. {e}");
+            tracing::error!("Error while getting messages of user from db. {e}");
             HttpResponse::InternalServerError().finish()
         }
     }
@@ -124,19 +329,425 @@ where
     }
 }
 
-#[tracing::instrument(skip(db))]
-async fn delete_user<T>(db: web::Data<T>, path: web::Path<Uuid>) -> impl Responder
+/// Token of the admin from the configuration, None when no admin is configured.
+struct AdminToken(Option<Secret<String>>);
+
+/// Extracted only from requests with the `Authorization: Bearer <token>` header of the admin, other requests get 401.
+struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req
+            .app_data::<web::Data<AdminToken>>()
+            .and_then(|token| token.0.as_ref());
+        let bearer = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match (token, bearer) {
+            (Some(token), Some(bearer)) if tokens_match(token.expose_secret(), bearer) => {
+                ready(Ok(Admin))
+            }
+            _ => {
+                tracing::warn!("Request without a valid admin token.");
+                ready(Err(actix_web::error::ErrorUnauthorized("Unauthorized")))
+            }
+        }
+    }
+}
+
+/// Compares the tokens in constant time. Their digests are compared, so not even the length of the token leaks.
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    let expected = digest::digest(&digest::SHA256, expected.as_bytes());
+    let actual = digest::digest(&digest::SHA256, actual.as_bytes());
+    verify_slices_are_equal(expected.as_ref(), actual.as_ref()).is_ok()
+}
+
+/// Deletes the user with all the messages, only the admin can do it. Responds with the number of deleted messages.
+#[tracing::instrument(skip(_admin, db))]
+async fn delete_user<T>(_admin: Admin, db: web::Data<T>, path: web::Path<Uuid>) -> impl Responder
 where
     T: ChatDb + Sync + Send,
 {
     match db.remove_user(path.deref()).await {
-        Ok(number) => match number {
-            1 => HttpResponse::NoContent().finish(),
-            _ => HttpResponse::NotFound().finish(),
-        },
+        Ok(Some(messages)) => {
+            HttpResponse::Ok().json(serde_json::json!({ "deleted_messages": messages }))
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
         Err(e) => {
             tracing::error!("Error while removing user from db. {e}");
             HttpResponse::InternalServerError().finish()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{ApplicationSettings, DatabaseSettings};
+    use crate::message_info::MessageInfo;
+    use crate::user::UserInfo;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use async_trait::async_trait;
+    use chrono::Duration;
+    use jsonwebtoken::{decode, DecodingKey, Validation};
+    use shared::message::{AuthUser, Message};
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    /// Returns as many messages as asked for and remembers the paging parameters. `ping` fails when it is `down`.
+    /// `deleted` is the only cursor that doesn't exist.
+    /// `user` is the only user that exists, `login` the only one that can be found by name.
+    /// Searches return the messages containing the text. Inserts are accepted and dropped, no users are listed.
+    #[derive(Default)]
+    struct FakeDb {
+        requested: Mutex<Vec<(i64, Option<Uuid>)>>,
+        deleted: Option<Uuid>,
+        down: bool,
+        user: Option<Uuid>,
+        login: Option<User>,
+    }
+
+    fn fake_messages(limit: i64) -> Vec<MessageInfo> {
+        let now = Utc::now();
+        (0..limit)
+            .map(|i| MessageInfo {
+                id: Uuid::new_v4(),
+                username: "alice".into(),
+                text: format!("message {i}"),
+                timestamp: now - Duration::seconds(i),
+                received_at: now,
+            })
+            .collect()
+    }
+
+    #[async_trait]
+    impl ChatDb for FakeDb {
+        async fn insert_message(&self, _: &Message, _: &Uuid) -> Result<(), ServerError> {
+            Ok(())
+        }
+
+        async fn get_messages(
+            &self,
+            _: &str,
+            limit: i64,
+            before_id: Option<Uuid>,
+        ) -> Result<Option<Vec<MessageInfo>>, ServerError> {
+            self.requested.lock().unwrap().push((limit, before_id));
+            Ok((before_id.is_none() || before_id != self.deleted).then(|| fake_messages(limit)))
+        }
+
+        async fn search_messages(
+            &self,
+            _: &str,
+            search: &str,
+            limit: i64,
+            _: Option<Uuid>,
+        ) -> Result<Option<Vec<MessageInfo>>, ServerError> {
+            let mut messages = fake_messages(limit);
+            messages.retain(|message| message.text.contains(search));
+            Ok(Some(messages))
+        }
+
+        async fn get_messages_by_user_id(
+            &self,
+            user_id: &Uuid,
+            limit: i64,
+        ) -> Result<Option<Vec<MessageInfo>>, ServerError> {
+            Ok((self.user == Some(*user_id)).then(|| fake_messages(limit)))
+        }
+
+        async fn insert_user(&self, _: &User) -> Result<(), ServerError> {
+            Ok(())
+        }
+
+        async fn get_user(&self, username: &str) -> Result<Option<User>, ServerError> {
+            Ok(self
+                .login
+                .as_ref()
+                .filter(|user| user.username == username)
+                .cloned())
+        }
+
+        async fn get_users(&self) -> Result<Vec<UserInfo>, ServerError> {
+            Ok(Vec::new())
+        }
+
+        async fn remove_user(&self, id: &Uuid) -> Result<Option<u64>, ServerError> {
+            Ok((self.user == Some(*id)).then_some(3))
+        }
+
+        async fn ping(&self) -> Result<(), ServerError> {
+            match self.down {
+                true => Err(ServerError::DatabaseUnavailable),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn readiness_fails_when_database_is_down() {
+        for (down, status) in [
+            (false, StatusCode::OK),
+            (true, StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let db = web::Data::new(FakeDb {
+                down,
+                ..Default::default()
+            });
+            let app = init_service(
+                App::new()
+                    .route("/health", web::get().to(health_check))
+                    .route("/health/ready", web::get().to(readiness_check::<FakeDb>))
+                    .app_data(db),
+            )
+            .await;
+
+            let request = TestRequest::get().uri("/health/ready").to_request();
+            assert_eq!(call_service(&app, request).await.status(), status);
+            let request = TestRequest::get().uri("/health").to_request();
+            assert_eq!(call_service(&app, request).await.status(), StatusCode::OK);
+        }
+    }
+
+    #[actix_web::test]
+    async fn messages_are_paged_with_limit_and_cursor() {
+        let db = web::Data::new(FakeDb::default());
+        let app = init_service(
+            App::new()
+                .route("/messages", web::get().to(get_messages::<FakeDb>))
+                .app_data(db.clone()),
+        )
+        .await;
+        let cursor = Uuid::new_v4();
+
+        let request = TestRequest::get().uri("/messages?limit=10").to_request();
+        let messages: Vec<serde_json::Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(messages.len(), 10);
+
+        for uri in [
+            format!("/messages?limit=0&before_id={cursor}"),
+            "/messages?limit=1000".to_string(),
+            "/messages".to_string(),
+        ] {
+            let request = TestRequest::get().uri(&uri).to_request();
+            assert!(call_service(&app, request).await.status().is_success());
+        }
+
+        assert_eq!(
+            *db.requested.lock().unwrap(),
+            [(10, None), (1, Some(cursor)), (500, None), (50, None)]
+        );
+    }
+
+    #[actix_web::test]
+    async fn unknown_cursor_is_rejected() {
+        let deleted = Uuid::new_v4();
+        let db = web::Data::new(FakeDb {
+            deleted: Some(deleted),
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .route("/messages", web::get().to(get_messages::<FakeDb>))
+                .app_data(db.clone()),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri(&format!("/messages?before_id={deleted}"))
+            .to_request();
+        assert_eq!(
+            call_service(&app, request).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn messages_are_searched_only_with_search() {
+        let db = web::Data::new(FakeDb::default());
+        let app = init_service(
+            App::new()
+                .route("/messages", web::get().to(get_messages::<FakeDb>))
+                .app_data(db.clone()),
+        )
+        .await;
+
+        for (uri, found) in [
+            ("/messages?limit=20&search=message%201", 11),
+            ("/messages?search=nothing", 0),
+            ("/messages?limit=20", 20),
+        ] {
+            let request = TestRequest::get().uri(uri).to_request();
+            let messages: Vec<serde_json::Value> = call_and_read_body_json(&app, request).await;
+            assert_eq!(messages.len(), found, "{uri}");
+        }
+        assert_eq!(*db.requested.lock().unwrap(), [(20, None)]);
+    }
+
+    #[actix_web::test]
+    async fn messages_of_unknown_user_are_not_found() {
+        let user = Uuid::new_v4();
+        let db = web::Data::new(FakeDb {
+            user: Some(user),
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .route(
+                    "/users/{id}/messages",
+                    web::get().to(get_user_messages::<FakeDb>),
+                )
+                .app_data(db),
+        )
+        .await;
+
+        let request = TestRequest::get()
+            .uri(&format!("/users/{user}/messages"))
+            .to_request();
+        let messages: Vec<serde_json::Value> = call_and_read_body_json(&app, request).await;
+        assert_eq!(messages.len(), DEFAULT_MESSAGES_LIMIT as usize);
+
+        let request = TestRequest::get()
+            .uri(&format!("/users/{}/messages", Uuid::new_v4()))
+            .to_request();
+        assert_eq!(
+            call_service(&app, request).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn only_admin_can_delete_users() {
+        let user = Uuid::new_v4();
+        let db = web::Data::new(FakeDb {
+            user: Some(user),
+            ..Default::default()
+        });
+        let token = |token: Option<&str>| {
+            web::Data::new(AdminToken(
+                token.map(|token| Secret::new(token.to_string())),
+            ))
+        };
+        let app = init_service(
+            App::new()
+                .route("/user/{id}", web::delete().to(delete_user::<FakeDb>))
+                .app_data(db.clone())
+                .app_data(token(Some("secret"))),
+        )
+        .await;
+        let delete = |header: Option<&str>| {
+            let request = TestRequest::delete().uri(&format!("/user/{user}"));
+            match header {
+                Some(header) => request.insert_header((AUTHORIZATION, header)),
+                None => request,
+            }
+            .to_request()
+        };
+
+        for header in [
+            None,
+            Some("Bearer wrong"),
+            Some("secret"),
+            Some("Basic secret"),
+        ] {
+            let status = call_service(&app, delete(header)).await.status();
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{header:?}");
+        }
+        let deleted: serde_json::Value =
+            call_and_read_body_json(&app, delete(Some("Bearer secret"))).await;
+        assert_eq!(deleted["deleted_messages"], 3);
+
+        // Without a configured token nobody is the admin
+        let app = init_service(
+            App::new()
+                .route("/user/{id}", web::delete().to(delete_user::<FakeDb>))
+                .app_data(db)
+                .app_data(token(None)),
+        )
+        .await;
+        let status = call_service(&app, delete(Some("Bearer "))).await.status();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn login_issues_token_only_for_correct_password() {
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        let id = user.id;
+        let db = web::Data::new(FakeDb {
+            login: Some(user),
+            ..Default::default()
+        });
+        let jwt = web::Data::new(JwtSettings {
+            secret: Some(Secret::new("secret".to_string())),
+            expiry_seconds: 60,
+        });
+        let app = init_service(
+            App::new()
+                .route("/login", web::post().to(login::<FakeDb>))
+                .app_data(db)
+                .app_data(jwt),
+        )
+        .await;
+        let login = |username: &str, password: &str| {
+            TestRequest::post()
+                .uri("/login")
+                .set_json(serde_json::json!({"username": username, "password": password}))
+                .to_request()
+        };
+
+        let response: serde_json::Value =
+            call_and_read_body_json(&app, login("alice", "password")).await;
+        let token = decode::<Claims>(
+            response["token"].as_str().unwrap(),
+            &DecodingKey::from_secret(b"secret"),
+            &Validation::default(),
+        )
+        .unwrap();
+        assert_eq!(token.claims.sub, id);
+        assert_eq!(token.claims.name, "alice");
+        assert_eq!(token.claims.exp, token.claims.iat + 60);
+        assert_eq!(response["expires_at"], token.claims.exp);
+
+        for (username, password) in [("alice", "wrong"), ("bob", "password")] {
+            let status = call_service(&app, login(username, password)).await.status();
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{username}");
+        }
+    }
+
+    #[test]
+    fn disabled_api_does_not_bind_port() {
+        let api_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = Settings {
+            database: DatabaseSettings {
+                username: "postgres".into(),
+                password: Secret::new("password".to_string()),
+                port: 5432,
+                host: "localhost".into(),
+                database_name: "chat_server_db".into(),
+                require_ssl: false,
+            },
+            application: ApplicationSettings {
+                port: 0,
+                host: Ipv4Addr::LOCALHOST,
+                api_port,
+                enable_api: false,
+                admin_token: None,
+                jwt_secret: None,
+                jwt_expiry_seconds: 3600,
+            },
+        };
+
+        assert!(Api::build_if_enabled(config).unwrap().is_none());
+        assert!(TcpListener::bind(("127.0.0.1", api_port)).is_ok());
+    }
+}
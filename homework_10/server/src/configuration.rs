@@ -48,6 +48,26 @@ pub struct ApplicationSettings {
     pub host: std::net::Ipv4Addr,
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub api_port: u16,
+    /// When false, only the chat server runs and nothing listens on the api port.
+    #[serde(default = "default_enable_api")]
+    pub enable_api: bool,
+    /// Bearer token required to delete users through the api. Without it nobody can delete users.
+    #[serde(default)]
+    pub admin_token: Option<Secret<String>>,
+    /// Secret signing the tokens issued by `POST /login`. Without it the api doesn't log users in.
+    #[serde(default)]
+    pub jwt_secret: Option<Secret<String>>,
+    /// How long a token issued by `POST /login` is valid.
+    #[serde(default = "default_jwt_expiry_seconds")]
+    pub jwt_expiry_seconds: u64,
+}
+
+fn default_enable_api() -> bool {
+    true
+}
+
+fn default_jwt_expiry_seconds() -> u64 {
+    3600
 }
 
 pub enum Environment {
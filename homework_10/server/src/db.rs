@@ -5,7 +5,7 @@ use crate::{
     user::{User, UserInfo},
 };
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use secrecy::ExposeSecret;
 use shared::message::{Message, MessagePayload};
 use sqlx::{postgres::PgPoolOptions, PgPool};
@@ -14,23 +14,70 @@ use uuid::Uuid;
 #[async_trait]
 pub trait ChatDb {
     async fn insert_message(&self, message: &Message, user_id: &Uuid) -> Result<(), ServerError>;
-    async fn get_messages(&self, username: &str) -> Result<Vec<MessageInfo>, ServerError>;
+    /// Returns at most `limit` messages, the newest first. With `before_id` only messages older than that message
+    /// are returned, so the history can be paged backwards with the id of the last returned message.
+    /// None when there is no message with `before_id`, so an unknown cursor isn't mistaken for the end of the history.
+    async fn get_messages(
+        &self,
+        username: &str,
+        limit: i64,
+        before_id: Option<Uuid>,
+    ) -> Result<Option<Vec<MessageInfo>>, ServerError>;
+    /// Like `get_messages`, but only messages containing `search` are returned, the case is ignored.
+    async fn search_messages(
+        &self,
+        username: &str,
+        search: &str,
+        limit: i64,
+        before_id: Option<Uuid>,
+    ) -> Result<Option<Vec<MessageInfo>>, ServerError>;
+    /// Returns at most `limit` messages of the user, the newest first. None when the user doesn't exist.
+    async fn get_messages_by_user_id(
+        &self,
+        user_id: &Uuid,
+        limit: i64,
+    ) -> Result<Option<Vec<MessageInfo>>, ServerError>;
     async fn insert_user(&self, user: &User) -> Result<(), ServerError>;
     async fn get_user(&self, username: &str) -> Result<Option<User>, ServerError>;
     async fn get_users(&self) -> Result<Vec<UserInfo>, ServerError>;
-    async fn remove_user(&self, id: &Uuid) -> Result<u64, ServerError>;
+    /// Deletes the user with all the messages. Returns the number of deleted messages, None if there is no such user.
+    async fn remove_user(&self, id: &Uuid) -> Result<Option<u64>, ServerError>;
+    /// Checks that the database answers a trivial query.
+    async fn ping(&self) -> Result<(), ServerError>;
 }
 
 pub struct ChatPostgresDb {
     db_pool: PgPool,
 }
 
+#[cfg(test)]
+impl ChatPostgresDb {
+    fn from_pool(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+}
+
 impl ChatPostgresDb {
     pub fn new(configuration: &DatabaseSettings) -> Self {
         let db_pool = Self::get_connection_pool(configuration);
         Self { db_pool }
     }
 
+    /// Whether the message of the paging cursor exists, true without a cursor.
+    async fn cursor_exists(&self, before_id: Option<Uuid>) -> Result<bool, ServerError> {
+        let Some(before_id) = before_id else {
+            return Ok(true);
+        };
+        let message = sqlx::query!("SELECT id FROM messages WHERE id = $1", before_id)
+            .fetch_optional(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                ServerError::GetMessages
+            })?;
+        Ok(message.is_some())
+    }
+
     fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
         PgPoolOptions::new()
             .acquire_timeout(std::time::Duration::from_secs(2))
@@ -40,18 +87,23 @@ impl ChatPostgresDb {
 
 #[async_trait]
 impl ChatDb for ChatPostgresDb {
+    /// Stores the message with the time the user sent it, the time it was received is kept separately.
+    /// A timestamp out of the supported range is replaced with the time of receiving.
     #[tracing::instrument(skip(self, message))]
     async fn insert_message(&self, message: &Message, user_id: &Uuid) -> Result<(), ServerError> {
         let data = MessagePayload::serialize_to_text(&message.data);
+        let received_at = Utc::now();
+        let sent_at = DateTime::from_timestamp(message.timestamp, 0).unwrap_or(received_at);
         sqlx::query!(
             r#"
-            INSERT INTO messages(id,user_id,data,timestamp)
-            VALUES ($1,$2,$3,$4)
+            INSERT INTO messages(id,user_id,data,timestamp,received_at)
+            VALUES ($1,$2,$3,$4,$5)
             "#,
             Uuid::new_v4(),
             user_id,
             &data,
-            Utc::now(),
+            sent_at,
+            received_at,
         )
         .execute(&self.db_pool)
         .await
@@ -115,19 +167,33 @@ impl ChatDb for ChatPostgresDb {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn get_messages(&self, username: &str) -> Result<Vec<MessageInfo>, ServerError> {
+    async fn get_messages(
+        &self,
+        username: &str,
+        limit: i64,
+        before_id: Option<Uuid>,
+    ) -> Result<Option<Vec<MessageInfo>>, ServerError> {
+        if !self.cursor_exists(before_id).await? {
+            return Ok(None);
+        }
         let pattern = format!("{}%", username);
+        // Ordered by the time the server received the messages, the sent timestamp comes from the client and can be anything.
+        // Messages received at the same time are ordered by id, so the cursor doesn't skip any of them
         let messages = sqlx::query_as!(
             MessageInfo,
             r#"
-            SELECT m.id, u.username, m.data as text, m.timestamp 
+            SELECT m.id, u.username, m.data as text, m.timestamp, m.received_at 
             FROM messages m 
             INNER JOIN users u on u.id = m.user_id
-            WHERE ($1 = '') OR u.username like $2
-            ORDER BY m.timestamp DESC LIMIT 50;
+            WHERE (($1 = '') OR u.username like $2)
+              AND ($3::uuid IS NULL
+                OR (m.received_at, m.id) < (SELECT c.received_at, c.id FROM messages c WHERE c.id = $3))
+            ORDER BY m.received_at DESC, m.id DESC LIMIT $4;
             "#,
             username,
-            pattern
+            pattern,
+            before_id,
+            limit
         )
         .fetch_all(&self.db_pool)
         .await
@@ -136,19 +202,338 @@ impl ChatDb for ChatPostgresDb {
             ServerError::GetMessages
         })?;
 
-        Ok(messages)
+        Ok(Some(messages))
     }
 
     #[tracing::instrument(skip(self))]
-    async fn remove_user(&self, id: &Uuid) -> Result<u64, ServerError> {
-        let result = sqlx::query!("DELETE from users where id = $1", id)
-            .execute(&self.db_pool)
+    async fn search_messages(
+        &self,
+        username: &str,
+        search: &str,
+        limit: i64,
+        before_id: Option<Uuid>,
+    ) -> Result<Option<Vec<MessageInfo>>, ServerError> {
+        if !self.cursor_exists(before_id).await? {
+            return Ok(None);
+        }
+        let pattern = format!("{}%", username);
+        let search = format!("%{}%", escape_like(search));
+        let messages = sqlx::query_as!(
+            MessageInfo,
+            r#"
+            SELECT m.id, u.username, m.data as text, m.timestamp, m.received_at
+            FROM messages m
+            INNER JOIN users u on u.id = m.user_id
+            WHERE (($1 = '') OR u.username like $2)
+              AND m.data ILIKE $3 ESCAPE '\'
+              AND ($4::uuid IS NULL
+                OR (m.received_at, m.id) < (SELECT c.received_at, c.id FROM messages c WHERE c.id = $4))
+            ORDER BY m.received_at DESC, m.id DESC LIMIT $5;
+            "#,
+            username,
+            pattern,
+            search,
+            before_id,
+            limit
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::GetMessages
+        })?;
+
+        Ok(Some(messages))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_messages_by_user_id(
+        &self,
+        user_id: &Uuid,
+        limit: i64,
+    ) -> Result<Option<Vec<MessageInfo>>, ServerError> {
+        let user = sqlx::query!("SELECT id FROM users WHERE id = $1", user_id)
+            .fetch_optional(&self.db_pool)
             .await
             .map_err(|e| {
                 tracing::error!("Failed to execute query: {:?}", e);
-                ServerError::DeleteUser
+                ServerError::GetUser
+            })?;
+        if user.is_none() {
+            return Ok(None);
+        }
+
+        let messages = sqlx::query_as!(
+            MessageInfo,
+            r#"
+            SELECT m.id, u.username, m.data as text, m.timestamp, m.received_at
+            FROM messages m
+            INNER JOIN users u on u.id = m.user_id
+            WHERE m.user_id = $1
+            ORDER BY m.received_at DESC, m.id DESC LIMIT $2;
+            "#,
+            user_id,
+            limit
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::GetMessages
+        })?;
+
+        Ok(Some(messages))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn remove_user(&self, id: &Uuid) -> Result<Option<u64>, ServerError> {
+        let map_err = |e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::DeleteUser
+        };
+        // Dropping the transaction without a commit rolls back the deleted messages
+        let mut transaction = self.db_pool.begin().await.map_err(map_err)?;
+
+        let messages = sqlx::query!("DELETE FROM messages WHERE user_id = $1", id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+        let users = sqlx::query!("DELETE FROM users WHERE id = $1", id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+        if users.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        transaction.commit().await.map_err(map_err)?;
+        Ok(Some(messages.rows_affected()))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<(), ServerError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Database is not reachable: {:?}", e);
+                ServerError::DatabaseUnavailable
             })?;
+        Ok(())
+    }
+}
+
+/// Escapes the wildcards of LIKE, so the text matches only itself.
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatDb, ChatPostgresDb};
+    use crate::message_info::MessageInfo;
+    use crate::user::User;
+    use chrono::{Duration, Utc};
+    use shared::message::{AuthUser, Message, MessagePayload};
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    #[sqlx::test]
+    async fn message_keeps_the_time_it_was_sent(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+        let sent_at = (Utc::now() - Duration::days(1)).timestamp();
+        let mut message = Message::new(MessagePayload::Text("hello".into()));
+        message.timestamp = sent_at;
+
+        db.insert_message(&message, &user.id).await.unwrap();
+
+        let messages = db.get_messages("", 10, None).await.unwrap().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].timestamp.timestamp(), sent_at);
+        assert!(messages[0].received_at > messages[0].timestamp + Duration::hours(23));
+    }
+
+    #[sqlx::test]
+    async fn messages_are_ordered_by_the_time_they_were_received(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+        let now = Utc::now().timestamp();
+        for (text, sent_at) in [("from the future", now + 3600), ("hi", now), ("hello", now)] {
+            let mut message = Message::new(MessagePayload::Text(text.into()));
+            message.timestamp = sent_at;
+            db.insert_message(&message, &user.id).await.unwrap();
+        }
+        let texts = |messages: Vec<MessageInfo>| -> Vec<String> {
+            messages.into_iter().map(|m| m.text).collect()
+        };
+
+        let newest = db.get_messages("", 2, None).await.unwrap().unwrap();
+        let cursor = newest[1].id;
+        assert_eq!(texts(newest), ["hello", "hi"]);
+        let older = db.get_messages("", 2, Some(cursor)).await.unwrap().unwrap();
+        assert_eq!(texts(older), ["from the future"]);
+        let found = db
+            .search_messages("", "h", 10, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts(found), ["hello", "hi", "from the future"]);
+    }
+
+    #[sqlx::test]
+    async fn unknown_cursor_is_not_the_end_of_history(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+        let message = Message::new(MessagePayload::Text("hi".into()));
+        db.insert_message(&message, &user.id).await.unwrap();
+        let unknown = Some(Uuid::new_v4());
+
+        assert!(db.get_messages("", 10, unknown).await.unwrap().is_none());
+        assert!(db
+            .search_messages("", "hi", 10, unknown)
+            .await
+            .unwrap()
+            .is_none());
+        let oldest = db.get_messages("", 10, None).await.unwrap().unwrap()[0].id;
+        let older = db.get_messages("", 10, Some(oldest)).await.unwrap();
+        assert_eq!(older.map(|messages| messages.len()), Some(0));
+    }
+
+    #[sqlx::test]
+    async fn messages_of_one_user_are_returned_newest_first(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        let bob = User::try_from(AuthUser::new("bob", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        db.insert_user(&bob).await.unwrap();
+        let now = Utc::now().timestamp();
+        for (user, text, sent_at) in [
+            (&alice, "first", now - 10),
+            (&bob, "hi", now - 5),
+            (&alice, "second", now),
+        ] {
+            let mut message = Message::new(MessagePayload::Text(text.into()));
+            message.timestamp = sent_at;
+            db.insert_message(&message, &user.id).await.unwrap();
+        }
+
+        let messages = db
+            .get_messages_by_user_id(&alice.id, 50)
+            .await
+            .unwrap()
+            .unwrap();
+        let texts: Vec<_> = messages.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(texts, ["second", "first"]);
+        assert_eq!(
+            db.get_messages_by_user_id(&alice.id, 1)
+                .await
+                .unwrap()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(db
+            .get_messages_by_user_id(&uuid::Uuid::new_v4(), 50)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[sqlx::test]
+    async fn search_matches_text_regardless_of_case(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        let bob = User::try_from(AuthUser::new("bob", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        db.insert_user(&bob).await.unwrap();
+        let now = Utc::now().timestamp();
+        for (user, text, sent_at) in [
+            (&alice, "Lunch at noon?", now - 10),
+            (&bob, "sure, LUNCH sounds good", now - 5),
+            (&alice, "100% agree", now),
+        ] {
+            let mut message = Message::new(MessagePayload::Text(text.into()));
+            message.timestamp = sent_at;
+            db.insert_message(&message, &user.id).await.unwrap();
+        }
+        let texts = |messages: Vec<MessageInfo>| -> Vec<String> {
+            messages.into_iter().map(|m| m.text).collect()
+        };
+
+        let found = db
+            .search_messages("", "lunch", 10, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts(found), ["sure, LUNCH sounds good", "Lunch at noon?"]);
+        let found = db
+            .search_messages("al", "lunch", 10, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts(found), ["Lunch at noon?"]);
+        // Wildcards are matched literally
+        let found = db
+            .search_messages("", "0%", 10, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts(found), ["100% agree"]);
+
+        for search in ["dinner", "_", "noon%"] {
+            let found = db
+                .search_messages("", search, 10, None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(found.is_empty(), "{search}");
+        }
+    }
+
+    #[sqlx::test]
+    async fn removed_user_takes_the_messages_along(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool.clone());
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        let bob = User::try_from(AuthUser::new("bob", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        db.insert_user(&bob).await.unwrap();
+        for user in [&alice, &alice, &bob] {
+            let message = Message::new(MessagePayload::Text("hello".into()));
+            db.insert_message(&message, &user.id).await.unwrap();
+        }
+
+        assert_eq!(db.remove_user(&alice.id).await.unwrap(), Some(2));
 
-        Ok(result.rows_affected())
+        let count = |query| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query_scalar::<_, i64>(query)
+                    .bind(alice.id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(count("SELECT count(*) FROM users WHERE id = $1").await, 0);
+        assert_eq!(
+            count("SELECT count(*) FROM messages WHERE user_id = $1").await,
+            0
+        );
+        assert_eq!(
+            db.get_messages("", 10, None).await.unwrap().unwrap().len(),
+            1
+        );
+        assert_eq!(db.remove_user(&alice.id).await.unwrap(), None);
     }
 }
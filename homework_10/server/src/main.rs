@@ -16,14 +16,19 @@ async fn main() {
 
     let configuration = get_configuration().expect("Failed to read configuration.");
 
-    let Ok(api) = Api::build(configuration.clone()) else {
+    let Ok(api) = Api::build_if_enabled(configuration.clone()) else {
         tracing::error!("Error while setting up api.");
         return;
     };
 
-    let api_task = tokio::spawn(api.run_until_stopped());
     let chat_server_task = tokio::spawn(start(configuration));
 
+    let Some(api) = api else {
+        log_exit("Chat server", chat_server_task.await);
+        return;
+    };
+    let api_task = tokio::spawn(api.run_until_stopped());
+
     tokio::select! {
         o = chat_server_task => log_exit("Chat server", o),
         o = api_task => log_exit("Api", o)
@@ -8,6 +8,10 @@ pub struct MessageInfo {
     pub id: Uuid,
     pub username: String,
     pub text: String,
+    /// When the user sent the message.
     #[serde(with = "ts_seconds")]
     pub timestamp: DateTime<Utc>,
+    /// When the server received and stored the message.
+    #[serde(with = "ts_seconds")]
+    pub received_at: DateTime<Utc>,
 }
@@ -21,6 +21,8 @@ pub enum ServerError {
     GetMessages,
     #[error("Failed to delete user")]
     DeleteUser,
+    #[error("Database is not reachable")]
+    DatabaseUnavailable,
     #[error("Failed to decode password")]
     PasswordDecode,
     #[error("Failed to create user")]
@@ -12,7 +12,7 @@ use uuid::Uuid;
 
 use crate::server_error::ServerError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
@@ -40,6 +40,17 @@ impl User {
         ))
     }
 
+    /// Runs the same hashing as `verify_user_password`, but for a user that doesn't exist, so it is always false.
+    /// A failed login of an unknown user then takes as long as one with a wrong password.
+    pub fn verify_unknown_user_password(password_to_verify: &[u8]) -> bool {
+        Self::verify(
+            &[0; CREDENTIAL_LEN],
+            &[0; CREDENTIAL_LEN],
+            password_to_verify,
+        );
+        false
+    }
+
     fn verify(secret: &[u8], salt: &[u8], password_to_verify: &[u8]) -> bool {
         pbkdf2::verify(
             pbkdf2::PBKDF2_HMAC_SHA512,
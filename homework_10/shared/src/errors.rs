@@ -11,6 +11,8 @@ pub enum MessageError {
     SendError(#[source] std::io::Error),
     #[error("Failed to receive message. {0}")]
     RecieveError(#[source] std::io::Error),
+    #[error("Message has {size} bytes, at most {limit} bytes are allowed.")]
+    MessageTooLarge { size: usize, limit: usize },
 }
 
 #[derive(Debug, Error)]
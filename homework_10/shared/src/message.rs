@@ -4,6 +4,9 @@ use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// Default limit of a received message, large enough for images and files.
+pub const MAX_MESSAGE_SIZE: usize = 50 * 1024 * 1024;
+
 /// Main message struct that wraps the data and other metadata fields.
 /// sender: the username of the sender
 /// timestamp: when msg was created, not used at the moment but it will be useful for the frontend
@@ -40,6 +43,27 @@ impl Message {
         self.sender = Some(sender.to_owned())
     }
 
+    /// Formats the message as one line of JSON with the sender, timestamp, kind and text, for piping the output to other tools.
+    /// Images and files are written without their content, files only with their name.
+    pub fn to_json_line(&self) -> String {
+        let mut line = serde_json::json!({
+            "sender": self.sender,
+            "timestamp": self.timestamp,
+            "kind": self.data.kind(),
+        });
+        match &self.data {
+            MessagePayload::Text(text) | MessagePayload::ServerInfo(text) => {
+                line["text"] = text.as_str().into();
+            }
+            MessagePayload::File(name, _) => line["file_name"] = name.as_str().into(),
+            MessagePayload::LoginResponse(data) => {
+                line["text"] = data.to_string().trim_end().into()
+            }
+            MessagePayload::Image(_) | MessagePayload::Login(_) => {}
+        }
+        format!("{line}\n")
+    }
+
     fn serialize(message: &Message) -> Result<Vec<u8>, MessageError> {
         bincode::serialize(message).map_err(MessageError::SerializeError)
     }
@@ -69,8 +93,20 @@ impl Message {
         Ok(())
     }
 
-    /// Receives a message from the given stream.
+    /// Receives a message from the given stream, at most `MAX_MESSAGE_SIZE` bytes long.
     pub async fn receive_msg<T>(stream: &mut T) -> Result<Message, MessageError>
+    where
+        T: AsyncRead + Unpin,
+    {
+        Message::receive_msg_with_limit(stream, MAX_MESSAGE_SIZE).await
+    }
+
+    /// Receives a message from the given stream. Messages longer than `limit` bytes are rejected
+    /// before their buffer is allocated, so a wrong length prefix can't exhaust the memory.
+    pub async fn receive_msg_with_limit<T>(
+        stream: &mut T,
+        limit: usize,
+    ) -> Result<Message, MessageError>
     where
         T: AsyncRead + Unpin,
     {
@@ -81,6 +117,9 @@ impl Message {
             .map_err(MessageError::RecieveError)?;
 
         let len = u32::from_be_bytes(len_bytes) as usize;
+        if len > limit {
+            return Err(MessageError::MessageTooLarge { size: len, limit });
+        }
 
         let mut buffer = vec![0u8; len];
 
@@ -132,6 +171,18 @@ pub enum MessagePayload {
 }
 
 impl MessagePayload {
+    /// Name of the payload type, used in the JSON output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessagePayload::Text(_) => "text",
+            MessagePayload::Image(_) => "image",
+            MessagePayload::File(_, _) => "file",
+            MessagePayload::ServerInfo(_) => "server_info",
+            MessagePayload::Login(_) => "login",
+            MessagePayload::LoginResponse(_) => "login_response",
+        }
+    }
+
     pub fn serialize_to_text(data: &MessagePayload) -> String {
         match data {
             MessagePayload::Text(text) => text.to_owned(),
@@ -237,3 +288,42 @@ pub enum AuthMessage {
 pub enum AuthError {
     IncorrectPassword,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn oversized_message_is_rejected() {
+        // Only the length prefix is sent, a buffer of that size must not be allocated
+        let mut stream: &[u8] = &u32::MAX.to_be_bytes();
+
+        let result = Message::receive_msg(&mut stream).await;
+
+        assert!(matches!(
+            result,
+            Err(MessageError::MessageTooLarge {
+                size,
+                limit: MAX_MESSAGE_SIZE,
+            }) if size == u32::MAX as usize
+        ));
+    }
+
+    #[test]
+    fn message_is_formatted_as_json_line() {
+        let mut message = Message::new(MessagePayload::Text("Hello \"world\"".to_string()));
+        message.set_from_user("alice");
+        message.timestamp = 1700000000;
+
+        assert_eq!(
+            message.to_json_line(),
+            "{\"kind\":\"text\",\"sender\":\"alice\",\"text\":\"Hello \\\"world\\\"\",\"timestamp\":1700000000}\n"
+        );
+
+        message.data = MessagePayload::Image(vec![1, 2, 3]);
+        assert_eq!(
+            message.to_json_line(),
+            "{\"kind\":\"image\",\"sender\":\"alice\",\"timestamp\":1700000000}\n"
+        );
+    }
+}
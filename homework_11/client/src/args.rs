@@ -1,5 +1,10 @@
-use clap::{command, Parser};
+use crate::autoreply::AutoReplyRule;
+use crate::transfer::{DEFAULT_MEMORY_THRESHOLD, DEFAULT_TRANSFER_TIMEOUT};
+use clap::Parser;
+use shared::compression::Algorithm;
+use shared::tracing::LogRotation;
 use std::net::Ipv4Addr;
+use tracing::Level;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -20,7 +25,110 @@ pub struct Args {
     #[arg(short, long, default_value = "./logs")]
     pub logs_dir: String,
 
+    /// Most verbose level of tracing logs: error, warn, info, debug or trace. `RUST_LOG` takes precedence
+    #[arg(long, default_value_t = Level::INFO)]
+    pub log_level: Level,
+
+    /// Also write the tracing logs to stderr, to watch them live next to the log file
+    #[arg(long)]
+    pub log_stderr: bool,
+
+    /// Start a new log file every hour or day (hourly or daily). By default there is one log file per start
+    #[arg(long)]
+    pub log_rotation: Option<LogRotation>,
+
     /// End-to-End Encryption key
     #[arg(long)]
     pub e2e_encryption_key: Option<String>,
+
+    /// End-to-End Encryption with keys exchanged with the users in the room instead of a shared key
+    #[arg(long, conflicts_with = "e2e_encryption_key")]
+    pub e2e_key_exchange: bool,
+
+    /// Compose multi-line messages. Lines are sent together after a `.send` line
+    #[arg(long)]
+    pub compose: bool,
+
+    /// Don't tell the others when you are writing a message
+    #[arg(long)]
+    pub no_typing_indicator: bool,
+
+    /// File where the draft of the compose mode is saved on every line, so it can be resumed with `.resume-draft` after a crash. Requires `--compose`
+    #[arg(long, requires = "compose")]
+    pub draft_file: Option<String>,
+
+    /// Send files with names that are not valid UTF-8, invalid characters are replaced. By default such files are rejected
+    #[arg(long)]
+    pub lossy_file_names: bool,
+
+    /// Seconds without sending anything after which a keepalive is sent. 0 disables the keepalive
+    #[arg(long, default_value_t = 30)]
+    pub keepalive_seconds: u64,
+
+    /// Compression of sent messages (zstd, gzip or none). It is used only if the server supports it
+    #[arg(long, default_value = "zstd")]
+    pub compression: Algorithm,
+
+    /// Compression level, zstd accepts 1-22, gzip 0-9
+    #[arg(long, default_value_t = 3)]
+    pub compression_level: i32,
+
+    /// Autoreply rule `<pattern>=><template>`, can be used multiple times. Received texts containing the pattern are answered with the template, `{sender}` and `{text}` are replaced
+    #[arg(long)]
+    pub autoreply: Vec<AutoReplyRule>,
+
+    /// Incoming chunked files up to this size are assembled in memory, larger files are written to disk as the chunks arrive
+    #[arg(long, default_value_t = DEFAULT_MEMORY_THRESHOLD)]
+    pub reassembly_buffer_bytes: usize,
+
+    /// Seconds an incoming chunked file waits for its next chunk, e.g. a missing one, before it is discarded
+    #[arg(long, default_value_t = DEFAULT_TRANSFER_TIMEOUT.as_secs())]
+    pub transfer_timeout_seconds: u64,
+
+    /// How many times the client tries to connect again when the connection to the server is lost, waiting 1s, 2s, 4s... up to 30s between attempts. 0 disables reconnecting
+    #[arg(long, default_value_t = 5)]
+    pub max_reconnects: u32,
+
+    /// Render **bold**, *italics* and `code` in received texts with ANSI escape codes. Texts are shown as they are when the output is not a terminal
+    #[arg(long)]
+    pub render_markdown: bool,
+
+    /// Report received messages of unknown types (sent by newer versions) instead of ignoring them
+    #[arg(long)]
+    pub strict: bool,
+
+    /// (testing) Sender set on sent messages. The server keeps it only for admins and only if it allows sender overrides
+    #[arg(long, hide = true)]
+    pub sender_override: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_log_level_is_rejected() {
+        let args = Args::try_parse_from(["client", "--log-level", "warn"]).unwrap();
+        assert_eq!(args.log_level, Level::WARN);
+        assert_eq!(
+            Args::try_parse_from(["client"]).unwrap().log_level,
+            Level::INFO
+        );
+
+        let error = Args::try_parse_from(["client", "--log-level", "loud"]).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn draft_file_requires_compose() {
+        let args =
+            Args::try_parse_from(["client", "--compose", "--draft-file", "draft.txt"]).unwrap();
+        assert_eq!(args.draft_file.as_deref(), Some("draft.txt"));
+
+        let error = Args::try_parse_from(["client", "--draft-file", "draft.txt"]).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            clap::error::ErrorKind::MissingRequiredArgument
+        );
+    }
 }
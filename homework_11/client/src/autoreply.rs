@@ -0,0 +1,151 @@
+use shared::message::{Message, MessagePayload};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const SEPARATOR: &str = "=>";
+
+/// Start of every autoreply. Texts starting with it are not answered, so two clients in the autoreply mode
+/// don't answer each other forever.
+pub const AUTOREPLY_MARKER: &str = "[auto] ";
+
+/// Each sender gets at most one autoreply in this interval, e.g. when the other side replies to every message
+/// without the marker.
+pub const REPLY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Rule of the autoreply mode written as `<pattern>=><template>`. When a received text contains the pattern,
+/// the template is sent back. `{sender}` and `{text}` in the template are replaced with the sender's name and the received text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoReplyRule {
+    pattern: String,
+    template: String,
+}
+
+impl FromStr for AutoReplyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(SEPARATOR) {
+            Some((pattern, template)) if !pattern.is_empty() => Ok(Self {
+                pattern: pattern.to_string(),
+                template: template.to_string(),
+            }),
+            _ => Err(format!(
+                "Rule has to be in format <pattern>{SEPARATOR}<template>"
+            )),
+        }
+    }
+}
+
+/// Rules of the autoreply mode, the first matching rule is used.
+#[derive(Debug, Default)]
+pub struct AutoReply {
+    rules: Vec<AutoReplyRule>,
+    /// When each sender was answered last, only the senders answered within `REPLY_INTERVAL` are kept.
+    last_replies: HashMap<String, Instant>,
+}
+
+impl AutoReply {
+    pub fn new(rules: Vec<AutoReplyRule>) -> Self {
+        Self {
+            rules,
+            last_replies: HashMap::new(),
+        }
+    }
+
+    /// Returns the reply to the message if it matches any rule, starting with `AUTOREPLY_MARKER`.
+    /// Only text messages from users are answered, except autoreplies and senders answered within `REPLY_INTERVAL`.
+    pub fn reply_to(&mut self, message: &Message) -> Option<String> {
+        self.reply_at(message, Instant::now())
+    }
+
+    fn reply_at(&mut self, message: &Message, now: Instant) -> Option<String> {
+        let MessagePayload::Text(text) = &message.data else {
+            return None;
+        };
+        let sender = message.sender.as_deref()?;
+        if text.starts_with(AUTOREPLY_MARKER) {
+            return None;
+        }
+
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| text.contains(&rule.pattern))?;
+        self.last_replies
+            .retain(|_, replied| now.duration_since(*replied) < REPLY_INTERVAL);
+        if self.last_replies.contains_key(sender) {
+            return None;
+        }
+        self.last_replies.insert(sender.to_string(), now);
+
+        let reply = rule
+            .template
+            .replace("{sender}", sender)
+            .replace("{text}", text);
+        Some(format!("{AUTOREPLY_MARKER}{reply}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_is_parsed() {
+        let rule: AutoReplyRule = "ping=>pong {sender}".parse().unwrap();
+
+        assert_eq!(rule.pattern, "ping");
+        assert_eq!(rule.template, "pong {sender}");
+        assert!("=>pong".parse::<AutoReplyRule>().is_err());
+        assert!("ping".parse::<AutoReplyRule>().is_err());
+    }
+
+    #[test]
+    fn only_matching_user_messages_are_answered() {
+        let mut autoreply = AutoReply::new(vec!["ping=>pong {sender}".parse().unwrap()]);
+        let mut message = Message::new(MessagePayload::Text("ping".into()));
+
+        assert_eq!(autoreply.reply_to(&message), None);
+
+        message.set_from_user("alice");
+        assert_eq!(
+            autoreply.reply_to(&message),
+            Some("[auto] pong alice".into())
+        );
+
+        message.set_from_user("bob");
+        message.data = MessagePayload::Text("hello".into());
+        assert_eq!(autoreply.reply_to(&message), None);
+    }
+
+    #[test]
+    fn autoreplies_are_not_answered() {
+        let mut autoreply = AutoReply::new(vec!["ping=>ping {sender}".parse().unwrap()]);
+        let mut message = Message::new(MessagePayload::Text("[auto] ping bob".into()));
+        message.set_from_user("alice");
+
+        assert_eq!(autoreply.reply_to(&message), None);
+    }
+
+    #[test]
+    fn sender_is_answered_once_per_interval() {
+        let mut autoreply = AutoReply::new(vec!["ping=>pong".parse().unwrap()]);
+        let mut message = Message::new(MessagePayload::Text("ping".into()));
+        message.set_from_user("alice");
+        let now = Instant::now();
+
+        assert!(autoreply.reply_at(&message, now).is_some());
+        assert!(autoreply
+            .reply_at(&message, now + REPLY_INTERVAL / 2)
+            .is_none());
+
+        message.set_from_user("bob");
+        assert!(autoreply
+            .reply_at(&message, now + REPLY_INTERVAL / 2)
+            .is_some());
+
+        message.set_from_user("alice");
+        assert!(autoreply.reply_at(&message, now + REPLY_INTERVAL).is_some());
+    }
+}
@@ -1,14 +1,33 @@
 use crate::{
+    autoreply::AutoReply,
     client_error::ClientError,
-    command::Command,
-    encryption::{self, decrypt_payload, encrypt_payload},
-    utils::{save_file, write_to_output},
+    command::{Command, CommandOutcome, HELP},
+    compose::Draft,
+    display::{render_markdown, DisplaySettings},
+    encryption::E2eEncryption,
+    key_exchange::{KeyChange, KeyExchange},
+    reconnect::{ConnectionEvent, Reconnect, ReconnectPolicy},
+    transfer::{
+        IncomingTransfers, OutgoingTransfer, OutgoingTransfers, Received, CHUNK_SIZE,
+        EXPIRY_CHECK_INTERVAL,
+    },
+    typing::{TypingNotifier, TypingUsers},
+    utils::{
+        ensure_writable_dir, list_attachments, preview_file, sanitize_file_name, save_file,
+        write_to_output, FileNamePolicy,
+    },
 };
 use anyhow::Result;
 use chrono::Utc;
+use futures::future::BoxFuture;
+use shared::compression::{Algorithm, Compression, Framing};
 use shared::message::{AuthUser, Message, MessagePayload};
-use std::{net::Ipv4Addr, str::FromStr};
-use tokio::io::AsyncWrite;
+use std::pin::pin;
+use std::sync::Mutex;
+use std::{net::Ipv4Addr, str::FromStr, sync::Arc, time::Duration};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, BufReader, Lines, Stdin};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::MissedTickBehavior;
 use tokio::{
     io::AsyncRead,
     net::{
@@ -16,6 +35,8 @@ use tokio::{
         TcpStream,
     },
 };
+use tracing::Instrument;
+use uuid::Uuid;
 
 /// The main client struct.
 ///
@@ -23,15 +44,27 @@ use tokio::{
 /// All output messages are written to `writer`.
 pub struct Client;
 
+/// Lines typed by the user. One reader is used for the login and the messages, so nothing it buffered is lost.
+pub type Input = Lines<BufReader<Stdin>>;
+
+/// Reads stdin without blocking the runtime, see `ClientSender::start`.
+pub fn stdin_lines() -> Input {
+    BufReader::new(tokio::io::stdin()).lines()
+}
+
 impl Client {
     /// Connects to the server and returns a sender and a receiver. The creation is inspired by the channel.
     /// writer: T is generic to abstract the output. It can be stdout, file or anything that implements Write. I made it generic to make it easier to test and not to use println! all the time.
+    /// When the connection is lost, the receiver connects again according to the `reconnect` policy and logs in
+    /// with the same username and session. Messages written in the meantime are sent after the reconnect.
     pub async fn connect<T>(
         mut writer: T,
         host: Ipv4Addr,
         port: u32,
         output_dir: &str,
-        e2e_encryption: Option<String>,
+        e2e_encryption: Option<E2eEncryption>,
+        compression: Compression,
+        reconnect: ReconnectPolicy,
     ) -> Result<(
         ClientSender<OwnedWriteHalf>,
         ClientReceiver<OwnedReadHalf, T>,
@@ -39,6 +72,9 @@ impl Client {
     where
         T: AsyncWrite + Unpin,
     {
+        // Fail before connecting rather than on the first received attachment.
+        ensure_writable_dir(output_dir).await?;
+
         let server = format!("{}:{}", host, port);
 
         write_to_output(
@@ -47,35 +83,88 @@ impl Client {
         )
         .await?;
 
-        let mut stream = TcpStream::connect(server).await?;
+        let mut stream = TcpStream::connect(&server).await?;
+        let mut input = stdin_lines();
 
-        while Self::authenticate(&mut writer, &mut stream).await.is_err() {
-            write_to_output(&mut writer, b"Please try to log in again.\n").await?;
-        }
+        let (credentials, framing, session_token) = loop {
+            match Self::authenticate(&mut writer, &mut input, &mut stream, compression).await {
+                Ok(authenticated) => break authenticated,
+                Err(e) if matches!(e.downcast_ref(), Some(ClientError::LoginFailed)) => {
+                    write_to_output(&mut writer, b"Please try to log in again.\n").await?;
+                }
+                Err(e) => return Err(e),
+            }
+        };
 
         let (read_half, write_half) = stream.into_split();
 
         write_to_output(&mut writer, b"Connected. You can now send messages.\n").await?;
 
-        if e2e_encryption.is_some() {
-            write_to_output(&mut writer, b"E2E encryption enabled.\n").await?;
+        match &e2e_encryption {
+            Some(E2eEncryption::Passphrase(_)) => {
+                write_to_output(&mut writer, b"E2E encryption enabled.\n").await?;
+            }
+            Some(E2eEncryption::KeyExchange(_)) => {
+                write_to_output(
+                    &mut writer,
+                    b"E2E encryption enabled. Your texts can be read only by users whose keys you received.\n",
+                )
+                .await?;
+            }
+            None => {}
         }
-        let key = e2e_encryption.map(|key| encryption::pad_to_32_bytes(key.as_bytes()));
+        connection_established(
+            &server,
+            &credentials.name,
+            framing,
+            e2e_encryption.is_some(),
+        );
+
+        let display = Arc::new(DisplaySettings::default());
 
         // Create both ends of the client. I split it to two structs to make it easier to test.
-        let receiver = ClientReceiver::new(read_half, writer, output_dir, key);
-        let sender = ClientSender::new(write_half, key);
+        let mut receiver = ClientReceiver::new(
+            read_half,
+            writer,
+            output_dir,
+            e2e_encryption.clone(),
+            display.clone(),
+        )
+        .framing(framing);
+        let mut sender = ClientSender::new(write_half, e2e_encryption, display)
+            .framing(framing)
+            .output_dir(output_dir)
+            .input(input);
+
+        if reconnect.max_retries > 0 {
+            let (events, events_receiver) = mpsc::unbounded_channel();
+            let connector = Connector {
+                server,
+                credentials,
+                compression,
+                session_token: Mutex::new(session_token),
+                events,
+            };
+            receiver = receiver.reconnect(reconnect, Box::new(connector));
+            sender = sender.reconnects(events_receiver);
+        }
 
         Ok((sender, receiver))
     }
 
-    async fn authenticate<T>(mut writer: T, stream: &mut TcpStream) -> Result<()>
+    /// Logs in the user. Returns what the user typed, the framing with the compression the server picked and the session token.
+    /// Connection compression is offered whenever the client wants to compress.
+    async fn authenticate<T>(
+        mut writer: T,
+        input: &mut Input,
+        stream: &mut TcpStream,
+        compression: Compression,
+    ) -> Result<(Credentials, Framing, Option<Uuid>)>
     where
         T: AsyncWrite + Unpin,
     {
         write_to_output(&mut writer, b"Enter your username.\n").await?;
-        let mut name = String::new();
-        std::io::stdin().read_line(&mut name)?;
+        let name = input.next_line().await?.unwrap_or_default();
         let name = name.trim();
 
         write_to_output(
@@ -84,74 +173,608 @@ impl Client {
         )
         .await?;
 
-        let mut password = String::new();
-        std::io::stdin().read_line(&mut password)?;
-        let password = password.trim();
+        let password = input.next_line().await?.unwrap_or_default();
+
+        let credentials = Credentials {
+            name: name.to_string(),
+            password: password.trim().to_string(),
+        };
+        let (framing, session_token) =
+            Self::login(writer, stream, &credentials, None, compression).await?;
+        Ok((credentials, framing, session_token))
+    }
 
-        let user = AuthUser::new(name, password);
+    /// Sends the login, with the session token of the previous connection when reconnecting.
+    /// Returns the negotiated framing and the session token.
+    async fn login<T>(
+        mut writer: T,
+        stream: &mut TcpStream,
+        credentials: &Credentials,
+        session_token: Option<Uuid>,
+        compression: Compression,
+    ) -> Result<(Framing, Option<Uuid>)>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let mut user = match compression.algorithm {
+            Algorithm::None => AuthUser::new(&credentials.name, &credentials.password),
+            algorithm => AuthUser::new(&credentials.name, &credentials.password)
+                .with_compression(vec![algorithm])
+                .with_connection_compression(),
+        };
+        user.session_token = session_token;
 
         let payload = Message::handshake(stream, user).await?.data;
 
-        if let MessagePayload::LoginResponse(data) = payload {
-            write_to_output(&mut writer, data.to_string().as_bytes()).await?;
-            if data.is_success() {
-                return Ok(());
+        match payload {
+            MessagePayload::LoginResponse(data) => {
+                write_to_output(&mut writer, data.to_string().as_bytes()).await?;
+                if data.is_success() {
+                    let compression = Compression::new(data.compression(), compression.level);
+                    let framing = Framing::negotiated(compression, data.connection_compression());
+                    return Ok((framing, data.session_token()));
+                }
             }
+            // Server rejected the connection before the login, e.g. it is too busy.
+            MessagePayload::ServerInfo(text) => {
+                write_to_output(&mut writer, format!("{text}\n").as_bytes()).await?;
+                return Err(ClientError::ConnectionRejected.into());
+            }
+            _ => {}
         }
 
         Err(ClientError::LoginFailed.into())
     }
 }
 
+/// Username and password typed at the start, they are used again to log in after a reconnect.
+struct Credentials {
+    name: String,
+    password: String,
+}
+
+/// Connects to the same server again and passes the write half of the new connection to the sender.
+struct Connector {
+    server: String,
+    credentials: Credentials,
+    compression: Compression,
+    /// Sent with the login, so the server knows it is the same session and delivers what was missed.
+    session_token: Mutex<Option<Uuid>>,
+    events: UnboundedSender<ConnectionEvent<OwnedWriteHalf>>,
+}
+
+impl Connector {
+    async fn connect_once(&self) -> Result<(OwnedReadHalf, Framing)> {
+        let mut stream = TcpStream::connect(&self.server).await?;
+        let session_token = *self.session_token.lock().unwrap();
+        // Output of the login is not shown, the receiver tells the user about the reconnect
+        let (framing, session_token) = Client::login(
+            tokio::io::sink(),
+            &mut stream,
+            &self.credentials,
+            session_token,
+            self.compression,
+        )
+        .await?;
+        *self.session_token.lock().unwrap() = session_token;
+        tracing::info!(server = self.server, "Reconnected");
+
+        let (read_half, write_half) = stream.into_split();
+        // The sender may have quit already, then there is nothing to send
+        _ = self
+            .events
+            .send(ConnectionEvent::Reconnected(write_half, framing));
+        Ok((read_half, framing))
+    }
+}
+
+impl Reconnect<OwnedReadHalf> for Connector {
+    fn lost(&self) -> bool {
+        // Sending fails when the sender finished
+        self.events.send(ConnectionEvent::Lost).is_ok()
+    }
+
+    fn connect(&mut self) -> BoxFuture<'_, Result<(OwnedReadHalf, Framing)>> {
+        Box::pin(self.connect_once())
+    }
+}
+
+/// Records how the session is configured, so it is clear from the logs what was negotiated with the server.
+fn connection_established(server: &str, username: &str, framing: Framing, e2e_encryption: bool) {
+    let compression = framing.compression();
+    tracing::info!(
+        server,
+        username,
+        compression = ?compression.algorithm,
+        compression_level = compression.level,
+        connection_compression = matches!(framing, Framing::Connection(_)),
+        e2e_encryption,
+        "Connection established"
+    );
+}
+
 /// The client sender. It is responsible for parsing user commands and sending messages to the server.
 pub struct ClientSender<T>
 where
     T: AsyncWrite + Unpin,
 {
     stream: T,
-    encryption_key: Option<[u8; 32]>,
+    encryption: Option<E2eEncryption>,
+    /// Set when our key is announced next with a request for the keys of the room:
+    /// after the login, a reconnect and a room change.
+    announce_key: bool,
+    display: Arc<DisplaySettings>,
+    draft: Option<Draft>,
+    /// Tells the others that the user is composing a message, None when turned off.
+    typing: Option<TypingNotifier>,
+    file_names: FileNamePolicy,
+    keepalive: Option<Duration>,
+    transfers: OutgoingTransfers,
+    framing: Framing,
+    replies: Option<UnboundedReceiver<String>>,
+    /// Where the received attachments are saved, used to list them.
+    output_dir: String,
+    /// Sender set on sent messages, the server keeps it only for admins when it allows overrides.
+    sender_override: Option<String>,
+    /// Lost and new connections reported by the receiver, `None` when the client doesn't reconnect.
+    reconnects: Option<UnboundedReceiver<ConnectionEvent<T>>>,
+    /// Set while the receiver reconnects, messages wait for the new connection.
+    disconnected: bool,
+    /// Lines typed by the user, `None` reads a new stdin reader.
+    input: Option<Input>,
 }
 
 impl<T> ClientSender<T>
 where
     T: AsyncWrite + Unpin,
 {
-    fn new(stream: T, encryption_key: Option<[u8; 32]>) -> Self {
+    fn new(stream: T, encryption: Option<E2eEncryption>, display: Arc<DisplaySettings>) -> Self {
         ClientSender {
             stream,
-            encryption_key,
+            announce_key: keys(&encryption).is_some(),
+            encryption,
+            display,
+            draft: None,
+            typing: None,
+            file_names: FileNamePolicy::default(),
+            keepalive: None,
+            transfers: OutgoingTransfers::default(),
+            framing: Framing::default(),
+            replies: None,
+            output_dir: ".".to_string(),
+            sender_override: None,
+            reconnects: None,
+            disconnected: false,
+            input: None,
         }
     }
 
+    /// Reads the lines from the reader that was used for the login.
+    fn input(mut self, input: Input) -> Self {
+        self.input = Some(input);
+        self
+    }
+
+    /// In compose mode text lines are collected and sent as one message after a `.send` line.
+    pub fn compose_mode(mut self, enabled: bool) -> Self {
+        self.draft = enabled.then(Draft::default);
+        self
+    }
+
+    /// Sends `Typing` while lines of the compose mode are collected, or before text lines without it,
+    /// at most once per `TYPING_INTERVAL`.
+    pub fn typing_indicator(mut self, enabled: bool) -> Self {
+        self.typing = enabled.then(TypingNotifier::default);
+        self
+    }
+
+    /// Saves the draft of the compose mode to the file on every line, so it can be resumed after a restart.
+    pub fn draft_file(mut self, path: Option<String>) -> Self {
+        if let (Some(path), Some(_)) = (path, &self.draft) {
+            self.draft = Some(Draft::persisted(path));
+        }
+        self
+    }
+
+    /// Sets the framing and compression of sent messages, they have to be negotiated with the server.
+    fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Sets the directory where the receiver saves attachments.
+    fn output_dir(mut self, output_dir: &str) -> Self {
+        self.output_dir = output_dir.to_string();
+        self
+    }
+
+    /// Sends messages as the given sender, for simulating many users from one connection in tests.
+    pub fn sender_override(mut self, sender: Option<String>) -> Self {
+        self.sender_override = sender;
+        self
+    }
+
+    /// Switches to the new connections opened by the receiver after the connection was lost.
+    fn reconnects(mut self, reconnects: UnboundedReceiver<ConnectionEvent<T>>) -> Self {
+        self.reconnects = Some(reconnects);
+        self
+    }
+
+    /// Sends texts from the channel as messages, used by the autoreply mode.
+    pub fn replies(mut self, replies: UnboundedReceiver<String>) -> Self {
+        self.replies = Some(replies);
+        self
+    }
+
+    /// Sets how file names that are not valid UTF-8 are handled when sending files.
+    pub fn file_name_policy(mut self, policy: FileNamePolicy) -> Self {
+        self.file_names = policy;
+        self
+    }
+
+    /// Sends a `Ping` when the user doesn't send anything for the given interval, so idle connections are not dropped by NAT.
+    pub fn keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.keepalive = interval;
+        self
+    }
+
     /// Starts listening for user input and sends it to the server.
+    ///
+    /// Stdin is read with `tokio::io::stdin` in its own task, a waiting read yields to the runtime instead of blocking a worker,
+    /// which would starve the other tasks under a single-threaded runtime. `spawn_blocking` with `read_line` wasn't chosen,
+    /// it would need a new blocking task for every line, and `tokio::io::stdin` already does the blocking reads on the blocking pool
+    /// one at a time, behind `AsyncRead`. The lines are forwarded over a channel, so `run` can wait for them next to the other events
+    /// and the tests can feed the lines directly.
     pub async fn start(mut self) -> Result<()> {
+        let (lines_sender, mut lines) = mpsc::unbounded_channel();
+
+        if self
+            .draft
+            .as_ref()
+            .is_some_and(|draft| draft.saved_lines() > 0)
+        {
+            println!(
+                "There is an unsent draft from the last run, use `.resume-draft` to continue it."
+            );
+        }
+
+        let mut input = self.input.take().unwrap_or_else(stdin_lines);
+        tokio::spawn(async move {
+            // Ends with the input, or when the sender doesn't take lines anymore.
+            while let Ok(Some(line)) = input.next_line().await {
+                if lines_sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.run(&mut lines).await
+    }
+
+    /// Processes lines until the user quits or the input ends. Keepalive timer is restarted with every line.
+    async fn run(&mut self, lines: &mut UnboundedReceiver<String>) -> Result<()> {
         loop {
-            let mut text = String::new();
-            std::io::stdin().read_line(&mut text)?;
+            let keepalive = self.keepalive;
+            let line = tokio::select! {
+                biased;
+                // Checked first, so a line isn't written to a connection that is already lost.
+                Some(event) = next_event(&mut self.reconnects) => {
+                    self.connection_changed(event);
+                    continue;
+                }
+                // Sent before any text, so the peers can answer with their keys before we need them.
+                _ = std::future::ready(()), if self.announce_key => {
+                    self.announce_key = false;
+                    self.send_key(true).await?;
+                    continue;
+                }
+                Some(()) = key_requested(keys(&self.encryption)) => {
+                    self.send_key(false).await?;
+                    continue;
+                }
+                line = lines.recv() => line,
+                // Chunks are sent between user commands, so a transfer can be cancelled while it is in progress.
+                _ = std::future::ready(()), if !self.transfers.is_empty() => {
+                    if let Some(chunk) = self.transfers.next_chunk() {
+                        self.send_payload(chunk, None).await?;
+                    }
+                    continue;
+                }
+                Some(reply) = next_reply(&mut self.replies) => {
+                    tracing::debug!("Sending autoreply.");
+                    self.send_payload(MessagePayload::Text(reply), None).await?;
+                    continue;
+                }
+                _ = tokio::time::sleep(keepalive.unwrap_or_default()), if keepalive.is_some() => {
+                    tracing::debug!("Sending keepalive ping.");
+                    self.send(MessagePayload::Ping).await?;
+                    continue;
+                }
+            };
 
-            let cmd = Command::from_str(text.trim())?;
+            let Some(text) = line else {
+                // Input ended, finish the transfers that are in progress.
+                while let Some(chunk) = self.transfers.next_chunk() {
+                    self.send_payload(chunk, None).await?;
+                }
+                return Ok(());
+            };
 
-            if cmd == Command::Quit {
+            if !self.process_line(text.trim()).await? {
                 return Ok(());
             }
+        }
+    }
+
+    /// Processes one line of user input. Returns false when the user wants to quit.
+    async fn process_line(&mut self, line: &str) -> Result<bool> {
+        let outcome = self.handle_line(line).await;
+        // Without the compose mode the terminal gives the client only the whole line, so the others see the indicator
+        // right before the first message of a burst.
+        if self.draft.is_none()
+            && matches!(outcome, CommandOutcome::Send(MessagePayload::Text(_), _))
+            && self
+                .typing
+                .as_mut()
+                .is_some_and(TypingNotifier::should_send)
+        {
+            self.send_payload(MessagePayload::Typing, None).await?;
+        }
+        match outcome {
+            CommandOutcome::Send(data, ttl_seconds) => {
+                // The users of the new room don't know our key yet
+                let joins_room = matches!(data, MessagePayload::JoinRoom(_));
+                self.send_payload(data, ttl_seconds).await?;
+                if joins_room {
+                    self.announce_key = keys(&self.encryption).is_some();
+                }
+            }
+            CommandOutcome::Local(text) => println!("{}", text.trim_end()),
+            CommandOutcome::Failed(text) => eprintln!("{text}"),
+            CommandOutcome::Quit => return Ok(false),
+            CommandOutcome::Nothing => {}
+        }
+        Ok(true)
+    }
+
+    /// Handles the command on the line. Local state (display settings, draft, transfers) is changed here,
+    /// everything that is sent or shown to the user is returned in the outcome.
+    async fn handle_line(&mut self, line: &str) -> CommandOutcome {
+        let cmd = match Command::from_str(line) {
+            Ok(cmd) => cmd,
+            Err(e) => return CommandOutcome::Failed(format!("Cannot parse command. {e}")),
+        };
+
+        let cmd = match (cmd, &mut self.draft) {
+            (Command::Text(text), Some(draft)) => match draft.push_line(&text) {
+                Some(message) => {
+                    if let Some(typing) = &mut self.typing {
+                        typing.message_sent();
+                    }
+                    Command::Text(message)
+                }
+                None if self
+                    .typing
+                    .as_mut()
+                    .is_some_and(TypingNotifier::should_send) =>
+                {
+                    return CommandOutcome::Send(MessagePayload::Typing, None);
+                }
+                None => return CommandOutcome::Nothing,
+            },
+            (cmd, _) => cmd,
+        };
+
+        match cmd {
+            Command::Quit => return CommandOutcome::Quit,
+            Command::Help => return CommandOutcome::Local(HELP.to_string()),
+            // Display settings are handled locally and are not sent to the server.
+            Command::Timestamps(enabled) => {
+                self.display.set_timestamps(enabled);
+                return CommandOutcome::Nothing;
+            }
+            Command::Colors(enabled) => {
+                self.display.set_colors(enabled);
+                return CommandOutcome::Nothing;
+            }
+            // Preview only describes the file, nothing is sent.
+            Command::Preview(path) => {
+                return match preview_file(&path).await {
+                    Ok(preview) => CommandOutcome::Local(preview),
+                    Err(e) => CommandOutcome::Failed(format!("Cannot preview file. {e}")),
+                };
+            }
+            Command::SaveHistory(path) => {
+                let messages = self.display.history().messages();
+                return match save_file(&path, messages.concat().as_bytes()).await {
+                    Ok(()) => CommandOutcome::Local(format!(
+                        "Saved {} messages to {path}.",
+                        messages.len()
+                    )),
+                    Err(e) => CommandOutcome::Failed(format!("Cannot save history. {e}")),
+                };
+            }
+            Command::Attachments => {
+                return match list_attachments(&self.output_dir).await {
+                    Ok(attachments) => CommandOutcome::Local(attachments),
+                    Err(e) => CommandOutcome::Failed(format!("Cannot list attachments. {e}")),
+                };
+            }
+            Command::ResumeDraft => {
+                return match self.draft.as_mut().map(Draft::resume) {
+                    Some(0) => CommandOutcome::Local("There is no saved draft.".to_string()),
+                    Some(lines) => {
+                        CommandOutcome::Local(format!("Resumed {lines} lines of the draft."))
+                    }
+                    None => CommandOutcome::Failed(
+                        "Drafts are available only in the compose mode.".to_string(),
+                    ),
+                };
+            }
+            Command::Cancel(id) => {
+                return match self.transfers.cancel(&id) {
+                    true => CommandOutcome::Send(MessagePayload::FileCancel { id }, None),
+                    false => CommandOutcome::Failed(format!(
+                        "No file transfer in progress with id {id}."
+                    )),
+                };
+            }
+            _ => {}
+        }
+
+        let ttl_seconds = match &cmd {
+            Command::Temp(ttl_seconds, _) => Some(*ttl_seconds),
+            _ => None,
+        };
+        let data = match cmd.into_message(self.file_names).await {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("Cannot process command. {e}");
+                return CommandOutcome::Failed(format!("Cannot process command. {e}"));
+            }
+        };
 
-            let mut data = match cmd.into_message().await {
+        match data {
+            MessagePayload::File(name, bytes) if bytes.len() > CHUNK_SIZE => {
+                // Every chunk would fail to be encrypted
+                if keys(&self.encryption).is_some_and(|keys| !keys.has_peers()) {
+                    return CommandOutcome::Failed(ClientError::NoPeerKeys.to_string());
+                }
+                let transfer = OutgoingTransfer::new(name, bytes);
+                let text = format!(
+                    "Sending file, use `.cancel {}` to stop the transfer.",
+                    transfer.id()
+                );
+                self.transfers.push(transfer);
+                CommandOutcome::Local(text)
+            }
+            data => CommandOutcome::Send(data, ttl_seconds),
+        }
+    }
+
+    /// Encrypts the payload if E2E encryption is enabled and sends it. Messages with `ttl_seconds` are ephemeral.
+    async fn send_payload(
+        &mut self,
+        mut data: MessagePayload,
+        ttl_seconds: Option<u64>,
+    ) -> Result<()> {
+        if let Some(encryption) = &self.encryption {
+            data = match encryption.encrypt_payload(data) {
                 Ok(data) => data,
-                Err(e) => {
-                    tracing::error!("Cannot process command. {e}");
-                    eprintln!("Cannot process command. {e}");
-                    continue;
+                Err(e @ ClientError::NoPeerKeys) => {
+                    eprintln!("{e}");
+                    return Ok(());
                 }
+                Err(e) => return Err(e.into()),
+            };
+        }
+        let mut msg = Message::new(data);
+        msg.ttl_seconds = ttl_seconds;
+        msg.sender = self.sender_override.clone();
+        self.send_message(msg).await
+    }
+
+    /// Announces our public key to the room. With `reply` the peers answer with their keys.
+    async fn send_key(&mut self, reply: bool) -> Result<()> {
+        let Some(keys) = keys(&self.encryption) else {
+            return Ok(());
+        };
+        tracing::debug!("Announcing encryption key.");
+        let announcement = keys.announcement(reply);
+        self.send(announcement).await
+    }
+
+    /// Wraps the payload to a message and sends it to the server.
+    async fn send(&mut self, data: MessagePayload) -> Result<()> {
+        self.send_message(Message::new(data)).await
+    }
+
+    /// Sends the message to the server. The span carries the message id, so it can be matched with the receiving side in logs.
+    /// When the connection is lost, the message is kept and sent again once the receiver reconnects.
+    async fn send_message(&mut self, msg: Message) -> Result<()> {
+        let span = tracing::info_span!(
+            "Sending message",
+            message.id = %msg.id,
+            message.kind = msg.data.kind(),
+            message.size = msg.data.size(),
+        );
+
+        loop {
+            if !self.disconnected {
+                let sent = Message::send_framed_msg(&msg, &mut self.stream, self.framing)
+                    .instrument(span.clone())
+                    .await;
+                match sent {
+                    Ok(()) => return Ok(()),
+                    Err(e) if self.reconnects.is_none() => return Err(e.into()),
+                    Err(e) => {
+                        tracing::warn!("Sending failed, waiting for reconnect. {e}");
+                        self.disconnected = true;
+                    }
+                }
+            }
+            // The receiver gave up reconnecting
+            let Some(event) = next_event(&mut self.reconnects).await else {
+                return Err(ClientError::Disconnected.into());
             };
+            self.connection_changed(event);
+        }
+    }
 
-            if let Some(key) = self.encryption_key {
-                data = encrypt_payload(data, &key)?;
+    fn connection_changed(&mut self, event: ConnectionEvent<T>) {
+        match event {
+            ConnectionEvent::Lost => self.disconnected = true,
+            ConnectionEvent::Reconnected(stream, framing) => {
+                self.stream = stream;
+                self.framing = framing;
+                self.disconnected = false;
+                // Users that joined while we were away don't know our key
+                self.announce_key = keys(&self.encryption).is_some();
             }
+        }
+    }
+}
 
-            let msg = Message::new(data);
+/// Waits for the next change of the connection. Finishes with None right away when the client doesn't reconnect.
+async fn next_event<T>(
+    reconnects: &mut Option<UnboundedReceiver<ConnectionEvent<T>>>,
+) -> Option<ConnectionEvent<T>> {
+    reconnects.as_mut()?.recv().await
+}
 
-            Message::send_msg(&msg, &mut self.stream).await?;
+/// Keys exchanged with the other users, None without the encryption or with a passphrase.
+fn keys(encryption: &Option<E2eEncryption>) -> Option<&Arc<KeyExchange>> {
+    encryption.as_ref().and_then(E2eEncryption::keys)
+}
+
+/// Waits until a peer asks for our key. Never finishes without the key exchange.
+async fn key_requested(keys: Option<&Arc<KeyExchange>>) -> Option<()> {
+    match keys {
+        Some(keys) => {
+            keys.reply_requested().await;
+            Some(())
         }
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next autoreply. Never finishes when the autoreply mode is off.
+async fn next_reply(replies: &mut Option<UnboundedReceiver<String>>) -> Option<String> {
+    match replies {
+        Some(replies) => replies.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Returns false for payload types added in newer versions, so they are ignored. In the strict mode they are reported as an error.
+fn is_known_payload(payload: &MessagePayload, strict: bool) -> Result<bool, ClientError> {
+    match payload {
+        MessagePayload::Unknown(variant) if strict => Err(ClientError::UnknownPayload(*variant)),
+        MessagePayload::Unknown(variant) => {
+            tracing::debug!("Ignoring message of unknown type {variant}");
+            Ok(false)
+        }
+        _ => Ok(true),
     }
 }
 
@@ -160,7 +783,16 @@ pub struct ClientReceiver<T, U> {
     stream: T,
     writer: U,
     output_dir: String,
-    encryption_key: Option<[u8; 32]>,
+    encryption: Option<E2eEncryption>,
+    display: Arc<DisplaySettings>,
+    transfers: IncomingTransfers,
+    autoreply: Option<(AutoReply, UnboundedSender<String>)>,
+    /// Reports messages of unknown types as errors instead of ignoring them.
+    strict: bool,
+    framing: Framing,
+    /// Connects again when the connection is lost, `None` when the client doesn't reconnect.
+    reconnect: Option<(ReconnectPolicy, Box<dyn Reconnect<T>>)>,
+    typing: TypingUsers,
 }
 
 impl<T, U> ClientReceiver<T, U>
@@ -168,30 +800,148 @@ where
     T: AsyncRead + Unpin,
     U: AsyncWrite + Unpin,
 {
-    fn new(stream: T, writer: U, output_dir: &str, encryption_key: Option<[u8; 32]>) -> Self {
+    fn new(
+        stream: T,
+        writer: U,
+        output_dir: &str,
+        encryption: Option<E2eEncryption>,
+        display: Arc<DisplaySettings>,
+    ) -> Self {
         Self {
             stream,
             writer,
             output_dir: output_dir.to_string(),
-            encryption_key,
+            encryption,
+            display,
+            transfers: IncomingTransfers::new(output_dir),
+            typing: TypingUsers::default(),
+            autoreply: None,
+            strict: false,
+            framing: Framing::default(),
+            reconnect: None,
         }
     }
 
+    /// Sets the framing of received messages, it has to be negotiated with the server.
+    fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Connects again with the `reconnect` when the connection is lost, retried with a backoff by the `policy`.
+    fn reconnect(mut self, policy: ReconnectPolicy, reconnect: Box<dyn Reconnect<T>>) -> Self {
+        self.reconnect = Some((policy, reconnect));
+        self
+    }
+
+    /// Sets how many bytes of an incoming chunked file are kept in memory before it is written to disk.
+    pub fn reassembly_buffer(mut self, bytes: usize) -> Self {
+        self.transfers = self.transfers.memory_threshold(bytes);
+        self
+    }
+
+    /// Sets how long an incoming chunked file waits for its next chunk before it is discarded.
+    pub fn transfer_timeout(mut self, timeout: Duration) -> Self {
+        self.transfers = self.transfers.timeout(timeout);
+        self
+    }
+
+    /// Turns on the autoreply mode. Replies to matching messages are passed to the sender through the channel.
+    pub fn autoreply(mut self, autoreply: AutoReply, replies: UnboundedSender<String>) -> Self {
+        self.autoreply = Some((autoreply, replies));
+        self
+    }
+
+    /// Turns on the strict mode, messages of types added in newer versions are reported instead of ignored.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Renders the markdown of received texts with ANSI escape codes, the writer has to be a terminal.
+    pub fn render_markdown(self, enabled: bool) -> Self {
+        self.display.set_markdown(enabled);
+        self
+    }
+
     pub async fn start(mut self) -> Result<()> {
         tracing::debug!("starting receiver");
+        let mut expiry = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        expiry.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-        while let Ok(message) = Message::receive_msg(&mut self.stream).await {
+        loop {
+            // Reading the stream isn't cancel-safe, the same read is polled again after every check of the transfers
+            let received = {
+                let mut receive = pin!(Message::receive_framed_msg(&mut self.stream, self.framing));
+                loop {
+                    tokio::select! {
+                        received = &mut receive => break received,
+                        _ = expiry.tick() => {
+                            if let Err(e) = Self::expire_transfers(&mut self.transfers, &mut self.writer).await {
+                                tracing::error!("Failed to discard expired file transfers. {e}");
+                            }
+                            for username in self.typing.expired() {
+                                let text = format!("{username} stopped typing.\n");
+                                if let Err(e) = write_to_output(&mut self.writer, text.as_bytes()).await {
+                                    tracing::error!("Failed to show that a user stopped typing. {e}");
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            let message = match received {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::warn!("Receiving failed. {e}");
+                    match self.reconnect_with_backoff().await {
+                        true => continue,
+                        false => break,
+                    }
+                }
+            };
             tracing::debug!("received msg");
-            if let Err(e) = Self::handle_message(
-                message,
-                &mut self.writer,
-                &self.output_dir,
-                &self.encryption_key,
-            )
-            .await
-            {
-                tracing::error!("Error while handling message. {e}");
-                eprintln!("Error while handling message. {e}");
+            if let Some(sender) = &message.sender {
+                if message.data == MessagePayload::Typing {
+                    // Shown once, not with every indicator while the user keeps typing
+                    if self.typing.typing(sender) {
+                        let text = self.display.format_message(&message);
+                        if let Err(e) = write_to_output(&mut self.writer, text.as_bytes()).await {
+                            tracing::error!("Failed to show typing user. {e}");
+                        }
+                    }
+                    continue;
+                }
+                self.typing.stopped(sender);
+            }
+            let autoreply = self.autoreply.as_mut();
+            let handled = match is_known_payload(&message.data, self.strict) {
+                Ok(false) => continue,
+                Ok(true) => {
+                    Self::handle_message(
+                        message,
+                        &mut self.writer,
+                        &self.output_dir,
+                        &self.encryption,
+                        &self.display,
+                        &mut self.transfers,
+                        autoreply.map(|(rules, _)| rules),
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            match handled {
+                Ok(Some(reply)) => {
+                    if let Some((_, replies)) = &self.autoreply {
+                        _ = replies.send(reply);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!("Error while handling message. {e}");
+                    eprintln!("Error while handling message. {e}");
+                }
             }
         }
         tracing::debug!("receiver end");
@@ -199,16 +949,76 @@ where
         Ok(())
     }
 
+    /// Tries to connect again, waiting longer after every failed attempt.
+    /// Returns false when the client doesn't reconnect or all attempts failed.
+    async fn reconnect_with_backoff(&mut self) -> bool {
+        let Some((policy, reconnect)) = &mut self.reconnect else {
+            return false;
+        };
+        if !reconnect.lost() {
+            return false;
+        }
+        for attempt in 1..=policy.max_retries {
+            _ = write_to_output(&mut self.writer, b"Reconnecting...\n").await;
+            tokio::time::sleep(policy.delay(attempt)).await;
+            match reconnect.connect().await {
+                Ok((stream, framing)) => {
+                    self.stream = stream;
+                    self.framing = framing;
+                    _ = write_to_output(&mut self.writer, b"Reconnected.\n").await;
+                    return true;
+                }
+                Err(e) => tracing::warn!("Reconnect attempt {attempt} failed. {e}"),
+            }
+        }
+        _ = write_to_output(&mut self.writer, b"Couldn't reconnect to the server.\n").await;
+        // The sender stops waiting for a new connection
+        self.reconnect = None;
+        false
+    }
+
+    /// Discards chunked files whose chunks stopped coming and tells the user.
+    async fn expire_transfers(
+        transfers: &mut IncomingTransfers,
+        writer: &mut U,
+    ) -> Result<(), ClientError> {
+        for expired in transfers.expire().await? {
+            let text = format!(
+                "Receiving {} timed out, only {}/{} chunks arrived.\n",
+                expired.name, expired.received, expired.total
+            );
+            write_to_output(writer, text.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
     /// Handles the received message. It writes the message to the `writer`. If message ista if it is an image or a file.
-    #[tracing::instrument(name = "Handling message", skip_all)]
+    #[tracing::instrument(
+        name = "Handling message",
+        skip_all,
+        fields(
+            message.id = %message.id,
+            message.kind = message.data.kind(),
+            message.size = message.data.size(),
+        )
+    )]
     async fn handle_message(
         mut message: Message,
         writer: &mut U,
         output_dir: &str,
-        encryption_key: &Option<[u8; 32]>,
-    ) -> Result<(), ClientError> {
-        if let Some(key) = encryption_key {
-            let decrypted_data = match decrypt_payload(message.data, key) {
+        encryption: &Option<E2eEncryption>,
+        display: &DisplaySettings,
+        transfers: &mut IncomingTransfers,
+        autoreply: Option<&mut AutoReply>,
+    ) -> Result<Option<String>, ClientError> {
+        if let MessagePayload::KeyAnnounce { public_key, reply } = message.data {
+            if let (Some(keys), Some(sender)) = (keys(encryption), &message.sender) {
+                Self::key_announced(keys, sender, public_key, reply, writer).await?;
+            }
+            return Ok(None);
+        }
+        if let Some(encryption) = encryption {
+            let decrypted_data = match encryption.decrypt_payload(message.data) {
                 Ok(data) => data,
                 Err(e) => {
                     tracing::warn!("Decrypting payload error. {e}");
@@ -222,14 +1032,59 @@ where
                     )
                     .await?;
 
-                    return Ok(());
+                    return Ok(None);
                 }
             };
             message.data = decrypted_data;
         }
 
-        write_to_output(writer, message.to_string().as_bytes()).await?;
-        Self::store_data(message.data, writer, output_dir).await?;
+        let reply = autoreply.and_then(|autoreply| autoreply.reply_to(&message));
+
+        // Only the first chunk of a file is announced.
+        let announced = !matches!(message.data, MessagePayload::FileChunk { seq, .. } if seq > 0);
+        // The history is saved to files, so it keeps the line without colors and markdown styling
+        let plain = announced.then(|| display.format_plain(&message));
+
+        if let MessagePayload::Text(text) = &mut message.data {
+            if display.markdown() {
+                *text = render_markdown(text);
+            }
+        }
+
+        if let Some(plain) = plain {
+            let text = display.format_message(&message);
+            write_to_output(writer, text.as_bytes()).await?;
+            display.history().push(plain);
+        }
+        Self::store_data(message.data, writer, output_dir, transfers).await?;
+        Ok(reply)
+    }
+
+    /// Remembers the key of the user and tells about new ones. Asks the sender to answer when the user wants our key.
+    async fn key_announced(
+        keys: &KeyExchange,
+        sender: &str,
+        public_key: [u8; 32],
+        reply: bool,
+        writer: &mut U,
+    ) -> Result<(), ClientError> {
+        let text = match keys.add_peer(sender, public_key) {
+            Ok(KeyChange::New) => Some(format!("Exchanged encryption keys with {sender}.\n")),
+            Ok(KeyChange::Replaced) => Some(format!(
+                "{sender} has a new encryption key, older texts for {sender} can't be read by it.\n"
+            )),
+            Ok(KeyChange::Same) => None,
+            Err(e) => {
+                tracing::warn!("{e}");
+                Some(format!("{e}.\n"))
+            }
+        };
+        if let Some(text) = text {
+            write_to_output(writer, text.as_bytes()).await?;
+        }
+        if reply {
+            keys.request_reply();
+        }
         Ok(())
     }
 
@@ -238,6 +1093,7 @@ where
         message: MessagePayload,
         writer: &mut U,
         output_dir: &str,
+        transfers: &mut IncomingTransfers,
     ) -> Result<(), ClientError> {
         match message {
             MessagePayload::Image(data) => {
@@ -252,11 +1108,45 @@ where
                 .await?;
             }
             MessagePayload::File(file_name, data) => {
-                let file_path = format!("{}/files/{}", output_dir, file_name);
+                let file_path = format!("{}/files/{}", output_dir, sanitize_file_name(&file_name));
                 save_file(&file_path, &data).await?;
                 write_to_output(writer, format!("File saved to: {}\n", file_path).as_bytes())
                     .await?;
             }
+            MessagePayload::FileChunk {
+                transfer_id,
+                name,
+                seq,
+                total,
+                data,
+            } => match transfers
+                .receive_chunk(transfer_id, &name, seq, total, data)
+                .await?
+            {
+                Received::Partial {
+                    name,
+                    received,
+                    total,
+                } => {
+                    // Shown after every tenth of the chunks, so large files don't flood the output
+                    let step = |received: u32| u64::from(received) * 10 / u64::from(total);
+                    if step(received) != step(received - 1) {
+                        write_to_output(
+                            writer,
+                            format!("Receiving {name}: {received}/{total} chunks\n").as_bytes(),
+                        )
+                        .await?;
+                    }
+                }
+                Received::Complete(path) => {
+                    write_to_output(
+                        writer,
+                        format!("File saved to: {}\n", path.display()).as_bytes(),
+                    )
+                    .await?;
+                }
+            },
+            MessagePayload::FileCancel { id } => transfers.cancel(&id).await?,
             _ => {}
         }
         Ok(())
@@ -266,15 +1156,75 @@ where
 #[cfg(test)]
 mod tests {
 
-    use super::ClientReceiver;
+    use super::{connection_established, is_known_payload, Client, ClientReceiver, ClientSender};
+    use crate::autoreply::AutoReply;
+    use crate::client_error::ClientError;
+    use crate::command::CommandOutcome;
+    use crate::display::DisplaySettings;
+    use crate::encryption::E2eEncryption;
+    use crate::key_exchange::KeyExchange;
+    use crate::reconnect::{ConnectionEvent, Reconnect, ReconnectPolicy};
+    use crate::transfer::{IncomingTransfers, OutgoingTransfer, CHUNK_SIZE, EXPIRY_CHECK_INTERVAL};
+    use crate::typing::TypingUsers;
+    use shared::compression::{Compression, Framing};
 
+    use futures::future::BoxFuture;
     use shared::message::{Message, MessagePayload};
-    use tokio::io::AsyncWrite;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWrite};
     use tokio::net::{TcpListener, TcpStream};
 
+    use std::collections::HashMap;
     use std::io::Result as IoResult;
     use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
     use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+    use tracing_subscriber::{Layer, Registry};
+    use uuid::Uuid;
+
+    type CapturedSpans = Arc<Mutex<Vec<(String, HashMap<String, String>)>>>;
+
+    /// Tracing layer that stores names and fields of all created spans and, if set, of all events.
+    struct SpanCapture {
+        spans: CapturedSpans,
+        events: Option<CapturedSpans>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for SpanCapture {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: LayerContext<'_, S>) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            self.spans
+                .lock()
+                .unwrap()
+                .push((attrs.metadata().name().to_string(), fields));
+        }
+
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: LayerContext<'_, S>) {
+            let Some(events) = &self.events else {
+                return;
+            };
+            let mut fields = HashMap::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            events
+                .lock()
+                .unwrap()
+                .push((event.metadata().name().to_string(), fields));
+        }
+    }
 
     struct TestWriter {
         buf: Vec<u8>,
@@ -319,7 +1269,14 @@ mod tests {
             stream,
             writer: test_writer,
             output_dir: "./".to_string(),
-            encryption_key: None,
+            encryption: None,
+            display: Default::default(),
+            transfers: IncomingTransfers::new("./"),
+            autoreply: None,
+            strict: false,
+            framing: Framing::default(),
+            reconnect: None,
+            typing: TypingUsers::default(),
         };
 
         let payload = MessagePayload::Text("Hello world!".to_string());
@@ -344,4 +1301,582 @@ mod tests {
         //     String::from("Hello world!").as_bytes()
         // );
     }
+
+    /// Connects to the address again, without a login.
+    struct TestReconnect(SocketAddr);
+
+    impl Reconnect<TcpStream> for TestReconnect {
+        fn lost(&self) -> bool {
+            true
+        }
+
+        fn connect(&mut self) -> BoxFuture<'_, anyhow::Result<(TcpStream, Framing)>> {
+            Box::pin(async move { Ok((TcpStream::connect(self.0).await?, Framing::default())) })
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_transfer_expires_without_further_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (writer, mut output) = tokio::io::duplex(4096);
+        let receiver = ClientReceiver::new(stream, writer, "./", None, Default::default())
+            .transfer_timeout(Duration::from_millis(50));
+
+        let mut chunk = Message::new(MessagePayload::FileChunk {
+            transfer_id: Uuid::new_v4(),
+            name: "a.txt".into(),
+            seq: 0,
+            total: 2,
+            data: b"ab".to_vec(),
+        });
+        chunk.set_from_user("alice");
+        let (mut socket, _) = listener.accept().await.unwrap();
+        Message::send_msg(&chunk, &mut socket).await.unwrap();
+        // The connection stays open without another message until the transfer is checked again
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(EXPIRY_CHECK_INTERVAL + Duration::from_millis(500)).await;
+            drop(socket);
+        });
+        receiver.start().await.unwrap();
+        server.await.unwrap();
+
+        let mut text = String::new();
+        output.read_to_string(&mut text).await.unwrap();
+        assert_eq!(
+            text,
+            "alice is sending a file a.txt\nReceiving a.txt: 1/2 chunks\nReceiving a.txt timed out, only 1/2 chunks arrived.\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn receiver_reconnects_with_backoff_after_connection_is_lost() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (first, _) = listener.accept().await.unwrap();
+            drop(first);
+            let (mut second, _) = listener.accept().await.unwrap();
+            let msg = Message::new(MessagePayload::ServerInfo("after reconnect".into()));
+            Message::send_msg(&msg, &mut second).await.unwrap();
+            // Further attempts fail, nothing listens anymore
+        });
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (writer, mut output) = tokio::io::duplex(4096);
+        let policy = ReconnectPolicy {
+            max_retries: 2,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+        };
+        let receiver = ClientReceiver::new(stream, writer, "./", None, Default::default())
+            .reconnect(policy, Box::new(TestReconnect(address)));
+
+        receiver.start().await.unwrap();
+        server.await.unwrap();
+
+        let mut text = String::new();
+        output.read_to_string(&mut text).await.unwrap();
+        assert_eq!(
+            text,
+            "Reconnecting...\nReconnected.\n--      after reconnect      --\nReconnecting...\nReconnecting...\nCouldn't reconnect to the server.\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn message_is_sent_again_after_reconnect() {
+        let (lost, lost_peer) = tokio::io::duplex(1024);
+        drop(lost_peer);
+        let (new, mut new_peer) = tokio::io::duplex(1024);
+        let (events, reconnects) = tokio::sync::mpsc::unbounded_channel();
+        events
+            .send(ConnectionEvent::Reconnected(new, Framing::default()))
+            .unwrap();
+        let mut sender = ClientSender::new(lost, None, Default::default()).reconnects(reconnects);
+
+        assert!(sender.process_line("kept").await.unwrap());
+
+        let msg = Message::receive_msg(&mut new_peer).await.unwrap();
+        assert_eq!(msg.data, MessagePayload::Text("kept".to_string()));
+    }
+
+    #[tokio::test]
+    async fn connect_fails_with_unwritable_output_dir() {
+        let result = Client::connect(
+            Vec::new(),
+            "127.0.0.1".parse().unwrap(),
+            1,
+            "Cargo.toml/data",
+            None,
+            Compression::NONE,
+            ReconnectPolicy::new(0),
+        )
+        .await;
+
+        let error = result.err().expect("Connect should fail");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ClientError::OutputDirNotWritable(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_span_carries_message_fields() {
+        let spans: CapturedSpans = Default::default();
+        let subscriber = Registry::default().with(SpanCapture {
+            spans: spans.clone(),
+            events: None,
+        });
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default());
+        sender
+            .send(MessagePayload::Text("Hello".to_string()))
+            .await
+            .unwrap();
+
+        let sent = Message::receive_msg(&mut sender.stream.as_slice())
+            .await
+            .unwrap();
+
+        let spans = spans.lock().unwrap();
+        let (_, fields) = spans
+            .iter()
+            .find(|(name, _)| name == "Sending message")
+            .expect("Send span was not created");
+
+        assert_eq!(fields["message.id"], sent.id.to_string());
+        assert_eq!(fields["message.kind"], "\"text\"");
+        assert_eq!(fields["message.size"], "5");
+    }
+
+    #[tokio::test]
+    async fn composed_lines_are_sent_as_one_message() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default()).compose_mode(true);
+
+        assert!(sender.process_line("first line").await.unwrap());
+        assert!(sender.process_line("second line").await.unwrap());
+        assert!(sender.stream.is_empty());
+
+        assert!(sender.process_line(".send").await.unwrap());
+
+        let mut sent = sender.stream.as_slice();
+        let msg = Message::receive_msg(&mut sent).await.unwrap();
+        assert_eq!(
+            msg.data,
+            MessagePayload::Text("first line\nsecond line".to_string())
+        );
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn typing_is_sent_once_while_composing() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default())
+            .compose_mode(true)
+            .typing_indicator(true);
+
+        assert_eq!(
+            sender.handle_line("first line").await,
+            CommandOutcome::Send(MessagePayload::Typing, None)
+        );
+        assert_eq!(
+            sender.handle_line("second line").await,
+            CommandOutcome::Nothing
+        );
+        assert_eq!(
+            sender.handle_line(".send").await,
+            CommandOutcome::Send(
+                MessagePayload::Text("first line\nsecond line".to_string()),
+                None
+            )
+        );
+        assert_eq!(
+            sender.handle_line("next message").await,
+            CommandOutcome::Send(MessagePayload::Typing, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn typing_is_sent_before_text_lines() {
+        let mut sender =
+            ClientSender::new(Vec::new(), None, Default::default()).typing_indicator(true);
+
+        assert!(sender.process_line("hello").await.unwrap());
+        assert!(sender.process_line("there").await.unwrap());
+
+        let mut sent = sender.stream.as_slice();
+        for expected in [
+            MessagePayload::Typing,
+            MessagePayload::Text("hello".to_string()),
+            MessagePayload::Text("there".to_string()),
+        ] {
+            assert_eq!(
+                Message::receive_msg(&mut sent).await.unwrap().data,
+                expected
+            );
+        }
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keepalive_is_sent_when_idle() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default())
+            .keepalive(Some(Duration::from_millis(20)));
+        let (_lines_sender, mut lines) = tokio::sync::mpsc::unbounded_channel();
+
+        let result = tokio::time::timeout(Duration::from_millis(100), sender.run(&mut lines)).await;
+
+        assert!(result.is_err(), "sender should keep running without input");
+        let mut sent = sender.stream.as_slice();
+        let msg = Message::receive_msg(&mut sent).await.unwrap();
+        assert_eq!(msg.data, MessagePayload::Ping);
+    }
+
+    #[tokio::test]
+    async fn progress_of_chunked_file_is_shown() {
+        let dir = format!("./test_output_{}", uuid::Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir);
+        let mut transfer = OutgoingTransfer::new("big.bin".into(), vec![0; CHUNK_SIZE * 2 + 1]);
+        let mut output = Vec::new();
+
+        while let Some(chunk) = transfer.next_chunk() {
+            ClientReceiver::<TcpStream, Vec<u8>>::store_data(
+                chunk,
+                &mut output,
+                &dir,
+                &mut transfers,
+            )
+            .await
+            .unwrap();
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "Receiving big.bin: 1/3 chunks\nReceiving big.bin: 2/3 chunks\nFile saved to: {dir}/files/big.bin\n"
+            )
+        );
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_transfer_stops_sending_chunks() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default());
+        let transfer = OutgoingTransfer::new("big.bin".into(), vec![0; CHUNK_SIZE * 3]);
+        let id = transfer.id();
+        sender.transfers.push(transfer);
+
+        let chunk = sender.transfers.next_chunk().unwrap();
+        sender.send(chunk).await.unwrap();
+        assert!(sender.process_line(&format!(".cancel {id}")).await.unwrap());
+
+        let (lines_sender, mut lines) = tokio::sync::mpsc::unbounded_channel();
+        drop(lines_sender);
+        sender.run(&mut lines).await.unwrap();
+
+        let mut sent = sender.stream.as_slice();
+        let first = Message::receive_msg(&mut sent).await.unwrap();
+        assert!(matches!(
+            first.data,
+            MessagePayload::FileChunk { seq: 0, .. }
+        ));
+        let cancel = Message::receive_msg(&mut sent).await.unwrap();
+        assert_eq!(cancel.data, MessagePayload::FileCancel { id });
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn help_is_local_and_text_is_sent() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default());
+
+        assert!(matches!(
+            sender.handle_line(".help").await,
+            CommandOutcome::Local(help) if help.contains(".quit")
+        ));
+        assert_eq!(
+            sender.handle_line("hello").await,
+            CommandOutcome::Send(MessagePayload::Text("hello".to_string()), None)
+        );
+        assert!(sender.stream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn received_messages_are_saved_to_file() {
+        let display = Arc::new(DisplaySettings::default());
+        let mut transfers = IncomingTransfers::new("./");
+        for text in ["first", "second"] {
+            let mut message = Message::new(MessagePayload::Text(text.to_string()));
+            message.sender = Some("alice".to_string());
+            ClientReceiver::<&[u8], TestWriter>::handle_message(
+                message,
+                &mut TestWriter { buf: Vec::new() },
+                "./",
+                &None,
+                &display,
+                &mut transfers,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        let mut sender = ClientSender::new(Vec::new(), None, display);
+        let path = std::env::temp_dir().join(format!("history-{}.txt", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        assert_eq!(
+            sender.handle_line(&format!(".save-history {path}")).await,
+            CommandOutcome::Local(format!("Saved 2 messages to {path}."))
+        );
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "alice: first\nalice: second\n"
+        );
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(
+            sender.handle_line(".save-history").await,
+            CommandOutcome::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn history_keeps_messages_without_styling() {
+        let display = DisplaySettings::default();
+        display.set_colors(true);
+        display.set_markdown(true);
+        let mut message = Message::new(MessagePayload::Text("**hi**".to_string()));
+        message.sender = Some("alice".to_string());
+        let mut writer = TestWriter { buf: Vec::new() };
+
+        ClientReceiver::<&[u8], TestWriter>::handle_message(
+            message,
+            &mut writer,
+            "./",
+            &None,
+            &display,
+            &mut IncomingTransfers::new("./"),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(String::from_utf8(writer.buf).unwrap().contains("\x1b["));
+        assert_eq!(display.history().messages(), ["alice: **hi**\n"]);
+    }
+
+    #[tokio::test]
+    async fn attachments_are_listed_locally() {
+        let dir = std::env::temp_dir().join(format!("attachments-{}", uuid::Uuid::new_v4()));
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default())
+            .output_dir(dir.to_str().unwrap());
+
+        assert_eq!(
+            sender.handle_line(".attachments").await,
+            CommandOutcome::Local("No attachments received yet.\n".to_string())
+        );
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        std::fs::write(dir.join("files/a.txt"), "hello").unwrap();
+        assert!(matches!(
+            sender.handle_line(".attachments").await,
+            CommandOutcome::Local(list) if list.starts_with("files/a.txt  5 bytes  ")
+        ));
+        assert!(sender.stream.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn last_requests_history() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default());
+
+        assert_eq!(
+            sender.handle_line(".last 5").await,
+            CommandOutcome::Send(MessagePayload::HistoryRequest(5), None)
+        );
+        assert!(matches!(
+            sender.handle_line(".last many").await,
+            CommandOutcome::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn dm_is_sent_to_the_user() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default());
+
+        assert_eq!(
+            sender.handle_line(".dm alice hello there").await,
+            CommandOutcome::Send(
+                MessagePayload::DirectMessage {
+                    to: "alice".to_string(),
+                    text: "hello there".to_string()
+                },
+                None
+            )
+        );
+        assert!(matches!(
+            sender.handle_line(".dm alice").await,
+            CommandOutcome::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_moves_to_room() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default());
+
+        assert_eq!(
+            sender.handle_line(".join rust").await,
+            CommandOutcome::Send(MessagePayload::JoinRoom("rust".to_string()), None)
+        );
+        assert!(matches!(
+            sender.handle_line(".join").await,
+            CommandOutcome::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sender_override_is_set_on_sent_messages() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default())
+            .sender_override(Some("ghost".to_string()));
+
+        assert!(sender.process_line("hello").await.unwrap());
+
+        let msg = Message::receive_msg(&mut sender.stream.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(msg.sender.as_deref(), Some("ghost"));
+    }
+
+    #[tokio::test]
+    async fn preview_does_not_send_anything() {
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default());
+        let name = format!("preview-{}.txt", uuid::Uuid::new_v4());
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, "hello").unwrap();
+
+        let preview = sender
+            .handle_line(&format!(".preview {}", path.display()))
+            .await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            preview,
+            CommandOutcome::Local(format!("File: {name}\nSize: 5 bytes\nType: text\n"))
+        );
+        assert!(sender.process_line(".preview ./missing.txt").await.unwrap());
+        assert!(sender.stream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn matching_message_triggers_autoreply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (replies_sender, replies) = tokio::sync::mpsc::unbounded_channel();
+        let autoreply = AutoReply::new(vec!["ping=>pong {sender}".parse().unwrap()]);
+        let receiver = ClientReceiver::new(stream, Vec::new(), "./", None, Default::default())
+            .autoreply(autoreply, replies_sender);
+
+        let mut msg = Message::new(MessagePayload::Text("ping".into()));
+        msg.set_from_user("alice");
+        let (mut socket, _) = listener.accept().await.unwrap();
+        Message::send_msg(&msg, &mut socket).await.unwrap();
+        drop(socket);
+        receiver.start().await.unwrap();
+
+        let mut sender = ClientSender::new(Vec::new(), None, Default::default()).replies(replies);
+        let (_lines_sender, mut lines) = tokio::sync::mpsc::unbounded_channel();
+        _ = tokio::time::timeout(Duration::from_millis(100), sender.run(&mut lines)).await;
+
+        let mut sent = sender.stream.as_slice();
+        let reply = Message::receive_msg(&mut sent).await.unwrap();
+        assert_eq!(reply.data, MessagePayload::Text("[auto] pong alice".into()));
+    }
+
+    #[tokio::test]
+    async fn keys_are_exchanged_with_peers_that_announce_them() {
+        let encryption = E2eEncryption::key_exchange();
+        let bob = KeyExchange::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let receiver = ClientReceiver::new(
+            stream,
+            Vec::new(),
+            "./",
+            Some(encryption.clone()),
+            Default::default(),
+        );
+
+        let mut announcement = Message::new(bob.announcement(true));
+        announcement.set_from_user("bob");
+        let (mut socket, _) = listener.accept().await.unwrap();
+        Message::send_msg(&announcement, &mut socket).await.unwrap();
+        drop(socket);
+        receiver.start().await.unwrap();
+
+        let mut sender = ClientSender::new(Vec::new(), Some(encryption), Default::default());
+        let (_lines_sender, mut lines) = tokio::sync::mpsc::unbounded_channel();
+        _ = tokio::time::timeout(Duration::from_millis(100), sender.run(&mut lines)).await;
+        assert!(sender.process_line("hi bob").await.unwrap());
+
+        let mut sent = sender.stream.as_slice();
+        let joined = Message::receive_msg(&mut sent).await.unwrap();
+        assert!(matches!(
+            joined.data,
+            MessagePayload::KeyAnnounce { reply: true, .. }
+        ));
+        let answer = Message::receive_msg(&mut sent).await.unwrap();
+        assert!(matches!(
+            answer.data,
+            MessagePayload::KeyAnnounce { reply: false, .. }
+        ));
+        let text = Message::receive_msg(&mut sent).await.unwrap();
+        assert_eq!(
+            bob.decrypt_payload(text.data).unwrap(),
+            MessagePayload::Text("hi bob".into())
+        );
+    }
+
+    #[test]
+    fn unknown_payload_is_reported_only_in_strict_mode() {
+        let unknown = MessagePayload::Unknown(42);
+
+        assert!(matches!(
+            is_known_payload(&unknown, true),
+            Err(ClientError::UnknownPayload(42))
+        ));
+        assert!(matches!(is_known_payload(&unknown, false), Ok(false)));
+        assert!(matches!(
+            is_known_payload(&MessagePayload::Text("hi".into()), true),
+            Ok(true)
+        ));
+    }
+
+    #[test]
+    fn connection_established_event_describes_session() {
+        let events: CapturedSpans = Default::default();
+        let subscriber = Registry::default().with(SpanCapture {
+            spans: Default::default(),
+            events: Some(events.clone()),
+        });
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        connection_established("127.0.0.1:11111", "alice", Framing::default(), true);
+
+        let events = events.lock().unwrap();
+        let (_, fields) = events
+            .iter()
+            .find(|(_, fields)| {
+                fields
+                    .get("message")
+                    .is_some_and(|m| m == "Connection established")
+            })
+            .expect("Connection event was not emitted");
+        assert_eq!(fields["username"], "\"alice\"");
+        assert_eq!(fields["e2e_encryption"], "true");
+        assert_eq!(fields["compression"], "None");
+        assert_eq!(fields["connection_compression"], "false");
+    }
 }
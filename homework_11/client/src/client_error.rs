@@ -9,6 +9,12 @@ pub enum ClientError {
     EncryptMessage,
     #[error("Failed to decrypt message. {}",.0.as_deref().unwrap_or("No additional info"))]
     DecryptMessage(Option<String>),
+    #[error("Nobody in the room has announced an encryption key yet, the message wasn't sent")]
+    NoPeerKeys,
+    #[error("Encryption key announced by {0} can't be used")]
+    InvalidPublicKey(String),
+    #[error("Output directory {0} is not writable. {1}")]
+    OutputDirNotWritable(String, #[source] io::Error),
     #[error("Failed to create directory for output files. {0}")]
     CreateDir(#[source] io::Error),
     #[error("Failed to create file in output directory. {0}")]
@@ -19,6 +25,8 @@ pub enum ClientError {
     ReadFromFile(#[source] io::Error),
     #[error("Cannot send the file, file does not exist")]
     FileNotExists,
+    #[error("Cannot send the file, file name {0} is not valid UTF-8")]
+    NonUtf8FileName(String),
     #[error("Failed to convert image to png format")]
     ConvertImagePng,
     #[error("Cannot open image or image does not exist. {0}")]
@@ -27,4 +35,14 @@ pub enum ClientError {
     InvalidCommand,
     #[error("Login failed")]
     LoginFailed,
+    #[error("Server rejected the connection")]
+    ConnectionRejected,
+    #[error("Connection to the server was lost")]
+    Disconnected,
+    #[error("Received a message of unknown type {0}, the sender probably uses a newer version")]
+    UnknownPayload(u32),
+    #[error("Chunk {seq} of file {name} is out of range of its {total} chunks, the transfer was discarded")]
+    ChunkOutOfRange { name: String, seq: u32, total: u32 },
+    #[error("Too many chunks of file {0} arrived out of order, the transfer was discarded")]
+    TooManyPendingChunks(String),
 }
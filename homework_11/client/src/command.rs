@@ -1,9 +1,10 @@
 use crate::{
     client_error::ClientError,
-    utils::{get_file, get_image},
+    utils::{get_file, get_image, FileNamePolicy},
 };
 use shared::message::MessagePayload;
 use std::str::FromStr;
+use uuid::Uuid;
 
 /// User commands.
 #[derive(PartialEq)]
@@ -11,16 +12,87 @@ pub enum Command {
     Text(String),
     File(String),
     Image(String),
+    Timestamps(bool),
+    Colors(bool),
+    Cancel(Uuid),
+    Preview(String),
+    Attachments,
+    /// Continues the draft of the compose mode saved by the previous run.
+    ResumeDraft,
+    Status,
+    /// Asks the server who is connected.
+    List,
+    Rename(String),
+    /// Name shown with our messages while we are connected.
+    Nick(String),
+    /// Asks the server for the given number of last messages.
+    Last(u32),
+    /// Moves the user to another chat room.
+    Join(String),
+    /// Private text for the user.
+    Dm(String, String),
+    /// Ephemeral text that disappears from the history after the given number of seconds.
+    Temp(u64, String),
+    /// Writes the last shown messages to the file.
+    SaveHistory(String),
+    Help,
     Quit,
 }
 
+/// What the sender does after a command was handled.
+#[derive(Debug, PartialEq)]
+pub enum CommandOutcome {
+    /// The payload is sent to the server, with `ttl_seconds` as an ephemeral message.
+    Send(MessagePayload, Option<u64>),
+    /// Text shown only to the user.
+    Local(String),
+    /// Error shown to the user, nothing is sent.
+    Failed(String),
+    Quit,
+    /// The command only changed the local state, e.g. a line was added to the draft.
+    Nothing,
+}
+
+/// Text of the `.help` command.
+pub const HELP: &str = "\
+.file <FILE_PATH>       Send a file.
+.image <IMAGE_PATH>     Send an image, it is converted to .png.
+.timestamps <on|off>    Show or hide time of the messages.
+.colors <on|off>        Turn colored output on or off.
+.cancel <TRANSFER_ID>   Stop sending a file.
+.rename <NEW_NAME>      Change your username.
+.nick <NAME>            Change the name shown with your messages until you disconnect.
+.temp <SECONDS> <TEXT>  Send a text that disappears from the history after the given time.
+.last <N>               Show the last N messages of the chat.
+.join <ROOM>            Move to another chat room.
+.dm <USER> <TEXT>       Send a private text to the user.
+.resume-draft           Continue the draft saved by the last run.
+.attachments            List received files and images.
+.save-history <PATH>    Save the last shown messages to a file.
+.status                 Show the server status.
+.list                   Show the users that are online.
+.preview <FILE_PATH>    Show name, size and type of a file without sending it.
+.help                   Show this help.
+.quit                   Disconnect and exit.
+";
+
 impl Command {
-    pub async fn into_message(self) -> Result<MessagePayload, ClientError> {
+    pub async fn into_message(
+        self,
+        file_names: FileNamePolicy,
+    ) -> Result<MessagePayload, ClientError> {
         match self {
-            Command::Text(text) => Ok(MessagePayload::Text(text.to_owned())),
-            Command::File(path) => get_file_message(&path).await,
+            Command::Text(text) | Command::Temp(_, text) => Ok(MessagePayload::Text(text)),
+            Command::File(path) => get_file_message(&path, file_names).await,
             Command::Image(path) => get_image_message(&path).await,
-            Command::Quit => Err(ClientError::InvalidCommand),
+            Command::Status => Ok(MessagePayload::StatusRequest),
+            Command::List => Ok(MessagePayload::ListUsers),
+            Command::Rename(name) => Ok(MessagePayload::Rename(name)),
+            Command::Nick(name) => Ok(MessagePayload::Nick(name)),
+            Command::Last(count) => Ok(MessagePayload::HistoryRequest(count)),
+            Command::Join(room) => Ok(MessagePayload::JoinRoom(room)),
+            Command::Dm(to, text) => Ok(MessagePayload::DirectMessage { to, text }),
+            _ => Err(ClientError::InvalidCommand),
         }
     }
 }
@@ -50,14 +122,70 @@ impl FromStr for Command {
         match first_arg {
             ".file" => Ok(Command::File(second_arg.to_string())),
             ".image" => Ok(Command::Image(second_arg.to_string())),
+            ".timestamps" => Ok(Command::Timestamps(parse_switch(second_arg)?)),
+            ".colors" => Ok(Command::Colors(parse_switch(second_arg)?)),
+            ".cancel" => Uuid::parse_str(second_arg.trim())
+                .map(Command::Cancel)
+                .map_err(|_| ClientError::InvalidCommand),
+            ".preview" => Ok(Command::Preview(second_arg.to_string())),
+            ".attachments" => Ok(Command::Attachments),
+            ".resume-draft" => Ok(Command::ResumeDraft),
+            ".save-history" => match second_arg.trim() {
+                "" => Err(ClientError::InvalidCommand),
+                path => Ok(Command::SaveHistory(path.to_string())),
+            },
+            ".status" => Ok(Command::Status),
+            ".list" => Ok(Command::List),
+            ".temp" => match second_arg.split_once(' ') {
+                Some((seconds, text)) if !text.trim().is_empty() => seconds
+                    .parse()
+                    .map(|seconds| Command::Temp(seconds, text.to_string()))
+                    .map_err(|_| ClientError::InvalidCommand),
+                _ => Err(ClientError::InvalidCommand),
+            },
+            ".rename" => match second_arg.trim() {
+                "" => Err(ClientError::InvalidCommand),
+                name => Ok(Command::Rename(name.to_string())),
+            },
+            ".nick" => match second_arg.trim() {
+                "" => Err(ClientError::InvalidCommand),
+                name => Ok(Command::Nick(name.to_string())),
+            },
+            ".last" => second_arg
+                .trim()
+                .parse()
+                .map(Command::Last)
+                .map_err(|_| ClientError::InvalidCommand),
+            ".dm" => match second_arg.split_once(' ') {
+                Some((to, text)) if !to.is_empty() && !text.trim().is_empty() => {
+                    Ok(Command::Dm(to.to_string(), text.to_string()))
+                }
+                _ => Err(ClientError::InvalidCommand),
+            },
+            ".join" => match second_arg.trim() {
+                "" => Err(ClientError::InvalidCommand),
+                room => Ok(Command::Join(room.to_string())),
+            },
+            ".help" => Ok(Command::Help),
             ".quit" => Ok(Command::Quit),
             _ => Ok(Command::Text(s.to_string())),
         }
     }
 }
 
-async fn get_file_message(path: &str) -> Result<MessagePayload, ClientError> {
-    let (name, data) = get_file(path).await?;
+fn parse_switch(value: &str) -> Result<bool, ClientError> {
+    match value.trim() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(ClientError::InvalidCommand),
+    }
+}
+
+async fn get_file_message(
+    path: &str,
+    file_names: FileNamePolicy,
+) -> Result<MessagePayload, ClientError> {
+    let (name, data) = get_file(path, file_names).await?;
     Ok(MessagePayload::File(name, data))
 }
 
@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+
+/// Line that sends the composed message.
+pub const SEND: &str = ".send";
+/// Line that adds a literal `.send` line to the message.
+const ESCAPED_SEND: &str = "\\.send";
+
+/// Multi-line message that is being composed. Lines are collected until the `.send` line.
+/// A persisted draft is written to a file on every line, so it survives a crash of the client.
+#[derive(Default)]
+pub struct Draft {
+    lines: Vec<String>,
+    path: Option<PathBuf>,
+    /// Lines of the draft saved by the previous run that were not resumed yet.
+    saved: Vec<String>,
+}
+
+impl Draft {
+    /// Creates a draft that is saved to the file. Lines already saved there can be resumed.
+    pub fn persisted(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(content) => content.lines().map(str::to_string).collect(),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("Failed to read the saved draft. {e}");
+                }
+                Vec::new()
+            }
+        };
+        Self {
+            lines: Vec::new(),
+            path: Some(path),
+            saved,
+        }
+    }
+
+    /// Number of lines saved by the previous run that can be resumed.
+    pub fn saved_lines(&self) -> usize {
+        self.saved.len()
+    }
+
+    /// Puts the saved lines before the lines written since the start. Returns the number of resumed lines.
+    pub fn resume(&mut self) -> usize {
+        let mut lines = std::mem::take(&mut self.saved);
+        let resumed = lines.len();
+        lines.append(&mut self.lines);
+        self.lines = lines;
+        resumed
+    }
+
+    /// Adds the line to the draft. Returns the whole message when the line is `.send` and the draft is not empty.
+    pub fn push_line(&mut self, line: &str) -> Option<String> {
+        let message = match line {
+            SEND if self.lines.is_empty() => return None,
+            SEND => Some(std::mem::take(&mut self.lines).join("\n")),
+            ESCAPED_SEND => {
+                self.lines.push(SEND.to_string());
+                None
+            }
+            _ => {
+                self.lines.push(line.to_string());
+                None
+            }
+        };
+        self.persist();
+        message
+    }
+
+    /// Writes the unsent lines to the file, the file is removed when there is nothing to keep.
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = match self.saved.is_empty() && self.lines.is_empty() {
+            true => std::fs::remove_file(path).or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            }),
+            false => {
+                let content: String = self
+                    .saved
+                    .iter()
+                    .chain(&self.lines)
+                    .map(|line| format!("{line}\n"))
+                    .collect();
+                std::fs::write(path, content)
+            }
+        };
+        if let Err(e) = result {
+            tracing::warn!("Failed to save the draft. {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_joined_on_send() {
+        let mut draft = Draft::default();
+
+        assert_eq!(draft.push_line("first"), None);
+        assert_eq!(draft.push_line("second"), None);
+        assert_eq!(draft.push_line(SEND), Some("first\nsecond".to_string()));
+        assert_eq!(draft.push_line(SEND), None);
+    }
+
+    #[test]
+    fn escaped_send_is_kept_as_text() {
+        let mut draft = Draft::default();
+
+        assert_eq!(draft.push_line("\\.send"), None);
+        assert_eq!(draft.push_line(SEND), Some(".send".to_string()));
+    }
+
+    #[test]
+    fn persisted_draft_is_resumed_after_restart() {
+        let path = format!("./test_draft_{}.txt", uuid::Uuid::new_v4());
+        let mut draft = Draft::persisted(&path);
+        draft.push_line("first");
+        draft.push_line("second");
+        drop(draft);
+
+        let mut draft = Draft::persisted(&path);
+        assert_eq!(draft.saved_lines(), 2);
+        draft.push_line("third");
+        assert_eq!(draft.resume(), 2);
+        assert_eq!(
+            draft.push_line(SEND),
+            Some("first\nsecond\nthird".to_string())
+        );
+
+        assert!(!std::path::Path::new(&path).exists());
+        assert_eq!(Draft::persisted(&path).saved_lines(), 0);
+    }
+}
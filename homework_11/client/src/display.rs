@@ -0,0 +1,218 @@
+use crate::history::MessageHistory;
+use chrono::{Local, TimeZone, Utc};
+use shared::message::{Message, MessagePayload};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const BOLD_CYAN: &str = "\x1b[1;36m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+const BOLD: (&str, &str) = ("\x1b[1m", "\x1b[22m");
+const ITALIC: (&str, &str) = ("\x1b[3m", "\x1b[23m");
+const CODE: (&str, &str) = ("\x1b[7m", "\x1b[27m");
+
+/// Display preferences shared between `ClientSender` (which changes them by commands) and `ClientReceiver` (which formats the messages).
+/// The receiver also keeps the shown messages here, so the sender can save them.
+#[derive(Default)]
+pub struct DisplaySettings {
+    timestamps: AtomicBool,
+    colors: AtomicBool,
+    markdown: AtomicBool,
+    history: MessageHistory,
+}
+
+impl DisplaySettings {
+    pub fn set_timestamps(&self, enabled: bool) {
+        self.timestamps.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_colors(&self, enabled: bool) {
+        self.colors.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_markdown(&self, enabled: bool) {
+        self.markdown.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether texts are rendered with `render_markdown`.
+    pub fn markdown(&self) -> bool {
+        self.markdown.load(Ordering::Relaxed)
+    }
+
+    /// Last messages shown to the user.
+    pub fn history(&self) -> &MessageHistory {
+        &self.history
+    }
+
+    /// Formats the message for the output based on the current settings.
+    pub fn format_message(&self, message: &Message) -> String {
+        self.format(message, self.colors.load(Ordering::Relaxed))
+    }
+
+    /// Formats the message like `format_message`, but without colors, e.g. for saving it to a file.
+    pub fn format_plain(&self, message: &Message) -> String {
+        self.format(message, false)
+    }
+
+    fn format(&self, message: &Message, colors: bool) -> String {
+        let mut line = String::new();
+
+        if self.timestamps.load(Ordering::Relaxed) {
+            if let Some(time) = format_timestamp(message.timestamp) {
+                line.push_str(&format!("[{time}] "));
+            }
+        }
+
+        if !colors {
+            line.push_str(&message.to_string());
+            return with_expiry_hint(line, message);
+        }
+
+        match &message.data {
+            MessagePayload::Text(text) => line.push_str(&format!(
+                "{BOLD_CYAN}{}{RESET}: {text}\n",
+                message.sender.as_deref().unwrap_or("anonymous")
+            )),
+            MessagePayload::ServerInfo(_)
+            | MessagePayload::ActiveUsers(_)
+            | MessagePayload::StatusResponse(_) => line.push_str(&format!(
+                "{YELLOW}{}{RESET}\n",
+                message.to_string().trim_end()
+            )),
+            _ => line.push_str(&message.to_string()),
+        }
+        with_expiry_hint(line, message)
+    }
+}
+
+/// Renders `**bold**`, `*italics*` and `` `code` `` with ANSI escape codes. Markers without a pair, or with whitespace
+/// right inside them like in `2 * 3 * 4`, are kept as they are. Code is shown verbatim.
+pub fn render_markdown(text: &str) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['*', '`']) {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        let (marker, (on, off)) = if tail.starts_with("**") {
+            ("**", BOLD)
+        } else if tail.starts_with('*') {
+            ("*", ITALIC)
+        } else {
+            ("`", CODE)
+        };
+        let inner = &tail[marker.len()..];
+        let content = inner
+            .find(marker)
+            .map(|end| &inner[..end])
+            .filter(|content| {
+                !content.is_empty()
+                    && (marker == "`"
+                        || !(content.starts_with(char::is_whitespace)
+                            || content.ends_with(char::is_whitespace)))
+            });
+        match content {
+            Some(content) => {
+                rendered.push_str(on);
+                match marker {
+                    "`" => rendered.push_str(content),
+                    _ => rendered.push_str(&render_markdown(content)),
+                }
+                rendered.push_str(off);
+                rest = &inner[content.len() + marker.len()..];
+            }
+            None => {
+                rendered.push_str(marker);
+                rest = inner;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Tells how long an ephemeral message stays in the history.
+fn with_expiry_hint(mut line: String, message: &Message) -> String {
+    let Some(expires_at) = message.expires_at() else {
+        return line;
+    };
+    let remaining = expires_at.saturating_sub(Utc::now().timestamp()).max(0);
+    line.truncate(line.trim_end().len());
+    line.push_str(&format!(" (disappears in {remaining}s)\n"));
+    line
+}
+
+/// Formats epoch seconds as a local time. Returns None for zero or invalid timestamps.
+fn format_timestamp(timestamp: i64) -> Option<String> {
+    if timestamp <= 0 {
+        return None;
+    }
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|time| time.format("%H:%M:%S").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_can_be_toggled() {
+        let settings = DisplaySettings::default();
+        let message = Message::new(MessagePayload::Text("hi".to_string()));
+
+        settings.set_timestamps(true);
+        assert!(settings.format_message(&message).starts_with('['));
+
+        settings.set_timestamps(false);
+        assert_eq!(settings.format_message(&message), "anonymous: hi\n");
+    }
+
+    #[test]
+    fn colors_wrap_sender_name() {
+        let settings = DisplaySettings::default();
+        let mut message = Message::new(MessagePayload::Text("hi".to_string()));
+        message.set_from_user("alice");
+
+        settings.set_colors(true);
+
+        assert_eq!(
+            settings.format_message(&message),
+            format!("{BOLD_CYAN}alice{RESET}: hi\n")
+        );
+    }
+
+    #[test]
+    fn markdown_is_rendered_with_ansi_codes() {
+        let (bold, unbold) = BOLD;
+        let (italic, unitalic) = ITALIC;
+        let (code, uncode) = CODE;
+
+        for (text, expected) in [
+            ("plain text", "plain text".to_string()),
+            ("**hi** there", format!("{bold}hi{unbold} there")),
+            (
+                "*very* **much**",
+                format!("{italic}very{unitalic} {bold}much{unbold}"),
+            ),
+            (
+                "run `cargo *test*`",
+                format!("run {code}cargo *test*{uncode}"),
+            ),
+            (
+                "**bold `code`**",
+                format!("{bold}bold {code}code{uncode}{unbold}"),
+            ),
+            ("2 * 3 * 4", "2 * 3 * 4".to_string()),
+            ("**unclosed and `", "**unclosed and `".to_string()),
+            ("a ** b", "a ** b".to_string()),
+        ] {
+            assert_eq!(render_markdown(text), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn invalid_timestamp_is_skipped() {
+        assert!(format_timestamp(0).is_none());
+        assert!(format_timestamp(i64::MAX).is_none());
+    }
+}
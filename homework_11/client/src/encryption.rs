@@ -1,17 +1,60 @@
 #![allow(dead_code)]
 #![deny(missing_docs)]
 use crate::client_error::ClientError;
+use crate::key_exchange::KeyExchange;
 use aes_gcm::{
     aead::{Aead, AeadCore, KeyInit, OsRng},
     Aes256Gcm, Key, Nonce,
 };
 use base64::{engine::general_purpose, Engine};
 use shared::message::MessagePayload;
+use std::sync::Arc;
 
-/// Provides encryption and decryption functions
+// Provides encryption and decryption functions
 
 pub const NONCE_SIZE: usize = 12;
 
+/// How texts are encrypted end-to-end.
+#[derive(Clone)]
+pub enum E2eEncryption {
+    /// Everybody uses the same key made of a passphrase agreed on out of band.
+    Passphrase([u8; 32]),
+    /// Keys are exchanged with the other users of the room.
+    KeyExchange(Arc<KeyExchange>),
+}
+
+impl E2eEncryption {
+    pub fn passphrase(passphrase: &str) -> Self {
+        E2eEncryption::Passphrase(pad_to_32_bytes(passphrase.as_bytes()))
+    }
+
+    pub fn key_exchange() -> Self {
+        E2eEncryption::KeyExchange(Arc::new(KeyExchange::new()))
+    }
+
+    pub fn encrypt_payload(&self, data: MessagePayload) -> Result<MessagePayload, ClientError> {
+        match self {
+            E2eEncryption::Passphrase(key) => encrypt_payload(data, key),
+            E2eEncryption::KeyExchange(keys) => keys.encrypt_payload(data),
+        }
+    }
+
+    pub fn decrypt_payload(&self, data: MessagePayload) -> Result<MessagePayload, ClientError> {
+        match self {
+            E2eEncryption::Passphrase(key) => decrypt_payload(data, key),
+            E2eEncryption::KeyExchange(keys) => keys.decrypt_payload(data),
+        }
+    }
+
+    /// Keys of the users, None with a passphrase.
+    pub fn keys(&self) -> Option<&Arc<KeyExchange>> {
+        match self {
+            E2eEncryption::Passphrase(_) => None,
+            E2eEncryption::KeyExchange(keys) => Some(keys),
+        }
+    }
+}
+
 pub fn encrypt(key: &[u8], message: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ClientError> {
     // key needs to be 32 bytes, otherwise from_slice panics
     let key = Key::<Aes256Gcm>::from_slice(key);
@@ -46,39 +89,119 @@ pub fn pad_to_32_bytes(input: &[u8]) -> [u8; 32] {
     padded
 }
 
+/// Encrypts the content of texts, images and files. The nonce is prepended to the ciphertext.
 pub fn encrypt_payload(data: MessagePayload, key: &[u8]) -> Result<MessagePayload, ClientError> {
-    if let MessagePayload::Text(text) = data {
-        let (encrypted_msg, nonce) = encrypt(key, text.as_bytes())?;
-
-        let mut message_to_send = nonce;
-        message_to_send.extend_from_slice(&encrypted_msg);
-        let encoded = general_purpose::STANDARD.encode(&message_to_send);
-        Ok(MessagePayload::Text(encoded))
-    } else {
-        Ok(data)
-    }
+    encrypt_content(data, |content| seal(key, content))
 }
 
 pub fn decrypt_payload(
     data: MessagePayload,
     encryption_key: &[u8],
 ) -> Result<MessagePayload, ClientError> {
-    if let MessagePayload::Text(text) = data {
-        let decoded = general_purpose::STANDARD
-            .decode(text.as_bytes())
-            .map_err(|_| {
-                ClientError::DecryptMessage(Some(
-                    "Error while decoding message from base64".to_string(),
-                ))
-            })?;
-        let nonce = &decoded[..NONCE_SIZE];
-        let encrypted_msg = &decoded[NONCE_SIZE..];
-        let decrypted = decrypt(encryption_key, nonce, encrypted_msg)?;
-        let text = String::from_utf8(decrypted).map_err(|_| ClientError::DecryptMessage(None))?;
-        Ok(MessagePayload::Text(text))
-    } else {
-        Ok(data)
+    decrypt_content(data, |sealed| open(encryption_key, sealed))
+}
+
+/// Encrypts the content with a random nonce and prepends the nonce to the ciphertext.
+fn seal(key: &[u8], content: &[u8]) -> Result<Vec<u8>, ClientError> {
+    let (encrypted, nonce) = encrypt(key, content)?;
+    let mut sealed = nonce;
+    sealed.extend_from_slice(&encrypted);
+    Ok(sealed)
+}
+
+fn open(key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, ClientError> {
+    if sealed.len() < NONCE_SIZE {
+        return Err(ClientError::DecryptMessage(Some(
+            "Encrypted content is shorter than the nonce".to_string(),
+        )));
     }
+    let (nonce, encrypted) = sealed.split_at(NONCE_SIZE);
+    decrypt(key, nonce, encrypted)
+}
+
+/// Encrypts the bytes of texts, direct messages, images, files and file chunks with `seal`. Encrypted texts are encoded to base64.
+/// File names and recipients of direct messages stay in the clear, so the server can deliver them and the receiver
+/// can show and join the chunks, other payloads are not encrypted.
+pub fn encrypt_content(
+    data: MessagePayload,
+    seal: impl Fn(&[u8]) -> Result<Vec<u8>, ClientError>,
+) -> Result<MessagePayload, ClientError> {
+    let encrypted = match data {
+        MessagePayload::Text(text) => MessagePayload::Text(seal_text(&text, &seal)?),
+        MessagePayload::DirectMessage { to, text } => MessagePayload::DirectMessage {
+            text: seal_text(&text, &seal)?,
+            to,
+        },
+        MessagePayload::Image(data) => MessagePayload::Image(seal(&data)?),
+        MessagePayload::File(name, data) => MessagePayload::File(name, seal(&data)?),
+        MessagePayload::FileChunk {
+            transfer_id,
+            name,
+            seq,
+            total,
+            data,
+        } => MessagePayload::FileChunk {
+            transfer_id,
+            name,
+            seq,
+            total,
+            data: seal(&data)?,
+        },
+        data => data,
+    };
+    Ok(encrypted)
+}
+
+/// Decrypts what `encrypt_content` encrypted with `open`.
+pub fn decrypt_content(
+    data: MessagePayload,
+    open: impl Fn(&[u8]) -> Result<Vec<u8>, ClientError>,
+) -> Result<MessagePayload, ClientError> {
+    let decrypted = match data {
+        MessagePayload::Text(text) => MessagePayload::Text(open_text(&text, &open)?),
+        MessagePayload::DirectMessage { to, text } => MessagePayload::DirectMessage {
+            text: open_text(&text, &open)?,
+            to,
+        },
+        MessagePayload::Image(data) => MessagePayload::Image(open(&data)?),
+        MessagePayload::File(name, data) => MessagePayload::File(name, open(&data)?),
+        MessagePayload::FileChunk {
+            transfer_id,
+            name,
+            seq,
+            total,
+            data,
+        } => MessagePayload::FileChunk {
+            transfer_id,
+            name,
+            seq,
+            total,
+            data: open(&data)?,
+        },
+        data => data,
+    };
+    Ok(decrypted)
+}
+
+fn seal_text(
+    text: &str,
+    seal: impl Fn(&[u8]) -> Result<Vec<u8>, ClientError>,
+) -> Result<String, ClientError> {
+    Ok(general_purpose::STANDARD.encode(seal(text.as_bytes())?))
+}
+
+fn open_text(
+    text: &str,
+    open: impl Fn(&[u8]) -> Result<Vec<u8>, ClientError>,
+) -> Result<String, ClientError> {
+    let decoded = general_purpose::STANDARD
+        .decode(text.as_bytes())
+        .map_err(|_| {
+            ClientError::DecryptMessage(Some(
+                "Error while decoding message from base64".to_string(),
+            ))
+        })?;
+    String::from_utf8(open(&decoded)?).map_err(|_| ClientError::DecryptMessage(None))
 }
 
 #[cfg(test)]
@@ -108,4 +231,74 @@ mod tests {
 
         assert_eq!(text, decrypted_message.as_slice());
     }
+
+    #[test]
+    fn image_and_file_content_is_encrypted() {
+        let key = pad_to_32_bytes(b"my_scrt_key");
+        let image = b"\x89PNG\r\n\x1a\nimage data".to_vec();
+        let file = b"%PDF-1.4 file data".to_vec();
+
+        let encrypted_image = encrypt_payload(MessagePayload::Image(image.clone()), &key).unwrap();
+        let encrypted_file = encrypt_payload(
+            MessagePayload::File("report.pdf".into(), file.clone()),
+            &key,
+        )
+        .unwrap();
+
+        let MessagePayload::Image(ref sealed) = encrypted_image else {
+            panic!("payload type changed");
+        };
+        assert_eq!(sealed.len(), NONCE_SIZE + image.len() + 16);
+        assert!(!sealed.windows(4).any(|bytes| bytes == b"\x89PNG"));
+        assert!(matches!(
+            encrypted_file,
+            MessagePayload::File(ref name, ref sealed) if name == "report.pdf" && !sealed.starts_with(b"%PDF")
+        ));
+
+        assert_eq!(
+            decrypt_payload(encrypted_image, &key).unwrap(),
+            MessagePayload::Image(image)
+        );
+        assert_eq!(
+            decrypt_payload(encrypted_file, &key).unwrap(),
+            MessagePayload::File("report.pdf".into(), file)
+        );
+    }
+
+    #[test]
+    fn direct_message_text_is_encrypted() {
+        let key = pad_to_32_bytes(b"my_scrt_key");
+        let dm = || MessagePayload::DirectMessage {
+            to: "bob".into(),
+            text: "secret".into(),
+        };
+
+        let encrypted = encrypt_payload(dm(), &key).unwrap();
+
+        let MessagePayload::DirectMessage { ref to, ref text } = encrypted else {
+            panic!("payload type changed");
+        };
+        assert_eq!(to, "bob");
+        assert_ne!(text, "secret");
+        assert_eq!(decrypt_payload(encrypted, &key).unwrap(), dm());
+    }
+
+    #[test]
+    fn attachment_with_wrong_key_is_not_decrypted() {
+        let key = pad_to_32_bytes(b"my_scrt_key");
+        let other_key = pad_to_32_bytes(b"other_key");
+        let file = MessagePayload::File("notes.txt".into(), b"notes".to_vec());
+
+        let encrypted = encrypt_payload(file, &key).unwrap();
+
+        assert!(matches!(
+            decrypt_payload(encrypted, &other_key),
+            Err(ClientError::DecryptMessage(_))
+        ));
+        // Shorter than the nonce, e.g. sent without the encryption
+        assert!(matches!(
+            decrypt_payload(MessagePayload::Image(vec![1, 2, 3]), &key),
+            Err(ClientError::DecryptMessage(_))
+        ));
+    }
 }
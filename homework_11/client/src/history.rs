@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// How many of the last shown messages are kept for `.save-history`.
+pub const HISTORY_SIZE: usize = 1000;
+
+/// Last messages shown to the user, filled by `ClientReceiver` and saved by the `.save-history` command of `ClientSender`.
+/// The oldest message is dropped when the history is full, so a long session doesn't keep every message in memory.
+pub struct MessageHistory {
+    capacity: usize,
+    messages: Mutex<VecDeque<String>>,
+}
+
+impl Default for MessageHistory {
+    fn default() -> Self {
+        Self::new(HISTORY_SIZE)
+    }
+}
+
+impl MessageHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: Mutex::new(VecDeque::with_capacity(capacity.min(HISTORY_SIZE))),
+        }
+    }
+
+    /// Adds the message as it was shown without styling, including its line break.
+    pub fn push(&self, message: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// Kept messages from the oldest.
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_kept_in_order_up_to_capacity() {
+        let history = MessageHistory::new(2);
+        assert!(history.messages().is_empty());
+
+        history.push("alice: hi\n".to_string());
+        history.push("bob: hello\n".to_string());
+        assert_eq!(history.messages(), ["alice: hi\n", "bob: hello\n"]);
+
+        history.push("alice: bye\n".to_string());
+        assert_eq!(history.messages(), ["bob: hello\n", "alice: bye\n"]);
+
+        let disabled = MessageHistory::new(0);
+        disabled.push("alice: hi\n".to_string());
+        assert!(disabled.messages().is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+use crate::client_error::ClientError;
+use crate::encryption::{decrypt, decrypt_content, encrypt, encrypt_content, NONCE_SIZE};
+use aes_gcm::aead::OsRng;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use shared::message::MessagePayload;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const KEY_SIZE: usize = 32;
+
+/// Binds the derived keys to this use, so the same shared secret couldn't be reused elsewhere.
+const KEY_INFO: &[u8] = b"rust_course chat e2e text key";
+
+/// What announcing a key changed.
+#[derive(Debug, PartialEq)]
+pub enum KeyChange {
+    /// First key of the user.
+    New,
+    /// The user runs a new client, messages sealed for the old key can't be read by it.
+    Replaced,
+    /// The key was already known, e.g. the user reconnected.
+    Same,
+}
+
+/// Peer with the key derived from its public key and our secret.
+struct Peer {
+    public_key: [u8; KEY_SIZE],
+    key: [u8; KEY_SIZE],
+}
+
+/// End-to-end encryption with keys exchanged with the other users instead of a shared passphrase.
+///
+/// Every run of the client generates a new X25519 keypair and announces the public key to its room with `KeyAnnounce`.
+/// The key of every peer is derived from its public key and our secret with Diffie-Hellman and HKDF-SHA256.
+/// Texts and attachments are encrypted with AES-256-GCM once for every peer known at the time of sending, together with the public key
+/// of the sender, so a receiver finds the part sealed for its key and derives the same key from the sender's public key.
+pub struct KeyExchange {
+    secret: StaticSecret,
+    public_key: PublicKey,
+    /// Peers by username.
+    peers: Mutex<HashMap<String, Peer>>,
+    /// Notified when a peer asks for our key.
+    reply_requested: Notify,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        Self {
+            public_key: PublicKey::from(&secret),
+            secret,
+            peers: Mutex::new(HashMap::new()),
+            reply_requested: Notify::new(),
+        }
+    }
+
+    /// Announcement of our public key. With `reply` the receivers answer with their keys.
+    pub fn announcement(&self, reply: bool) -> MessagePayload {
+        MessagePayload::KeyAnnounce {
+            public_key: self.public_key.to_bytes(),
+            reply,
+        }
+    }
+
+    /// Remembers the key announced by the user, a new key replaces the previous one.
+    /// Fails for keys that can't be used, e.g. low order points that would give everybody the same key.
+    pub fn add_peer(
+        &self,
+        username: &str,
+        public_key: [u8; KEY_SIZE],
+    ) -> Result<KeyChange, ClientError> {
+        let key = derive_key(&self.secret, public_key)
+            .ok_or_else(|| ClientError::InvalidPublicKey(username.to_string()))?;
+        let mut peers = self.peers.lock().unwrap();
+        // Renamed user announces the same key under the new name, texts are not sealed for it twice
+        peers.retain(|name, peer| name == username || peer.public_key != public_key);
+        let change = match peers.insert(username.to_string(), Peer { public_key, key }) {
+            None => KeyChange::New,
+            Some(old) if old.public_key == public_key => KeyChange::Same,
+            Some(_) => KeyChange::Replaced,
+        };
+        Ok(change)
+    }
+
+    /// Asks the sender to answer with our key.
+    pub fn request_reply(&self) {
+        self.reply_requested.notify_one();
+    }
+
+    /// Waits until a peer asks for our key. Requests made in the meantime are answered with one reply.
+    pub async fn reply_requested(&self) {
+        self.reply_requested.notified().await
+    }
+
+    /// Whether a key of any peer is known, nothing can be sent encrypted without it.
+    pub fn has_peers(&self) -> bool {
+        !self.peers.lock().unwrap().is_empty()
+    }
+
+    /// Seals texts, images and files for every known peer, see `encrypt_content`.
+    pub fn encrypt_payload(&self, data: MessagePayload) -> Result<MessagePayload, ClientError> {
+        encrypt_content(data, |content| self.seal(content))
+    }
+
+    /// Opens the part sealed for our key. It doesn't need to know the sender,
+    /// so content from users whose announcement was missed can be read too.
+    pub fn decrypt_payload(&self, data: MessagePayload) -> Result<MessagePayload, ClientError> {
+        decrypt_content(data, |sealed| self.open(sealed))
+    }
+
+    /// Our public key followed by the recipient key, length, nonce and ciphertext for every peer.
+    fn seal(&self, content: &[u8]) -> Result<Vec<u8>, ClientError> {
+        let peers = self.peers.lock().unwrap();
+        if peers.is_empty() {
+            return Err(ClientError::NoPeerKeys);
+        }
+        let mut sealed = self.public_key.to_bytes().to_vec();
+        for peer in peers.values() {
+            let (ciphertext, nonce) = encrypt(&peer.key, content)?;
+            let length = u32::try_from(nonce.len() + ciphertext.len())
+                .map_err(|_| ClientError::EncryptMessage)?;
+            sealed.extend_from_slice(&peer.public_key);
+            sealed.extend_from_slice(&length.to_be_bytes());
+            sealed.extend_from_slice(&nonce);
+            sealed.extend_from_slice(&ciphertext);
+        }
+        Ok(sealed)
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, ClientError> {
+        let malformed =
+            || ClientError::DecryptMessage(Some("Malformed sealed message".to_string()));
+        let mut rest = sealed;
+        let sender = take_key(&mut rest).ok_or_else(malformed)?;
+        while !rest.is_empty() {
+            let recipient = take_key(&mut rest).ok_or_else(malformed)?;
+            let length = take(&mut rest, 4)
+                .and_then(|length| length.try_into().ok())
+                .map(u32::from_be_bytes)
+                .ok_or_else(malformed)?;
+            let part = take(&mut rest, length as usize)
+                .filter(|part| part.len() >= NONCE_SIZE)
+                .ok_or_else(malformed)?;
+            if recipient != self.public_key.to_bytes() {
+                continue;
+            }
+            let key = derive_key(&self.secret, sender).ok_or_else(malformed)?;
+            let (nonce, ciphertext) = part.split_at(NONCE_SIZE);
+            return decrypt(&key, nonce, ciphertext);
+        }
+        Err(ClientError::DecryptMessage(Some(
+            "The message wasn't sealed for this client, it was sent before the keys were exchanged"
+                .to_string(),
+        )))
+    }
+}
+
+/// Derives the key shared with the owner of the public key. None for keys that don't contribute to the
+/// shared secret, the secret would then be known to anybody.
+fn derive_key(secret: &StaticSecret, public_key: [u8; KEY_SIZE]) -> Option<[u8; KEY_SIZE]> {
+    let shared = secret.diffie_hellman(&PublicKey::from(public_key));
+    if !shared.was_contributory() {
+        return None;
+    }
+    let mut key = [0u8; KEY_SIZE];
+    Hkdf::<Sha256>::new(None, shared.as_bytes())
+        .expand(KEY_INFO, &mut key)
+        .expect("32 bytes is a valid length of HKDF-SHA256 output");
+    Some(key)
+}
+
+/// Splits off the first `n` bytes, None when there are not enough of them.
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if data.len() < n {
+        return None;
+    }
+    let (head, rest) = data.split_at(n);
+    *data = rest;
+    Some(head)
+}
+
+fn take_key(data: &mut &[u8]) -> Option<[u8; KEY_SIZE]> {
+    take(data, KEY_SIZE).and_then(|key| key.try_into().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public_key(keys: &KeyExchange) -> [u8; KEY_SIZE] {
+        keys.public_key.to_bytes()
+    }
+
+    #[test]
+    fn text_is_readable_only_by_peers_known_when_it_was_sent() {
+        let (alice, bob, carol) = (KeyExchange::new(), KeyExchange::new(), KeyExchange::new());
+        let text = || MessagePayload::Text("hello".to_string());
+        assert!(matches!(
+            alice.encrypt_payload(text()),
+            Err(ClientError::NoPeerKeys)
+        ));
+
+        assert_eq!(
+            alice.add_peer("bob", public_key(&bob)).unwrap(),
+            KeyChange::New
+        );
+        let sealed = alice.encrypt_payload(text()).unwrap();
+
+        assert_eq!(bob.decrypt_payload(sealed).unwrap(), text());
+
+        // Carol joined after the text was sent
+        assert_eq!(
+            alice.add_peer("carol", public_key(&carol)).unwrap(),
+            KeyChange::New
+        );
+        let sealed = alice.encrypt_payload(text()).unwrap();
+        assert_eq!(carol.decrypt_payload(sealed).unwrap(), text());
+        let sealed = alice.encrypt_payload(text()).unwrap();
+        assert_eq!(bob.decrypt_payload(sealed).unwrap(), text());
+
+        // Bob restarted the client with a new key
+        let bob = KeyExchange::new();
+        let sealed = alice.encrypt_payload(text()).unwrap();
+        assert!(bob.decrypt_payload(sealed).is_err());
+        assert_eq!(
+            alice.add_peer("bob", public_key(&bob)).unwrap(),
+            KeyChange::Replaced
+        );
+        let sealed = alice.encrypt_payload(text()).unwrap();
+        assert_eq!(bob.decrypt_payload(sealed).unwrap(), text());
+    }
+
+    #[test]
+    fn renamed_peer_keeps_one_key() {
+        let (alice, bob) = (KeyExchange::new(), KeyExchange::new());
+        alice.add_peer("bob", public_key(&bob)).unwrap();
+
+        assert_eq!(
+            alice.add_peer("robert", public_key(&bob)).unwrap(),
+            KeyChange::New
+        );
+        assert_eq!(
+            alice.add_peer("robert", public_key(&bob)).unwrap(),
+            KeyChange::Same
+        );
+        assert_eq!(alice.peers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn low_order_key_is_rejected() {
+        let alice = KeyExchange::new();
+
+        assert!(matches!(
+            alice.add_peer("mallory", [0; KEY_SIZE]),
+            Err(ClientError::InvalidPublicKey(_))
+        ));
+    }
+}
@@ -1,25 +1,59 @@
 mod args;
+mod autoreply;
 mod client;
 mod client_error;
 mod command;
+mod compose;
+mod display;
 mod encryption;
+mod history;
+mod key_exchange;
+mod reconnect;
+mod transfer;
+mod typing;
 mod utils;
 
 use anyhow::Result;
 use args::Args;
+use autoreply::AutoReply;
 use clap::Parser;
 use client::Client;
-use shared::tracing::{create_log_file, get_subscriber, init_subscriber};
+use encryption::E2eEncryption;
+use reconnect::ReconnectPolicy;
+use shared::compression::Compression;
+use shared::tracing::{
+    create_log_writer, get_subscriber, get_subscriber_tee, init_subscriber_or_warn, LogRotation,
+};
+use std::io::IsTerminal;
+use std::time::Duration;
 use tokio::io::AsyncWrite;
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use utils::FileNamePolicy;
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start the tokio runtime");
+    runtime.block_on(run());
+    // A read of stdin can still wait for a line in the blocking pool, dropping the runtime would wait for it
+    // and keep the client running until the user pressed enter.
+    runtime.shutdown_background();
+}
+
+async fn run() {
     let args = Args::parse();
-    if let Err(e) = setup_tracing(&args.logs_dir) {
-        let msg = "Error while starting a chat client.";
-        log_error(msg, e);
-        return;
-    }
+    // Logs are written until the guard is dropped at the end of the client
+    let _log_guard = match setup_tracing(
+        &args.logs_dir,
+        args.log_rotation,
+        args.log_level,
+        args.log_stderr,
+    ) {
+        Ok(guard) => Some(guard),
+        Err(e) => {
+            eprintln!("Tracing couldn't be initialized, running without it. {e}");
+            None
+        }
+    };
 
     let output_writer = tokio::io::stdout();
 
@@ -30,14 +64,31 @@ async fn main() {
 }
 
 /// Sets up tracing for the client.
-/// The logs will be written to the `logs_dir` directory. The default tracing file is ./logs/client-<timestamp>.log
+/// The logs will be written to the `logs_dir` directory. The default tracing file is ./logs/client-<timestamp>.log,
+/// with a `rotation` they are written to ./logs/client.<date>.log, a new file every hour or day.
 /// I didn't want to mix up the tracing logs and chat messages so the default output is a file.
-fn setup_tracing(logs_dir: &str) -> Result<()> {
-    let log_file = create_log_file(logs_dir, "client")?;
+/// With `log_stderr` the logs are written to stderr too, stdout is kept for the chat.
+/// Failing to set up tracing doesn't stop the client, it runs without it.
+/// The returned guard has to be kept, the logs stop being written when it is dropped.
+fn setup_tracing(
+    logs_dir: &str,
+    rotation: Option<LogRotation>,
+    log_level: Level,
+    log_stderr: bool,
+) -> Result<WorkerGuard> {
+    let (log_file, guard) = create_log_writer(logs_dir, "client", rotation)?;
 
-    let tracing_subscriber = get_subscriber("client".into(), "debug".into(), log_file);
-    init_subscriber(tracing_subscriber)?;
-    Ok(())
+    let env_filter = log_level.as_str().to_lowercase();
+    match log_stderr {
+        true => init_subscriber_or_warn(get_subscriber_tee(
+            "client".into(),
+            env_filter,
+            log_file,
+            std::io::stderr,
+        )),
+        false => init_subscriber_or_warn(get_subscriber("client".into(), env_filter, log_file)),
+    };
+    Ok(guard)
 }
 
 /// Starts the client. It will connect to the server and start listening for commands.
@@ -47,15 +98,52 @@ async fn start<T>(args: Args, writer: T) -> Result<()>
 where
     T: AsyncWrite + Unpin + Send + 'static,
 {
+    let e2e_encryption = match (args.e2e_encryption_key, args.e2e_key_exchange) {
+        (Some(passphrase), _) => Some(E2eEncryption::passphrase(&passphrase)),
+        (None, true) => Some(E2eEncryption::key_exchange()),
+        (None, false) => None,
+    };
     let (client_sender, client_receiver) = Client::connect(
         writer,
         args.host,
         args.port,
         &args.output_dir,
-        args.e2e_encryption_key,
+        e2e_encryption,
+        Compression::new(args.compression, args.compression_level),
+        ReconnectPolicy::new(args.max_reconnects),
     )
     .await?;
 
+    let file_names = match args.lossy_file_names {
+        true => FileNamePolicy::Lossy,
+        false => FileNamePolicy::Reject,
+    };
+    let client_sender = client_sender
+        .compose_mode(args.compose)
+        .typing_indicator(!args.no_typing_indicator)
+        .draft_file(args.draft_file)
+        .sender_override(args.sender_override)
+        .file_name_policy(file_names)
+        .keepalive(
+            (args.keepalive_seconds > 0).then(|| Duration::from_secs(args.keepalive_seconds)),
+        );
+    let client_receiver = client_receiver
+        .reassembly_buffer(args.reassembly_buffer_bytes)
+        .transfer_timeout(Duration::from_secs(args.transfer_timeout_seconds))
+        .strict(args.strict)
+        .render_markdown(args.render_markdown && std::io::stdout().is_terminal());
+
+    let (client_sender, client_receiver) = match args.autoreply.is_empty() {
+        true => (client_sender, client_receiver),
+        false => {
+            let (replies_sender, replies) = tokio::sync::mpsc::unbounded_channel();
+            (
+                client_sender.replies(replies),
+                client_receiver.autoreply(AutoReply::new(args.autoreply), replies_sender),
+            )
+        }
+    };
+
     let handle = tokio::spawn(client_sender.start());
     let handle_receiver = tokio::spawn(client_receiver.start());
 
@@ -0,0 +1,68 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use shared::compression::Framing;
+use std::time::Duration;
+
+/// How many times and how often the client tries to connect again after the connection to the server is lost.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Waits 1s before the first attempt, the delay doubles with every attempt up to 30s.
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    /// Delay before the attempt, attempts are numbered from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+/// Told to the sender by the receiver, which notices a lost connection first.
+pub enum ConnectionEvent<T> {
+    /// Messages are held until the client reconnects.
+    Lost,
+    /// Write half and framing of the new connection.
+    Reconnected(T, Framing),
+}
+
+/// Connects the receiver to the server again, each call of `connect` is one attempt.
+pub trait Reconnect<T>: Send {
+    /// Called once when the connection is lost, before the attempts. Returns false when there is no reason
+    /// to reconnect, e.g. the user quit and the server closed the connection because of it.
+    fn lost(&self) -> bool;
+
+    /// Connects and logs in again, returns the read half and framing of the new connection.
+    fn connect(&mut self) -> BoxFuture<'_, Result<(T, Framing)>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let policy = ReconnectPolicy::new(10);
+
+        let delays: Vec<_> = (1..=7)
+            .map(|attempt| policy.delay(attempt).as_secs())
+            .collect();
+
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(policy.delay(100), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,591 @@
+use crate::{client_error::ClientError, utils::sanitize_file_name};
+use shared::message::MessagePayload;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// Files larger than this are sent in chunks, so the transfer can be cancelled.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Incoming transfers up to this size are assembled in memory.
+pub const DEFAULT_MEMORY_THRESHOLD: usize = 1024 * 1024;
+
+/// Incoming transfer without a new chunk for this long is discarded.
+pub const DEFAULT_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the receiver looks for incoming transfers that timed out.
+pub const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Transfer is discarded when more chunks than this wait for the chunks before them.
+pub const MAX_PENDING_CHUNKS: usize = 64;
+
+/// File that is being sent chunk by chunk.
+pub struct OutgoingTransfer {
+    id: Uuid,
+    name: String,
+    data: Vec<u8>,
+    next_seq: u32,
+    total: u32,
+}
+
+impl OutgoingTransfer {
+    pub fn new(name: String, data: Vec<u8>) -> Self {
+        let total = data.len().div_ceil(CHUNK_SIZE).max(1) as u32;
+        Self {
+            id: Uuid::new_v4(),
+            name,
+            data,
+            next_seq: 0,
+            total,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Returns the next chunk to send, None when all chunks were sent.
+    pub fn next_chunk(&mut self) -> Option<MessagePayload> {
+        if self.next_seq >= self.total {
+            return None;
+        }
+        let start = self.next_seq as usize * CHUNK_SIZE;
+        let end = (start + CHUNK_SIZE).min(self.data.len());
+        let chunk = MessagePayload::FileChunk {
+            transfer_id: self.id,
+            name: self.name.clone(),
+            seq: self.next_seq,
+            total: self.total,
+            data: self.data[start..end].to_vec(),
+        };
+        self.next_seq += 1;
+        Some(chunk)
+    }
+}
+
+/// Transfers that are being sent. Chunks of the transfers are sent in turns between user commands.
+#[derive(Default)]
+pub struct OutgoingTransfers {
+    transfers: VecDeque<OutgoingTransfer>,
+}
+
+impl OutgoingTransfers {
+    pub fn push(&mut self, transfer: OutgoingTransfer) {
+        self.transfers.push_back(transfer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+
+    /// Returns the next chunk of the transfer in turn. Finished transfers are removed.
+    pub fn next_chunk(&mut self) -> Option<MessagePayload> {
+        while let Some(mut transfer) = self.transfers.pop_front() {
+            if let Some(chunk) = transfer.next_chunk() {
+                self.transfers.push_back(transfer);
+                return Some(chunk);
+            }
+        }
+        None
+    }
+
+    /// Stops the transfer. Returns false if there is no such transfer in progress.
+    pub fn cancel(&mut self, id: &Uuid) -> bool {
+        let count = self.transfers.len();
+        self.transfers.retain(|transfer| transfer.id != *id);
+        count != self.transfers.len()
+    }
+}
+
+/// Data of a transfer received so far.
+enum Storage {
+    Memory(Vec<u8>),
+    /// Partial file in the output directory, it is renamed when the last chunk arrives.
+    Disk {
+        path: PathBuf,
+        file: File,
+    },
+}
+
+struct PartialFile {
+    name: String,
+    storage: Storage,
+    total: u32,
+    /// Sequence number of the chunk that is written next.
+    next_seq: u32,
+    /// Chunks that arrived before the chunks preceding them.
+    pending: BTreeMap<u32, Vec<u8>>,
+    /// Size of the pending chunks, they are held in memory and count toward the memory threshold.
+    pending_bytes: usize,
+    last_chunk_at: Instant,
+}
+
+impl PartialFile {
+    fn received(&self) -> u32 {
+        self.next_seq + self.pending.len() as u32
+    }
+}
+
+/// Result of a received chunk.
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    /// More chunks are expected.
+    Partial {
+        name: String,
+        received: u32,
+        total: u32,
+    },
+    Complete(PathBuf),
+}
+
+/// Transfer that was discarded because its chunks stopped coming.
+#[derive(Debug, PartialEq)]
+pub struct ExpiredTransfer {
+    pub name: String,
+    pub received: u32,
+    pub total: u32,
+}
+
+impl PartialFile {
+    /// Appends the chunk. Data is moved to a partial file once it doesn't fit to the memory threshold.
+    async fn write(
+        &mut self,
+        dir: &Path,
+        transfer_id: Uuid,
+        data: &[u8],
+        memory_threshold: usize,
+    ) -> Result<(), ClientError> {
+        if let Storage::Memory(buffer) = &mut self.storage {
+            if buffer.len() + data.len() <= memory_threshold {
+                buffer.extend_from_slice(data);
+                return Ok(());
+            }
+            let buffered = std::mem::take(buffer);
+            self.storage = spill_to_disk(dir, transfer_id, &buffered).await?;
+        }
+        if let Storage::Disk { file, .. } = &mut self.storage {
+            file.write_all(data)
+                .await
+                .map_err(ClientError::WriteToFile)?;
+        }
+        Ok(())
+    }
+}
+
+async fn spill_to_disk(dir: &Path, transfer_id: Uuid, data: &[u8]) -> Result<Storage, ClientError> {
+    fs::create_dir_all(dir)
+        .await
+        .map_err(ClientError::CreateDir)?;
+    let path = dir.join(format!(".{transfer_id}.part"));
+    let mut file = File::create(&path).await.map_err(ClientError::CreateFile)?;
+    file.write_all(data)
+        .await
+        .map_err(ClientError::WriteToFile)?;
+    Ok(Storage::Disk { path, file })
+}
+
+/// Chunked files that are being received. Small files are assembled in memory, larger are written
+/// to a partial file in the output directory as the chunks arrive, so they don't have to fit to memory.
+pub struct IncomingTransfers {
+    dir: PathBuf,
+    partial: HashMap<Uuid, PartialFile>,
+    memory_threshold: usize,
+    timeout: Duration,
+}
+
+impl IncomingTransfers {
+    pub fn new(output_dir: &str) -> Self {
+        Self {
+            dir: Path::new(output_dir).join("files"),
+            partial: HashMap::new(),
+            memory_threshold: DEFAULT_MEMORY_THRESHOLD,
+            timeout: DEFAULT_TRANSFER_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a transfer waits for its next chunk, e.g. a missing one, before it is discarded.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many bytes of a transfer are kept in memory before it is written to disk.
+    pub fn memory_threshold(mut self, bytes: usize) -> Self {
+        self.memory_threshold = bytes;
+        self
+    }
+
+    /// Stores the chunk. Chunks that arrive out of order are held until the chunks before them arrive,
+    /// repeated chunks are ignored. The transfer is discarded when the chunk is out of its range, or when
+    /// too many chunks or bytes wait for a missing chunk.
+    pub async fn receive_chunk(
+        &mut self,
+        transfer_id: Uuid,
+        name: &str,
+        seq: u32,
+        total: u32,
+        data: Vec<u8>,
+    ) -> Result<Received, ClientError> {
+        let partial = self
+            .partial
+            .entry(transfer_id)
+            .or_insert_with(|| PartialFile {
+                name: sanitize_file_name(name),
+                storage: Storage::Memory(Vec::new()),
+                total,
+                next_seq: 0,
+                pending: BTreeMap::new(),
+                pending_bytes: 0,
+                last_chunk_at: Instant::now(),
+            });
+        partial.last_chunk_at = Instant::now();
+
+        if seq >= partial.total {
+            let (name, total) = (partial.name.clone(), partial.total);
+            self.cancel(&transfer_id).await?;
+            return Err(ClientError::ChunkOutOfRange { name, seq, total });
+        }
+        if seq >= partial.next_seq && !partial.pending.contains_key(&seq) {
+            partial.pending_bytes += data.len();
+            partial.pending.insert(seq, data);
+        }
+        if partial.pending.len() > MAX_PENDING_CHUNKS
+            || partial.pending_bytes > self.memory_threshold.max(CHUNK_SIZE * MAX_PENDING_CHUNKS)
+        {
+            let name = partial.name.clone();
+            self.cancel(&transfer_id).await?;
+            return Err(ClientError::TooManyPendingChunks(name));
+        }
+        while let Some(data) = partial.pending.remove(&partial.next_seq) {
+            partial.pending_bytes -= data.len();
+            let memory_threshold = self.memory_threshold.saturating_sub(partial.pending_bytes);
+            partial
+                .write(&self.dir, transfer_id, &data, memory_threshold)
+                .await?;
+            partial.next_seq += 1;
+        }
+
+        if partial.next_seq < partial.total {
+            return Ok(Received::Partial {
+                name: partial.name.clone(),
+                received: partial.received(),
+                total: partial.total,
+            });
+        }
+
+        let partial = self
+            .partial
+            .remove(&transfer_id)
+            .expect("transfer is present");
+        let path = self.dir.join(&partial.name);
+        match partial.storage {
+            Storage::Memory(data) => {
+                fs::create_dir_all(&self.dir)
+                    .await
+                    .map_err(ClientError::CreateDir)?;
+                fs::write(&path, data)
+                    .await
+                    .map_err(ClientError::WriteToFile)?;
+            }
+            Storage::Disk {
+                path: part_path,
+                mut file,
+            } => {
+                file.flush().await.map_err(ClientError::WriteToFile)?;
+                fs::rename(&part_path, &path)
+                    .await
+                    .map_err(ClientError::WriteToFile)?;
+            }
+        }
+        Ok(Received::Complete(path))
+    }
+
+    /// Discards the partial file of the transfer.
+    pub async fn cancel(&mut self, id: &Uuid) -> Result<(), ClientError> {
+        if let Some(partial) = self.partial.remove(id) {
+            remove_partial(partial).await?;
+        }
+        Ok(())
+    }
+
+    /// Discards the transfers whose last chunk came more than the timeout ago.
+    pub async fn expire(&mut self) -> Result<Vec<ExpiredTransfer>, ClientError> {
+        self.expire_at(Instant::now()).await
+    }
+
+    async fn expire_at(&mut self, now: Instant) -> Result<Vec<ExpiredTransfer>, ClientError> {
+        let expired_ids: Vec<_> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| now.duration_since(partial.last_chunk_at) >= self.timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut expired = Vec::new();
+        for id in expired_ids {
+            let partial = self.partial.remove(&id).expect("transfer is present");
+            expired.push(ExpiredTransfer {
+                name: partial.name.clone(),
+                received: partial.received(),
+                total: partial.total,
+            });
+            remove_partial(partial).await?;
+        }
+        Ok(expired)
+    }
+}
+
+async fn remove_partial(partial: PartialFile) -> Result<(), ClientError> {
+    if let Storage::Disk { path, file } = partial.storage {
+        drop(file);
+        fs::remove_file(&path)
+            .await
+            .map_err(ClientError::WriteToFile)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part_path(transfers: &IncomingTransfers, id: &Uuid) -> Option<PathBuf> {
+        match &transfers.partial.get(id)?.storage {
+            Storage::Disk { path, .. } => Some(path.clone()),
+            Storage::Memory(_) => None,
+        }
+    }
+
+    #[test]
+    fn file_is_split_to_chunks() {
+        let mut transfer = OutgoingTransfer::new("file".into(), vec![1; CHUNK_SIZE * 2 + 1]);
+
+        let sizes: Vec<_> = std::iter::from_fn(|| transfer.next_chunk())
+            .map(|chunk| match chunk {
+                MessagePayload::FileChunk { data, total, .. } => (data.len(), total),
+                _ => panic!("Expected file chunk"),
+            })
+            .collect();
+
+        assert_eq!(sizes, vec![(CHUNK_SIZE, 3), (CHUNK_SIZE, 3), (1, 3)]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_transfer_removes_partial_file() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir).memory_threshold(0);
+        let id = Uuid::new_v4();
+
+        let done = transfers
+            .receive_chunk(id, "a.txt", 0, 2, b"abc".to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(done, Received::Partial { received: 1, .. }));
+        let partial = part_path(&transfers, &id).expect("transfer is on disk");
+        assert!(partial.exists());
+
+        transfers.cancel(&id).await.unwrap();
+
+        assert!(!partial.exists());
+        assert!(transfers.partial.is_empty());
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunks_are_joined_to_file() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir);
+        let id = Uuid::new_v4();
+
+        transfers
+            .receive_chunk(id, "a.txt", 0, 2, b"ab".to_vec())
+            .await
+            .unwrap();
+        let Received::Complete(path) = transfers
+            .receive_chunk(id, "a.txt", 1, 2, b"c".to_vec())
+            .await
+            .unwrap()
+        else {
+            panic!("Expected complete transfer");
+        };
+
+        assert_eq!(fs::read(&path).await.unwrap(), b"abc");
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfer_over_memory_threshold_is_assembled_on_disk() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir).memory_threshold(4);
+        let id = Uuid::new_v4();
+
+        transfers
+            .receive_chunk(id, "a.txt", 0, 3, b"abc".to_vec())
+            .await
+            .unwrap();
+        assert!(part_path(&transfers, &id).is_none());
+        transfers
+            .receive_chunk(id, "a.txt", 1, 3, b"def".to_vec())
+            .await
+            .unwrap();
+        assert!(part_path(&transfers, &id).is_some_and(|path| path.exists()));
+        let Received::Complete(path) = transfers
+            .receive_chunk(id, "a.txt", 2, 3, b"gh".to_vec())
+            .await
+            .unwrap()
+        else {
+            panic!("Expected complete transfer");
+        };
+
+        assert_eq!(fs::read(&path).await.unwrap(), b"abcdefgh");
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunks_out_of_order_are_written_in_order() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir);
+        let id = Uuid::new_v4();
+
+        // The first chunk is repeated
+        for (seq, data, received) in [(2, "gh", 1), (0, "abc", 2), (0, "abc", 2)] {
+            let result = transfers
+                .receive_chunk(id, "a.txt", seq, 3, data.into())
+                .await
+                .unwrap();
+            assert_eq!(
+                result,
+                Received::Partial {
+                    name: "a.txt".into(),
+                    received,
+                    total: 3,
+                }
+            );
+        }
+        let Received::Complete(path) = transfers
+            .receive_chunk(id, "a.txt", 1, 3, b"def".to_vec())
+            .await
+            .unwrap()
+        else {
+            panic!("Expected complete transfer");
+        };
+
+        assert_eq!(fs::read(&path).await.unwrap(), b"abcdefgh");
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfer_with_missing_chunk_times_out() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir)
+            .memory_threshold(0)
+            .timeout(Duration::from_secs(10));
+        let id = Uuid::new_v4();
+        for seq in [0, 2] {
+            transfers
+                .receive_chunk(id, "a.txt", seq, 3, b"abc".to_vec())
+                .await
+                .unwrap();
+        }
+        let partial = part_path(&transfers, &id).expect("transfer is on disk");
+
+        assert!(transfers.expire().await.unwrap().is_empty());
+        let expired = transfers
+            .expire_at(Instant::now() + Duration::from_secs(10))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            expired,
+            vec![ExpiredTransfer {
+                name: "a.txt".into(),
+                received: 2,
+                total: 3,
+            }]
+        );
+        assert!(!partial.exists());
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunk_out_of_range_discards_transfer() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir);
+        let id = Uuid::new_v4();
+
+        transfers
+            .receive_chunk(id, "a.txt", 0, 2, b"ab".to_vec())
+            .await
+            .unwrap();
+        let result = transfers
+            .receive_chunk(id, "a.txt", 5, 2, b"cd".to_vec())
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(ClientError::ChunkOutOfRange {
+                seq: 5,
+                total: 2,
+                ..
+            })
+        ));
+        assert!(transfers.partial.is_empty());
+    }
+
+    #[tokio::test]
+    async fn too_many_pending_chunks_discard_transfer() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let total = MAX_PENDING_CHUNKS as u32 + 2;
+        let mut by_count = IncomingTransfers::new(&dir);
+        let id = Uuid::new_v4();
+
+        // Chunk 0 never arrives
+        for seq in 1..=MAX_PENDING_CHUNKS as u32 {
+            by_count
+                .receive_chunk(id, "a.txt", seq, total, b"a".to_vec())
+                .await
+                .unwrap();
+        }
+        let result = by_count
+            .receive_chunk(id, "a.txt", total - 1, total, b"a".to_vec())
+            .await;
+        assert!(matches!(result, Err(ClientError::TooManyPendingChunks(_))));
+        assert!(by_count.partial.is_empty());
+
+        let mut by_size = IncomingTransfers::new(&dir).memory_threshold(0);
+        let big_chunk = vec![0; CHUNK_SIZE * MAX_PENDING_CHUNKS + 1];
+        let result = by_size.receive_chunk(id, "a.txt", 1, 2, big_chunk).await;
+        assert!(matches!(result, Err(ClientError::TooManyPendingChunks(_))));
+        assert!(by_size.partial.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pending_chunks_count_toward_memory_threshold() {
+        let dir = format!("./test_output_{}", Uuid::new_v4());
+        let mut transfers = IncomingTransfers::new(&dir).memory_threshold(4);
+        let id = Uuid::new_v4();
+
+        transfers
+            .receive_chunk(id, "a.txt", 2, 3, b"efg".to_vec())
+            .await
+            .unwrap();
+        transfers
+            .receive_chunk(id, "a.txt", 0, 3, b"ab".to_vec())
+            .await
+            .unwrap();
+        // Two bytes in memory and three pending are over the threshold
+        assert!(part_path(&transfers, &id).is_some_and(|path| path.exists()));
+        let Received::Complete(path) = transfers
+            .receive_chunk(id, "a.txt", 1, 3, b"cd".to_vec())
+            .await
+            .unwrap()
+        else {
+            panic!("Expected complete transfer");
+        };
+
+        assert_eq!(fs::read(&path).await.unwrap(), b"abcdefg");
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// While the user composes a message, `Typing` is sent at most this often.
+pub const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// A user stops being shown as typing when no `Typing` came from them for this long.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Decides when the sender tells the others that the user is typing. Holding a key, or pasting many lines,
+/// sends one `Typing` per interval.
+pub struct TypingNotifier {
+    interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl Default for TypingNotifier {
+    fn default() -> Self {
+        Self {
+            interval: TYPING_INTERVAL,
+            last_sent: None,
+        }
+    }
+}
+
+impl TypingNotifier {
+    /// Whether to send `Typing` for input written now.
+    pub fn should_send(&mut self) -> bool {
+        self.should_send_at(Instant::now())
+    }
+
+    /// The message was sent, the next input starts a new message.
+    pub fn message_sent(&mut self) {
+        self.last_sent = None;
+    }
+
+    fn should_send_at(&mut self, now: Instant) -> bool {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.saturating_duration_since(last_sent) < self.interval)
+        {
+            return false;
+        }
+        self.last_sent = Some(now);
+        true
+    }
+}
+
+/// Users that are typing. A user is shown once, until they send a message or stop typing for the timeout,
+/// the timeout is checked with `expired`.
+pub struct TypingUsers {
+    timeout: Duration,
+    typing: HashMap<String, Instant>,
+}
+
+impl Default for TypingUsers {
+    fn default() -> Self {
+        Self {
+            timeout: TYPING_TIMEOUT,
+            typing: HashMap::new(),
+        }
+    }
+}
+
+impl TypingUsers {
+    /// Remembers that the user is typing. Returns true when it should be shown, i.e. the user just started.
+    pub fn typing(&mut self, username: &str) -> bool {
+        self.typing_at(username, Instant::now())
+    }
+
+    /// The user sent a message, the next `Typing` is shown again.
+    pub fn stopped(&mut self, username: &str) {
+        self.typing.remove(username);
+    }
+
+    /// Forgets the users without `Typing` for the timeout and returns them, sorted.
+    pub fn expired(&mut self) -> Vec<String> {
+        self.expired_at(Instant::now())
+    }
+
+    fn typing_at(&mut self, username: &str, now: Instant) -> bool {
+        self.typing
+            .insert(username.to_string(), now)
+            .is_none_or(|last| now.saturating_duration_since(last) >= self.timeout)
+    }
+
+    fn expired_at(&mut self, now: Instant) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.typing.retain(|username, last| {
+            let typing = now.saturating_duration_since(*last) < self.timeout;
+            if !typing {
+                expired.push(username.clone());
+            }
+            typing
+        });
+        expired.sort();
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_is_sent_once_per_interval() {
+        let mut notifier = TypingNotifier::default();
+        let now = Instant::now();
+
+        assert!(notifier.should_send_at(now));
+        assert!(!notifier.should_send_at(now + Duration::from_secs(1)));
+        assert!(notifier.should_send_at(now + TYPING_INTERVAL));
+
+        notifier.message_sent();
+        assert!(notifier.should_send_at(now + TYPING_INTERVAL));
+    }
+
+    #[test]
+    fn typing_user_is_shown_again_after_timeout_or_message() {
+        let mut users = TypingUsers::default();
+        let now = Instant::now();
+
+        assert!(users.typing_at("alice", now));
+        assert!(!users.typing_at("alice", now + Duration::from_secs(3)));
+        assert!(users.typing_at("bob", now + Duration::from_secs(3)));
+        // Timeout counts from the last `Typing` of the user
+        assert!(!users.typing_at("alice", now + Duration::from_secs(7)));
+        assert!(users.typing_at("alice", now + Duration::from_secs(7) + TYPING_TIMEOUT));
+
+        users.stopped("bob");
+        assert!(users.typing("bob"));
+    }
+
+    #[test]
+    fn users_without_typing_for_timeout_expire() {
+        let mut users = TypingUsers::default();
+        let now = Instant::now();
+        users.typing_at("bob", now);
+        users.typing_at("alice", now);
+        users.typing_at("carol", now + Duration::from_secs(3));
+
+        assert!(users.expired_at(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(users.expired_at(now + TYPING_TIMEOUT), ["alice", "bob"]);
+        assert!(users.expired_at(now + TYPING_TIMEOUT).is_empty());
+        assert!(users.typing_at("alice", now + TYPING_TIMEOUT));
+    }
+}
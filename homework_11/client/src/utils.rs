@@ -8,7 +8,7 @@ use std::{
 use tokio::fs::{self};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-/// Utility functions that are relevant only to client.
+// Utility functions that are relevant only to client.
 
 pub async fn save_file<T>(path: &T, data: &[u8]) -> Result<(), ClientError>
 where
@@ -30,25 +30,73 @@ where
     file.write_all(data)
         .await
         .map_err(ClientError::WriteToFile)?;
+    // Tokio writes in the background, without flush the file may be incomplete right after this returns.
+    file.flush().await.map_err(ClientError::WriteToFile)?;
     Ok(())
 }
 
-pub async fn get_file<T>(path: &T) -> Result<(String, Vec<u8>), ClientError>
+/// Checks that files can be written to the directory. The directory is created if it doesn't exist.
+pub async fn ensure_writable_dir(path: &str) -> Result<(), ClientError> {
+    let not_writable = |e| ClientError::OutputDirNotWritable(path.to_string(), e);
+
+    fs::create_dir_all(path).await.map_err(not_writable)?;
+
+    let test_file = Path::new(path).join(format!(".write_test_{}", uuid::Uuid::new_v4()));
+    fs::File::create(&test_file).await.map_err(not_writable)?;
+    fs::remove_file(&test_file).await.map_err(not_writable)?;
+    Ok(())
+}
+
+/// What to do with a file name that is not valid UTF-8 when sending a file.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum FileNamePolicy {
+    /// The file is not sent.
+    #[default]
+    Reject,
+    /// Invalid bytes are replaced with `U+FFFD`.
+    Lossy,
+}
+
+pub async fn get_file<T>(path: &T, policy: FileNamePolicy) -> Result<(String, Vec<u8>), ClientError>
 where
     T: AsRef<OsStr> + ?Sized,
 {
     let path = path::Path::new(path);
 
-    let file_name_os = path.file_name();
+    let Some(file_name_os) = path.file_name() else {
+        return Err(ClientError::FileNotExists);
+    };
 
-    let file_name = match file_name_os {
-        Some(file_name) => file_name.to_string_lossy(),
-        None => return Err(ClientError::FileNotExists),
+    let file_name = match (file_name_os.to_str(), policy) {
+        (Some(file_name), _) => file_name.to_string(),
+        (None, FileNamePolicy::Lossy) => file_name_os.to_string_lossy().to_string(),
+        (None, FileNamePolicy::Reject) => {
+            return Err(ClientError::NonUtf8FileName(
+                file_name_os.to_string_lossy().to_string(),
+            ))
+        }
     };
 
     let bytes = fs::read(path).await.map_err(ClientError::ReadFromFile)?;
 
-    Ok((file_name.to_string(), bytes))
+    Ok((file_name, bytes))
+}
+
+/// Makes the received file name safe to be used for saving. Only the last path component is kept,
+/// so the file can't be written outside of the output directory.
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let name: String = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or("")
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+
+    match name.trim() {
+        "" | "." | ".." => "file".to_string(),
+        _ => name,
+    }
 }
 
 pub async fn get_image<T>(path: &T) -> Result<Vec<u8>, ClientError>
@@ -57,13 +105,81 @@ where
 {
     let path = Path::new(path);
 
-    let bytes = match path.ends_with(".png") {
+    let bytes = match path.extension().is_some_and(|ext| ext == "png") {
         true => fs::read(path).await.map_err(ClientError::ReadFromFile)?,
         false => convert_to_png(path)?,
     };
     Ok(bytes)
 }
 
+/// Describes the file without sending it: name, size, detected type and dimensions for images.
+pub async fn preview_file(path: &str) -> Result<String, ClientError> {
+    let (name, bytes) = get_file(path, FileNamePolicy::Lossy).await?;
+
+    let mut preview = format!("File: {name}\nSize: {} bytes\n", bytes.len());
+    match image::guess_format(&bytes) {
+        Ok(format) => {
+            preview.push_str(&format!("Type: {format:?} image\n"));
+            let dimensions =
+                ImageReader::with_format(Cursor::new(&bytes), format).into_dimensions();
+            if let Ok((width, height)) = dimensions {
+                preview.push_str(&format!("Dimensions: {width}x{height}\n"));
+            }
+        }
+        Err(_) if std::str::from_utf8(&bytes).is_ok() => preview.push_str("Type: text\n"),
+        Err(_) => preview.push_str("Type: binary\n"),
+    }
+    Ok(preview)
+}
+
+/// Subdirectories of the output directory where received attachments are saved.
+const ATTACHMENT_DIRS: [&str; 2] = ["files", "images"];
+
+/// Lists received attachments saved in the output directory with their size and modification time.
+pub async fn list_attachments(output_dir: &str) -> Result<String, ClientError> {
+    let mut attachments = Vec::new();
+    for dir in ATTACHMENT_DIRS {
+        let mut entries = match fs::read_dir(Path::new(output_dir).join(dir)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(ClientError::ReadFromFile(e)),
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(ClientError::ReadFromFile)?
+        {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Partial files of transfers in progress are hidden
+            if name.starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata().await.map_err(ClientError::ReadFromFile)?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata
+                .modified()
+                .map(|time| {
+                    chrono::DateTime::<chrono::Local>::from(time)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string()
+                })
+                .unwrap_or_default();
+            attachments.push((format!("{dir}/{name}"), metadata.len(), modified));
+        }
+    }
+
+    if attachments.is_empty() {
+        return Ok("No attachments received yet.\n".to_string());
+    }
+    attachments.sort();
+    Ok(attachments
+        .into_iter()
+        .map(|(path, size, modified)| format!("{path}  {size} bytes  {modified}\n"))
+        .collect())
+}
+
 fn convert_to_png<T>(path: &T) -> Result<Vec<u8>, ClientError>
 where
     T: AsRef<Path> + ?Sized,
@@ -94,7 +210,9 @@ mod tests {
 
     #[tokio::test]
     async fn get_file() {
-        let (file_name, bytes) = super::get_file("Cargo.toml").await.unwrap();
+        let (file_name, bytes) = super::get_file("Cargo.toml", super::FileNamePolicy::Reject)
+            .await
+            .unwrap();
         assert_eq!(file_name, "Cargo.toml");
         assert!(!bytes.is_empty());
     }
@@ -121,6 +239,16 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn ensure_writable_dir_creates_missing_dir() {
+        let dir = format!("./test_output_{}", uuid::Uuid::new_v4());
+
+        super::ensure_writable_dir(&dir).await.unwrap();
+
+        assert!(std::path::Path::new(&dir).is_dir());
+        tokio::fs::remove_dir(&dir).await.unwrap();
+    }
+
     #[tokio::test]
     async fn save_file() {
         let file_name = "test_file.txt";
@@ -130,11 +258,78 @@ mod tests {
 
         assert!(result.is_ok());
 
-        let (file_name, bytes) = super::get_file(file_name).await.unwrap();
+        let (file_name, bytes) = super::get_file(file_name, super::FileNamePolicy::Reject)
+            .await
+            .unwrap();
 
         assert_eq!(file_name, "test_file.txt");
         assert_eq!(bytes, data);
 
         tokio::fs::remove_file(file_name).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn get_file_handles_non_utf8_file_name() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = std::ffi::OsStr::from_bytes(b"test_file_\xff.txt");
+        tokio::fs::write(name, b"data").await.unwrap();
+
+        let rejected = super::get_file(name, super::FileNamePolicy::Reject).await;
+        let lossy = super::get_file(name, super::FileNamePolicy::Lossy).await;
+        tokio::fs::remove_file(name).await.unwrap();
+
+        assert!(matches!(
+            rejected.unwrap_err(),
+            super::ClientError::NonUtf8FileName(_)
+        ));
+        assert_eq!(lossy.unwrap().0, "test_file_\u{FFFD}.txt");
+    }
+
+    #[test]
+    fn sanitize_file_name_keeps_only_the_name() {
+        assert_eq!(super::sanitize_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(
+            super::sanitize_file_name("dir\\report\n.txt"),
+            "report_.txt"
+        );
+        assert_eq!(super::sanitize_file_name(".."), "file");
+    }
+
+    #[tokio::test]
+    async fn preview_reports_image_size_and_dimensions() {
+        let path = format!("./test_preview_{}.png", uuid::Uuid::new_v4());
+        image::RgbImage::new(3, 2).save(&path).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        let preview = super::preview_file(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        let preview = preview.unwrap();
+        assert!(preview.contains(&format!("Size: {size} bytes")));
+        assert!(preview.contains("Type: Png image"));
+        assert!(preview.contains("Dimensions: 3x2"));
+    }
+
+    #[tokio::test]
+    async fn saved_attachments_are_listed() {
+        let dir = format!("./test_output_{}", uuid::Uuid::new_v4());
+        super::save_file(&format!("{dir}/files/notes.txt"), b"hello")
+            .await
+            .unwrap();
+        super::save_file(&format!("{dir}/images/1700000000.png"), b"png")
+            .await
+            .unwrap();
+        super::save_file(&format!("{dir}/files/.transfer.part"), b"partial")
+            .await
+            .unwrap();
+
+        let listed = super::list_attachments(&dir).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let lines: Vec<_> = listed.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("files/notes.txt  5 bytes"));
+        assert!(lines[1].starts_with("images/1700000000.png  3 bytes"));
+    }
 }
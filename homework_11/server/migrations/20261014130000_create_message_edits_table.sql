@@ -0,0 +1,11 @@
+CREATE TABLE message_edits(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    message_id uuid NOT NULL,
+    data TEXT NOT NULL,
+    timestamp timestamptz NOT NULL,
+    CONSTRAINT fk_message
+      FOREIGN KEY(message_id)
+	  REFERENCES messages(id)
+      ON DELETE CASCADE
+);
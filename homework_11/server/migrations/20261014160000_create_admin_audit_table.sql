@@ -0,0 +1,10 @@
+-- Actions are kept when the admin or the target user is deleted, so there is no foreign key.
+CREATE TABLE admin_audit(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    admin_id uuid NOT NULL,
+    action VARCHAR(64) NOT NULL,
+    target VARCHAR(255) NOT NULL,
+    details TEXT,
+    timestamp timestamptz NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE messages ADD COLUMN room VARCHAR(64) NOT NULL DEFAULT 'general';
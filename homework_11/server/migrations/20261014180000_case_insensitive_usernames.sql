@@ -0,0 +1,2 @@
+-- Usernames keep the case they were registered with, but "Alice" and "alice" can't both exist
+CREATE UNIQUE INDEX users_username_lower_key ON users (lower(username));
@@ -0,0 +1,70 @@
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::configuration::ChatSettings;
+use crate::db::ChatDb;
+use crate::lockout::LoginLockout;
+use crate::server_error::ServerError;
+use crate::user::UserInfo;
+
+/// Administrative action recorded in the audit log.
+/// target: what the action was done to, e.g. the username of a kicked user
+/// details: optional free text, e.g. the reason of a kick
+#[derive(Serialize, Debug, Clone)]
+pub struct AdminAction {
+    pub id: Uuid,
+    pub admin_id: Uuid,
+    pub action: String,
+    pub target: String,
+    pub details: Option<String>,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Users allowed to do administrative actions. They log in with the same credentials as to the chat.
+/// Admins are matched by user id, so a user that registers or renames to the name of an admin doesn't get the rights.
+#[derive(Debug, Clone, Default)]
+pub struct Admins {
+    ids: Vec<Uuid>,
+}
+
+impl Admins {
+    pub fn from_settings(settings: &ChatSettings) -> Self {
+        Self {
+            ids: settings.admins.clone(),
+        }
+    }
+
+    pub fn is_admin(&self, id: &Uuid) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Returns the admin if the credentials are valid and the user is an admin.
+    /// Wrong passwords of the admins are counted in the `lockout`.
+    pub async fn verify<D: ChatDb>(
+        &self,
+        db: &D,
+        lockout: &LoginLockout,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<UserInfo>, ServerError> {
+        let Some(user) = db.get_user(username).await? else {
+            return Ok(None);
+        };
+        if !self.is_admin(&user.id) {
+            return Ok(None);
+        }
+        match user.verify_user_password(password.as_bytes())? {
+            true => {
+                lockout.record_success(username);
+                Ok(Some(user.into()))
+            }
+            false => {
+                lockout.record_failure(username);
+                Ok(None)
+            }
+        }
+    }
+}
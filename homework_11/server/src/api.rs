@@ -1,28 +1,47 @@
 use actix_cors::Cors;
-use actix_web::http::header::ContentType;
+use actix_web::http::header::{self, ContentType};
 use actix_web::{dev::Server, web, App, HttpServer};
-use actix_web::{HttpResponse, Responder};
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose, Engine};
 use prometheus::{Encoder, TextEncoder};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use shared::compression::{Algorithm, SUPPORTED_ALGORITHMS};
+use shared::message::{Message, MessagePayload, DEFAULT_ROOM, PROTOCOL_VERSION};
 use std::net::TcpListener;
 use std::ops::Deref;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 use uuid::Uuid;
 
+use crate::admin::Admins;
+use crate::breaker::ResilientDb;
+use crate::bridge::ChatBridge;
+use crate::lockout::LoginLockout;
+use crate::message_info::MessageInfo;
 use crate::server_error::ServerError;
+use crate::user::{validate_username, DuplicateUserPolicy, ExportedUser, User, UserInfo};
 use crate::{
-    configuration::Settings,
-    db::{ChatDb, ChatPostgresDb},
+    configuration::{ChatSettings, Settings},
+    db::{is_valid_room, ChatDb, ChatPostgresDb, MAX_ROOM_LENGTH},
 };
 
+type ApiDb = ResilientDb<ChatPostgresDb>;
+
 pub struct Api {
     port: u16,
     server: Server,
 }
 
 impl Api {
-    pub fn build(config: Settings) -> Result<Self, ServerError> {
-        let db = ChatPostgresDb::new(&config.database);
+    pub fn build(
+        config: Settings,
+        bridge: Arc<ChatBridge>,
+        lockout: Arc<LoginLockout>,
+    ) -> Result<Self, ServerError> {
+        let db = ResilientDb::new(
+            ChatPostgresDb::new(&config.database),
+            &config.database.circuit_breaker,
+        );
 
         let address = format!("{}:{}", config.api.host, config.api.port);
 
@@ -30,7 +49,8 @@ impl Api {
 
         let listener = TcpListener::bind(address).map_err(ServerError::Bind)?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(listener, db)?;
+        let data = ApiData::new(Arc::new(db), &config.chat, bridge, lockout);
+        let server = run(listener, data)?;
 
         Ok(Self { port, server })
     }
@@ -44,22 +64,12 @@ impl Api {
     }
 }
 
-fn run(listener: std::net::TcpListener, db_pool: ChatPostgresDb) -> Result<Server, ServerError> {
-    let db_pool = web::Data::new(db_pool);
-
+fn run(listener: std::net::TcpListener, data: ApiData<ApiDb>) -> Result<Server, ServerError> {
     let server = HttpServer::new(move || {
         App::new()
             .wrap(Cors::permissive())
             .wrap(TracingLogger::default())
-            .route("/health", web::get().to(health_check))
-            .route("/metrics", web::get().to(metrics_handler))
-            .route("/messages", web::get().to(get_messages::<ChatPostgresDb>))
-            .route(
-                "/user/{id}",
-                web::delete().to(delete_user::<ChatPostgresDb>),
-            )
-            .route("/users", web::get().to(get_users::<ChatPostgresDb>))
-            .app_data(db_pool.clone())
+            .configure(|cfg| data.configure(cfg))
     })
     .listen(listener)
     .map_err(ServerError::StartApi)?
@@ -68,58 +78,425 @@ fn run(listener: std::net::TcpListener, db_pool: ChatPostgresDb) -> Result<Serve
     Ok(server)
 }
 
+/// Routes of the api with the data of their handlers. The api runs over the Postgres database, tests mount it over any `ChatDb`.
+pub(crate) struct ApiData<T> {
+    db: web::Data<T>,
+    capabilities: web::Data<Capabilities>,
+    bridge: web::Data<ChatBridge>,
+    admins: web::Data<Admins>,
+    lockout: web::Data<LoginLockout>,
+}
+
+impl<T> Clone for ApiData<T> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            capabilities: self.capabilities.clone(),
+            bridge: self.bridge.clone(),
+            admins: self.admins.clone(),
+            lockout: self.lockout.clone(),
+        }
+    }
+}
+
+impl<T: ChatDb + Send + Sync + 'static> ApiData<T> {
+    pub(crate) fn new(
+        db: Arc<T>,
+        settings: &ChatSettings,
+        bridge: Arc<ChatBridge>,
+        lockout: Arc<LoginLockout>,
+    ) -> Self {
+        Self {
+            db: web::Data::from(db),
+            capabilities: web::Data::new(Capabilities::from_settings(settings)),
+            bridge: web::Data::from(bridge),
+            admins: web::Data::new(Admins::from_settings(settings)),
+            lockout: web::Data::from(lockout),
+        }
+    }
+
+    pub(crate) fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.route("/health", web::get().to(health_check))
+            .route("/capabilities", web::get().to(get_capabilities))
+            .route("/metrics", web::get().to(metrics_handler))
+            .route("/messages", web::get().to(get_messages::<T>))
+            .route("/messages", web::post().to(post_message::<T>))
+            .route("/poll", web::get().to(poll))
+            .route("/messages/{id}", web::put().to(edit_message::<T>))
+            .route(
+                "/messages/{id}/history",
+                web::get().to(get_message_history::<T>),
+            )
+            .route("/user/{id}", web::delete().to(delete_user::<T>))
+            .route("/users/{id}", web::put().to(rename_user::<T>))
+            .route("/users", web::get().to(get_users::<T>))
+            .route("/users/{id}/kick", web::post().to(kick_user::<T>))
+            .route("/audit", web::get().to(get_audit::<T>))
+            .route("/export/users", web::get().to(export_users::<T>))
+            .route("/import/users", web::post().to(import_users::<T>))
+            .app_data(self.db.clone())
+            .app_data(self.capabilities.clone())
+            .app_data(self.bridge.clone())
+            .app_data(self.admins.clone())
+            .app_data(self.lockout.clone());
+    }
+}
+
+/// 429 response when the login of the username is locked after failed attempts, the same as the chat login.
+fn check_lockout(lockout: &LoginLockout, username: &str) -> Result<(), HttpResponse> {
+    match lockout.retry_after(username) {
+        Some(retry_after) => Err(HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            .finish()),
+        None => Ok(()),
+    }
+}
+
+/// Response for a failed database call. 503 when the database isn't called because it keeps failing.
+fn db_error(e: &ServerError) -> HttpResponse {
+    match e {
+        ServerError::CircuitOpen => HttpResponse::ServiceUnavailable().finish(),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
 async fn health_check() -> impl Responder {
     HttpResponse::Ok().finish()
 }
 
+/// Features supported by the chat server, so clients can discover them before connecting.
+#[derive(Serialize, Debug, Clone)]
+struct Capabilities {
+    protocol_version: u32,
+    compression: bool,
+    compression_algorithms: Vec<Algorithm>,
+    encryption_required: bool,
+    chunked_files: bool,
+    /// None means there is no limit.
+    max_message_size: Option<u64>,
+    max_bytes_per_minute: Option<u64>,
+    allowed_attachment_types: Vec<String>,
+}
+
+impl Capabilities {
+    fn from_settings(settings: &ChatSettings) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            compression: settings.compression_algorithm != Algorithm::None,
+            compression_algorithms: SUPPORTED_ALGORITHMS.to_vec(),
+            // E2E encryption is optional and done by clients, the server only relays the payloads.
+            encryption_required: false,
+            chunked_files: true,
+            max_message_size: settings.max_message_bytes,
+            max_bytes_per_minute: settings.max_bytes_per_minute,
+            allowed_attachment_types: settings.allowed_attachment_types.clone(),
+        }
+    }
+}
+
+async fn get_capabilities(capabilities: web::Data<Capabilities>) -> impl Responder {
+    HttpResponse::Ok().json(capabilities.get_ref())
+}
+
+/// Most messages returned by `GET /messages`.
+const MESSAGES_LIMIT: u32 = 50;
+
 #[derive(Deserialize, Debug)]
 struct MessageQuery {
     username: Option<String>,
+    room: Option<String>,
 }
 
-#[tracing::instrument(skip(db))]
-async fn get_messages<T>(db: web::Data<T>, query: web::Query<MessageQuery>) -> impl Responder
+const MSGPACK: &str = "application/msgpack";
+
+/// Format of the response body, chosen by the `Accept` header. JSON unless MessagePack is asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ResponseFormat {
+    Json,
+    MessagePack,
+}
+
+impl ResponseFormat {
+    fn from_request(request: &HttpRequest) -> Self {
+        let accepts_msgpack = request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|accept| {
+                accept
+                    .split(',')
+                    .any(|media| media.split(';').next().unwrap_or("").trim() == MSGPACK)
+            });
+        if accepts_msgpack {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+
+    fn respond<T: Serialize>(self, value: &T) -> HttpResponse {
+        let (body, content_type) = match self {
+            Self::Json => (
+                serde_json::to_vec(value).map_err(|e| e.to_string()),
+                ContentType::json(),
+            ),
+            Self::MessagePack => (
+                rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+                ContentType(MSGPACK.parse().unwrap()),
+            ),
+        };
+        match body {
+            Ok(body) => HttpResponse::Ok().content_type(content_type).body(body),
+            Err(e) => {
+                tracing::error!("Error while serializing the response. {e}");
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip(db, request))]
+async fn get_messages<T>(
+    db: web::Data<T>,
+    query: web::Query<MessageQuery>,
+    request: HttpRequest,
+) -> impl Responder
 where
     T: ChatDb + Sync + Send,
 {
     match db
-        .get_messages(query.username.as_deref().unwrap_or(""))
+        .get_messages(
+            query.username.as_deref().unwrap_or(""),
+            query.room.as_deref(),
+            MESSAGES_LIMIT,
+        )
         .await
     {
-        Ok(messages) => {
-            let Ok(body) = serde_json::to_string(&messages) else {
-                tracing::error!("Error while serializing messages.");
-                return HttpResponse::InternalServerError().finish();
-            };
-            HttpResponse::Ok()
-                .content_type(ContentType::json())
-                .body(body)
-        }
+        Ok(messages) => ResponseFormat::from_request(&request).respond(&messages),
         Err(e) => {
             tracing::error!("Error while getting messages from db. {e}");
+            db_error(&e)
+        }
+    }
+}
+
+/// Message sent over HTTP by a client that can't keep a TCP connection open. Credentials are the same as for the chat login.
+#[derive(Deserialize)]
+struct PostMessageRequest {
+    username: String,
+    password: String,
+    text: String,
+    /// `DEFAULT_ROOM` when not given.
+    room: Option<String>,
+}
+
+#[tracing::instrument(skip(db, bridge, lockout, request))]
+async fn post_message<T>(
+    db: web::Data<T>,
+    bridge: web::Data<ChatBridge>,
+    lockout: web::Data<LoginLockout>,
+    request: web::Json<PostMessageRequest>,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    if let Err(response) = check_lockout(&lockout, &request.username) {
+        return response;
+    }
+    let user = match db.get_user(&request.username).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => {
+            tracing::error!("Error while getting user from db. {e}");
+            return db_error(&e);
+        }
+    };
+    match user.verify_user_password(request.password.as_bytes()) {
+        Ok(true) => lockout.record_success(&request.username),
+        Ok(false) => {
+            lockout.record_failure(&request.username);
+            return HttpResponse::Unauthorized().finish();
+        }
+        Err(e) => {
+            tracing::error!("Error while verifying password. {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let room = request.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    if !is_valid_room(room) {
+        return HttpResponse::BadRequest().body(format!(
+            "Room name has to have 1 to {MAX_ROOM_LENGTH} characters."
+        ));
+    }
+    let mut message = Message::new(MessagePayload::Text(request.text.clone()));
+    message.room = Some(room.to_string());
+    match db.insert_message(&message, &user.id).await {
+        Ok(()) => {}
+        Err(e @ ServerError::ValueTooLong { .. }) => {
+            return HttpResponse::BadRequest().body(e.to_string())
+        }
+        Err(e) => tracing::error!("Error while storing message in db. {e}"),
+    }
+    message.set_from_user(&user.username);
+
+    match bridge.send(message).await {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => {
+            tracing::error!("Error while sending message to chat. {e}");
             HttpResponse::InternalServerError().finish()
         }
     }
 }
 
+#[derive(Deserialize, Debug)]
+struct PollQuery {
+    since: Option<u64>,
+}
+
+/// Messages relayed after the cursor from the query. `cursor` is passed as `since` to the next poll.
+#[derive(Serialize)]
+struct PollResponse {
+    cursor: u64,
+    messages: Vec<MessageInfo>,
+}
+
+#[tracing::instrument(skip(bridge))]
+async fn poll(bridge: web::Data<ChatBridge>, query: web::Query<PollQuery>) -> impl Responder {
+    let (cursor, messages) = bridge.poll(query.since.unwrap_or(0)).await;
+    let messages = messages
+        .iter()
+        .filter_map(|message| MessageInfo::from_message(message))
+        .collect();
+    HttpResponse::Ok().json(PollResponse { cursor, messages })
+}
+
+#[derive(Deserialize, Debug)]
+struct EditMessageRequest {
+    text: String,
+}
+
+/// Replaces the text of a stored message. Only admins can edit, the edit is recorded in the audit log.
+#[tracing::instrument(skip(db, admins, lockout, request, body))]
+async fn edit_message<T>(
+    db: web::Data<T>,
+    admins: web::Data<Admins>,
+    lockout: web::Data<LoginLockout>,
+    request: HttpRequest,
+    path: web::Path<Uuid>,
+    body: web::Json<EditMessageRequest>,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    let admin = match authorize_admin(&request, db.get_ref(), &admins, &lockout).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    match db.edit_message(path.deref(), &body.text).await {
+        Ok(true) => {
+            let target = path.to_string();
+            if let Err(e) = db
+                .record_admin_action(&admin.id, "edit_message", &target, None)
+                .await
+            {
+                tracing::error!("Error while recording admin action. {e}");
+                return db_error(&e);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e @ ServerError::ValueTooLong { .. }) => HttpResponse::BadRequest().body(e.to_string()),
+        Err(e) => {
+            tracing::error!("Error while editing message in db. {e}");
+            db_error(&e)
+        }
+    }
+}
+
 #[tracing::instrument(skip(db))]
-async fn get_users<T>(db: web::Data<T>) -> impl Responder
+async fn get_message_history<T>(db: web::Data<T>, path: web::Path<Uuid>) -> impl Responder
 where
     T: ChatDb + Sync + Send,
 {
-    match db.get_users().await {
-        Ok(users) => {
-            let Ok(body) = serde_json::to_string(&users) else {
-                tracing::error!("Error while serializing users.");
+    match db.get_message_history(path.deref()).await {
+        Ok(Some(history)) => {
+            let Ok(body) = serde_json::to_string(&history) else {
+                tracing::error!("Error while serializing message history.");
                 return HttpResponse::InternalServerError().finish();
             };
             HttpResponse::Ok()
                 .content_type(ContentType::json())
                 .body(body)
         }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            tracing::error!("Error while getting message history from db. {e}");
+            db_error(&e)
+        }
+    }
+}
+
+#[tracing::instrument(skip(db, request))]
+async fn get_users<T>(db: web::Data<T>, request: HttpRequest) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    match db.get_users().await {
+        Ok(users) => ResponseFormat::from_request(&request).respond(&users),
         Err(e) => {
             tracing::error!("Error while getting users from db. {e}");
-            HttpResponse::InternalServerError().finish()
+            db_error(&e)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RenameUserRequest {
+    username: String,
+}
+
+/// Renames the user, connected clients are told. Only admins can rename, the rename is recorded in the audit log.
+#[tracing::instrument(skip(db, bridge, admins, lockout, request))]
+async fn rename_user<T>(
+    db: web::Data<T>,
+    bridge: web::Data<ChatBridge>,
+    admins: web::Data<Admins>,
+    lockout: web::Data<LoginLockout>,
+    request: HttpRequest,
+    path: web::Path<Uuid>,
+    body: web::Json<RenameUserRequest>,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    let admin = match authorize_admin(&request, db.get_ref(), &admins, &lockout).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    match bridge
+        .rename_user(db.get_ref(), path.deref(), &body.username)
+        .await
+    {
+        Ok(Some(previous)) => {
+            let details = format!("renamed to {}", body.username.trim());
+            if let Err(e) = db
+                .record_admin_action(&admin.id, "rename_user", &previous, Some(&details))
+                .await
+            {
+                tracing::error!("Error while recording admin action. {e}");
+                return db_error(&e);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e @ ServerError::UsernameTaken(_)) => HttpResponse::Conflict().body(e.to_string()),
+        Err(e @ (ServerError::InvalidUsername | ServerError::ValueTooLong { .. })) => {
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Err(e) => {
+            tracing::error!("Error while renaming user in db. {e}");
+            db_error(&e)
         }
     }
 }
@@ -136,20 +513,895 @@ where
         },
         Err(e) => {
             tracing::error!("Error while removing user from db. {e}");
-            HttpResponse::InternalServerError().finish()
+            db_error(&e)
         }
     }
 }
 
-async fn metrics_handler() -> impl Responder {
-    println!("scraped");
+/// Username and password from the `Authorization: Basic` header.
+fn basic_credentials(request: &HttpRequest) -> Option<(String, String)> {
+    let value = request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let decoded = general_purpose::STANDARD
+        .decode(value.strip_prefix("Basic ")?)
+        .ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Returns the admin that sent the request. Otherwise returns the response for the request.
+async fn authorize_admin<T: ChatDb>(
+    request: &HttpRequest,
+    db: &T,
+    admins: &Admins,
+    lockout: &LoginLockout,
+) -> Result<UserInfo, HttpResponse> {
+    let Some((username, password)) = basic_credentials(request) else {
+        return Err(HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Basic"))
+            .finish());
+    };
+    check_lockout(lockout, &username)?;
+    match admins.verify(db, lockout, &username, &password).await {
+        Ok(Some(admin)) => Ok(admin),
+        Ok(None) => Err(HttpResponse::Forbidden().finish()),
+        Err(e) => {
+            tracing::error!("Error while verifying admin. {e}");
+            Err(db_error(&e))
+        }
+    }
+}
 
+#[derive(Deserialize, Debug)]
+struct KickQuery {
+    reason: Option<String>,
+}
+
+/// Disconnects the user. The kick is recorded in the audit log first, so there is no kick without a record.
+#[tracing::instrument(skip(db, bridge, admins, lockout, request))]
+async fn kick_user<T>(
+    db: web::Data<T>,
+    bridge: web::Data<ChatBridge>,
+    admins: web::Data<Admins>,
+    lockout: web::Data<LoginLockout>,
+    request: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<KickQuery>,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    let admin = match authorize_admin(&request, db.get_ref(), &admins, &lockout).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    let target = match db.get_users().await {
+        Ok(users) => users.into_iter().find(|user| user.id == *path),
+        Err(e) => {
+            tracing::error!("Error while getting users from db. {e}");
+            return db_error(&e);
+        }
+    };
+    let Some(target) = target else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let reason = query.reason.as_deref();
+    if let Err(e) = db
+        .record_admin_action(&admin.id, "kick", &target.username, reason)
+        .await
+    {
+        tracing::error!("Error while recording admin action. {e}");
+        return db_error(&e);
+    }
+    tracing::info!("Admin {} kicked user {}", admin.username, target.username);
+
+    let text = match reason {
+        Some(reason) => format!("You were disconnected by an admin. {reason}"),
+        None => "You were disconnected by an admin.".to_string(),
+    };
+    bridge.kick_user(&target.id, &text);
+    HttpResponse::NoContent().finish()
+}
+
+#[tracing::instrument(skip(db, admins, lockout, request))]
+async fn get_audit<T>(
+    db: web::Data<T>,
+    admins: web::Data<Admins>,
+    lockout: web::Data<LoginLockout>,
+    request: HttpRequest,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    if let Err(response) = authorize_admin(&request, db.get_ref(), &admins, &lockout).await {
+        return response;
+    }
+    match db.get_admin_actions().await {
+        Ok(actions) => HttpResponse::Ok().json(actions),
+        Err(e) => {
+            tracing::error!("Error while getting admin actions from db. {e}");
+            db_error(&e)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportQuery {
+    #[serde(default)]
+    include_passwords: bool,
+}
+
+/// Returns all users. Password hashes are included only when asked for, such an export is a full backup that can be imported.
+#[tracing::instrument(skip(db, admins, lockout, request))]
+async fn export_users<T>(
+    db: web::Data<T>,
+    admins: web::Data<Admins>,
+    lockout: web::Data<LoginLockout>,
+    request: HttpRequest,
+    query: web::Query<ExportQuery>,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    let admin = match authorize_admin(&request, db.get_ref(), &admins, &lockout).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    let users = match db.export_users().await {
+        Ok(users) => users,
+        Err(e) => {
+            tracing::error!("Error while exporting users from db. {e}");
+            return db_error(&e);
+        }
+    };
+
+    let details = query.include_passwords.then_some("with passwords");
+    if let Err(e) = db
+        .record_admin_action(&admin.id, "export_users", "users", details)
+        .await
+    {
+        tracing::error!("Error while recording admin action. {e}");
+        return db_error(&e);
+    }
+
+    let users: Vec<_> = users
+        .into_iter()
+        .map(|user| ExportedUser::new(user, query.include_passwords))
+        .collect();
+    HttpResponse::Ok().json(users)
+}
+
+#[derive(Deserialize, Debug)]
+struct ImportQuery {
+    #[serde(default)]
+    on_duplicate: DuplicateUserPolicy,
+}
+
+/// Inserts users from a full export. Returns how many were imported, overwritten and skipped, with the names of the skipped
+/// users. Usernames are validated like at registration, invalid ones are skipped.
+#[tracing::instrument(skip(db, admins, lockout, request, users))]
+async fn import_users<T>(
+    db: web::Data<T>,
+    admins: web::Data<Admins>,
+    lockout: web::Data<LoginLockout>,
+    request: HttpRequest,
+    query: web::Query<ImportQuery>,
+    users: web::Json<Vec<ExportedUser>>,
+) -> impl Responder
+where
+    T: ChatDb + Sync + Send,
+{
+    let admin = match authorize_admin(&request, db.get_ref(), &admins, &lockout).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    let users = match users
+        .into_inner()
+        .into_iter()
+        .map(User::try_from)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(users) => users,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let mut invalid = Vec::new();
+    let users: Vec<_> = users
+        .into_iter()
+        .filter_map(|user| match validate_username(&user.username) {
+            Ok(username) => Some(User { username, ..user }),
+            Err(_) => {
+                invalid.push(user.username);
+                None
+            }
+        })
+        .collect();
+
+    let mut summary = match db.import_users(&users, query.on_duplicate).await {
+        Ok(summary) => summary,
+        Err(e @ ServerError::ValueTooLong { .. }) => {
+            return HttpResponse::BadRequest().body(e.to_string())
+        }
+        Err(e) => {
+            tracing::error!("Error while importing users. {e}");
+            return db_error(&e);
+        }
+    };
+    for username in invalid {
+        summary.skip(&username);
+    }
+
+    let details = format!(
+        "imported {}, overwritten {}, skipped {}",
+        summary.imported, summary.overwritten, summary.skipped
+    );
+    if let Err(e) = db
+        .record_admin_action(&admin.id, "import_users", "users", Some(&details))
+        .await
+    {
+        tracing::error!("Error while recording admin action. {e}");
+        return db_error(&e);
+    }
+    HttpResponse::Ok().json(summary)
+}
+
+/// Metrics of the default registry in the Prometheus text format.
+async fn metrics_handler() -> impl Responder {
     let encoder = TextEncoder::new();
     let mut buffer = vec![];
 
     let metrics = prometheus::gather();
 
-    encoder.encode(&metrics, &mut buffer).unwrap();
+    if let Err(e) = encoder.encode(&metrics, &mut buffer) {
+        tracing::error!("Error while encoding metrics. {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitBreakerSettings;
+    use crate::test_utils::{
+        login, receive_server_info, receive_with_timeout, InMemoryDb, TestServer, UnreachableDb,
+    };
+    use crate::user::User;
+    use actix_web::{http::StatusCode, test};
+    use shared::message::{AuthError, AuthUser};
+    use std::sync::atomic::Ordering;
+    use tokio::net::TcpStream;
+
+    #[actix_web::test]
+    async fn capabilities_contain_expected_keys() {
+        let settings = ChatSettings {
+            max_bytes_per_minute: Some(1000),
+            ..Default::default()
+        };
+        let capabilities = web::Data::new(Capabilities::from_settings(&settings));
+        let app = test::init_service(
+            App::new()
+                .app_data(capabilities)
+                .route("/capabilities", web::get().to(get_capabilities)),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/capabilities").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+
+        for key in [
+            "protocol_version",
+            "compression",
+            "encryption_required",
+            "chunked_files",
+            "max_message_size",
+        ] {
+            assert!(body.get(key).is_some(), "missing key {key}");
+        }
+        assert_eq!(body["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(body["max_bytes_per_minute"], 1000);
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_exposed_in_prometheus_format() {
+        crate::metrics::register_metrics();
+        let app =
+            test::init_service(App::new().route("/metrics", web::get().to(metrics_handler))).await;
 
-    HttpResponse::Ok().body(buffer)
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        assert!(body.contains("# TYPE messages_counter counter"));
+        assert!(body.contains("# TYPE active_connections_counter gauge"));
+        assert!(body.contains("# TYPE broadcast_latency_seconds histogram"));
+        crate::metrics::MESSAGES_PER_ROOM
+            .with_label_values(&["general"])
+            .inc();
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let body =
+            String::from_utf8(test::call_and_read_body(&app, request).await.to_vec()).unwrap();
+        assert!(body.contains("messages_per_room{room=\"general\"}"));
+    }
+
+    #[actix_web::test]
+    async fn chat_message_is_relayed_stored_and_served_by_api() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        // The first login registers the user
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "New user connected: bob"
+        );
+
+        let message = Message::new(MessagePayload::Text("hello everyone".into()));
+        Message::send_msg(&message, &mut alice).await.unwrap();
+        let received = receive_with_timeout(&mut bob).await.unwrap();
+        assert_eq!(received.sender.as_deref(), Some("alice"));
+        assert!(
+            matches!(received.data, MessagePayload::Text(ref text) if text == "hello everyone")
+        );
+
+        let api = server.api();
+        let app = test::init_service(App::new().configure(|cfg| api.configure(cfg))).await;
+
+        let request = test::TestRequest::get().uri("/messages").to_request();
+        let messages: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let messages = messages.as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["username"], "alice");
+        assert_eq!(messages[0]["text"], "hello everyone");
+
+        let request = test::TestRequest::get().uri("/users").to_request();
+        let users: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let mut usernames: Vec<_> = users
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["username"].as_str().unwrap())
+            .collect();
+        usernames.sort();
+        assert_eq!(usernames, ["alice", "bob"]);
+    }
+
+    #[actix_web::test]
+    async fn open_breaker_returns_503_without_calling_db() {
+        let settings = CircuitBreakerSettings {
+            max_failures: 3,
+            retries: 1,
+            retry_delay_ms: 0,
+            ..Default::default()
+        };
+        let unreachable = UnreachableDb::default();
+        let calls = unreachable.calls.clone();
+        let db = web::Data::new(ResilientDb::new(unreachable, &settings));
+        let app = test::init_service(App::new().app_data(db.clone()).route(
+            "/users",
+            web::get().to(get_users::<ResilientDb<UnreachableDb>>),
+        ))
+        .await;
+        let get_users = || test::TestRequest::get().uri("/users").to_request();
+
+        for _ in 0..3 {
+            let response = test::call_service(&app, get_users()).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        // every failed request was tried twice
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+
+        let response = test::call_service(&app, get_users()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[actix_web::test]
+    async fn users_are_returned_as_msgpack_when_accepted() {
+        let db = Arc::new(InMemoryDb::default());
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db))
+                .route("/users", web::get().to(get_users::<InMemoryDb>)),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/users")
+            .insert_header((header::ACCEPT, "application/msgpack"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            MSGPACK
+        );
+        let body = test::read_body(response).await;
+        #[derive(Deserialize)]
+        struct ReceivedUser {
+            id: Uuid,
+            username: String,
+        }
+        let users: Vec<ReceivedUser> = rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(users[0].id, alice.id);
+        assert_eq!(users[0].username, "alice");
+
+        let request = test::TestRequest::get().uri("/users").to_request();
+        let users: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(users[0]["username"], "alice");
+    }
+
+    #[actix_web::test]
+    async fn poll_returns_messages_posted_after_cursor() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        server.db.insert_user(&alice).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(server.db.clone()))
+                .app_data(web::Data::from(server.bridge.clone()))
+                .app_data(web::Data::from(server.lockout.clone()))
+                .route("/messages", web::post().to(post_message::<InMemoryDb>))
+                .route("/poll", web::get().to(poll)),
+        )
+        .await;
+        let post = |text: &str| {
+            test::TestRequest::post()
+                .uri("/messages")
+                .set_json(
+                    serde_json::json!({"username": "alice", "password": "password", "text": text}),
+                )
+                .to_request()
+        };
+        let poll = |since: u64| {
+            test::TestRequest::get()
+                .uri(&format!("/poll?since={since}"))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, post("first")).await;
+        assert_eq!(response.status(), 202);
+        let body: serde_json::Value = test::call_and_read_body_json(&app, poll(0)).await;
+        assert_eq!(body["messages"][0]["text"], "first");
+        let cursor = body["cursor"].as_u64().unwrap();
+
+        test::call_service(&app, post("second")).await;
+        let body: serde_json::Value = test::call_and_read_body_json(&app, poll(cursor)).await;
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["text"], "second");
+        assert_eq!(messages[0]["username"], "alice");
+    }
+
+    #[actix_web::test]
+    async fn message_to_invalid_room_is_rejected() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        server.db.insert_user(&alice).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(server.db.clone()))
+                .app_data(web::Data::from(server.bridge.clone()))
+                .app_data(web::Data::from(server.lockout.clone()))
+                .route("/messages", web::post().to(post_message::<InMemoryDb>)),
+        )
+        .await;
+
+        for room in [String::new(), "a".repeat(65)] {
+            let request = test::TestRequest::post()
+                .uri("/messages")
+                .set_json(serde_json::json!({
+                    "username": "alice", "password": "password", "text": "hi", "room": room
+                }))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), 400);
+        }
+        assert!(server.db.messages.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn wrong_passwords_over_http_lock_the_login() {
+        let settings = ChatSettings {
+            max_failed_logins: Some(2),
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        server.db.insert_user(&alice).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(server.db.clone()))
+                .app_data(web::Data::from(server.bridge.clone()))
+                .app_data(web::Data::from(server.lockout.clone()))
+                .route("/messages", web::post().to(post_message::<InMemoryDb>)),
+        )
+        .await;
+        let post = |password: &str| {
+            test::TestRequest::post()
+                .uri("/messages")
+                .set_json(
+                    serde_json::json!({"username": "alice", "password": password, "text": "hi"}),
+                )
+                .to_request()
+        };
+
+        assert_eq!(test::call_service(&app, post("wrong")).await.status(), 401);
+        assert_eq!(test::call_service(&app, post("wrong")).await.status(), 401);
+        let response = test::call_service(&app, post("password")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        assert!(server.db.messages.lock().unwrap().is_empty());
+
+        // The chat login of the user is locked too
+        let mut stream = TcpStream::connect(server.address).await.unwrap();
+        let response = login(&mut stream, "alice", "password").await;
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert_eq!(auth.error(), Some(&AuthError::LockedOut));
+    }
+
+    fn basic(credentials: &str) -> (header::HeaderName, String) {
+        (
+            header::AUTHORIZATION,
+            format!("Basic {}", general_purpose::STANDARD.encode(credentials)),
+        )
+    }
+
+    #[actix_web::test]
+    async fn only_admin_can_edit_messages() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            ..Default::default()
+        };
+        let db = web::Data::new(InMemoryDb::default());
+        db.insert_user(&admin).await.unwrap();
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        let alice = db.get_user("alice").await.unwrap().unwrap();
+        let message = Message::new(MessagePayload::Text("original".into()));
+        db.insert_message(&message, &alice.id).await.unwrap();
+        let id = db.messages.lock().unwrap()[0].1.id;
+        let app = test::init_service(
+            App::new()
+                .app_data(db.clone())
+                .app_data(web::Data::new(Admins::from_settings(&settings)))
+                .app_data(web::Data::new(LoginLockout::from_settings(&settings)))
+                .route("/messages/{id}", web::put().to(edit_message::<InMemoryDb>)),
+        )
+        .await;
+        let edit = |credentials: Option<&str>| {
+            let request = test::TestRequest::put()
+                .uri(&format!("/messages/{id}"))
+                .set_json(serde_json::json!({"text": "edited"}));
+            match credentials {
+                Some(credentials) => request.insert_header(basic(credentials)),
+                None => request,
+            }
+            .to_request()
+        };
+
+        assert_eq!(test::call_service(&app, edit(None)).await.status(), 401);
+        assert_eq!(
+            test::call_service(&app, edit(Some("alice:password")))
+                .await
+                .status(),
+            403
+        );
+        assert_eq!(db.messages.lock().unwrap()[0].1.text, "original");
+
+        assert_eq!(
+            test::call_service(&app, edit(Some("admin:password")))
+                .await
+                .status(),
+            204
+        );
+        assert_eq!(db.messages.lock().unwrap()[0].1.text, "edited");
+        assert_eq!(db.audit.lock().unwrap()[0].action, "edit_message");
+    }
+
+    #[actix_web::test]
+    async fn guessing_admin_password_is_locked_out() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            max_failed_logins: Some(2),
+            ..Default::default()
+        };
+        let db = web::Data::new(InMemoryDb::default());
+        db.insert_user(&admin).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(db.clone())
+                .app_data(web::Data::new(Admins::from_settings(&settings)))
+                .app_data(web::Data::new(LoginLockout::from_settings(&settings)))
+                .route("/audit", web::get().to(get_audit::<InMemoryDb>)),
+        )
+        .await;
+        let audit = |credentials: &str| {
+            test::TestRequest::get()
+                .uri("/audit")
+                .insert_header(basic(credentials))
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let response = test::call_service(&app, audit("admin:wrong")).await;
+            assert_eq!(response.status(), 403);
+        }
+
+        let response = test::call_service(&app, audit("admin:password")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[actix_web::test]
+    async fn only_admin_can_rename_users() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            ..Default::default()
+        };
+        let db = web::Data::new(InMemoryDb::default());
+        db.insert_user(&admin).await.unwrap();
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        let alice_id = db.get_user("alice").await.unwrap().unwrap().id;
+        let app = test::init_service(
+            App::new()
+                .app_data(db.clone())
+                .app_data(web::Data::new(ChatBridge::from_settings(&settings)))
+                .app_data(web::Data::new(Admins::from_settings(&settings)))
+                .app_data(web::Data::new(LoginLockout::from_settings(&settings)))
+                .route("/users/{id}", web::put().to(rename_user::<InMemoryDb>)),
+        )
+        .await;
+        let rename = |credentials: Option<&str>| {
+            let request = test::TestRequest::put()
+                .uri(&format!("/users/{alice_id}"))
+                .set_json(serde_json::json!({"username": "admin2"}));
+            match credentials {
+                Some(credentials) => request.insert_header(basic(credentials)),
+                None => request,
+            }
+            .to_request()
+        };
+
+        assert_eq!(test::call_service(&app, rename(None)).await.status(), 401);
+        assert_eq!(
+            test::call_service(&app, rename(Some("alice:password")))
+                .await
+                .status(),
+            403
+        );
+        assert!(db.get_user("alice").await.unwrap().is_some());
+
+        assert_eq!(
+            test::call_service(&app, rename(Some("admin:password")))
+                .await
+                .status(),
+            204
+        );
+        assert!(db.get_user("admin2").await.unwrap().is_some());
+        let audit = db.audit.lock().unwrap();
+        assert_eq!(audit[0].action, "rename_user");
+        assert_eq!(audit[0].target, "alice");
+    }
+
+    #[actix_web::test]
+    async fn admin_rights_stay_with_the_user_not_the_name() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            ..Default::default()
+        };
+        let db = web::Data::new(InMemoryDb::default());
+        db.insert_user(&admin).await.unwrap();
+        db.rename_user(&admin.id, "boss").await.unwrap();
+        // Somebody else registers the name the admin had
+        let squatter = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        db.insert_user(&squatter).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(db.clone())
+                .app_data(web::Data::new(Admins::from_settings(&settings)))
+                .app_data(web::Data::new(LoginLockout::from_settings(&settings)))
+                .route("/audit", web::get().to(get_audit::<InMemoryDb>)),
+        )
+        .await;
+        let audit = |credentials: &str| {
+            test::TestRequest::get()
+                .uri("/audit")
+                .insert_header(basic(credentials))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, audit("admin:password")).await;
+        assert_eq!(response.status(), 403);
+        let response = test::call_service(&app, audit("boss:password")).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn kick_is_recorded_in_audit_log() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings.clone()).await;
+        server.db.insert_user(&admin).await.unwrap();
+        let mut bob = server.connect_user("bob").await;
+        let bob_id = server.db.get_user("bob").await.unwrap().unwrap().id;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(server.db.clone()))
+                .app_data(web::Data::from(server.bridge.clone()))
+                .app_data(web::Data::new(Admins::from_settings(&settings)))
+                .app_data(web::Data::new(LoginLockout::from_settings(&settings)))
+                .route("/users/{id}/kick", web::post().to(kick_user::<InMemoryDb>))
+                .route("/audit", web::get().to(get_audit::<InMemoryDb>)),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/audit")
+            .insert_header(basic("bob:password"))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 403);
+
+        let request = test::TestRequest::post()
+            .uri(&format!("/users/{bob_id}/kick?reason=spam"))
+            .insert_header(basic("admin:password"))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 204);
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "You were disconnected by an admin. spam"
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/audit")
+            .insert_header(basic("admin:password"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body[0]["action"], "kick");
+        assert_eq!(body[0]["target"], "bob");
+        assert_eq!(body[0]["details"], "spam");
+        assert_eq!(body[0]["admin_id"], admin.id.to_string());
+    }
+
+    #[actix_web::test]
+    async fn exported_users_can_be_imported() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            ..Default::default()
+        };
+        let source = Arc::new(InMemoryDb::default());
+        let target = Arc::new(InMemoryDb::default());
+        for db in [&source, &target] {
+            db.insert_user(&admin).await.unwrap();
+        }
+        for name in ["alice", "bob"] {
+            let user = User::try_from(AuthUser::new(name, "password")).unwrap();
+            source.insert_user(&user).await.unwrap();
+        }
+        let app = |db: Arc<InMemoryDb>| {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::from(db))
+                    .app_data(web::Data::new(Admins::from_settings(&settings)))
+                    .app_data(web::Data::new(LoginLockout::from_settings(&settings)))
+                    .route("/export/users", web::get().to(export_users::<InMemoryDb>))
+                    .route("/import/users", web::post().to(import_users::<InMemoryDb>)),
+            )
+        };
+        let source_app = app(source.clone()).await;
+        let target_app = app(target.clone()).await;
+        let credentials = (
+            header::AUTHORIZATION,
+            format!(
+                "Basic {}",
+                general_purpose::STANDARD.encode("admin:password")
+            ),
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/export/users")
+            .insert_header(credentials.clone())
+            .to_request();
+        let without_passwords: serde_json::Value =
+            test::call_and_read_body_json(&source_app, request).await;
+        assert!(without_passwords[0].get("password").is_none());
+
+        let request = test::TestRequest::get()
+            .uri("/export/users?include_passwords=true")
+            .insert_header(credentials.clone())
+            .to_request();
+        let export: serde_json::Value = test::call_and_read_body_json(&source_app, request).await;
+
+        let request = test::TestRequest::post()
+            .uri("/import/users?on_duplicate=skip")
+            .insert_header(credentials)
+            .set_json(&export)
+            .to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&target_app, request).await;
+        assert_eq!(summary["imported"], 2);
+        assert_eq!(summary["skipped"], 1);
+
+        let mut usernames: Vec<_> = target
+            .get_users()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.username)
+            .collect();
+        usernames.sort();
+        assert_eq!(usernames, vec!["admin", "alice", "bob"]);
+        let alice = target.get_user("alice").await.unwrap().unwrap();
+        assert!(alice.verify_user_password(b"password").unwrap());
+    }
+
+    #[actix_web::test]
+    async fn import_skips_invalid_and_conflicting_users() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            ..Default::default()
+        };
+        let db = Arc::new(InMemoryDb::default());
+        db.insert_user(&admin).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(db.clone()))
+                .app_data(web::Data::new(Admins::from_settings(&settings)))
+                .app_data(web::Data::new(LoginLockout::from_settings(&settings)))
+                .route("/import/users", web::post().to(import_users::<InMemoryDb>)),
+        )
+        .await;
+        let bob = User::try_from(AuthUser::new("bob", "password")).unwrap();
+        let users: Vec<_> = [
+            User {
+                username: "al ice".into(),
+                ..User::try_from(AuthUser::new("alice", "password")).unwrap()
+            },
+            User {
+                username: "mallory".into(),
+                ..admin.clone()
+            },
+            bob.clone(),
+            bob,
+        ]
+        .into_iter()
+        .map(|user| ExportedUser::new(user, true))
+        .collect();
+
+        let request = test::TestRequest::post()
+            .uri("/import/users")
+            .insert_header(basic("admin:password"))
+            .set_json(&users)
+            .to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+
+        assert_eq!(summary["imported"], 1);
+        assert_eq!(summary["skipped"], 3);
+        assert_eq!(
+            summary["skipped_users"],
+            serde_json::json!(["mallory", "bob", "al ice"])
+        );
+        assert_eq!(db.get_users().await.unwrap().len(), 2);
+    }
 }
@@ -0,0 +1,240 @@
+use shared::message::MessagePayload;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// Chunked files one connection can send at the same time when the attachment types are limited.
+pub const MAX_OPEN_TRANSFERS: usize = 16;
+
+/// File type recognized from the leading bytes of an attachment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileType {
+    pub extension: &'static str,
+    pub mime: &'static str,
+}
+
+const SIGNATURES: &[(&[u8], FileType)] = &[
+    (
+        b"\x89PNG\r\n\x1a\n",
+        FileType {
+            extension: "png",
+            mime: "image/png",
+        },
+    ),
+    (
+        b"\xff\xd8\xff",
+        FileType {
+            extension: "jpg",
+            mime: "image/jpeg",
+        },
+    ),
+    (
+        b"GIF8",
+        FileType {
+            extension: "gif",
+            mime: "image/gif",
+        },
+    ),
+    (
+        b"%PDF-",
+        FileType {
+            extension: "pdf",
+            mime: "application/pdf",
+        },
+    ),
+    (
+        b"PK\x03\x04",
+        FileType {
+            extension: "zip",
+            mime: "application/zip",
+        },
+    ),
+    (
+        b"\x1f\x8b",
+        FileType {
+            extension: "gz",
+            mime: "application/gzip",
+        },
+    ),
+];
+
+const TEXT: FileType = FileType {
+    extension: "txt",
+    mime: "text/plain",
+};
+
+/// Detects the type of the attachment from its content. The file name is not trusted.
+pub fn detect_type(data: &[u8]) -> Option<FileType> {
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+        .map(|(_, file_type)| *file_type)
+        .or_else(|| std::str::from_utf8(data).ok().map(|_| TEXT))
+}
+
+/// Outcome of the attachment policy for a payload.
+#[derive(Debug, PartialEq)]
+pub enum AttachmentCheck {
+    Allowed,
+    /// The attachment is of a type that is not allowed, the sender is told.
+    Denied,
+    /// Chunk of a transfer whose first chunk was denied or never came, it is dropped silently.
+    UnknownTransfer,
+}
+
+/// Allowlist of attachment types, entries are either extensions (`png`) or MIME types (`image/png`).
+/// An empty allowlist allows everything. It is kept per connection, so the later chunks of an allowed file
+/// are let through by the id of the transfer, their content can't be recognized.
+///
+/// The type is detected from the content, so attachments the server can't read are denied. That includes every
+/// end-to-end encrypted attachment, the server doesn't have the key. Servers whose users encrypt attachments
+/// have to leave the allowlist empty.
+pub struct AttachmentPolicy {
+    allowed: Vec<String>,
+    /// Chunked files whose first chunk was allowed and whose last chunk didn't come yet.
+    open_transfers: HashSet<Uuid>,
+}
+
+impl AttachmentPolicy {
+    pub fn new(allowed: &[String]) -> Self {
+        Self {
+            allowed: allowed
+                .iter()
+                .map(|entry| entry.trim().trim_start_matches('.').to_lowercase())
+                .collect(),
+            open_transfers: HashSet::new(),
+        }
+    }
+
+    /// Checks the payload. The first chunk of a file decides about the whole transfer.
+    pub fn check(&mut self, payload: &MessagePayload) -> AttachmentCheck {
+        if self.allowed.is_empty() {
+            return AttachmentCheck::Allowed;
+        }
+        match payload {
+            MessagePayload::Image(data) | MessagePayload::File(_, data) => self.check_type(data),
+            // The type can be detected only from the beginning of the file.
+            MessagePayload::FileChunk {
+                transfer_id,
+                seq: 0,
+                total,
+                data,
+                ..
+            } => {
+                let check = self.check_type(data);
+                if check != AttachmentCheck::Allowed || *total <= 1 {
+                    return check;
+                }
+                if self.open_transfers.len() >= MAX_OPEN_TRANSFERS {
+                    return AttachmentCheck::Denied;
+                }
+                self.open_transfers.insert(*transfer_id);
+                AttachmentCheck::Allowed
+            }
+            MessagePayload::FileChunk {
+                transfer_id,
+                seq,
+                total,
+                ..
+            } => {
+                let open = match seq.saturating_add(1) >= *total {
+                    true => self.open_transfers.remove(transfer_id),
+                    false => self.open_transfers.contains(transfer_id),
+                };
+                match open {
+                    true => AttachmentCheck::Allowed,
+                    false => AttachmentCheck::UnknownTransfer,
+                }
+            }
+            MessagePayload::FileCancel { id } => {
+                self.open_transfers.remove(id);
+                AttachmentCheck::Allowed
+            }
+            _ => AttachmentCheck::Allowed,
+        }
+    }
+
+    fn check_type(&self, data: &[u8]) -> AttachmentCheck {
+        let allowed = detect_type(data).is_some_and(|file_type| {
+            self.allowed
+                .iter()
+                .any(|entry| entry == file_type.extension || entry == file_type.mime)
+        });
+        match allowed {
+            true => AttachmentCheck::Allowed,
+            false => AttachmentCheck::Denied,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn type_is_detected_from_content() {
+        let png = b"\x89PNG\r\n\x1a\nrest".to_vec();
+        assert_eq!(detect_type(&png).map(|t| t.mime), Some("image/png"));
+        assert_eq!(detect_type(b"hello").map(|t| t.extension), Some("txt"));
+        assert_eq!(detect_type(&[0, 159, 146, 150]), None);
+    }
+
+    #[test]
+    fn file_name_is_ignored() {
+        let mut policy = AttachmentPolicy::new(&["png".to_string()]);
+        let payload = MessagePayload::File("image.png".into(), b"%PDF-1.4".to_vec());
+
+        assert_eq!(policy.check(&payload), AttachmentCheck::Denied);
+    }
+
+    #[test]
+    fn empty_allowlist_allows_everything() {
+        let mut policy = AttachmentPolicy::new(&[]);
+
+        assert_eq!(
+            policy.check(&MessagePayload::File("a".into(), vec![0, 159])),
+            AttachmentCheck::Allowed
+        );
+    }
+
+    fn chunk(transfer_id: Uuid, seq: u32, data: &[u8]) -> MessagePayload {
+        MessagePayload::FileChunk {
+            transfer_id,
+            name: "a.png".into(),
+            seq,
+            total: 3,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn later_chunks_follow_the_first_chunk() {
+        let mut policy = AttachmentPolicy::new(&["png".to_string()]);
+        let (allowed, denied, unknown) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(
+            policy.check(&chunk(allowed, 0, b"\x89PNG\r\n\x1a\n")),
+            AttachmentCheck::Allowed
+        );
+        assert_eq!(
+            policy.check(&chunk(denied, 0, b"%PDF-1.4")),
+            AttachmentCheck::Denied
+        );
+        for seq in 1..3 {
+            assert_eq!(
+                policy.check(&chunk(allowed, seq, &[0, 159])),
+                AttachmentCheck::Allowed
+            );
+            for id in [denied, unknown] {
+                assert_eq!(
+                    policy.check(&chunk(id, seq, &[0, 159])),
+                    AttachmentCheck::UnknownTransfer
+                );
+            }
+        }
+        // The transfer is finished by its last chunk
+        assert_eq!(
+            policy.check(&chunk(allowed, 2, &[0, 159])),
+            AttachmentCheck::UnknownTransfer
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::configuration::ChatSettings;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Meters how many bytes a single connection sent during the last minute (rolling window).
+/// Once the budget is used up, only small payloads are let through until older entries fall out of the window.
+/// File chunks never pass over the budget, otherwise a file could be sent in small chunks without any limit.
+pub struct BandwidthMeter {
+    max_bytes_per_minute: Option<u64>,
+    small_payload_bytes: usize,
+    window: VecDeque<(Instant, u64)>,
+    bytes_in_window: u64,
+}
+
+impl BandwidthMeter {
+    pub fn new(max_bytes_per_minute: Option<u64>, small_payload_bytes: usize) -> Self {
+        Self {
+            max_bytes_per_minute,
+            small_payload_bytes,
+            window: VecDeque::new(),
+            bytes_in_window: 0,
+        }
+    }
+
+    pub fn from_settings(settings: &ChatSettings) -> Self {
+        Self::new(settings.max_bytes_per_minute, settings.small_payload_bytes)
+    }
+
+    /// Records a payload of the given size. Returns false if the payload should be rejected.
+    pub fn try_consume(&mut self, bytes: usize) -> bool {
+        self.try_consume_at(bytes, true, Instant::now())
+    }
+
+    /// Same as `try_consume` for a file chunk, which is rejected over the budget whatever its size.
+    pub fn try_consume_chunk(&mut self, bytes: usize) -> bool {
+        self.try_consume_at(bytes, false, Instant::now())
+    }
+
+    /// With `lets_small_through`, payloads up to `small_payload_bytes` pass even over the budget.
+    fn try_consume_at(&mut self, bytes: usize, lets_small_through: bool, now: Instant) -> bool {
+        let Some(limit) = self.max_bytes_per_minute else {
+            return true;
+        };
+
+        while let Some((time, size)) = self.window.front() {
+            if now.duration_since(*time) < WINDOW {
+                break;
+            }
+            self.bytes_in_window -= size;
+            self.window.pop_front();
+        }
+
+        let size = bytes as u64;
+        let small = lets_small_through && bytes <= self.small_payload_bytes;
+        if !small && self.bytes_in_window + size > limit {
+            return false;
+        }
+
+        self.window.push_back((now, size));
+        self.bytes_in_window += size;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_payloads_are_throttled_over_budget() {
+        let mut meter = BandwidthMeter::new(Some(10_000), 100);
+        let now = Instant::now();
+
+        assert!(meter.try_consume_at(6_000, true, now));
+        assert!(!meter.try_consume_at(6_000, true, now));
+
+        // small messages still pass even over the budget
+        assert!(meter.try_consume_at(50, true, now));
+        assert!(meter.try_consume_at(100, true, now));
+    }
+
+    #[test]
+    fn small_chunks_are_throttled_over_budget() {
+        let mut meter = BandwidthMeter::new(Some(10_000), 100);
+
+        assert!(meter.try_consume(10_000));
+        assert!(meter.try_consume(50));
+        assert!(!meter.try_consume_chunk(50));
+    }
+
+    #[test]
+    fn budget_resets_after_window() {
+        let mut meter = BandwidthMeter::new(Some(10_000), 100);
+        let now = Instant::now();
+
+        assert!(meter.try_consume_at(8_000, true, now));
+        assert!(!meter.try_consume_at(8_000, true, now + Duration::from_secs(30)));
+        assert!(meter.try_consume_at(8_000, true, now + WINDOW));
+    }
+
+    #[test]
+    fn no_limit_allows_everything() {
+        let mut meter = BandwidthMeter::new(None, 0);
+
+        assert!(meter.try_consume(usize::MAX));
+        assert!(meter.try_consume(usize::MAX));
+    }
+}
@@ -0,0 +1,281 @@
+use async_trait::async_trait;
+use shared::message::Message;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::admin::AdminAction;
+use crate::db::ChatDb;
+use crate::message_info::{MessageHistory, MessageInfo};
+use crate::server_error::ServerError;
+use crate::user::{DuplicateUserPolicy, ImportSummary, User, UserInfo};
+
+/// Configured in the `database.circuit_breaker` section.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CircuitBreakerSettings {
+    /// Failed calls in a row after which the database is not called until the cooldown passes.
+    pub max_failures: u32,
+    pub cooldown_seconds: u64,
+    /// How many times a failed read is repeated before it counts as a failure, writes are not repeated.
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            cooldown_seconds: 30,
+            retries: 2,
+            retry_delay_ms: 50,
+        }
+    }
+}
+
+/// Opens after too many failures in a row, so a database that is down isn't hammered by every request.
+pub struct CircuitBreaker {
+    max_failures: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_failures: u32, cooldown: Duration) -> Self {
+        Self {
+            max_failures,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Whether calls are rejected. After the cooldown calls are let through again, the first failure opens the breaker.
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(Instant::now())
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::default();
+    }
+
+    fn is_open_at(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state.open_until.is_some_and(|open_until| now < open_until)
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.max_failures {
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+/// `ChatDb` that retries failed reads and stops calling the database when the circuit breaker is open.
+/// Writes are not retried, a write that timed out after the commit would be applied twice.
+pub struct ResilientDb<T> {
+    inner: T,
+    breaker: CircuitBreaker,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl<T: ChatDb + Send + Sync> ResilientDb<T> {
+    pub fn new(inner: T, settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            inner,
+            breaker: CircuitBreaker::new(
+                settings.max_failures,
+                Duration::from_secs(settings.cooldown_seconds),
+            ),
+            retries: settings.retries,
+            retry_delay: Duration::from_millis(settings.retry_delay_ms),
+        }
+    }
+
+    /// Calls the database once, for calls that change data.
+    async fn write<'a, R, F, Fut>(&'a self, f: F) -> Result<R, ServerError>
+    where
+        F: Fn(&'a T) -> Fut,
+        Fut: Future<Output = Result<R, ServerError>>,
+    {
+        self.call(f, 0).await
+    }
+
+    /// Calls the database and retries it when it fails, for calls that only read.
+    async fn read<'a, R, F, Fut>(&'a self, f: F) -> Result<R, ServerError>
+    where
+        F: Fn(&'a T) -> Fut,
+        Fut: Future<Output = Result<R, ServerError>>,
+    {
+        self.call(f, self.retries).await
+    }
+
+    async fn call<'a, R, F, Fut>(&'a self, f: F, retries: u32) -> Result<R, ServerError>
+    where
+        F: Fn(&'a T) -> Fut,
+        Fut: Future<Output = Result<R, ServerError>>,
+    {
+        if self.breaker.is_open() {
+            return Err(ServerError::CircuitOpen);
+        }
+        let mut attempt = 0;
+        loop {
+            match f(&self.inner).await {
+                Ok(value) => {
+                    self.breaker.record_success();
+                    return Ok(value);
+                }
+                // The database answered, the request itself is wrong.
+                Err(e) if e.is_invalid_input() => {
+                    self.breaker.record_success();
+                    return Err(e);
+                }
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    tracing::warn!("Database call failed, retrying ({attempt}). {e}");
+                    tokio::time::sleep(self.retry_delay).await;
+                }
+                Err(e) => {
+                    self.breaker.record_failure();
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: ChatDb + Send + Sync> ChatDb for ResilientDb<T> {
+    async fn insert_message(&self, message: &Message, user_id: &Uuid) -> Result<(), ServerError> {
+        self.write(|db| db.insert_message(message, user_id)).await
+    }
+
+    async fn get_messages(
+        &self,
+        username: &str,
+        room: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<MessageInfo>, ServerError> {
+        self.read(|db| db.get_messages(username, room, limit)).await
+    }
+
+    async fn edit_message(&self, id: &Uuid, text: &str) -> Result<bool, ServerError> {
+        self.write(|db| db.edit_message(id, text)).await
+    }
+
+    async fn get_message_history(&self, id: &Uuid) -> Result<Option<MessageHistory>, ServerError> {
+        self.read(|db| db.get_message_history(id)).await
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), ServerError> {
+        self.write(|db| db.insert_user(user)).await
+    }
+
+    async fn get_user(&self, username: &str) -> Result<Option<User>, ServerError> {
+        self.read(|db| db.get_user(username)).await
+    }
+
+    async fn get_users(&self) -> Result<Vec<UserInfo>, ServerError> {
+        self.read(|db| db.get_users()).await
+    }
+
+    async fn export_users(&self) -> Result<Vec<User>, ServerError> {
+        self.read(|db| db.export_users()).await
+    }
+
+    async fn import_users(
+        &self,
+        users: &[User],
+        on_duplicate: DuplicateUserPolicy,
+    ) -> Result<ImportSummary, ServerError> {
+        self.write(|db| db.import_users(users, on_duplicate)).await
+    }
+
+    async fn remove_user(&self, id: &Uuid) -> Result<u64, ServerError> {
+        self.write(|db| db.remove_user(id)).await
+    }
+
+    async fn rename_user(&self, id: &Uuid, new_name: &str) -> Result<Option<String>, ServerError> {
+        self.write(|db| db.rename_user(id, new_name)).await
+    }
+
+    /// Goes straight to the database, so the health of the database is visible even when the breaker is open.
+    async fn ping(&self) -> Result<(), ServerError> {
+        self.inner.ping().await
+    }
+
+    async fn record_admin_action(
+        &self,
+        admin_id: &Uuid,
+        action: &str,
+        target: &str,
+        details: Option<&str>,
+    ) -> Result<(), ServerError> {
+        self.write(|db| db.record_admin_action(admin_id, action, target, details))
+            .await
+    }
+
+    async fn get_admin_actions(&self) -> Result<Vec<AdminAction>, ServerError> {
+        self.read(|db| db.get_admin_actions()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::UnreachableDb;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn breaker_opens_after_failures_and_closes_after_cooldown() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+
+        breaker.record_failure_at(now);
+        assert!(!breaker.is_open_at(now));
+        breaker.record_failure_at(now);
+        assert!(breaker.is_open_at(now + Duration::from_secs(10)));
+        assert!(!breaker.is_open_at(now + Duration::from_secs(30)));
+
+        // still failing after the cooldown
+        breaker.record_failure_at(now + Duration::from_secs(30));
+        assert!(breaker.is_open_at(now + Duration::from_secs(31)));
+
+        breaker.record_success();
+        assert!(!breaker.is_open_at(now + Duration::from_secs(31)));
+    }
+
+    #[tokio::test]
+    async fn only_reads_are_retried() {
+        let unreachable = UnreachableDb::default();
+        let calls = unreachable.calls.clone();
+        let db = ResilientDb::new(
+            unreachable,
+            &CircuitBreakerSettings {
+                retries: 2,
+                retry_delay_ms: 0,
+                ..Default::default()
+            },
+        );
+
+        assert!(db.get_users().await.is_err());
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+
+        let message = Message::new(shared::message::MessagePayload::Text("hi".into()));
+        assert!(db.insert_message(&message, &Uuid::new_v4()).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,199 @@
+use flume::{Receiver, Sender};
+use shared::message::Message;
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use uuid::Uuid;
+
+use crate::configuration::ChatSettings;
+use crate::db::ChatDb;
+use crate::server_error::ServerError;
+use crate::user::validate_username;
+
+/// Origin of messages sent over HTTP. It doesn't match any connected client, so the message is sent to everybody.
+pub const HTTP_ORIGIN: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+type Broadcast = (SocketAddr, Message);
+
+/// Change of a user made outside of the user's connection, connections of the user react to it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserEvent {
+    /// The user has a new name, connections update the name they send with.
+    Renamed(Uuid, String),
+    /// An admin disconnected the user, the reason is sent to the user.
+    Kicked(Uuid, String),
+}
+
+/// Connects the HTTP api with the chat server, so clients without a persistent TCP connection can chat.
+/// Messages sent over HTTP go to the broadcaster like messages from connected clients,
+/// and the recently relayed messages are kept for clients that poll them.
+pub struct ChatBridge {
+    sender: Sender<Broadcast>,
+    receiver: Receiver<Broadcast>,
+    /// Relayed messages with their cursor, oldest first.
+    recent: Mutex<VecDeque<(u64, Arc<Message>)>>,
+    capacity: usize,
+    /// Cursor of the last relayed message, polling clients wait for it to change.
+    cursor: watch::Sender<u64>,
+    poll_timeout: Duration,
+    user_events: broadcast::Sender<UserEvent>,
+}
+
+impl ChatBridge {
+    pub fn new(capacity: usize, poll_timeout: Duration) -> Self {
+        let (sender, receiver) = flume::unbounded();
+        Self {
+            sender,
+            receiver,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            cursor: watch::Sender::new(0),
+            poll_timeout,
+            user_events: broadcast::channel(16).0,
+        }
+    }
+
+    pub fn from_settings(settings: &ChatSettings) -> Self {
+        Self::new(
+            settings.poll_buffer_size,
+            Duration::from_secs(settings.poll_timeout_seconds),
+        )
+    }
+
+    /// Sender of the messages that are broadcast to all connected clients.
+    pub fn sender(&self) -> Sender<Broadcast> {
+        self.sender.clone()
+    }
+
+    pub fn receiver(&self) -> Receiver<Broadcast> {
+        self.receiver.clone()
+    }
+
+    /// Sends the message to all connected clients.
+    pub async fn send(&self, message: Message) -> Result<(), ServerError> {
+        self.sender
+            .send_async((HTTP_ORIGIN, message))
+            .await
+            .map_err(|e| ServerError::ChannelSend(Box::new(e)))
+    }
+
+    /// Renames the user and tells all connected clients about the new name. The name is validated like at registration.
+    /// Returns the previous username, None if there is no such user.
+    pub async fn rename_user<D: ChatDb>(
+        &self,
+        db: &D,
+        id: &Uuid,
+        new_name: &str,
+    ) -> Result<Option<String>, ServerError> {
+        let new_name = validate_username(new_name)?;
+        let Some(previous) = db.rename_user(id, &new_name).await? else {
+            return Ok(None);
+        };
+        tracing::info!("User {previous} renamed to {new_name}");
+
+        _ = self
+            .user_events
+            .send(UserEvent::Renamed(*id, new_name.clone()));
+        let text = format!("{previous} is now known as {new_name}");
+        self.send(Message::new_server_msg(&text)).await?;
+        Ok(Some(previous))
+    }
+
+    /// Disconnects all connections of the user.
+    pub fn kick_user(&self, id: &Uuid, reason: &str) {
+        _ = self
+            .user_events
+            .send(UserEvent::Kicked(*id, reason.to_string()));
+    }
+
+    pub fn subscribe_user_events(&self) -> broadcast::Receiver<UserEvent> {
+        self.user_events.subscribe()
+    }
+
+    /// Keeps the relayed message for polling clients. The oldest message is forgotten when the buffer is full.
+    pub fn record(&self, message: Arc<Message>) {
+        let mut recent = self.recent.lock().unwrap();
+        let cursor = *self.cursor.borrow() + 1;
+        recent.push_back((cursor, message));
+        if recent.len() > self.capacity {
+            recent.pop_front();
+        }
+        self.cursor.send_replace(cursor);
+    }
+
+    /// Returns the messages relayed after `since` and the cursor to use for the next poll. Expired ephemeral messages are left out.
+    /// If there are no such messages yet, waits for them up to the poll timeout.
+    pub async fn poll(&self, since: u64) -> (u64, Vec<Arc<Message>>) {
+        let mut cursor = self.cursor.subscribe();
+        _ = tokio::time::timeout(self.poll_timeout, cursor.wait_for(|cursor| *cursor > since))
+            .await;
+
+        let now = chrono::Utc::now().timestamp();
+        let recent = self.recent.lock().unwrap();
+        let messages = recent
+            .iter()
+            .filter(|(cursor, _)| *cursor > since)
+            .filter(|(_, message)| !message.is_expired_at(now))
+            .map(|(_, message)| message.clone())
+            .collect();
+        (*self.cursor.borrow(), messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::InMemoryDb;
+    use crate::user::User;
+    use shared::message::{AuthUser, MessagePayload};
+
+    fn text(text: &str) -> Arc<Message> {
+        Arc::new(Message::new(MessagePayload::Text(text.to_string())))
+    }
+
+    #[tokio::test]
+    async fn only_the_newest_messages_are_kept() {
+        let bridge = ChatBridge::new(2, Duration::from_millis(10));
+        for i in 0..3 {
+            bridge.record(text(&i.to_string()));
+        }
+
+        let (cursor, messages) = bridge.poll(0).await;
+
+        assert_eq!(cursor, 3);
+        let texts: Vec<_> = messages.iter().map(|m| &m.data).collect();
+        assert_eq!(
+            texts,
+            vec![
+                &MessagePayload::Text("1".into()),
+                &MessagePayload::Text("2".into())
+            ]
+        );
+        assert!(bridge.poll(cursor).await.1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_names_are_rejected_on_rename() {
+        let bridge = ChatBridge::new(2, Duration::from_millis(10));
+        let db = InMemoryDb::default();
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.users.lock().unwrap().push(alice.clone());
+
+        for name in ["", "  ", "al", &"a".repeat(33), "ali\u{7}ce", "ali ce"] {
+            assert!(
+                matches!(
+                    bridge.rename_user(&db, &alice.id, name).await,
+                    Err(ServerError::InvalidUsername)
+                ),
+                "{name:?} is not valid"
+            );
+        }
+        assert_eq!(db.users.lock().unwrap()[0].username, "alice");
+
+        let previous = bridge.rename_user(&db, &alice.id, " carol ").await.unwrap();
+        assert_eq!(previous.as_deref(), Some("alice"));
+        assert_eq!(db.users.lock().unwrap()[0].username, "carol");
+    }
+}
@@ -1,13 +1,19 @@
+use crate::breaker::CircuitBreakerSettings;
+use crate::db::StorageLimits;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
+use shared::compression::Algorithm;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
+use uuid::Uuid;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub api: ApplicationSettings,
+    #[serde(default)]
+    pub chat: ChatSettings,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -19,6 +25,13 @@ pub struct DatabaseSettings {
     pub host: String,
     pub database_name: String,
     pub require_ssl: bool,
+    #[serde(default)]
+    pub limits: StorageLimits,
+    /// Whether previous versions of edited messages are kept in the `message_edits` table.
+    #[serde(default)]
+    pub store_edit_history: bool,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerSettings,
 }
 
 impl DatabaseSettings {
@@ -49,6 +62,167 @@ pub struct ApplicationSettings {
     pub host: std::net::Ipv4Addr,
 }
 
+/// Settings of the chat protocol itself (limits, policies...). Every field has a default so the section can be omitted.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChatSettings {
+    /// How many bytes a single user can send per minute. `None` disables the limit.
+    pub max_bytes_per_minute: Option<u64>,
+    /// Payloads up to this size, except file chunks, are always allowed, even when the user is over the byte budget.
+    pub small_payload_bytes: usize,
+    /// How many messages a single connection can send per second on average. `None` disables the limit.
+    pub max_messages_per_second: Option<f64>,
+    /// How many messages can be sent at once before `max_messages_per_second` applies.
+    pub message_burst: u32,
+    /// How many connections can be in the middle of authentication at the same time.
+    pub max_pending_authentications: usize,
+    /// How long a connection can take to authenticate before it is closed, so idle sockets don't hold the pending slots.
+    pub auth_timeout_seconds: u64,
+    /// How long a session token stays valid after the user disconnects.
+    pub session_ttl_seconds: u64,
+    /// Whether to tell other users that someone reconnected.
+    pub announce_reconnects: bool,
+    /// What to do when a user logs in while the same username already has the maximum of connections.
+    pub duplicate_login_policy: DuplicateLoginPolicy,
+    /// How many connections one user can have open at the same time.
+    pub max_connections_per_user: usize,
+    /// Extensions or MIME types of attachments that can be sent. Empty list allows all types.
+    pub allowed_attachment_types: Vec<String>,
+    /// Preferred compression of messages sent to clients. It is used only if the client supports it.
+    pub compression_algorithm: Algorithm,
+    /// Compression level, zstd accepts 1-22, gzip 0-9.
+    pub compression_level: i32,
+    /// Whether the whole connection is compressed when the client offers it, frames then don't carry the compression flag.
+    pub connection_compression: bool,
+    /// How far the login timestamp can be from the server time, older logins are rejected as replayed.
+    pub login_max_clock_skew_seconds: u64,
+    /// New connections one IP can open in `connection_rate_window_seconds`, more are closed right away.
+    /// `None` disables the limit.
+    pub max_connections_per_ip: Option<u32>,
+    pub connection_rate_window_seconds: u64,
+    /// Failed logins in a row after which the username is locked for `login_lockout_seconds`. `None` disables the lockout.
+    pub max_failed_logins: Option<u32>,
+    /// How long the login of a username stays locked after too many failed attempts.
+    pub login_lockout_seconds: u64,
+    /// Whether whitespace around text messages is trimmed. Messages without any text are dropped.
+    pub trim_text: bool,
+    /// Maximum size of a serialized message. Larger messages are not relayed and the sender is told. `None` disables the limit.
+    pub max_message_bytes: Option<u64>,
+    /// How long `GET /poll` waits for new messages before it returns an empty list.
+    pub poll_timeout_seconds: u64,
+    /// How many recently relayed messages are kept for clients polling over HTTP.
+    pub poll_buffer_size: usize,
+    /// Messages waiting for a client after which it is disconnected as too slow. `None` lets the queue grow.
+    pub max_queued_messages: Option<usize>,
+    /// Most messages sent back for one history request.
+    pub max_history_messages: u32,
+    /// How many last messages are sent to a user after the login. 0 disables the replay.
+    pub replay_on_connect: u32,
+    /// Message of the day sent to a user right after the login, `{active_users}` is replaced with the number of connected users.
+    /// `None` or an empty text sends nothing.
+    pub motd: Option<String>,
+    /// How long messages for a user that lost the connection are kept, so they are delivered when the same session
+    /// reconnects. `None` disables the buffering.
+    pub reconnect_grace_seconds: Option<u64>,
+    /// Most messages kept for one disconnected session, the oldest are dropped.
+    pub reconnect_buffer_size: usize,
+    /// Ids of users that can do administrative actions over the api. Ids are used rather than usernames,
+    /// which can be registered or renamed to by anybody.
+    pub admins: Vec<Uuid>,
+    /// Whether admins can set the sender of their messages, for simulating many users from one connection in tests.
+    /// Senders set by other users are always replaced with their username.
+    pub allow_sender_override: bool,
+    /// Where join and leave events are posted. `None` disables the webhook.
+    pub presence_webhook: Option<WebhookSettings>,
+    /// Most rooms counted separately in the `messages_per_room` metric, messages of further rooms are counted as `_other`.
+    pub max_room_metric_labels: usize,
+}
+
+/// Webhook that receives presence events as JSON.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct WebhookSettings {
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature of the body, sent in the `X-Webhook-Signature` header.
+    pub secret: Option<Secret<String>>,
+    /// How many times a failed delivery is retried, the delay doubles after each attempt.
+    #[serde(default = "default_webhook_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_webhook_retry_delay")]
+    pub retry_delay_ms: u64,
+    /// Events waiting for delivery. When the queue is full, new events are dropped.
+    #[serde(default = "default_webhook_queue_size")]
+    pub queue_size: usize,
+    /// How long one delivery can take, a webhook that doesn't answer in time counts as failed.
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout_ms: u64,
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+fn default_webhook_retry_delay() -> u64 {
+    500
+}
+
+fn default_webhook_queue_size() -> usize {
+    100
+}
+
+fn default_webhook_timeout() -> u64 {
+    5000
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    /// The new login is rejected.
+    #[default]
+    RejectNew,
+    /// The old connection is disconnected and replaced with the new one.
+    KickOld,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            max_bytes_per_minute: None,
+            small_payload_bytes: 1024,
+            max_messages_per_second: None,
+            message_burst: 10,
+            max_pending_authentications: 64,
+            auth_timeout_seconds: 10,
+            session_ttl_seconds: 300,
+            announce_reconnects: true,
+            duplicate_login_policy: DuplicateLoginPolicy::default(),
+            max_connections_per_user: 1,
+            allowed_attachment_types: Vec::new(),
+            compression_algorithm: Algorithm::None,
+            compression_level: 3,
+            connection_compression: true,
+            login_max_clock_skew_seconds: 60,
+            max_connections_per_ip: None,
+            connection_rate_window_seconds: 10,
+            max_failed_logins: Some(5),
+            login_lockout_seconds: 30,
+            trim_text: false,
+            max_message_bytes: None,
+            poll_timeout_seconds: 30,
+            poll_buffer_size: 1000,
+            max_queued_messages: Some(1000),
+            max_history_messages: 50,
+            replay_on_connect: 20,
+            motd: None,
+            reconnect_grace_seconds: None,
+            reconnect_buffer_size: 100,
+            admins: Vec::new(),
+            allow_sender_override: false,
+            presence_webhook: None,
+            max_room_metric_labels: 100,
+        }
+    }
+}
+
 pub enum Environment {
     Local,
     Production,
@@ -1,11 +1,12 @@
 use crate::{
+    admin::AdminAction,
     configuration::DatabaseSettings,
-    message_info::MessageInfo,
+    message_info::{MessageEdit, MessageHistory, MessageInfo},
     server_error::ServerError,
-    user::{User, UserInfo},
+    user::{DuplicateUserPolicy, ImportSummary, User, UserInfo},
 };
 use async_trait::async_trait;
-use chrono::Utc;
+use chrono::{DateTime, TimeZone, Utc};
 use secrecy::ExposeSecret;
 use shared::message::{Message, MessagePayload};
 use sqlx::{postgres::PgPoolOptions, PgPool};
@@ -14,21 +15,112 @@ use uuid::Uuid;
 #[async_trait]
 pub trait ChatDb {
     async fn insert_message(&self, message: &Message, user_id: &Uuid) -> Result<(), ServerError>;
-    async fn get_messages(&self, username: &str) -> Result<Vec<MessageInfo>, ServerError>;
+    /// Returns at most `limit` last messages, newest first. They are filtered by the start of the username and by the room
+    /// when given.
+    async fn get_messages(
+        &self,
+        username: &str,
+        room: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<MessageInfo>, ServerError>;
+    /// Replaces the text of the message. Returns false if there is no such message.
+    async fn edit_message(&self, id: &Uuid, text: &str) -> Result<bool, ServerError>;
+    /// Returns the message with its previous versions, None if there is no such message.
+    async fn get_message_history(&self, id: &Uuid) -> Result<Option<MessageHistory>, ServerError>;
     async fn insert_user(&self, user: &User) -> Result<(), ServerError>;
     async fn get_user(&self, username: &str) -> Result<Option<User>, ServerError>;
     async fn get_users(&self) -> Result<Vec<UserInfo>, ServerError>;
+    /// Returns all users with their password hashes, for backups.
+    async fn export_users(&self) -> Result<Vec<User>, ServerError>;
+    /// Inserts the users in one transaction. Existing usernames are handled by `on_duplicate`.
+    async fn import_users(
+        &self,
+        users: &[User],
+        on_duplicate: DuplicateUserPolicy,
+    ) -> Result<ImportSummary, ServerError>;
     async fn remove_user(&self, id: &Uuid) -> Result<u64, ServerError>;
+    /// Changes the username, it has to be unique. Returns the previous username, None if there is no such user.
+    async fn rename_user(&self, id: &Uuid, new_name: &str) -> Result<Option<String>, ServerError>;
+    /// Checks that the database is reachable.
+    async fn ping(&self) -> Result<(), ServerError>;
+    /// Adds the action to the audit log.
+    async fn record_admin_action(
+        &self,
+        admin_id: &Uuid,
+        action: &str,
+        target: &str,
+        details: Option<&str>,
+    ) -> Result<(), ServerError>;
+    /// Returns the most recent actions from the audit log, newest first.
+    async fn get_admin_actions(&self) -> Result<Vec<AdminAction>, ServerError>;
+}
+
+/// Maximum lengths of stored values, they are checked before the insert. The columns are TEXT, so the limits
+/// are enforced only here and changing them doesn't need a migration.
+#[derive(serde::Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct StorageLimits {
+    pub max_username_length: usize,
+    pub max_message_length: usize,
+}
+
+impl Default for StorageLimits {
+    fn default() -> Self {
+        Self {
+            max_username_length: 64,
+            max_message_length: 10_000,
+        }
+    }
+}
+
+impl StorageLimits {
+    pub fn check_username(&self, username: &str) -> Result<(), ServerError> {
+        check_length("username", username, self.max_username_length)
+    }
+
+    pub fn check_message(&self, data: &str) -> Result<(), ServerError> {
+        check_length("message", data, self.max_message_length)
+    }
+}
+
+/// Length of the `room` column of the messages table.
+pub const MAX_ROOM_LENGTH: usize = 64;
+
+/// Rooms are checked when they are joined or posted to, so every message can be stored to its room.
+pub fn is_valid_room(room: &str) -> bool {
+    !room.is_empty() && room.chars().count() <= MAX_ROOM_LENGTH
+}
+
+/// Postgres counts the length of VARCHAR in characters, not bytes.
+fn check_length(field: &'static str, value: &str, max: usize) -> Result<(), ServerError> {
+    if value.chars().count() > max {
+        tracing::warn!("Value of {field} is too long to be stored.");
+        return Err(ServerError::ValueTooLong { field, max });
+    }
+    Ok(())
+}
+
+/// Expiry of an ephemeral message counted from the time it is stored. TTLs too large to represent never expire.
+pub fn expiry(ttl_seconds: Option<u64>) -> Option<DateTime<Utc>> {
+    let ttl = i64::try_from(ttl_seconds?).ok()?;
+    Utc.timestamp_opt(Utc::now().timestamp().checked_add(ttl)?, 0)
+        .single()
 }
 
 pub struct ChatPostgresDb {
     db_pool: PgPool,
+    limits: StorageLimits,
+    store_edit_history: bool,
 }
 
 impl ChatPostgresDb {
     pub fn new(configuration: &DatabaseSettings) -> Self {
         let db_pool = Self::get_connection_pool(configuration);
-        Self { db_pool }
+        Self {
+            db_pool,
+            limits: configuration.limits,
+            store_edit_history: configuration.store_edit_history,
+        }
     }
 
     fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
@@ -42,16 +134,22 @@ impl ChatPostgresDb {
 impl ChatDb for ChatPostgresDb {
     #[tracing::instrument(skip(self, message))]
     async fn insert_message(&self, message: &Message, user_id: &Uuid) -> Result<(), ServerError> {
+        if message.data.is_transient() {
+            return Ok(());
+        }
         let data = MessagePayload::serialize_to_text(&message.data);
+        self.limits.check_message(&data)?;
         sqlx::query!(
             r#"
-            INSERT INTO messages(id,user_id,data,timestamp)
-            VALUES ($1,$2,$3,$4)
+            INSERT INTO messages(id,user_id,data,timestamp,expires_at,room)
+            VALUES ($1,$2,$3,$4,$5,$6)
             "#,
             Uuid::new_v4(),
             user_id,
             &data,
             Utc::now(),
+            expiry(message.ttl_seconds),
+            message.room_name(),
         )
         .execute(&self.db_pool)
         .await
@@ -64,6 +162,7 @@ impl ChatDb for ChatPostgresDb {
 
     #[tracing::instrument(skip(self, user))]
     async fn insert_user(&self, user: &User) -> Result<(), ServerError> {
+        self.limits.check_username(&user.username)?;
         sqlx::query!(
             r#"
             INSERT INTO users(id,password,username,salt,last_login)
@@ -88,7 +187,7 @@ impl ChatDb for ChatPostgresDb {
     async fn get_user(&self, username: &str) -> Result<Option<User>, ServerError> {
         let user = sqlx::query_as!(
             User,
-            "SELECT id, password, username, salt FROM users WHERE username = $1",
+            "SELECT id, password, username, salt FROM users WHERE lower(username) = lower($1)",
             username
         )
         .fetch_optional(&self.db_pool)
@@ -115,19 +214,112 @@ impl ChatDb for ChatPostgresDb {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn get_messages(&self, username: &str) -> Result<Vec<MessageInfo>, ServerError> {
+    async fn export_users(&self) -> Result<Vec<User>, ServerError> {
+        let users = sqlx::query_as!(
+            User,
+            "SELECT id, password, username, salt FROM users ORDER BY username"
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::GetUser
+        })?;
+
+        Ok(users)
+    }
+
+    #[tracing::instrument(skip(self, users))]
+    async fn import_users(
+        &self,
+        users: &[User],
+        on_duplicate: DuplicateUserPolicy,
+    ) -> Result<ImportSummary, ServerError> {
+        for user in users {
+            self.limits.check_username(&user.username)?;
+        }
+        let map_err = |e: sqlx::Error| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::ImportUsers
+        };
+
+        let mut summary = ImportSummary::default();
+        let mut transaction = self.db_pool.begin().await.map_err(map_err)?;
+        for user in users {
+            let existing = sqlx::query_scalar!(
+                "SELECT id FROM users WHERE lower(username) = lower($1) FOR UPDATE",
+                user.username
+            )
+            .fetch_optional(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+
+            match (existing, on_duplicate) {
+                (Some(_), DuplicateUserPolicy::Skip) => summary.skip(&user.username),
+                (Some(id), DuplicateUserPolicy::Overwrite) => {
+                    sqlx::query!(
+                        "UPDATE users SET password = $1, salt = $2 WHERE id = $3",
+                        user.password.expose_secret(),
+                        user.salt,
+                        id,
+                    )
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(map_err)?;
+                    summary.overwritten += 1;
+                }
+                (None, _) => {
+                    // The id can belong to another user, such a row is skipped instead of failing the whole import
+                    let inserted = sqlx::query!(
+                        r#"
+                        INSERT INTO users(id,password,username,salt,last_login)
+                        VALUES ($1,$2,$3,$4,$5)
+                        ON CONFLICT DO NOTHING
+                        "#,
+                        user.id,
+                        user.password.expose_secret(),
+                        user.username,
+                        user.salt,
+                        Utc::now(),
+                    )
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(map_err)?
+                    .rows_affected();
+                    match inserted {
+                        0 => summary.skip(&user.username),
+                        _ => summary.imported += 1,
+                    }
+                }
+            }
+        }
+        transaction.commit().await.map_err(map_err)?;
+        Ok(summary)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_messages(
+        &self,
+        username: &str,
+        room: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<MessageInfo>, ServerError> {
         let pattern = format!("{}%", username);
         let messages = sqlx::query_as!(
             MessageInfo,
             r#"
-            SELECT m.id, u.username, m.data as text, m.timestamp 
+            SELECT m.id, u.username, m.data as text, m.timestamp, m.room
             FROM messages m 
             INNER JOIN users u on u.id = m.user_id
-            WHERE ($1 = '') OR u.username like $2
-            ORDER BY m.timestamp DESC LIMIT 50;
+            WHERE (($1 = '') OR u.username like $2)
+              AND ($3::text IS NULL OR m.room = $3)
+              AND (m.expires_at IS NULL OR m.expires_at > now())
+            ORDER BY m.timestamp DESC LIMIT $4;
             "#,
             username,
-            pattern
+            pattern,
+            room,
+            i64::from(limit)
         )
         .fetch_all(&self.db_pool)
         .await
@@ -139,6 +331,89 @@ impl ChatDb for ChatPostgresDb {
         Ok(messages)
     }
 
+    #[tracing::instrument(skip(self, text))]
+    async fn edit_message(&self, id: &Uuid, text: &str) -> Result<bool, ServerError> {
+        self.limits.check_message(text)?;
+        let map_err = |e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::EditMessage
+        };
+
+        let mut transaction = self.db_pool.begin().await.map_err(map_err)?;
+        let old_text =
+            sqlx::query_scalar!("SELECT data FROM messages WHERE id = $1 FOR UPDATE", id)
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(map_err)?;
+        let Some(old_text) = old_text else {
+            return Ok(false);
+        };
+
+        if self.store_edit_history {
+            sqlx::query!(
+                r#"
+                INSERT INTO message_edits(id,message_id,data,timestamp)
+                VALUES ($1,$2,$3,$4)
+                "#,
+                Uuid::new_v4(),
+                id,
+                old_text,
+                Utc::now(),
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+        }
+
+        sqlx::query!("UPDATE messages SET data = $1 WHERE id = $2", text, id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+        transaction.commit().await.map_err(map_err)?;
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_message_history(&self, id: &Uuid) -> Result<Option<MessageHistory>, ServerError> {
+        let map_err = |e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::GetMessages
+        };
+
+        let current = sqlx::query_as!(
+            MessageInfo,
+            r#"
+            SELECT m.id, u.username, m.data as text, m.timestamp, m.room
+            FROM messages m
+            INNER JOIN users u on u.id = m.user_id
+            WHERE m.id = $1 AND (m.expires_at IS NULL OR m.expires_at > now())
+            "#,
+            id
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(map_err)?;
+        let Some(current) = current else {
+            return Ok(None);
+        };
+
+        let edits = sqlx::query_as!(
+            MessageEdit,
+            r#"
+            SELECT data as text, timestamp
+            FROM message_edits
+            WHERE message_id = $1
+            ORDER BY timestamp ASC
+            "#,
+            id
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(map_err)?;
+
+        Ok(Some(MessageHistory { current, edits }))
+    }
+
     #[tracing::instrument(skip(self))]
     async fn remove_user(&self, id: &Uuid) -> Result<u64, ServerError> {
         let result = sqlx::query!("DELETE from users where id = $1", id)
@@ -151,4 +426,339 @@ impl ChatDb for ChatPostgresDb {
 
         Ok(result.rows_affected())
     }
+
+    #[tracing::instrument(skip(self))]
+    async fn rename_user(&self, id: &Uuid, new_name: &str) -> Result<Option<String>, ServerError> {
+        self.limits.check_username(new_name)?;
+        let map_err = |e: sqlx::Error| {
+            if e.as_database_error()
+                .is_some_and(|e| e.is_unique_violation())
+            {
+                return ServerError::UsernameTaken(new_name.to_string());
+            }
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::RenameUser
+        };
+
+        let mut transaction = self.db_pool.begin().await.map_err(map_err)?;
+        let previous =
+            sqlx::query_scalar!("SELECT username FROM users WHERE id = $1 FOR UPDATE", id)
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(map_err)?;
+        let Some(previous) = previous else {
+            return Ok(None);
+        };
+
+        sqlx::query!("UPDATE users SET username = $1 WHERE id = $2", new_name, id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+        transaction.commit().await.map_err(map_err)?;
+        Ok(Some(previous))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<(), ServerError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.db_pool)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to execute query: {:?}", e);
+                ServerError::DatabaseUnreachable
+            })?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn record_admin_action(
+        &self,
+        admin_id: &Uuid,
+        action: &str,
+        target: &str,
+        details: Option<&str>,
+    ) -> Result<(), ServerError> {
+        sqlx::query!(
+            r#"
+            INSERT INTO admin_audit(id,admin_id,action,target,details,timestamp)
+            VALUES ($1,$2,$3,$4,$5,$6)
+            "#,
+            Uuid::new_v4(),
+            admin_id,
+            action,
+            target,
+            details,
+            Utc::now(),
+        )
+        .execute(&self.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::RecordAdminAction
+        })?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_admin_actions(&self) -> Result<Vec<AdminAction>, ServerError> {
+        let actions = sqlx::query_as!(
+            AdminAction,
+            r#"
+            SELECT id, admin_id, action, target, details, timestamp
+            FROM admin_audit
+            ORDER BY timestamp DESC LIMIT 100
+            "#
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            ServerError::GetAdminActions
+        })?;
+
+        Ok(actions)
+    }
+}
+
+#[cfg(test)]
+impl ChatPostgresDb {
+    fn from_pool(db_pool: PgPool) -> Self {
+        Self {
+            db_pool,
+            limits: StorageLimits::default(),
+            store_edit_history: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatDb, ChatPostgresDb, StorageLimits};
+    use crate::server_error::ServerError;
+    use crate::user::{DuplicateUserPolicy, ImportSummary, User};
+    use shared::message::{AuthUser, Message, MessagePayload};
+    use sqlx::PgPool;
+
+    #[test]
+    fn overlong_username_is_rejected() {
+        let limits = StorageLimits::default();
+
+        assert!(limits.check_username(&"a".repeat(64)).is_ok());
+        assert!(matches!(
+            limits.check_username(&"a".repeat(65)),
+            Err(ServerError::ValueTooLong {
+                field: "username",
+                max: 64
+            })
+        ));
+    }
+
+    #[test]
+    fn overlong_message_is_rejected() {
+        let limits = StorageLimits {
+            max_message_length: 5,
+            ..Default::default()
+        };
+
+        // Length is counted in characters
+        assert!(limits.check_message("ěščřž").is_ok());
+        assert!(matches!(
+            limits.check_message("hello!"),
+            Err(ServerError::ValueTooLong {
+                field: "message",
+                max: 5
+            })
+        ));
+    }
+
+    #[sqlx::test]
+    async fn overlong_message_is_not_stored(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+        let max = StorageLimits::default().max_message_length;
+
+        let long = Message::new(MessagePayload::Text("a".repeat(max + 1)));
+        assert!(matches!(
+            db.insert_message(&long, &user.id).await,
+            Err(ServerError::ValueTooLong {
+                field: "message",
+                ..
+            })
+        ));
+        let longest = Message::new(MessagePayload::Text("a".repeat(max)));
+        db.insert_message(&longest, &user.id).await.unwrap();
+
+        let stored = db.get_messages("alice", None, 50).await.unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn edits_are_kept_in_history(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+        let message = Message::new(MessagePayload::Text("first".into()));
+        db.insert_message(&message, &user.id).await.unwrap();
+        let id = db.get_messages("alice", None, 50).await.unwrap()[0].id;
+
+        assert!(db.edit_message(&id, "second").await.unwrap());
+        assert!(db.edit_message(&id, "third").await.unwrap());
+
+        let history = db.get_message_history(&id).await.unwrap().unwrap();
+        assert_eq!(history.current.text, "third");
+        let edits: Vec<_> = history.edits.iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(edits, vec!["first", "second"]);
+    }
+
+    #[sqlx::test]
+    async fn long_message_can_be_edited_with_raised_limit(pool: PgPool) {
+        let mut db = ChatPostgresDb::from_pool(pool);
+        db.limits.max_message_length = 20_000;
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+        let long = Message::new(MessagePayload::Text("a".repeat(15_000)));
+        db.insert_message(&long, &user.id).await.unwrap();
+        let id = db.get_messages("alice", None, 50).await.unwrap()[0].id;
+
+        assert!(db.edit_message(&id, &"b".repeat(15_000)).await.unwrap());
+
+        let history = db.get_message_history(&id).await.unwrap().unwrap();
+        assert_eq!(history.edits[0].text.len(), 15_000);
+    }
+
+    #[sqlx::test]
+    async fn username_has_to_be_unique(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        let bob = User::try_from(AuthUser::new("bob", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        db.insert_user(&bob).await.unwrap();
+
+        let previous = db.rename_user(&alice.id, "carol").await.unwrap();
+        assert_eq!(previous.as_deref(), Some("alice"));
+        assert!(db.get_user("carol").await.unwrap().is_some());
+
+        assert!(matches!(
+            db.rename_user(&bob.id, "carol").await,
+            Err(ServerError::UsernameTaken(name)) if name == "carol"
+        ));
+    }
+
+    #[sqlx::test]
+    async fn expired_ephemeral_message_is_not_returned(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+
+        let ephemeral = Message::new(MessagePayload::Text("secret".into())).with_ttl(0);
+        db.insert_message(&ephemeral, &user.id).await.unwrap();
+        let lasting = Message::new(MessagePayload::Text("hello".into())).with_ttl(3600);
+        db.insert_message(&lasting, &user.id).await.unwrap();
+
+        let texts: Vec<_> = db
+            .get_messages("", None, 50)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.text)
+            .collect();
+        assert_eq!(texts, vec!["hello"]);
+    }
+
+    #[sqlx::test]
+    async fn typing_indicator_is_not_stored(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+
+        db.insert_message(&Message::new(MessagePayload::Typing), &user.id)
+            .await
+            .unwrap();
+
+        assert!(db.get_messages("", None, 50).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn messages_are_filtered_by_room(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let user = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&user).await.unwrap();
+
+        let general = Message::new(MessagePayload::Text("hello".into()));
+        db.insert_message(&general, &user.id).await.unwrap();
+        let mut rust = Message::new(MessagePayload::Text("borrowck".into()));
+        rust.room = Some("rust".into());
+        db.insert_message(&rust, &user.id).await.unwrap();
+
+        let messages = db.get_messages("", Some("rust"), 50).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].text, "borrowck");
+        assert_eq!(messages[0].room, "rust");
+        assert_eq!(db.get_messages("", None, 50).await.unwrap().len(), 2);
+        assert_eq!(db.get_messages("", None, 1).await.unwrap().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn import_handles_duplicate_usernames(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let alice = User::try_from(AuthUser::new("alice", "old")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        let imported = vec![
+            User::try_from(AuthUser::new("alice", "new")).unwrap(),
+            User::try_from(AuthUser::new("bob", "password")).unwrap(),
+        ];
+
+        let summary = db
+            .import_users(&imported, DuplicateUserPolicy::Skip)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                imported: 1,
+                overwritten: 0,
+                skipped: 1,
+                skipped_users: vec!["alice".into()],
+            }
+        );
+        let stored = db.get_user("alice").await.unwrap().unwrap();
+        assert!(stored.verify_user_password(b"old").unwrap());
+
+        let summary = db
+            .import_users(&imported[..1], DuplicateUserPolicy::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(summary.overwritten, 1);
+        let stored = db.get_user("alice").await.unwrap().unwrap();
+        assert_eq!(stored.id, alice.id);
+        assert!(stored.verify_user_password(b"new").unwrap());
+    }
+
+    #[sqlx::test]
+    async fn import_skips_taken_ids(pool: PgPool) {
+        let db = ChatPostgresDb::from_pool(pool);
+        let alice = User::try_from(AuthUser::new("alice", "password")).unwrap();
+        db.insert_user(&alice).await.unwrap();
+        let carol = User::try_from(AuthUser::new("carol", "password")).unwrap();
+        let imported = vec![
+            User {
+                username: "mallory".into(),
+                ..alice.clone()
+            },
+            User {
+                username: "Carol".into(),
+                ..carol.clone()
+            },
+            carol,
+        ];
+
+        let summary = db
+            .import_users(&imported, DuplicateUserPolicy::Skip)
+            .await
+            .unwrap();
+        assert_eq!(summary.imported, 1);
+        assert_eq!(summary.skipped_users, ["mallory", "carol"]);
+        assert!(db.get_user("mallory").await.unwrap().is_none());
+    }
 }
@@ -1,8 +1,24 @@
+pub mod admin;
 pub mod api;
+pub mod attachment;
+pub mod bandwidth;
+pub mod breaker;
+pub mod bridge;
 pub mod configuration;
 pub mod db;
+pub mod lockout;
 pub mod message_info;
 pub mod metrics;
+pub mod outbound;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod replay;
 pub mod server_error;
+pub mod session;
 pub mod startup;
+pub mod stats;
+#[cfg(test)]
+mod test_utils;
+pub mod transform;
 pub mod user;
+pub mod webhook;
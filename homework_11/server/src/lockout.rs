@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::configuration::ChatSettings;
+
+/// Locks the login of a username after too many failed attempts in a row, so passwords can't be guessed quickly.
+/// Usernames are matched like at the login, ignoring the case and the surrounding whitespace.
+/// One lockout is shared by the chat login and the HTTP api, so the password can't be guessed over the other one.
+pub struct LoginLockout {
+    /// `None` when the lockout is disabled.
+    max_failures: Option<u32>,
+    lockout: Duration,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+impl LoginLockout {
+    pub fn new(max_failures: u32, lockout: Duration) -> Self {
+        Self {
+            max_failures: Some(max_failures),
+            lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_settings(settings: &ChatSettings) -> Self {
+        Self {
+            max_failures: settings.max_failed_logins,
+            lockout: Duration::from_secs(settings.login_lockout_seconds),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns for how long the login of the username is locked, None if it can be tried.
+    pub fn locked_for(&self, username: &str) -> Option<Duration> {
+        self.locked_for_at(username, Instant::now())
+    }
+
+    /// Same as `locked_for` in whole seconds rounded up, as sent to clients to retry after.
+    pub fn retry_after(&self, username: &str) -> Option<u64> {
+        self.locked_for(username)
+            .map(|locked_for| locked_for.as_secs() + u64::from(locked_for.subsec_nanos() > 0))
+    }
+
+    /// Counts the failed login, the username is locked when it reaches the maximum.
+    pub fn record_failure(&self, username: &str) {
+        self.record_failure_at(username, Instant::now())
+    }
+
+    /// Successful login resets the count of failures.
+    pub fn record_success(&self, username: &str) {
+        self.failures.lock().unwrap().remove(&key(username));
+    }
+
+    fn locked_for_at(&self, username: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = failures.get(&key(username))?.locked_until?;
+        Some(locked_until.checked_duration_since(now)?).filter(|left| !left.is_zero())
+    }
+
+    fn record_failure_at(&self, username: &str, now: Instant) {
+        let Some(max_failures) = self.max_failures else {
+            return;
+        };
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(key(username)).or_default();
+        entry.count += 1;
+        if entry.count >= max_failures {
+            entry.count = 0;
+            entry.locked_until = Some(now + self.lockout);
+        }
+    }
+}
+
+fn key(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_is_locked_after_max_failures() {
+        let lockout = LoginLockout::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+
+        lockout.record_failure_at("alice", now);
+        assert_eq!(lockout.locked_for_at("alice", now), None);
+
+        lockout.record_failure_at("alice", now);
+        assert_eq!(
+            lockout.locked_for_at("alice", now + Duration::from_secs(10)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(lockout.locked_for_at("bob", now), None);
+        assert_eq!(
+            lockout.locked_for_at("alice", now + Duration::from_secs(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let lockout = LoginLockout::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+
+        lockout.record_failure_at("alice", now);
+        lockout.record_success("alice");
+        lockout.record_failure_at("alice", now);
+
+        assert_eq!(lockout.locked_for_at("alice", now), None);
+    }
+
+    #[test]
+    fn variants_of_username_share_failures() {
+        let lockout = LoginLockout::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+
+        lockout.record_failure_at("Alice", now);
+        lockout.record_failure_at(" alice  ", now);
+
+        assert!(lockout.locked_for_at("alice", now).is_some());
+        assert!(lockout.locked_for_at("ALICE ", now).is_some());
+    }
+
+    #[test]
+    fn disabled_lockout_never_locks() {
+        let settings = ChatSettings {
+            max_failed_logins: None,
+            ..Default::default()
+        };
+        let lockout = LoginLockout::from_settings(&settings);
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            lockout.record_failure_at("alice", now);
+        }
+
+        assert_eq!(lockout.locked_for_at("alice", now), None);
+    }
+}
@@ -1,35 +1,43 @@
+use server::bridge::ChatBridge;
+use server::lockout::LoginLockout;
 use server::metrics::{self};
 use server::startup::start;
+use server::stats::ServerStats;
 use server::{api::Api, configuration::get_configuration};
-use shared::tracing::{get_subscriber, init_subscriber};
+use shared::tracing::{get_subscriber, init_subscriber_or_warn};
 use std::fmt::{Debug, Display};
+use std::sync::Arc;
 use tokio::task::JoinError;
 
 #[tokio::main]
 async fn main() {
     // Setup tracing, default output is stdout.
     let tracing_subscriber = get_subscriber("server".into(), "debug".into(), std::io::stdout);
-    if let Err(e) = init_subscriber(tracing_subscriber) {
-        tracing::error!("Error while setting up server. {e}");
-        return;
-    }
+    init_subscriber_or_warn(tracing_subscriber);
 
     let configuration = get_configuration().expect("Failed to read configuration.");
 
     metrics::register_metrics();
 
-    let Ok(api) = Api::build(configuration.clone()) else {
+    let bridge = Arc::new(ChatBridge::from_settings(&configuration.chat));
+    let lockout = Arc::new(LoginLockout::from_settings(&configuration.chat));
+
+    let Ok(api) = Api::build(configuration.clone(), bridge.clone(), lockout.clone()) else {
         tracing::error!("Error while setting up api.");
         return;
     };
 
+    let stats = Arc::new(ServerStats::new());
+
     let api_task = tokio::spawn(api.run_until_stopped());
-    let chat_server_task = tokio::spawn(start(configuration));
+    let chat_server_task = tokio::spawn(start(configuration, stats.clone(), bridge, lockout));
 
     tokio::select! {
         o = chat_server_task => log_exit("Chat server", o),
         o = api_task => log_exit("Api", o)
     };
+
+    stats.report().log();
 }
 
 fn log_exit(name: &str, result: Result<Result<(), impl Debug + Display>, JoinError>) {
@@ -1,6 +1,7 @@
 use chrono::serde::ts_seconds;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use serde::Serialize;
+use shared::message::{Message, MessagePayload};
 use uuid::Uuid;
 
 #[derive(Serialize)]
@@ -10,4 +11,34 @@ pub struct MessageInfo {
     pub text: String,
     #[serde(with = "ts_seconds")]
     pub timestamp: DateTime<Utc>,
+    pub room: String,
+}
+
+impl MessageInfo {
+    /// Converts a relayed message the same way it is stored. Returns None for messages without a sender, e.g. server info.
+    pub fn from_message(message: &Message) -> Option<Self> {
+        Some(Self {
+            id: message.id,
+            username: message.sender.clone()?,
+            text: MessagePayload::serialize_to_text(&message.data),
+            timestamp: Utc.timestamp_opt(message.timestamp, 0).single()?,
+            room: message.room_name().to_string(),
+        })
+    }
+}
+
+/// Previous version of an edited message.
+/// timestamp: when the text was replaced by the edit
+#[derive(Serialize)]
+pub struct MessageEdit {
+    pub text: String,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Current version of a message with its previous versions, oldest first.
+#[derive(Serialize)]
+pub struct MessageHistory {
+    pub current: MessageInfo,
+    pub edits: Vec<MessageEdit>,
 }
@@ -1,5 +1,12 @@
 use lazy_static::lazy_static;
-use prometheus::{Gauge, IntCounter, Opts};
+use prometheus::{
+    exponential_buckets, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts,
+};
+use std::collections::HashSet;
+use std::sync::Once;
+
+/// Label of the rooms over the limit of `RoomLabels`, the underscore keeps it apart from a room called `other`.
+pub const OTHER_ROOM_LABEL: &str = "_other";
 
 lazy_static! {
     pub static ref MESSAGES_COUNTER: IntCounter = IntCounter::new(
@@ -14,14 +21,95 @@ lazy_static! {
         );
         Gauge::with_opts(gauge_opts).expect("Failed to create gauge")
     };
+    /// Buckets from 1ms to about 1s, doubling.
+    pub static ref BROADCAST_LATENCY: Histogram = {
+        let histogram_opts = HistogramOpts::new(
+            "broadcast_latency_seconds",
+            "How long it takes to hand a message over to the queues of all receiving clients.",
+        )
+        .buckets(exponential_buckets(0.001, 2.0, 11).expect("Invalid buckets"));
+        Histogram::with_opts(histogram_opts).expect("Failed to create histogram")
+    };
+    /// Labeled by the room. Every label is a separate time series and users create rooms just by joining them,
+    /// so the labels are capped with `RoomLabels`.
+    pub static ref MESSAGES_PER_ROOM: IntCounterVec = IntCounterVec::new(
+        Opts::new("messages_per_room", "How many messages were sent to a room."),
+        &["room"]
+    )
+    .expect("Failed to create room counter");
+}
+
+/// Rooms that have their own label in `MESSAGES_PER_ROOM`. The first `max` rooms with a message get one,
+/// messages of later rooms are counted under `OTHER_ROOM_LABEL`.
+pub struct RoomLabels {
+    max: usize,
+    rooms: HashSet<String>,
+}
+
+impl RoomLabels {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            rooms: HashSet::new(),
+        }
+    }
+
+    /// Counts a message sent to the room.
+    pub fn message_sent(&mut self, room: &str) {
+        MESSAGES_PER_ROOM
+            .with_label_values(&[self.label(room)])
+            .inc();
+    }
+
+    fn label<'a>(&mut self, room: &'a str) -> &'a str {
+        if self.rooms.contains(room) {
+            return room;
+        }
+        if self.rooms.len() >= self.max {
+            return OTHER_ROOM_LABEL;
+        }
+        self.rooms.insert(room.to_string());
+        room
+    }
 }
 
+static REGISTER: Once = Once::new();
+
+/// Registers the metrics into the default registry served on `/metrics`. Registering them again does nothing,
+/// so every test can make sure they are registered.
 pub fn register_metrics() {
-    prometheus::default_registry()
-        .register(Box::new(MESSAGES_COUNTER.clone()))
-        .expect("Failed to register message counter");
+    REGISTER.call_once(|| {
+        prometheus::default_registry()
+            .register(Box::new(MESSAGES_COUNTER.clone()))
+            .expect("Failed to register message counter");
+
+        prometheus::default_registry()
+            .register(Box::new(ACTIVE_CONNECTIONS.clone()))
+            .expect("Failed to register connections counter");
+
+        prometheus::default_registry()
+            .register(Box::new(BROADCAST_LATENCY.clone()))
+            .expect("Failed to register broadcast latency");
+
+        prometheus::default_registry()
+            .register(Box::new(MESSAGES_PER_ROOM.clone()))
+            .expect("Failed to register room counter");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_over_the_limit_share_a_label() {
+        let mut labels = RoomLabels::new(2);
 
-    prometheus::default_registry()
-        .register(Box::new(ACTIVE_CONNECTIONS.clone()))
-        .expect("Failed to register connections counter");
+        assert_eq!(labels.label("general"), "general");
+        assert_eq!(labels.label("rust"), "rust");
+        assert_eq!(labels.label("random"), OTHER_ROOM_LABEL);
+        // Rooms that already have a label keep it
+        assert_eq!(labels.label("general"), "general");
+        assert_eq!(labels.label("rust"), "rust");
+    }
 }
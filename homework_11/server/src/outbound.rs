@@ -0,0 +1,163 @@
+use shared::compression::Framing;
+use shared::errors::MessageError;
+use shared::message::{Message, Priority};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+
+/// Queue of messages waiting to be written to a single client.
+/// Messages with a higher priority are sent first, messages with the same priority keep their order.
+#[derive(Default)]
+pub struct OutboundQueue {
+    heap: Mutex<BinaryHeap<QueuedMessage>>,
+    next_seq: AtomicU64,
+    notify: Notify,
+    closed: AtomicBool,
+}
+
+struct QueuedMessage {
+    priority: Priority,
+    seq: u64,
+    message: Arc<Message>,
+}
+
+impl Ord for QueuedMessage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for QueuedMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedMessage {}
+
+impl OutboundQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, message: Arc<Message>) {
+        let seq = self.next_seq.fetch_add(1, AtomicOrdering::Relaxed);
+        self.heap.lock().unwrap().push(QueuedMessage {
+            priority: message.priority,
+            seq,
+            message,
+        });
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next message. Returns None once the queue is closed and all queued messages were taken.
+    pub async fn pop(&self) -> Option<Arc<Message>> {
+        loop {
+            if let Some(queued) = self.heap.lock().unwrap().pop() {
+                return Some(queued.message);
+            }
+            if self.is_closed() {
+                return None;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Closes the queue. Already queued messages can still be taken.
+    pub fn close(&self) {
+        self.closed.store(true, AtomicOrdering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops all queued messages.
+    pub fn clear(&self) {
+        self.heap.lock().unwrap().clear();
+    }
+}
+
+/// Writes messages from the queue to the `stream` until the queue is closed or the write fails. Then the stream is shut down.
+/// The queue is closed on a failed write so the broadcaster knows the client is gone.
+/// Messages that can't be serialized are skipped, the stream is still fine.
+pub async fn write_queued_messages<T>(
+    queue: Arc<OutboundQueue>,
+    mut stream: T,
+    framing: Framing,
+) -> Result<(), MessageError>
+where
+    T: AsyncWrite + Unpin,
+{
+    while let Some(message) = queue.pop().await {
+        match Message::send_framed_msg(&message, &mut stream, framing).await {
+            Ok(()) => {}
+            Err(e) if e.is_serialization() => {
+                tracing::error!("Skipping message {} that can't be sent. {e}", message.id);
+            }
+            Err(e) => {
+                queue.close();
+                return Err(e);
+            }
+        }
+    }
+    stream.shutdown().await.map_err(MessageError::SendError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::message::MessagePayload;
+
+    fn text(text: &str) -> Arc<Message> {
+        Arc::new(Message::new(MessagePayload::Text(text.to_string())))
+    }
+
+    #[tokio::test]
+    async fn high_priority_message_jumps_the_queue() {
+        let queue = OutboundQueue::new();
+        for i in 0..100 {
+            queue.push(text(&i.to_string()));
+        }
+        queue.push(Arc::new(Message::new_server_msg("shutdown")));
+
+        let first = queue.pop().await.unwrap();
+        assert_eq!(
+            first.data,
+            MessagePayload::ServerInfo("shutdown".to_string())
+        );
+
+        let second = queue.pop().await.unwrap();
+        assert_eq!(second.data, MessagePayload::Text("0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn closed_queue_is_drained_before_ending() {
+        let queue = OutboundQueue::new();
+        queue.push(text("last"));
+        queue.close();
+
+        assert!(queue.pop().await.is_some());
+        assert!(queue.pop().await.is_none());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// Token bucket of one IP or connection. Every new connection or message takes a token, tokens are refilled evenly.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: Instant) -> Self {
+        Self {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: f64, per_second: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.updated = now;
+    }
+
+    /// Takes a token, returns false when the bucket is empty.
+    fn try_take(&mut self, capacity: f64, per_second: f64, now: Instant) -> bool {
+        self.refill(capacity, per_second, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Limits how many messages one connection can send, `per_second` on average with bursts of up to `burst` messages.
+/// Without `per_second` every message is allowed.
+pub struct MessageRateLimit {
+    per_second: Option<f64>,
+    burst: f64,
+    bucket: Option<Bucket>,
+}
+
+impl MessageRateLimit {
+    pub fn new(per_second: Option<f64>, burst: u32) -> Self {
+        Self {
+            per_second,
+            // A bucket smaller than one token would never let anything through
+            burst: f64::from(burst.max(1)),
+            bucket: None,
+        }
+    }
+
+    /// Whether another message can be sent now. Allowed messages take a token.
+    pub fn try_send(&mut self) -> bool {
+        self.try_send_at(Instant::now())
+    }
+
+    fn try_send_at(&mut self, now: Instant) -> bool {
+        let Some(per_second) = self.per_second else {
+            return true;
+        };
+        let burst = self.burst;
+        self.bucket
+            .get_or_insert_with(|| Bucket::full(burst, now))
+            .try_take(burst, per_second, now)
+    }
+}
+
+/// Limits how many new connections one IP can open in a window, so a single host can't open thousands of sockets.
+/// Ports are ignored, every connection of a host counts.
+pub struct ConnectionRateLimiter {
+    capacity: f64,
+    per_second: f64,
+    window: Duration,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(max_connections: u32, window: Duration) -> Self {
+        let capacity = f64::from(max_connections);
+        Self {
+            capacity,
+            per_second: capacity / window.as_secs_f64().max(f64::EPSILON),
+            window,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether a new connection from the IP is allowed. Allowed connections take a token of the IP.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    /// Removes the buckets of the IPs that didn't connect for the whole window every window, so the map doesn't grow
+    /// with every IP that ever connected. The task ends when the limiter is dropped.
+    /// A zero window is allowed by the configuration, the buckets are then cleaned up every second.
+    pub fn spawn_cleanup(&self) {
+        let buckets = Arc::downgrade(&self.buckets);
+        let (capacity, per_second) = (self.capacity, self.per_second);
+        let mut interval = tokio::time::interval(self.window.max(Duration::from_secs(1)));
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                if !clean_up(&buckets, capacity, per_second, Instant::now()) {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets
+            .entry(ip)
+            .or_insert_with(|| Bucket::full(self.capacity, now))
+            .try_take(self.capacity, self.per_second, now)
+    }
+}
+
+/// Removes the buckets that are full again. Returns false when the limiter doesn't exist anymore.
+fn clean_up(
+    buckets: &Weak<Mutex<HashMap<IpAddr, Bucket>>>,
+    capacity: f64,
+    per_second: f64,
+    now: Instant,
+) -> bool {
+    let Some(buckets) = buckets.upgrade() else {
+        return false;
+    };
+    buckets.lock().unwrap().retain(|_, bucket| {
+        bucket.refill(capacity, per_second, now);
+        bucket.tokens < capacity
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_is_refilled_over_the_window() {
+        let limiter = ConnectionRateLimiter::new(5, Duration::from_secs(10));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!((0..5).all(|_| limiter.allow_at(ip, now)));
+        assert!(!limiter.allow_at(ip, now));
+        assert!(limiter.allow_at("10.0.0.2".parse().unwrap(), now));
+
+        // One token is refilled every 2 seconds
+        assert!(!limiter.allow_at(ip, now + Duration::from_secs(1)));
+        assert!(limiter.allow_at(ip, now + Duration::from_secs(2)));
+        assert!(!limiter.allow_at(ip, now + Duration::from_secs(2)));
+
+        // The bucket doesn't hold more than the maximum
+        let later = now + Duration::from_secs(100);
+        assert!((0..5).all(|_| limiter.allow_at(ip, later)));
+        assert!(!limiter.allow_at(ip, later));
+    }
+
+    #[test]
+    fn messages_are_limited_after_a_burst() {
+        let mut limit = MessageRateLimit::new(Some(2.0), 3);
+        let now = Instant::now();
+
+        assert!((0..3).all(|_| limit.try_send_at(now)));
+        assert!(!limit.try_send_at(now));
+        assert!(limit.try_send_at(now + Duration::from_millis(500)));
+        assert!(!limit.try_send_at(now + Duration::from_millis(500)));
+
+        let mut unlimited = MessageRateLimit::new(None, 0);
+        assert!((0..100).all(|_| unlimited.try_send_at(now)));
+    }
+
+    #[test]
+    fn full_buckets_are_cleaned_up() {
+        let limiter = ConnectionRateLimiter::new(5, Duration::from_secs(10));
+        let now = Instant::now();
+        limiter.allow_at("10.0.0.1".parse().unwrap(), now);
+        limiter.allow_at("10.0.0.2".parse().unwrap(), now + Duration::from_secs(5));
+        let buckets = Arc::downgrade(&limiter.buckets);
+
+        assert!(clean_up(&buckets, 5.0, 0.5, now + Duration::from_secs(6)));
+
+        let ips: Vec<_> = limiter.buckets.lock().unwrap().keys().copied().collect();
+        assert_eq!(ips, ["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        drop(limiter);
+        assert!(!clean_up(&buckets, 5.0, 0.5, now));
+    }
+
+    #[tokio::test]
+    async fn cleanup_runs_with_zero_window() {
+        let limiter = ConnectionRateLimiter::new(5, Duration::ZERO);
+        limiter.spawn_cleanup();
+        assert!(limiter.allow("10.0.0.1".parse().unwrap()));
+    }
+}
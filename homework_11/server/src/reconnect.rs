@@ -0,0 +1,158 @@
+use shared::message::{Message, MessagePayload};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Messages for users that just disconnected. When the user reconnects with the same session in the grace period,
+/// the messages are delivered, so a short drop of the connection doesn't lose anything.
+pub struct ReconnectBuffers {
+    grace: Duration,
+    capacity: usize,
+    buffers: Mutex<HashMap<Uuid, Buffer>>,
+}
+
+struct Buffer {
+    username: String,
+    room: String,
+    until: Instant,
+    messages: VecDeque<Arc<Message>>,
+}
+
+impl Buffer {
+    /// Keeps at most `capacity` messages, the oldest are dropped.
+    fn push(&mut self, message: &Arc<Message>, capacity: usize) {
+        if self.messages.len() >= capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message.clone());
+    }
+}
+
+impl ReconnectBuffers {
+    pub fn new(grace: Duration, capacity: usize) -> Self {
+        Self {
+            grace,
+            capacity,
+            buffers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts buffering messages for the session of a user that disconnected from the room.
+    pub fn start(&self, session_token: Uuid, username: &str, room: &str) {
+        self.start_at(session_token, username, room, Instant::now())
+    }
+
+    /// Buffers the message for disconnected users in its room. Messages without a room are for all of them.
+    pub fn push(&self, message: &Arc<Message>) {
+        self.push_at(message, Instant::now())
+    }
+
+    /// Buffers the direct message for the disconnected user. Returns false if the user isn't in the grace period.
+    pub fn push_direct(&self, to: &str, message: &Arc<Message>) -> bool {
+        let now = Instant::now();
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.retain(|_, buffer| buffer.until > now);
+        let mut buffered = false;
+        for buffer in buffers.values_mut().filter(|buffer| buffer.username == to) {
+            buffer.push(message, self.capacity);
+            buffered = true;
+        }
+        buffered
+    }
+
+    /// Returns the messages buffered for the session, the oldest first. Empty if the grace period is over.
+    pub fn take(&self, session_token: &Uuid) -> Vec<Arc<Message>> {
+        self.take_at(session_token, Instant::now())
+    }
+
+    fn start_at(&self, session_token: Uuid, username: &str, room: &str, now: Instant) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.retain(|_, buffer| buffer.until > now);
+        buffers.insert(
+            session_token,
+            Buffer {
+                username: username.to_string(),
+                room: room.to_string(),
+                until: now + self.grace,
+                messages: VecDeque::new(),
+            },
+        );
+    }
+
+    fn push_at(&self, message: &Arc<Message>, now: Instant) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.retain(|_, buffer| buffer.until > now);
+        if buffers.is_empty() || matches!(message.data, MessagePayload::DirectMessage { .. }) {
+            return;
+        }
+        for buffer in buffers.values_mut() {
+            if message
+                .room
+                .as_ref()
+                .is_some_and(|room| *room != buffer.room)
+            {
+                continue;
+            }
+            buffer.push(message, self.capacity);
+        }
+    }
+
+    fn take_at(&self, session_token: &Uuid, now: Instant) -> Vec<Arc<Message>> {
+        match self.buffers.lock().unwrap().remove(session_token) {
+            Some(buffer) if buffer.until > now => buffer.messages.into(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Arc<Message> {
+        let mut message = Message::new(MessagePayload::Text(text.to_string()));
+        message.room = Some("general".to_string());
+        Arc::new(message)
+    }
+
+    fn texts(messages: &[Arc<Message>]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| MessagePayload::serialize_to_text(&message.data))
+            .collect()
+    }
+
+    #[test]
+    fn newest_messages_are_kept_until_the_grace_period_ends() {
+        let buffers = ReconnectBuffers::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        buffers.start_at(alice, "alice", "general", now);
+        buffers.start_at(bob, "bob", "rust", now);
+
+        for message in ["1", "2", "3"] {
+            buffers.push_at(&text(message), now);
+        }
+
+        assert_eq!(texts(&buffers.take_at(&alice, now)), ["2", "3"]);
+        assert!(buffers.take_at(&alice, now).is_empty());
+        assert!(buffers
+            .take_at(&bob, now + Duration::from_secs(1))
+            .is_empty());
+    }
+
+    #[test]
+    fn late_reconnect_gets_nothing() {
+        let buffers = ReconnectBuffers::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+        let alice = Uuid::new_v4();
+        buffers.start_at(alice, "alice", "general", now);
+        buffers.push_at(&text("1"), now);
+
+        assert!(buffers
+            .take_at(&alice, now + Duration::from_secs(10))
+            .is_empty());
+    }
+}
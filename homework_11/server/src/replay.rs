@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Protects the login against replayed messages. A login is accepted only if its timestamp is within
+/// the allowed clock skew and its nonce wasn't seen yet.
+pub struct ReplayGuard {
+    max_skew: Duration,
+    seen_nonces: Mutex<HashMap<Uuid, Instant>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReplayCheck {
+    Accepted,
+    Stale,
+    ReusedNonce,
+}
+
+impl ReplayGuard {
+    pub fn new(max_skew: Duration) -> Self {
+        Self {
+            max_skew,
+            seen_nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the login timestamp (epoch seconds) and remembers the nonce.
+    pub fn check(&self, timestamp: i64, nonce: Uuid) -> ReplayCheck {
+        self.check_at(
+            timestamp,
+            nonce,
+            chrono::Utc::now().timestamp(),
+            Instant::now(),
+        )
+    }
+
+    fn check_at(&self, timestamp: i64, nonce: Uuid, now_epoch: i64, now: Instant) -> ReplayCheck {
+        if now_epoch.abs_diff(timestamp) > self.max_skew.as_secs() {
+            return ReplayCheck::Stale;
+        }
+
+        let mut seen_nonces = self.seen_nonces.lock().unwrap();
+        // A nonce older than twice the skew can't come with a timestamp that is still accepted
+        seen_nonces.retain(|_, seen_at| now.duration_since(*seen_at) <= self.max_skew * 2);
+        if seen_nonces.insert(nonce, now).is_some() {
+            return ReplayCheck::ReusedNonce;
+        }
+        ReplayCheck::Accepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_outside_of_skew_window_is_stale() {
+        let guard = ReplayGuard::new(Duration::from_secs(30));
+        let now = Instant::now();
+
+        assert_eq!(
+            guard.check_at(1000, Uuid::new_v4(), 1031, now),
+            ReplayCheck::Stale
+        );
+        assert_eq!(
+            guard.check_at(1000, Uuid::new_v4(), 970, now),
+            ReplayCheck::Accepted
+        );
+    }
+
+    #[test]
+    fn nonce_can_be_used_only_once() {
+        let guard = ReplayGuard::new(Duration::from_secs(30));
+        let nonce = Uuid::new_v4();
+        let now = Instant::now();
+
+        assert_eq!(
+            guard.check_at(1000, nonce, 1000, now),
+            ReplayCheck::Accepted
+        );
+        assert_eq!(
+            guard.check_at(1000, nonce, 1001, now),
+            ReplayCheck::ReusedNonce
+        );
+    }
+}
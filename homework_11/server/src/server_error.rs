@@ -10,7 +10,7 @@ pub enum ServerError {
     #[error("Failed to send message: {0}")]
     SendMessage(#[source] MessageError),
     #[error("Channel send error: {0}")]
-    ChannelSend(#[source] SendError<(SocketAddr, Message)>),
+    ChannelSend(#[source] Box<SendError<(SocketAddr, Message)>>),
     #[error("Failed to store message")]
     StoreMessage,
     #[error("Failed to store user")]
@@ -19,14 +19,76 @@ pub enum ServerError {
     GetUser,
     #[error("Failed to get messages")]
     GetMessages,
+    #[error("Failed to edit message")]
+    EditMessage,
+    #[error("Failed to rename user")]
+    RenameUser,
+    #[error("Username {0} is already taken")]
+    UsernameTaken(String),
+    #[error(
+        "Username has to have {} to {} letters, digits, underscores or dashes",
+        crate::user::MIN_USERNAME_LENGTH,
+        crate::user::MAX_USERNAME_LENGTH
+    )]
+    InvalidUsername,
+    #[error("Nickname {0} is already used by another user")]
+    NicknameTaken(String),
     #[error("Failed to delete user")]
     DeleteUser,
+    #[error("Failed to import users")]
+    ImportUsers,
+    #[error("User {0} has no password hash, only full exports can be imported")]
+    MissingPassword(String),
+    #[error("Failed to record admin action")]
+    RecordAdminAction,
+    #[error("Failed to get admin actions")]
+    GetAdminActions,
     #[error("Failed to decode password")]
     PasswordDecode,
     #[error("Failed to create user")]
     CreateUser,
     #[error("Failed to start api. {0}")]
     StartApi(#[source] io::Error),
+    #[error("Value of {field} is longer than {max} characters")]
+    ValueTooLong { field: &'static str, max: usize },
+    #[error("Database is not reachable")]
+    DatabaseUnreachable,
+    #[error("Database is failing, calls are paused")]
+    CircuitOpen,
     #[error("Connection is closed.")]
     ClosedConnection,
+    #[error("Authentication didn't finish in time")]
+    AuthTimeout,
+    #[error("Message of {size} bytes is larger than the limit of {limit} bytes.")]
+    MessageTooLarge { size: usize, limit: usize },
+    #[error("Message couldn't be decoded. {0}")]
+    MalformedMessage(#[source] MessageError),
+    #[error("Failed to receive message. {0}")]
+    ReceiveMessage(#[source] MessageError),
+}
+
+impl ServerError {
+    /// Tells apart why a message couldn't be received: it is too large, it can't be decoded, or the connection
+    /// ended or failed. The client can be told about the first two.
+    pub fn from_received(error: MessageError) -> Self {
+        match error {
+            MessageError::MessageTooLarge { size, limit } => Self::MessageTooLarge { size, limit },
+            e if e.is_malformed() => Self::MalformedMessage(e),
+            MessageError::RecieveError(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                Self::ClosedConnection
+            }
+            e => Self::ReceiveMessage(e),
+        }
+    }
+
+    /// Errors caused by the values sent to the database, not by the database itself.
+    pub fn is_invalid_input(&self) -> bool {
+        matches!(
+            self,
+            Self::UsernameTaken(_)
+                | Self::InvalidUsername
+                | Self::ValueTooLong { .. }
+                | Self::MissingPassword(_)
+        )
+    }
 }
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Sessions of logged in users. A session token is given to the client on login and when the client
+/// reconnects with a valid token, the server knows it's the same user and not a new one.
+pub struct Sessions {
+    ttl: Duration,
+    sessions: Mutex<HashMap<Uuid, Session>>,
+}
+
+struct Session {
+    user_id: Uuid,
+    expires_at: Instant,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a new session for the user and returns its token.
+    pub fn create(&self, user_id: Uuid) -> Uuid {
+        let token = Uuid::new_v4();
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            token,
+            Session {
+                user_id,
+                expires_at: now + self.ttl,
+            },
+        );
+        token
+    }
+
+    /// Returns true if the token belongs to the user and is not expired.
+    pub fn is_valid(&self, token: &Uuid, user_id: &Uuid) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(token).is_some_and(|session| {
+            session.user_id == *user_id && session.expires_at > Instant::now()
+        })
+    }
+
+    /// Extends the expiration of the session. Called when the user disconnects so the ttl counts from the disconnect.
+    pub fn touch(&self, token: &Uuid) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(token) {
+            session.expires_at = Instant::now() + self.ttl;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_valid_only_for_its_user() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let user_id = Uuid::new_v4();
+
+        let token = sessions.create(user_id);
+
+        assert!(sessions.is_valid(&token, &user_id));
+        assert!(!sessions.is_valid(&token, &Uuid::new_v4()));
+        assert!(!sessions.is_valid(&Uuid::new_v4(), &user_id));
+    }
+
+    #[test]
+    fn expired_token_is_invalid() {
+        let sessions = Sessions::new(Duration::ZERO);
+        let user_id = Uuid::new_v4();
+
+        let token = sessions.create(user_id);
+
+        assert!(!sessions.is_valid(&token, &user_id));
+    }
+}
@@ -1,21 +1,115 @@
-use configuration::Settings;
-use flume::{Receiver, Sender};
-use futures::stream::{self, StreamExt};
+use configuration::{ChatSettings, DuplicateLoginPolicy, Settings};
+use flume::Sender;
+use futures::stream::StreamExt;
 use server_error::ServerError;
-use shared::message::{AuthPayload, AuthUser, Message, MessagePayload};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::net::tcp::OwnedWriteHalf;
+use shared::compression::{Algorithm, Compression, Framing};
+use shared::errors::MessageError;
+use shared::message::{
+    AuthError, AuthPayload, AuthUser, HistoryEntry, Message, MessagePayload, ServerStatus,
+    DEFAULT_ROOM, MAX_FRAME_SIZE,
+};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::Instrument;
+use uuid::Uuid;
 
-use crate::db::{ChatDb, ChatPostgresDb};
-use crate::metrics::{ACTIVE_CONNECTIONS, MESSAGES_COUNTER};
-use crate::user::UserInfo;
+use crate::admin::Admins;
+use crate::bridge::{ChatBridge, UserEvent};
+use crate::db::{is_valid_room, ChatDb, ChatPostgresDb, MAX_ROOM_LENGTH};
+use crate::lockout::LoginLockout;
+use crate::metrics::{RoomLabels, ACTIVE_CONNECTIONS, BROADCAST_LATENCY, MESSAGES_COUNTER};
+use crate::outbound::{write_queued_messages, OutboundQueue};
+use crate::rate_limit::ConnectionRateLimiter;
+use crate::reconnect::ReconnectBuffers;
+use crate::replay::{ReplayCheck, ReplayGuard};
+use crate::session::Sessions;
+use crate::stats::ServerStats;
+use crate::transform::{Pipeline, TransformResult};
+use crate::user::{validate_username, UserInfo};
+use crate::webhook::{PresenceEvent, PresenceKind, PresenceWebhook};
 use crate::{configuration, server_error};
 
+/// Typing indicators of one connection are relayed at most this often, more are dropped.
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long the connections can take to close when the server shuts down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the queued messages are written after the connection ended, a client that stopped reading doesn't keep it open.
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Clients = Arc<Mutex<HashMap<SocketAddr, ConnectedClient>>>;
+
+/// Authenticated client that receives messages.
+struct ConnectedClient {
+    username: String,
+    session_token: Uuid,
+    connected_at: Instant,
+    queue: Arc<OutboundQueue>,
+    /// Notified when the server disconnects the client.
+    kicked: Arc<Notify>,
+    /// The client gets only messages from this room and server messages without a room.
+    room: String,
+    /// Name shown instead of the username with the messages of this connection.
+    nick: Option<String>,
+}
+
+impl ConnectedClient {
+    fn display_name(&self) -> &str {
+        self.nick.as_deref().unwrap_or(&self.username)
+    }
+}
+
+/// State shared by all connections of the server.
+struct ServerState<D> {
+    db: Arc<D>,
+    settings: ChatSettings,
+    clients: Clients,
+    sender: Sender<(SocketAddr, Message)>,
+    sessions: Sessions,
+    replay_guard: ReplayGuard,
+    /// Locks logins after failed attempts, shared with the api.
+    lockout: Arc<LoginLockout>,
+    admins: Admins,
+    /// Gets join and leave events, `None` when not configured.
+    webhook: Option<PresenceWebhook>,
+    /// Messages for users that just lost the connection, `None` when disabled.
+    reconnect: Option<Arc<ReconnectBuffers>>,
+    stats: Arc<ServerStats>,
+    bridge: Arc<ChatBridge>,
+}
+
+/// Result of a successful authentication. The connection is already registered among the connected clients.
+struct AuthenticatedUser {
+    user: UserInfo,
+    session_token: Uuid,
+    is_reconnect: bool,
+    /// Framing and compression of messages on the connection.
+    framing: Framing,
+    queue: Arc<OutboundQueue>,
+    kicked: Arc<Notify>,
+}
+
 /// Starts the server. It will listen for incoming connections and spawn a new thread for each connection.
 /// In a separate thread runs a broadcasting function that will send messages to all connected clients.
-pub async fn start(config: Settings) -> Result<(), ServerError> {
+/// Statistics of the run are collected to `stats`. Relayed messages are shared with HTTP clients through the `bridge`,
+/// failed logins are counted in the `lockout` shared with the api.
+pub async fn start(
+    config: Settings,
+    stats: Arc<ServerStats>,
+    bridge: Arc<ChatBridge>,
+    lockout: Arc<LoginLockout>,
+) -> Result<(), ServerError> {
     let db = Arc::new(ChatPostgresDb::new(&config.database));
 
     let server = format!("{}:{}", config.application.host, config.application.port);
@@ -23,139 +117,918 @@ pub async fn start(config: Settings) -> Result<(), ServerError> {
 
     let listener = TcpListener::bind(server).await.map_err(ServerError::Bind)?;
 
-    let (sender, receiver) = flume::unbounded();
+    let shutdown = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(
+                "Failed to listen for Ctrl-C, the server can't be shut down gracefully. {e}"
+            );
+            std::future::pending::<()>().await;
+        }
+    };
+    run_server(listener, db, config.chat, stats, bridge, lockout, shutdown).await
+}
+
+/// Runs the chat server on an already bound `listener`. Split from `start` so the server can run with any `ChatDb`.
+/// When `shutdown` completes, the server stops accepting connections, tells the connected clients and waits
+/// up to `SHUTDOWN_TIMEOUT` until their messages are written.
+pub async fn run_server<D>(
+    listener: TcpListener,
+    db: Arc<D>,
+    settings: ChatSettings,
+    stats: Arc<ServerStats>,
+    bridge: Arc<ChatBridge>,
+    lockout: Arc<LoginLockout>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError>
+where
+    D: ChatDb + Send + Sync + 'static,
+{
+    let pending_auth = Arc::new(Semaphore::new(settings.max_pending_authentications));
+    let rate_limiter = settings.max_connections_per_ip.map(|max_connections| {
+        let limiter = ConnectionRateLimiter::new(
+            max_connections,
+            Duration::from_secs(settings.connection_rate_window_seconds),
+        );
+        limiter.spawn_cleanup();
+        limiter
+    });
+
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let reconnect = settings.reconnect_grace_seconds.map(|grace| {
+        Arc::new(ReconnectBuffers::new(
+            Duration::from_secs(grace),
+            settings.reconnect_buffer_size,
+        ))
+    });
 
-    let clients: Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    let state = Arc::new(ServerState {
+        db,
+        sessions: Sessions::new(Duration::from_secs(settings.session_ttl_seconds)),
+        replay_guard: ReplayGuard::new(Duration::from_secs(settings.login_max_clock_skew_seconds)),
+        lockout,
+        admins: Admins::from_settings(&settings),
+        webhook: settings
+            .presence_webhook
+            .clone()
+            .map(PresenceWebhook::spawn),
+        reconnect: reconnect.clone(),
+        settings,
+        clients: clients.clone(),
+        sender: bridge.sender(),
+        stats: stats.clone(),
+        bridge: bridge.clone(),
+    });
 
     tokio::spawn({
-        let clients = clients.clone();
-        broadcast_messages(clients, receiver)
+        let stats = stats.clone();
+        let max_queued = state.settings.max_queued_messages;
+        let room_labels = RoomLabels::new(state.settings.max_room_metric_labels);
+        broadcast_messages(clients, bridge, stats, max_queued, reconnect, room_labels)
     });
 
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
     loop {
-        match listener.accept().await {
-            Ok((stream, address)) => {
-                let sender = sender.clone();
-                let clients = Arc::clone(&clients);
-                let db = Arc::clone(&db);
-                tokio::spawn(async move {
-                    tracing::debug!("New connection");
-                    ACTIVE_CONNECTIONS.inc();
-                    let _guard = scopeguard::guard((), |_| {
-                        ACTIVE_CONNECTIONS.sub(1.0);
-                        tracing::debug!("Connection ended.")
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+            // Finished connections are taken out of the set
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
+        match accepted {
+            Ok((mut stream, address)) => {
+                if rate_limiter
+                    .as_ref()
+                    .is_some_and(|limiter| !limiter.allow(address.ip()))
+                {
+                    tracing::warn!(
+                        "Too many new connections from {}, closing the connection.",
+                        address.ip()
+                    );
+                    drop(stream);
+                    continue;
+                }
+                // The permit is held until the user is authenticated
+                let Ok(auth_permit) = pending_auth.clone().try_acquire_owned() else {
+                    tracing::warn!("Too many pending authentications, rejecting {address}.");
+                    tokio::spawn(async move {
+                        let msg = Message::new_server_msg(
+                            "Server is busy with other logins. Try again later.",
+                        );
+                        _ = Message::send_msg(&msg, &mut stream).await;
                     });
-                    if let Err(e) = handle_connection(stream, address, sender, clients, db).await {
-                        tracing::error!("Error while handling connection: {}", e);
+                    continue;
+                };
+
+                let state = Arc::clone(&state);
+                let stats = Arc::clone(&stats);
+                // Every log line of the connection is in this span, the user and room are recorded once known.
+                // Only the username is recorded of the login, never the password.
+                let span = tracing::info_span!(
+                    "connection",
+                    peer = %address,
+                    user.name = tracing::field::Empty,
+                    room = tracing::field::Empty,
+                );
+                connections.spawn(
+                    async move {
+                        tracing::debug!("New connection");
+                        ACTIVE_CONNECTIONS.inc();
+                        stats.connection_opened();
+                        let _guard = scopeguard::guard((), |_| {
+                            ACTIVE_CONNECTIONS.sub(1.0);
+                            stats.connection_closed();
+                            tracing::debug!("Connection ended.")
+                        });
+                        if let Err(e) = handle_connection(stream, address, state, auth_permit).await
+                        {
+                            tracing::error!("Error while handling connection: {}", e);
+                        }
                     }
-                });
+                    .instrument(span),
+                );
             }
             Err(e) => tracing::error!("Encountered network error from Tcp stream: {e}"),
         }
     }
+
+    tracing::info!("Shutting down the server...");
+    drop(listener);
+    disconnect_all(&state.clients, "Server shutting down").await;
+    let closed = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if closed.is_err() {
+        tracing::warn!("Some connections didn't close in time, they are dropped.");
+        connections.shutdown().await;
+    }
+    Ok(())
 }
 
 /// Handles a connection from a client.
 /// In a loop it will listen for incoming messages and send them to the broadcasting thread using chanel.
-async fn handle_connection(
+async fn handle_connection<D: ChatDb>(
     mut stream: TcpStream,
     address: SocketAddr,
-    sender: Sender<(SocketAddr, Message)>,
-    clients: Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>>,
-    db: Arc<impl ChatDb>,
+    state: Arc<ServerState<D>>,
+    auth_permit: OwnedSemaphorePermit,
 ) -> Result<(), ServerError> {
     tracing::info!("New connection from: {address}. Authenticating...");
-    let current_user = run_until_authenticated(&mut stream, db.clone()).await?;
+    let auth_timeout = Duration::from_secs(state.settings.auth_timeout_seconds);
+    let authenticated = tokio::time::timeout(
+        auth_timeout,
+        run_until_authenticated(&mut stream, address, &state),
+    )
+    .await;
+    drop(auth_permit);
+    let authenticated = match authenticated {
+        Ok(authenticated) => authenticated,
+        Err(_) => {
+            tracing::warn!("Connection from {address} didn't authenticate in time, closing it.");
+            Err(ServerError::AuthTimeout)
+        }
+    };
+    let AuthenticatedUser {
+        user: mut current_user,
+        session_token,
+        is_reconnect,
+        framing,
+        queue,
+        kicked,
+    } = match authenticated {
+        Ok(authenticated) => authenticated,
+        Err(e) => {
+            // The connection may be registered already when sending the login response failed or timed out
+            remove_client(&state.clients, &address).await;
+            return Err(e);
+        }
+    };
+    let span = tracing::Span::current();
+    span.record("user.name", current_user.username.as_str());
+    span.record("room", DEFAULT_ROOM);
+    let compression = framing.compression();
     tracing::info!(
-        "User {} authenticated. Starting listening for messages..",
-        &current_user.username
+        peer = %address,
+        username = %current_user.username,
+        compression = ?compression.algorithm,
+        compression_level = compression.level,
+        connection_compression = matches!(framing, Framing::Connection(_)),
+        reconnect = is_reconnect,
+        "Connection established"
     );
-    let clients_count = clients.lock().await.len();
+    let clients = &state.clients;
+    let (read_half, mut write_half) = stream.into_split();
+    if let Err(e) = send_welcome(&state, &mut write_half, framing, is_reconnect).await {
+        remove_client(clients, &address).await;
+        return Err(e);
+    }
 
-    let (mut read_half, mut write_half) = stream.into_split();
+    // Messages to the client are written by a separate task, so a slow client doesn't block the broadcaster.
+    let writer = tokio::spawn({
+        let queue = queue.clone();
+        async move {
+            if let Err(e) = write_queued_messages(queue, write_half, framing).await {
+                tracing::debug!("Stopped writing to client {address}. {e}");
+            }
+        }
+        .instrument(tracing::Span::current())
+    });
+    let mut current_room = DEFAULT_ROOM.to_string();
+    let mut nick: Option<String> = None;
+    let mut last_typing: Option<Instant> = None;
 
-    Message::send_active_users_msg(&mut write_half, clients_count)
-        .await
-        .map_err(ServerError::SendMessage)?;
+    // Broadcast to other users that new user was connected. Reconnects of the same session are not announced as new users.
+    let announcement = match is_reconnect {
+        false => Some(format!("New user connected: {}", current_user.username)),
+        true if state.settings.announce_reconnects => {
+            Some(format!("{} reconnected", current_user.username))
+        }
+        true => None,
+    };
+    if let Some(webhook) = &state.webhook {
+        webhook.notify(PresenceEvent::new(
+            PresenceKind::Join,
+            &current_user.username,
+        ));
+    }
+    if let Some(text) = announcement {
+        let sent = state
+            .sender
+            .send_async((address, Message::new_server_msg(&text)))
+            .await;
+        if let Err(e) = sent {
+            tracing::error!("Failed to announce user {}. {e}", current_user.username);
+            // Nothing can be relayed anymore, the session ends like a kick so the connection is cleaned up
+            kicked.notify_one();
+        }
+    }
 
-    clients.lock().await.insert(address, write_half);
+    let mut pipeline = Pipeline::from_settings(&state.settings);
+    let mut user_events = state.bridge.subscribe_user_events();
 
-    // Broadcast to other users that new user was connected
-    let msg = Message::new_server_msg(&format!("New user connected: {}", current_user.username));
-    sender
-        .send_async((address, msg))
-        .await
-        .map_err(ServerError::ChannelSend)?;
+    let mut kicked_by_server = false;
+    let (reader, mut frames) = spawn_frame_reader(read_half, framing);
 
     // Start receiving messages from user and broadcast them
-    while let Ok(mut message) = Message::receive_msg(&mut read_half).await {
+    loop {
+        let received = tokio::select! {
+            received = frames.recv() => match received {
+                Some(received) => received,
+                None => break,
+            },
+            _ = kicked.notified() => {
+                tracing::info!("User {} was disconnected by the server.", current_user.username);
+                kicked_by_server = true;
+                break;
+            }
+            event = user_events.recv() => {
+                match event {
+                    Ok(UserEvent::Renamed(id, new_name)) if id == current_user.id => {
+                        rename_client(clients, &address, &new_name).await;
+                        tracing::Span::current().record("user.name", new_name.as_str());
+                        current_user.username = new_name;
+                    }
+                    Ok(UserEvent::Kicked(id, reason)) if id == current_user.id => {
+                        kick_client(clients, &address, &reason).await;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+        };
+        let message = match received.map_err(ServerError::from_received) {
+            Ok(message) => message,
+            Err(ServerError::ClosedConnection) => {
+                tracing::debug!("User {} closed the connection.", current_user.username);
+                break;
+            }
+            Err(e @ (ServerError::MessageTooLarge { .. } | ServerError::MalformedMessage(_))) => {
+                tracing::warn!(
+                    "Rejected a message from user {}, closing the connection. {e}",
+                    current_user.username
+                );
+                let reason = format!("Your message was rejected and you were disconnected. {e}");
+                send_to_client(clients, &address, Message::new_server_msg(&reason)).await;
+                kicked_by_server = true;
+                break;
+            }
+            Err(e) => {
+                tracing::info!("Connection of user {} failed. {e}", current_user.username);
+                break;
+            }
+        };
+        // Requests handled right here go through the pipeline too, so they are rate limited like messages
+        let mut message = match pipeline.apply(message) {
+            TransformResult::Continue(message) => message,
+            TransformResult::Drop => continue,
+            TransformResult::Reject(reason) => {
+                tracing::warn!(
+                    "Message from user {} was rejected. {reason}",
+                    current_user.username
+                );
+                send_to_client(clients, &address, Message::new_server_msg(&reason)).await;
+                continue;
+            }
+        };
+        match &message.data {
+            MessagePayload::Ping => {
+                tracing::trace!("Keepalive from: {address}");
+                continue;
+            }
+            MessagePayload::StatusRequest => {
+                let status = server_status(&state).await;
+                let msg = Message::new(MessagePayload::StatusResponse(status));
+                send_to_client(clients, &address, msg).await;
+                continue;
+            }
+            MessagePayload::HistoryRequest(count) => {
+                let msg = history(&state, &current_room, *count).await;
+                send_to_client(clients, &address, msg).await;
+                continue;
+            }
+            MessagePayload::ListUsers => {
+                let msg = online_users(clients).await;
+                send_to_client(clients, &address, msg).await;
+                continue;
+            }
+            MessagePayload::JoinRoom(room) => {
+                let room = room.trim();
+                let reply = if !is_valid_room(room) {
+                    format!("Room name has to have 1 to {MAX_ROOM_LENGTH} characters.")
+                } else {
+                    join_room(clients, &address, room).await;
+                    current_room = room.to_string();
+                    tracing::Span::current().record("room", room);
+                    format!("You are now in room {room}.")
+                };
+                send_to_client(clients, &address, Message::new_server_msg(&reply)).await;
+                continue;
+            }
+            MessagePayload::Rename(new_name) => {
+                let renamed = state
+                    .bridge
+                    .rename_user(state.db.as_ref(), &current_user.id, new_name)
+                    .await;
+                let reason = match renamed {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => "Your user doesn't exist anymore.".to_string(),
+                    Err(
+                        e @ (ServerError::UsernameTaken(_)
+                        | ServerError::InvalidUsername
+                        | ServerError::ValueTooLong { .. }),
+                    ) => e.to_string(),
+                    Err(e) => {
+                        tracing::error!("Failed to rename user {}. {e}", current_user.username);
+                        "Failed to rename, try again later.".to_string()
+                    }
+                };
+                send_to_client(clients, &address, Message::new_server_msg(&reason)).await;
+                continue;
+            }
+            MessagePayload::Nick(new_nick) => {
+                let changed = match check_nick(state.db.as_ref(), &current_user.id, new_nick).await
+                {
+                    Ok(new_nick) => set_nick(clients, &address, &new_nick)
+                        .await
+                        .map(|previous| (previous, new_nick)),
+                    Err(e) => Err(e),
+                };
+                let reason = match changed {
+                    Ok((previous, new_nick)) => {
+                        // The username itself clears the nickname
+                        nick = (new_nick != current_user.username).then(|| new_nick.clone());
+                        let text = format!("{previous} is now known as {new_nick}");
+                        if let Err(e) = state.bridge.send(Message::new_server_msg(&text)).await {
+                            tracing::error!("Failed to announce the nickname {new_nick}. {e}");
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(e @ ServerError::InvalidUsername) => format!("Nickname is not valid. {e}."),
+                    Err(e @ ServerError::NicknameTaken(_)) => e.to_string(),
+                    Err(e) => {
+                        tracing::error!("Failed to set nickname of {}. {e}", current_user.username);
+                        "Failed to change the nickname, try again later.".to_string()
+                    }
+                };
+                send_to_client(clients, &address, Message::new_server_msg(&reason)).await;
+                continue;
+            }
+            MessagePayload::Typing => {
+                if last_typing.is_some_and(|last| last.elapsed() < TYPING_INTERVAL) {
+                    tracing::trace!("Dropping typing indicator from: {address}");
+                    continue;
+                }
+                last_typing = Some(Instant::now());
+            }
+            MessagePayload::Unknown(variant) => {
+                tracing::warn!("Ignoring message of unknown type {variant} from: {address}");
+                continue;
+            }
+            _ => {}
+        }
         tracing::info!("New message from: {address}");
-        _ = db.insert_message(&message, &current_user.id).await;
 
-        message.set_from_user(&current_user.username);
+        let may_override_sender =
+            state.settings.allow_sender_override && state.admins.is_admin(&current_user.id);
+        if !(may_override_sender && message.sender.is_some()) {
+            message.set_from_user(nick.as_deref().unwrap_or(&current_user.username));
+        }
+        message.room = Some(current_room.clone());
 
-        sender
-            .send_async((address, message))
-            .await
-            .map_err(ServerError::ChannelSend)?;
+        // A message that can't be serialized would fail for every receiver, so it is not relayed at all
+        let max_message_bytes = state.settings.max_message_bytes.unwrap_or(MAX_FRAME_SIZE);
+        if let Err(e) = Message::serialized_size(&message, max_message_bytes) {
+            tracing::warn!(
+                "Message from user {} can't be sent. {e}",
+                current_user.username
+            );
+            let reason = "Your message couldn't be sent, it is too large.";
+            send_to_client(clients, &address, Message::new_server_msg(reason)).await;
+            continue;
+        }
+
+        // A message over the storage limits is not relayed either, so the history has every message the others saw
+        if message.data.is_stored() {
+            if let Err(e @ ServerError::ValueTooLong { .. }) =
+                state.db.insert_message(&message, &current_user.id).await
+            {
+                let reason = format!("Your message couldn't be sent. {e}.");
+                send_to_client(clients, &address, Message::new_server_msg(&reason)).await;
+                continue;
+            }
+        }
+
+        if let Err(e) = state.sender.send_async((address, message)).await {
+            tracing::error!(
+                "Failed to relay a message from user {}. {e}",
+                current_user.username
+            );
+            break;
+        }
     }
 
+    reader.abort();
+
+    // Only a lost connection may come back, kicked users don't get the messages they missed.
+    // Started before the client is removed, so no message relayed in between is missed.
+    if let (false, Some(reconnect)) = (kicked_by_server, &state.reconnect) {
+        reconnect.start(session_token, &current_user.username, &current_room);
+    }
     // If the user disconnects, we remove it from the list of connected clients.
-    remove_client(&clients, &address).await;
+    remove_client(clients, &address).await;
+    queue.close();
+    if let Some(webhook) = &state.webhook {
+        webhook.notify(PresenceEvent::new(
+            PresenceKind::Leave,
+            &current_user.username,
+        ));
+    }
+    state.sessions.touch(&session_token);
+    // Messages that are already queued, like the reason of a kick, are still written
+    let mut writer = writer;
+    if tokio::time::timeout(WRITER_DRAIN_TIMEOUT, &mut writer)
+        .await
+        .is_err()
+    {
+        tracing::debug!("Client {address} didn't read the queued messages in time.");
+        writer.abort();
+    }
+    Ok(())
+}
+
+/// Sends the number of active users and, to a new session, the message of the day and the replayed messages.
+async fn send_welcome<D: ChatDb>(
+    state: &ServerState<D>,
+    write_half: &mut OwnedWriteHalf,
+    framing: Framing,
+    is_reconnect: bool,
+) -> Result<(), ServerError> {
+    // Without this connection, it is registered already
+    let clients_count = state.clients.lock().await.len().saturating_sub(1);
+    let active_users = Message::new(MessagePayload::ActiveUsers(clients_count));
+    Message::send_framed_msg(&active_users, write_half, framing)
+        .await
+        .map_err(ServerError::SendMessage)?;
+
+    if !is_reconnect {
+        if let Some(motd) = motd_message(state.settings.motd.as_deref(), clients_count) {
+            Message::send_framed_msg(&motd, write_half, framing)
+                .await
+                .map_err(ServerError::SendMessage)?;
+        }
+        for message in replayed_messages(state).await {
+            Message::send_framed_msg(&message, write_half, framing)
+                .await
+                .map_err(ServerError::SendMessage)?;
+        }
+    }
     Ok(())
 }
 
-/// Broadcasts messages to all connected clients.
+/// Reads the frames of the client in its own task and passes them over the channel. Reading a frame can't be cancelled
+/// halfway without losing the rest of it, so the connection loop selects on the channel instead of on the read.
+/// The channel holds one frame, the next one is read only after the loop took it. The task ends after the first error.
+fn spawn_frame_reader(
+    mut read_half: OwnedReadHalf,
+    framing: Framing,
+) -> (
+    tokio::task::JoinHandle<()>,
+    mpsc::Receiver<Result<Message, MessageError>>,
+) {
+    let (frames_sender, frames) = mpsc::channel(1);
+    let reader = tokio::spawn(
+        async move {
+            loop {
+                let received = Message::receive_framed_msg(&mut read_half, framing).await;
+                let failed = received.is_err();
+                if frames_sender.send(received).await.is_err() || failed {
+                    break;
+                }
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+    (reader, frames)
+}
+
+/// Broadcasts messages to all connected clients by putting them to the clients' queues.
 /// If a client is disconnected it will be removed from the list of connected clients.
+/// Clients with `max_queued` messages waiting can't keep up, they are disconnected instead of slowing down the others.
+/// Every relayed message, except direct messages and transient ones, is also recorded in the bridge for HTTP clients
+/// and in the `reconnect` buffers of users that just lost the connection.
 async fn broadcast_messages(
-    clients: Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>>,
-    receiver: Receiver<(SocketAddr, Message)>,
+    clients: Clients,
+    bridge: Arc<ChatBridge>,
+    stats: Arc<ServerStats>,
+    max_queued: Option<usize>,
+    reconnect: Option<Arc<ReconnectBuffers>>,
+    mut room_labels: RoomLabels,
 ) {
-    let mut recv_stream = receiver.into_stream();
+    let mut recv_stream = bridge.receiver().into_stream();
 
-    while let Some((ip_addr, ref message)) = recv_stream.next().await {
+    while let Some((ip_addr, mut message)) = recv_stream.next().await {
+        // Observed when dropped, so messages that take the early `continue` are measured too
+        let _latency = BROADCAST_LATENCY.start_timer();
         MESSAGES_COUNTER.inc();
-        let mut clients_iter = clients.lock().await;
-
-        let clients_to_remove: Vec<SocketAddr> = stream::iter(
-            clients_iter
-                .iter_mut()
-                .filter(|(client_addr, _)| **client_addr != ip_addr), // Filter out the client that sent the message
-        )
-        .filter_map(|(client_addr, stream)| async move {
-            tracing::debug!("Sending message to {client_addr}");
-            if let Err(e) = Message::send_msg(message, stream).await {
-                tracing::error!(
-                    "Error while broadcasting message to client {client_addr}. Error: {e}"
-                );
-                Some(client_addr)
-            } else {
-                None
+        stats.message_relayed();
+
+        // Priority is decided by the server, not by the sender
+        message.priority = message.data.priority();
+        let message = Arc::new(message);
+
+        let mut clients = clients.lock().await;
+
+        // Queue of a client is closed when writing to it failed
+        clients.retain(|client_addr, client| {
+            if client.queue.is_closed() {
+                tracing::info!("Removing client from list {client_addr}");
+                return false;
             }
+            if max_queued.is_some_and(|max| client.queue.len() >= max) {
+                tracing::warn!("Disconnecting client {client_addr}, it is too slow.");
+                client.queue.clear();
+                client.queue.push(Arc::new(Message::new_server_msg(
+                    "You were disconnected because your client is too slow to receive messages.",
+                )));
+                client.queue.close();
+                client.kicked.notify_one();
+                return false;
+            }
+            true
+        });
+
+        if let MessagePayload::DirectMessage { to, .. } = &message.data {
+            send_direct_message(&clients, ip_addr, to, message.clone(), reconnect.as_deref());
+            continue;
+        }
+        // Typing indicators are stale by the time a polling or reconnecting client would get them
+        if !message.data.is_transient() {
+            if let Some(room) = &message.room {
+                room_labels.message_sent(room);
+            }
+            bridge.record(message.clone());
+            if let Some(reconnect) = &reconnect {
+                reconnect.push(&message);
+            }
+        }
+
+        for (client_addr, client) in clients.iter() {
+            // Filter out the client that sent the message
+            if *client_addr == ip_addr {
+                continue;
+            }
+            if message
+                .room
+                .as_ref()
+                .is_some_and(|room| *room != client.room)
+            {
+                continue;
+            }
+            tracing::debug!("Sending message to {client_addr}");
+            client.queue.push(message.clone());
+        }
+    }
+}
+
+/// Delivers the private message to all connections of the user `to` and back to the sender.
+/// A user that just lost the connection gets it after the reconnect. The sender is told when the user isn't connected.
+fn send_direct_message(
+    clients: &HashMap<SocketAddr, ConnectedClient>,
+    sender: SocketAddr,
+    to: &str,
+    message: Arc<Message>,
+    reconnect: Option<&ReconnectBuffers>,
+) {
+    let Some(sender_client) = clients.get(&sender) else {
+        return;
+    };
+    // Nicknames are only shown, private texts are routed by the username
+    let mut delivered = sender_client.username == to;
+    for (address, client) in clients.iter() {
+        if client.username == to && *address != sender {
+            client.queue.push(message.clone());
+            delivered = true;
+        }
+    }
+    if let Some(reconnect) = reconnect {
+        delivered |= reconnect.push_direct(to, &message);
+    }
+    if delivered {
+        sender_client.queue.push(message);
+    } else {
+        let reply = format!("User {to} is offline, the message was not delivered.");
+        sender_client
+            .queue
+            .push(Arc::new(Message::new_server_msg(&reply)));
+    }
+}
+
+/// Sends the message only to the client with the given address.
+async fn send_to_client(clients: &Clients, address: &SocketAddr, message: Message) {
+    if let Some(client) = clients.lock().await.get(address) {
+        client.queue.push(Arc::new(message));
+    }
+}
+
+/// Validates the nickname with the rules of usernames. Usernames of other registered users can't be taken,
+/// even when they are offline, so nobody can pose as them.
+async fn check_nick<D: ChatDb>(db: &D, user_id: &Uuid, nick: &str) -> Result<String, ServerError> {
+    let nick = validate_username(nick)?;
+    match db.get_user(&nick).await? {
+        Some(user) if user.id != *user_id => Err(ServerError::NicknameTaken(nick)),
+        _ => Ok(nick),
+    }
+}
+
+/// Sets the nickname of the connected client. Returns the previous display name. Nicknames and usernames of
+/// other connected users can't be taken, the user's own username clears the nickname. Names are compared
+/// case-insensitively like usernames.
+async fn set_nick(
+    clients: &Clients,
+    address: &SocketAddr,
+    new_nick: &str,
+) -> Result<String, ServerError> {
+    let mut clients = clients.lock().await;
+    let Some(username) = clients.get(address).map(|client| client.username.clone()) else {
+        return Err(ServerError::ClosedConnection);
+    };
+    let taken = clients.values().any(|client| {
+        client.username != username
+            && (client.username.eq_ignore_ascii_case(new_nick)
+                || client
+                    .nick
+                    .as_deref()
+                    .is_some_and(|nick| nick.eq_ignore_ascii_case(new_nick)))
+    });
+    if taken {
+        return Err(ServerError::NicknameTaken(new_nick.to_string()));
+    }
+    let client = clients
+        .get_mut(address)
+        .expect("client was found under the same lock");
+    let previous = client.display_name().to_string();
+    client.nick = (new_nick != username).then(|| new_nick.to_string());
+    Ok(previous)
+}
+
+/// Changes the username of the connected client.
+async fn rename_client(clients: &Clients, address: &SocketAddr, new_name: &str) {
+    if let Some(client) = clients.lock().await.get_mut(address) {
+        client.username = new_name.to_string();
+    }
+}
+
+/// Message of the day with the `{active_users}` placeholder filled in, None when there is nothing to send.
+fn motd_message(motd: Option<&str>, active_users: usize) -> Option<Message> {
+    let motd = motd.filter(|motd| !motd.trim().is_empty())?;
+    let text = motd.replace("{active_users}", &active_users.to_string());
+    Some(Message::new(MessagePayload::ServerInfo(text)))
+}
+
+/// Last `replay_on_connect` messages of the default room for a new connection, the oldest first.
+/// Stored messages without text, e.g. server info, are not replayed.
+async fn replayed_messages<D: ChatDb>(state: &ServerState<D>) -> Vec<Message> {
+    let count = state.settings.replay_on_connect;
+    if count == 0 {
+        return Vec::new();
+    }
+    // Messages without text are skipped after they are fetched, so more of them are fetched than replayed.
+    let fetched = count.max(state.settings.max_history_messages);
+    let messages = match state.db.get_messages("", Some(DEFAULT_ROOM), fetched).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            tracing::error!("Failed to get messages for the replay. {e}");
+            return Vec::new();
+        }
+    };
+    let mut replayed: Vec<_> = messages
+        .into_iter()
+        .filter(|info| !info.text.is_empty())
+        .take(count as usize)
+        .map(|info| {
+            let mut message = Message::new(MessagePayload::Text(info.text));
+            message.set_from_user(&info.username);
+            message.timestamp = info.timestamp.timestamp();
+            message.room = Some(info.room);
+            message
         })
-        .collect()
-        .await;
+        .collect();
+    replayed.reverse();
+    replayed
+}
 
-        // I could use stream::iter and run all removes concurrently, but since the remove_client locks the clients, it will end up running sequentially anyway
-        for addr in clients_to_remove {
-            remove_client(&clients, &addr).await;
+/// Moves the connected client to the room.
+async fn join_room(clients: &Clients, address: &SocketAddr, room: &str) {
+    if let Some(client) = clients.lock().await.get_mut(address) {
+        client.room = room.to_string();
+    }
+}
+
+/// Collects the current status of the server.
+async fn server_status<D: ChatDb>(state: &ServerState<D>) -> ServerStatus {
+    ServerStatus {
+        active_connections: state.clients.lock().await.len(),
+        uptime_seconds: state.stats.uptime().as_secs(),
+        db_reachable: state.db.ping().await.is_ok(),
+    }
+}
+
+/// Names of the connected clients in alphabetical order, users with more connections are listed once.
+/// Nicknames are followed by the username.
+async fn online_users(clients: &Clients) -> Message {
+    let clients = clients.lock().await;
+    let usernames: BTreeSet<_> = clients
+        .values()
+        .map(|client| match &client.nick {
+            Some(nick) => format!("{nick} ({})", client.username),
+            None => client.username.clone(),
+        })
+        .collect();
+    let usernames: Vec<_> = usernames.into_iter().collect();
+    Message::new_server_msg(&format!("Online: {}", usernames.join(", ")))
+}
+
+/// Last `count` messages of the room from the database, at most `max_history_messages`. The oldest first.
+async fn history<D: ChatDb>(state: &ServerState<D>, room: &str, count: u32) -> Message {
+    let count = count.min(state.settings.max_history_messages);
+    match state.db.get_messages("", Some(room), count).await {
+        Ok(messages) => {
+            let mut entries: Vec<_> = messages
+                .into_iter()
+                .map(|message| HistoryEntry {
+                    sender: message.username,
+                    text: message.text,
+                    timestamp: message.timestamp.timestamp(),
+                })
+                .collect();
+            entries.reverse();
+            Message::new(MessagePayload::History(entries))
+        }
+        Err(e) => {
+            tracing::error!("Failed to get messages for history. {e}");
+            Message::new_server_msg("History is not available, try again later.")
         }
     }
 }
 
-async fn remove_client(
-    clients: &Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>>,
-    ip_addr: &SocketAddr,
+/// Returns the addresses and session tokens of connected clients with the given username, the oldest connection first.
+fn find_clients_by_username(
+    clients: &HashMap<SocketAddr, ConnectedClient>,
+    username: &str,
+) -> Vec<(SocketAddr, Uuid)> {
+    let mut connections: Vec<_> = clients
+        .iter()
+        .filter(|(_, client)| client.username == username)
+        .collect();
+    connections.sort_by_key(|(_, client)| client.connected_at);
+    connections
+        .into_iter()
+        .map(|(address, client)| (*address, client.session_token))
+        .collect()
+}
+
+/// Disconnects the client. The `reason` is sent to the client before the connection is closed.
+async fn kick_client(clients: &Clients, address: &SocketAddr, reason: &str) {
+    kick_locked_client(&mut *clients.lock().await, address, reason);
+}
+
+/// Same as `kick_client` for a caller that holds the lock of the clients.
+fn kick_locked_client(
+    clients: &mut HashMap<SocketAddr, ConnectedClient>,
+    address: &SocketAddr,
+    reason: &str,
 ) {
+    if let Some(client) = clients.remove(address) {
+        tracing::info!("Kicking client {address}. {reason}");
+        client.queue.push(Arc::new(Message::new_server_msg(reason)));
+        client.queue.close();
+        client.kicked.notify_one();
+    }
+}
+
+/// Disconnects all connected clients with the `reason`.
+async fn disconnect_all(clients: &Clients, reason: &str) {
+    let addresses: Vec<_> = clients.lock().await.keys().copied().collect();
+    for address in addresses {
+        kick_client(clients, &address, reason).await;
+    }
+}
+
+/// Adds the connection of the user to the connected clients and returns its session token. A reconnect with a valid
+/// session replaces the connection of the session, which is probably dead anyway, or the oldest connection if the user
+/// has the maximum of connections. For other logins over the maximum, the duplicate login policy decides whether
+/// the oldest connection is kicked or the login is rejected with the returned error.
+/// The connections are counted and the new one is inserted under one lock, so simultaneous logins can't both pass.
+async fn register_connection<D>(
+    state: &ServerState<D>,
+    address: SocketAddr,
+    user: &UserInfo,
+    session_token: Option<Uuid>,
+    queue: Arc<OutboundQueue>,
+    kicked: Arc<Notify>,
+) -> Result<Uuid, AuthError> {
+    let max_connections = state.settings.max_connections_per_user.max(1);
+    let mut clients = state.clients.lock().await;
+    let connections = find_clients_by_username(&clients, &user.username);
+    let own = session_token.and_then(|token| connections.iter().find(|(_, t)| *t == token));
+
+    let replaced = match (own, session_token) {
+        (Some((address, _)), _) => Some(*address),
+        _ if connections.len() < max_connections => None,
+        (None, Some(_)) => Some(connections[0].0),
+        (None, None) => match state.settings.duplicate_login_policy {
+            DuplicateLoginPolicy::RejectNew if max_connections == 1 => {
+                return Err(AuthError::AlreadyConnected)
+            }
+            DuplicateLoginPolicy::RejectNew => return Err(AuthError::TooManyConnections),
+            DuplicateLoginPolicy::KickOld => Some(connections[0].0),
+        },
+    };
+    if let Some(replaced) = replaced {
+        kick_locked_client(
+            &mut clients,
+            &replaced,
+            "You were disconnected because you logged in from another place.",
+        );
+    }
+
+    let token = match session_token {
+        Some(token) => token,
+        None => state.sessions.create(user.id),
+    };
+    // Taken while the broadcaster is locked out, so no message is lost between the buffer and the queue
+    if let (Some(token), Some(reconnect)) = (session_token, &state.reconnect) {
+        for message in reconnect.take(&token) {
+            queue.push(message);
+        }
+    }
+    clients.insert(
+        address,
+        ConnectedClient {
+            username: user.username.clone(),
+            session_token: token,
+            connected_at: Instant::now(),
+            queue,
+            kicked,
+            room: DEFAULT_ROOM.to_string(),
+            nick: None,
+        },
+    );
+    Ok(token)
+}
+
+async fn remove_client(clients: &Clients, ip_addr: &SocketAddr) {
     tracing::info!("Removing client from list {ip_addr}");
     clients.lock().await.remove(ip_addr);
 }
 
-async fn run_until_authenticated(
+async fn run_until_authenticated<D: ChatDb>(
     stream: &mut TcpStream,
-    db: Arc<impl ChatDb>,
-) -> Result<UserInfo, ServerError> {
+    address: SocketAddr,
+    state: &ServerState<D>,
+) -> Result<AuthenticatedUser, ServerError> {
     loop {
         let msg: Message = match Message::receive_msg(stream).await {
             Ok(msg) => msg,
@@ -170,21 +1043,101 @@ async fn run_until_authenticated(
 
         if let MessagePayload::Login(auth_user) = msg.data {
             let username = auth_user.name.clone();
+            let previous_token = auth_user.session_token;
+            let compression = Compression::new(
+                Algorithm::negotiate(state.settings.compression_algorithm, &auth_user.compression),
+                state.settings.compression_level,
+            );
+            let framing = Framing::negotiated(
+                compression,
+                auth_user.connection_compression && state.settings.connection_compression,
+            );
             tracing::debug!("Received request to log in user: {}.", username);
-            match verify_or_create_user(auth_user, &db).await {
+
+            let replay_check = state
+                .replay_guard
+                .check(auth_user.timestamp, auth_user.nonce);
+            if replay_check != ReplayCheck::Accepted {
+                tracing::warn!("Rejected login of user {}. {:?}", username, replay_check);
+                let payload = MessagePayload::LoginResponse(AuthPayload::new_auth_error(
+                    AuthError::ReplayedLogin,
+                ));
+                Message::send_msg(&Message::new(payload), stream)
+                    .await
+                    .map_err(ServerError::SendMessage)?;
+                continue;
+            }
+
+            if let Some(retry_after) = state.lockout.retry_after(&username) {
+                tracing::warn!("Rejected login of locked user {}.", username);
+                let payload = MessagePayload::LoginResponse(
+                    AuthPayload::new_auth_error(AuthError::LockedOut).with_retry_after(retry_after),
+                );
+                Message::send_msg(&Message::new(payload), stream)
+                    .await
+                    .map_err(ServerError::SendMessage)?;
+                continue;
+            }
+
+            match verify_or_create_user(auth_user, state.db.as_ref()).await {
                 Ok(Some(user)) => {
                     tracing::debug!("User {} successfully logged in.", username);
-                    let payload = MessagePayload::LoginResponse(AuthPayload::new_login());
+                    state.lockout.record_success(&username);
+
+                    let previous_token =
+                        previous_token.filter(|token| state.sessions.is_valid(token, &user.id));
+
+                    let queue = Arc::new(OutboundQueue::new());
+                    let kicked = Arc::new(Notify::new());
+                    let registered = register_connection(
+                        state,
+                        address,
+                        &user,
+                        previous_token,
+                        queue.clone(),
+                        kicked.clone(),
+                    )
+                    .await;
+                    let session_token = match registered {
+                        Ok(session_token) => session_token,
+                        Err(err) => {
+                            tracing::debug!(
+                                "User {} has too many connections. {:?}",
+                                username,
+                                err
+                            );
+                            let payload =
+                                MessagePayload::LoginResponse(AuthPayload::new_auth_error(err));
+                            Message::send_msg(&Message::new(payload), stream)
+                                .await
+                                .map_err(ServerError::SendMessage)?;
+                            continue;
+                        }
+                    };
+
+                    let payload = MessagePayload::LoginResponse(
+                        AuthPayload::new_login(session_token)
+                            .with_compression(compression.algorithm)
+                            .with_connection_compression(matches!(framing, Framing::Connection(_))),
+                    );
 
                     let msg = Message::new(payload);
                     Message::send_msg(&msg, stream)
                         .await
                         .map_err(ServerError::SendMessage)?;
 
-                    return Ok(user);
+                    return Ok(AuthenticatedUser {
+                        user,
+                        session_token,
+                        is_reconnect: previous_token.is_some(),
+                        framing,
+                        queue,
+                        kicked,
+                    });
                 }
                 Ok(None) => {
                     tracing::debug!("Incorrect login for user: {}", username);
+                    state.lockout.record_failure(&username);
                     let payload = MessagePayload::LoginResponse(AuthPayload::new_error());
 
                     let msg = Message::new(payload);
@@ -192,6 +1145,15 @@ async fn run_until_authenticated(
                         .await
                         .map_err(ServerError::SendMessage)?;
                 }
+                Err(e @ ServerError::InvalidUsername) => {
+                    tracing::debug!("Rejected registration of user {}. {}", username, e);
+                    let payload = MessagePayload::LoginResponse(AuthPayload::new_auth_error(
+                        AuthError::InvalidUsername(e.to_string()),
+                    ));
+                    Message::send_msg(&Message::new(payload), stream)
+                        .await
+                        .map_err(ServerError::SendMessage)?;
+                }
                 Err(e) => {
                     tracing::error!("Error while logging in user {}. Error {}", username, e);
                     let payload = MessagePayload::LoginResponse(AuthPayload::new_error());
@@ -207,11 +1169,11 @@ async fn run_until_authenticated(
 }
 
 async fn verify_or_create_user(
-    auth_user: AuthUser,
-    db: &Arc<impl ChatDb>,
+    mut auth_user: AuthUser,
+    db: &impl ChatDb,
 ) -> Result<Option<UserInfo>, ServerError> {
-    let user_result = db.get_user(&auth_user.name).await?;
-    return match user_result {
+    let user_result = db.get_user(auth_user.name.trim()).await?;
+    match user_result {
         Some(user) => {
             let verification_result = user.verify_user_password(auth_user.password.as_bytes());
 
@@ -223,10 +1185,1154 @@ async fn verify_or_create_user(
         None => {
             tracing::debug!("Registering new user.");
 
+            auth_user.name = validate_username(&auth_user.name)?;
             let user = auth_user.try_into()?;
 
             db.insert_user(&user).await?;
             Ok(Some(user.into()))
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{ChatSettings, DuplicateLoginPolicy, WebhookSettings};
+    use crate::db::{ChatDb, StorageLimits};
+    use crate::test_utils::{
+        login, receive_server_info, receive_with_timeout, spawn_webhook, TestServer,
     };
+    use crate::user::User;
+    use shared::compression::{Algorithm, Compression, Framing};
+    use shared::message::{AuthError, AuthUser, Message, MessagePayload, MAX_MESSAGE_SIZE};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn pending_authentications_are_capped() {
+        let settings = ChatSettings {
+            max_pending_authentications: 1,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+
+        let mut first = TcpStream::connect(server.address).await.unwrap();
+        let mut second = TcpStream::connect(server.address).await.unwrap();
+
+        let rejection = Message::receive_msg(&mut second).await.unwrap();
+        assert!(matches!(rejection.data, MessagePayload::ServerInfo(_)));
+        assert!(Message::receive_msg(&mut second).await.is_err());
+
+        let response = login(&mut first, "first", "password").await;
+        assert!(
+            matches!(response.data, MessagePayload::LoginResponse(ref auth) if auth.is_success())
+        );
+
+        let mut third = TcpStream::connect(server.address).await.unwrap();
+        let response = login(&mut third, "third", "password").await;
+        assert!(
+            matches!(response.data, MessagePayload::LoginResponse(ref auth) if auth.is_success())
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_connection_releases_pending_authentication_after_timeout() {
+        let settings = ChatSettings {
+            max_pending_authentications: 1,
+            auth_timeout_seconds: 1,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+
+        let mut idle = TcpStream::connect(server.address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(Message::receive_msg(&mut idle).await.is_err());
+
+        let mut stream = TcpStream::connect(server.address).await.unwrap();
+        let response = login(&mut stream, "alice", "password").await;
+        assert!(
+            matches!(response.data, MessagePayload::LoginResponse(ref auth) if auth.is_success())
+        );
+    }
+
+    #[tokio::test]
+    async fn client_is_told_why_its_message_was_rejected() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        // Length, flag of no compression and the body
+        let oversized = [&((MAX_MESSAGE_SIZE + 1) as u32).to_be_bytes()[..], &[0]].concat();
+        let malformed = [&3u32.to_be_bytes()[..], &[0, 1, 2, 3]].concat();
+
+        for (frame, reason) in [
+            (oversized, "is larger than the limit"),
+            (malformed, "couldn't be decoded"),
+        ] {
+            let mut alice = server.connect_user("alice").await;
+            alice.write_all(&frame).await.unwrap();
+
+            let info = receive_server_info(&mut alice).await;
+            assert!(info.contains(reason), "{info}");
+            assert!(Message::receive_msg(&mut alice).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_over_ip_rate_limit_are_closed() {
+        let settings = ChatSettings {
+            max_connections_per_ip: Some(1),
+            connection_rate_window_seconds: 60,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let _alice = server.connect_user("alice").await;
+
+        let mut second = TcpStream::connect(server.address).await.unwrap();
+
+        assert!(
+            Message::handshake(&mut second, AuthUser::new("bob", "password"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_with_session_token_is_not_announced_as_new_user() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut observer = server.connect_user("observer").await;
+
+        let (bob, token) = server.connect_user_with_token("bob").await;
+        assert_eq!(
+            receive_server_info(&mut observer).await,
+            "New user connected: bob"
+        );
+        drop(bob);
+
+        let mut bob = TcpStream::connect(server.address).await.unwrap();
+        let user = AuthUser::new("bob", "password").with_session_token(token);
+        let response = Message::handshake(&mut bob, user).await.unwrap();
+        assert!(
+            matches!(response.data, MessagePayload::LoginResponse(ref auth) if auth.session_token() == Some(token))
+        );
+
+        assert_eq!(receive_server_info(&mut observer).await, "bob reconnected");
+    }
+
+    #[tokio::test]
+    async fn message_sent_during_grace_period_is_delivered_on_reconnect() {
+        let settings = ChatSettings {
+            reconnect_grace_seconds: Some(10),
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let (mut bob, token) = server.connect_user_with_token("bob").await;
+        let mut alice = server.connect_user("alice").await;
+        let mut carol = server.connect_user("carol").await;
+        for name in ["alice", "carol"] {
+            assert_eq!(
+                receive_server_info(&mut bob).await,
+                format!("New user connected: {name}")
+            );
+        }
+
+        // The server closes the connection after it stopped reading from it
+        bob.shutdown().await.unwrap();
+        while Message::receive_msg(&mut bob).await.is_ok() {}
+        let message = Message::new(MessagePayload::Text("while you were away".into()));
+        Message::send_msg(&message, &mut alice).await.unwrap();
+        // Relayed to carol, so it was buffered before bob is back
+        receive_with_timeout(&mut carol).await.unwrap();
+
+        let mut bob = TcpStream::connect(server.address).await.unwrap();
+        let user = AuthUser::new("bob", "password").with_session_token(token);
+        Message::handshake(&mut bob, user).await.unwrap();
+
+        let active_users = receive_with_timeout(&mut bob).await.unwrap();
+        assert!(matches!(active_users.data, MessagePayload::ActiveUsers(_)));
+        let buffered = receive_with_timeout(&mut bob).await.unwrap();
+        assert_eq!(buffered.sender.as_deref(), Some("alice"));
+        assert!(
+            matches!(buffered.data, MessagePayload::Text(ref text) if text == "while you were away")
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_login_is_rejected() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let _alice = server.connect_user("alice").await;
+
+        let mut second = TcpStream::connect(server.address).await.unwrap();
+        let response = login(&mut second, "alice", "password").await;
+
+        assert!(
+            matches!(response.data, MessagePayload::LoginResponse(ref auth) if !auth.is_success())
+        );
+    }
+
+    /// Logs in `count` connections of an existing user at once. Returns how many of the logins succeeded.
+    async fn simultaneous_logins(server: &TestServer, name: &str, count: usize) -> usize {
+        let user = User::try_from(AuthUser::new(name, "password")).unwrap();
+        server.db.insert_user(&user).await.unwrap();
+        let logins: Vec<_> = (0..count)
+            .map(|_| {
+                let (address, name) = (server.address, name.to_string());
+                tokio::spawn(async move {
+                    let mut stream = TcpStream::connect(address).await.unwrap();
+                    let response = login(&mut stream, &name, "password").await;
+                    let success = matches!(response.data, MessagePayload::LoginResponse(ref auth) if auth.is_success());
+                    (stream, success)
+                })
+            })
+            .collect();
+        let mut streams = Vec::new();
+        for login in logins {
+            streams.push(login.await.unwrap());
+        }
+        streams.iter().filter(|(_, success)| *success).count()
+    }
+
+    #[tokio::test]
+    async fn only_one_of_simultaneous_logins_passes() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+
+        assert_eq!(simultaneous_logins(&server, "alice", 2).await, 1);
+    }
+
+    #[tokio::test]
+    async fn simultaneous_logins_dont_exceed_connection_limit() {
+        let settings = ChatSettings {
+            max_connections_per_user: 2,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+
+        assert_eq!(simultaneous_logins(&server, "alice", 4).await, 2);
+    }
+
+    #[tokio::test]
+    async fn logins_over_connection_limit_are_rejected() {
+        let settings = ChatSettings {
+            max_connections_per_user: 2,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let _first = server.connect_user("alice").await;
+        let _second = server.connect_user("alice").await;
+
+        let mut third = TcpStream::connect(server.address).await.unwrap();
+        let response = login(&mut third, "alice", "password").await;
+
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert!(!auth.is_success());
+        assert_eq!(
+            auth.to_string(),
+            "Login failed, the user has too many open connections.\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_username_is_not_registered() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        drop(server.connect_user("Alice").await);
+        let mut stream = TcpStream::connect(server.address).await.unwrap();
+
+        let response = login(&mut stream, "al ice", "password").await;
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert!(matches!(auth.error(), Some(AuthError::InvalidUsername(_))));
+
+        // Names differing only in case or whitespace belong to the same user
+        let response = login(&mut stream, " alice ", "other").await;
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert_eq!(auth.error(), Some(&AuthError::IncorrectPassword));
+        assert_eq!(server.db.get_users().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn locked_out_login_carries_retry_after() {
+        let settings = ChatSettings {
+            max_failed_logins: Some(2),
+            login_lockout_seconds: 30,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        drop(server.connect_user("alice").await);
+
+        let mut stream = TcpStream::connect(server.address).await.unwrap();
+        login(&mut stream, "alice", "wrong").await;
+        login(&mut stream, "alice", "wrong").await;
+        let response = login(&mut stream, "alice", "password").await;
+
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert!(!auth.is_success());
+        assert_eq!(auth.error(), Some(&AuthError::LockedOut));
+        assert!(matches!(auth.retry_after_seconds(), Some(1..=30)));
+    }
+
+    #[tokio::test]
+    async fn lockout_ignores_case_and_whitespace_of_username() {
+        let settings = ChatSettings {
+            max_failed_logins: Some(2),
+            login_lockout_seconds: 30,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        drop(server.connect_user("alice").await);
+
+        let mut stream = TcpStream::connect(server.address).await.unwrap();
+        login(&mut stream, "Alice", "wrong").await;
+        login(&mut stream, " alice  ", "wrong").await;
+        let response = login(&mut stream, "alice", "password").await;
+
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert_eq!(auth.error(), Some(&AuthError::LockedOut));
+    }
+
+    #[tokio::test]
+    async fn stalled_client_is_disconnected_as_too_slow() {
+        let settings = ChatSettings {
+            max_queued_messages: Some(2),
+            max_bytes_per_minute: None,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut stalled = server.connect_user("carol").await;
+        let mut bob = server.connect_user("bob").await;
+        let mut alice = server.connect_user("alice").await;
+        receive_server_info(&mut bob).await;
+
+        // Carol never reads, so her socket buffers fill up and the messages stay in her queue.
+        // Bob reads every message before the next one is sent, so he keeps up.
+        for _ in 0..32 {
+            let file = MessagePayload::File("big.bin".to_string(), vec![0; 256 * 1024]);
+            Message::send_msg(&Message::new(file), &mut alice)
+                .await
+                .unwrap();
+            let msg = receive_with_timeout(&mut bob).await.unwrap();
+            assert!(matches!(msg.data, MessagePayload::File(..)));
+        }
+
+        // The reason is written only while the client reads in WRITER_DRAIN_TIMEOUT, carol was kicked long before
+        let mut notices = Vec::new();
+        while let Ok(message) = Message::receive_msg(&mut stalled).await {
+            if let MessagePayload::ServerInfo(text) = message.data {
+                notices.push(text);
+            }
+        }
+        assert!(notices
+            .iter()
+            .all(|text| text.starts_with("New user") || text.contains("too slow")));
+    }
+
+    #[tokio::test]
+    async fn only_admins_can_override_sender() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            allow_sender_override: true,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        server.db.insert_user(&admin).await.unwrap();
+        let mut bob = server.connect_user("bob").await;
+        let mut admin = server.connect_user("admin").await;
+        let mut alice = server.connect_user("alice").await;
+        receive_server_info(&mut bob).await;
+        receive_server_info(&mut bob).await;
+
+        let mut senders = Vec::new();
+        for stream in [&mut admin, &mut alice] {
+            let mut msg = Message::new(MessagePayload::Text("hi".to_string()));
+            msg.sender = Some("ghost".to_string());
+            Message::send_msg(&msg, stream).await.unwrap();
+            let received = receive_with_timeout(&mut bob).await.unwrap();
+            senders.push(received.sender.unwrap());
+        }
+
+        assert_eq!(senders, vec!["ghost", "alice"]);
+    }
+
+    #[tokio::test]
+    async fn sender_override_is_ignored_when_not_allowed() {
+        let admin = User::try_from(AuthUser::new("admin", "password")).unwrap();
+        let settings = ChatSettings {
+            admins: vec![admin.id],
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        server.db.insert_user(&admin).await.unwrap();
+        let mut bob = server.connect_user("bob").await;
+        let mut admin = server.connect_user("admin").await;
+        receive_server_info(&mut bob).await;
+
+        let mut msg = Message::new(MessagePayload::Text("hi".to_string()));
+        msg.sender = Some("ghost".to_string());
+        Message::send_msg(&msg, &mut admin).await.unwrap();
+
+        let received = receive_with_timeout(&mut bob).await.unwrap();
+        assert_eq!(received.sender.as_deref(), Some("admin"));
+    }
+
+    #[test]
+    fn server_runs_when_tracing_setup_fails() {
+        // Without tracing the events go nowhere, the server runs under a subscriber that ignores them.
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let no_tracing = tracing::subscriber::NoSubscriber::default();
+        tracing::subscriber::with_default(no_tracing, || {
+            runtime.block_on(async {
+                let server = TestServer::spawn(ChatSettings::default()).await;
+                let mut alice = server.connect_user("alice").await;
+                let mut bob = server.connect_user("bob").await;
+                receive_server_info(&mut alice).await;
+
+                let msg = Message::new(MessagePayload::Text("hi".to_string()));
+                Message::send_msg(&msg, &mut alice).await.unwrap();
+
+                let received = receive_with_timeout(&mut bob).await.unwrap();
+                assert_eq!(received.data, MessagePayload::Text("hi".to_string()));
+            })
+        });
+    }
+
+    #[tokio::test]
+    async fn join_is_posted_to_presence_webhook() {
+        let (url, mut webhook) = spawn_webhook(0);
+        let settings = ChatSettings {
+            presence_webhook: Some(WebhookSettings {
+                url,
+                secret: None,
+                max_retries: 0,
+                retry_delay_ms: 0,
+                queue_size: 10,
+                timeout_ms: 5000,
+            }),
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+
+        let _alice = server.connect_user("alice").await;
+
+        let request = tokio::time::timeout(Duration::from_secs(5), webhook.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body["event"], "join");
+        assert_eq!(request.body["username"], "alice");
+        assert!(request.body["timestamp"].is_i64());
+        assert!(request.signature.is_none());
+    }
+
+    #[tokio::test]
+    async fn clients_are_told_when_server_shuts_down() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let address = server.address;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        receive_server_info(&mut alice).await;
+
+        server.shutdown().await.unwrap();
+
+        for stream in [&mut alice, &mut bob] {
+            assert_eq!(receive_server_info(stream).await, "Server shutting down");
+            assert!(Message::receive_msg(stream).await.is_err());
+        }
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn history_is_sent_only_to_requesting_client() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        receive_server_info(&mut alice).await;
+        let alice_id = server.db.get_user("alice").await.unwrap().unwrap().id;
+        for i in 1..=7 {
+            let message = Message::new(MessagePayload::Text(i.to_string()));
+            server.db.insert_message(&message, &alice_id).await.unwrap();
+        }
+
+        let request = Message::new(MessagePayload::HistoryRequest(5));
+        Message::send_msg(&request, &mut alice).await.unwrap();
+
+        let response = receive_with_timeout(&mut alice).await.unwrap();
+        let MessagePayload::History(entries) = response.data else {
+            panic!("Expected history, got {:?}", response.data);
+        };
+        let texts: Vec<_> = entries.iter().map(|entry| entry.text.as_str()).collect();
+        assert_eq!(texts, ["3", "4", "5", "6", "7"]);
+        assert!(entries.iter().all(|entry| entry.sender == "alice"));
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn online_users_are_listed_only_to_requesting_client() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut bob = server.connect_user("bob").await;
+        let mut alice = server.connect_user("alice").await;
+        receive_server_info(&mut bob).await;
+
+        let request = Message::new(MessagePayload::ListUsers);
+        Message::send_msg(&request, &mut alice).await.unwrap();
+
+        assert_eq!(receive_server_info(&mut alice).await, "Online: alice, bob");
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn typing_is_relayed_once_per_interval_and_not_stored() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        receive_server_info(&mut alice).await;
+
+        for _ in 0..3 {
+            let typing = Message::new(MessagePayload::Typing);
+            Message::send_msg(&typing, &mut alice).await.unwrap();
+        }
+
+        let received = receive_with_timeout(&mut bob).await.unwrap();
+        assert_eq!(received.data, MessagePayload::Typing);
+        assert_eq!(received.sender.as_deref(), Some("alice"));
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+        assert!(server
+            .db
+            .get_messages("", None, 50)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn burst_of_messages_is_rate_limited() {
+        let settings = ChatSettings {
+            max_messages_per_second: Some(0.1),
+            message_burst: 2,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        receive_server_info(&mut alice).await;
+
+        for i in 0..3 {
+            let text = Message::new(MessagePayload::Text(format!("spam {i}")));
+            Message::send_msg(&text, &mut alice).await.unwrap();
+        }
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "You are being rate limited"
+        );
+        let typing = Message::new(MessagePayload::Typing);
+        Message::send_msg(&typing, &mut alice).await.unwrap();
+
+        for expected in [
+            MessagePayload::Text("spam 0".into()),
+            MessagePayload::Text("spam 1".into()),
+            MessagePayload::Typing,
+        ] {
+            assert_eq!(receive_with_timeout(&mut bob).await.unwrap().data, expected);
+        }
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn requests_handled_by_server_are_rate_limited() {
+        let settings = ChatSettings {
+            max_messages_per_second: Some(0.1),
+            message_burst: 1,
+            ..Default::default()
+        };
+        for request in [
+            MessagePayload::Nick("ally".into()),
+            MessagePayload::Rename("alicia".into()),
+            MessagePayload::HistoryRequest(10),
+            MessagePayload::StatusRequest,
+            MessagePayload::KeyAnnounce {
+                public_key: [1; 32],
+                reply: true,
+            },
+        ] {
+            let server = TestServer::spawn(settings.clone()).await;
+            let mut alice = server.connect_user("alice").await;
+
+            let text = Message::new(MessagePayload::Text("hi".into()));
+            Message::send_msg(&text, &mut alice).await.unwrap();
+            Message::send_msg(&Message::new(request), &mut alice)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                receive_server_info(&mut alice).await,
+                "You are being rate limited"
+            );
+            assert!(receive_with_timeout(&mut alice).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_relayed_only_within_room() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        receive_server_info(&mut alice).await;
+        let mut carol = server.connect_user("carol").await;
+        receive_server_info(&mut alice).await;
+        receive_server_info(&mut bob).await;
+
+        let join = Message::new(MessagePayload::JoinRoom("rust".into()));
+        Message::send_msg(&join, &mut bob).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "You are now in room rust."
+        );
+
+        let text = Message::new(MessagePayload::Text("hi general".into()));
+        Message::send_msg(&text, &mut alice).await.unwrap();
+        let received = receive_with_timeout(&mut carol).await.unwrap();
+        assert_eq!(received.room_name(), "general");
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+
+        let text = Message::new(MessagePayload::Text("hi rust".into()));
+        Message::send_msg(&text, &mut bob).await.unwrap();
+        assert!(receive_with_timeout(&mut alice).await.is_none());
+        assert!(receive_with_timeout(&mut carol).await.is_none());
+
+        let rooms: Vec<_> = server
+            .db
+            .get_messages("", Some("rust"), 50)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.text)
+            .collect();
+        assert_eq!(rooms, ["hi rust"]);
+    }
+
+    #[tokio::test]
+    async fn direct_message_reaches_only_the_recipient() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        receive_server_info(&mut alice).await;
+        let mut carol = server.connect_user("carol").await;
+        receive_server_info(&mut alice).await;
+        receive_server_info(&mut bob).await;
+
+        let dm = Message::new(MessagePayload::DirectMessage {
+            to: "bob".into(),
+            text: "secret".into(),
+        });
+        Message::send_msg(&dm, &mut alice).await.unwrap();
+
+        for stream in [&mut bob, &mut alice] {
+            let received = receive_with_timeout(stream).await.unwrap();
+            assert_eq!(received.sender.as_deref(), Some("alice"));
+            assert!(matches!(
+                received.data,
+                MessagePayload::DirectMessage { ref text, .. } if text == "secret"
+            ));
+        }
+        assert!(receive_with_timeout(&mut carol).await.is_none());
+
+        let dm = Message::new(MessagePayload::DirectMessage {
+            to: "dave".into(),
+            text: "hello?".into(),
+        });
+        Message::send_msg(&dm, &mut alice).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "User dave is offline, the message was not delivered."
+        );
+    }
+
+    #[tokio::test]
+    async fn last_messages_are_replayed_on_connect() {
+        let settings = ChatSettings {
+            replay_on_connect: 2,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let _alice = server.connect_user("alice").await;
+        let alice_id = server.db.get_user("alice").await.unwrap().unwrap().id;
+        for payload in [
+            MessagePayload::Text("first".into()),
+            MessagePayload::Text("second".into()),
+            MessagePayload::Text("third".into()),
+            MessagePayload::ServerInfo("not replayed".into()),
+        ] {
+            let message = Message::new(payload);
+            server.db.insert_message(&message, &alice_id).await.unwrap();
+        }
+
+        let mut bob = server.connect_user("bob").await;
+
+        for expected in ["second", "third"] {
+            let replayed = receive_with_timeout(&mut bob).await.unwrap();
+            assert_eq!(replayed.sender.as_deref(), Some("alice"));
+            assert!(matches!(replayed.data, MessagePayload::Text(ref text) if text == expected));
+        }
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn motd_is_sent_after_login() {
+        let settings = ChatSettings {
+            motd: Some("Welcome! Users online: {active_users}".to_string()),
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut alice = server.connect_user("alice").await;
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "Welcome! Users online: 0"
+        );
+
+        let mut bob = server.connect_user("bob").await;
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "Welcome! Users online: 1"
+        );
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+
+        let settings = ChatSettings {
+            motd: Some(" ".to_string()),
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut carol = server.connect_user("carol").await;
+        assert!(receive_with_timeout(&mut carol).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn duplicate_login_kicks_old_connection() {
+        let settings = ChatSettings {
+            duplicate_login_policy: DuplicateLoginPolicy::KickOld,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut old = server.connect_user("alice").await;
+
+        let _new = server.connect_user("alice").await;
+
+        assert_eq!(
+            receive_server_info(&mut old).await,
+            "You were disconnected because you logged in from another place."
+        );
+        assert!(Message::receive_msg(&mut old).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn attachments_are_checked_against_allowlist() {
+        let settings = ChatSettings {
+            allowed_attachment_types: vec!["image/png".to_string()],
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let pdf = MessagePayload::File("image.png".into(), b"%PDF-1.4".to_vec());
+        Message::send_msg(&Message::new(pdf), &mut alice)
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "This type of attachment is not allowed."
+        );
+
+        let png = MessagePayload::Image(b"\x89PNG\r\n\x1a\ndata".to_vec());
+        Message::send_msg(&Message::new(png), &mut alice)
+            .await
+            .unwrap();
+        let received = Message::receive_msg(&mut bob).await.unwrap();
+        assert!(matches!(received.data, MessagePayload::Image(_)));
+    }
+
+    #[tokio::test]
+    async fn active_users_count_is_sent_structurally() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let _alice = server.connect_user("alice").await;
+
+        let mut bob = TcpStream::connect(server.address).await.unwrap();
+        login(&mut bob, "bob", "password").await;
+        let active_users = Message::receive_msg(&mut bob).await.unwrap();
+
+        assert_eq!(active_users.data, MessagePayload::ActiveUsers(1));
+        assert_eq!(
+            active_users.to_string(),
+            "--      Active users: 1      --\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn compression_is_negotiated_at_login() {
+        let settings = ChatSettings {
+            compression_algorithm: Algorithm::Zstd,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut alice = TcpStream::connect(server.address).await.unwrap();
+        let user = AuthUser::new("alice", "password").with_compression(vec![Algorithm::Zstd]);
+        let response = Message::handshake(&mut alice, user).await.unwrap();
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert_eq!(auth.compression(), Algorithm::Zstd);
+        // Frames compressed with another algorithm than the negotiated one are rejected
+        let framing = Framing::PerMessage(Compression::new(auth.compression(), 3));
+        // active users message
+        Message::receive_framed_msg(&mut alice, framing)
+            .await
+            .unwrap();
+
+        let mut bob = server.connect_user("bob").await;
+        let joined = Message::receive_framed_msg(&mut alice, framing)
+            .await
+            .unwrap();
+        assert_eq!(
+            joined.data,
+            MessagePayload::ServerInfo("New user connected: bob".into())
+        );
+        let text = MessagePayload::Text("compressed".into());
+        Message::send_msg(&Message::new(text), &mut bob)
+            .await
+            .unwrap();
+
+        let received = Message::receive_framed_msg(&mut alice, framing)
+            .await
+            .unwrap();
+        assert_eq!(received.data, MessagePayload::Text("compressed".into()));
+    }
+
+    #[tokio::test]
+    async fn connection_compression_is_used_only_when_both_sides_support_it() {
+        let settings = ChatSettings {
+            compression_algorithm: Algorithm::Zstd,
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let login = |user: AuthUser| async {
+            let mut stream = TcpStream::connect(server.address).await.unwrap();
+            let response = Message::handshake(&mut stream, user).await.unwrap();
+            let MessagePayload::LoginResponse(auth) = response.data else {
+                panic!("Expected login response, got {:?}", response.data);
+            };
+            let compression = Compression::new(auth.compression(), 3);
+            let framing = Framing::negotiated(compression, auth.connection_compression());
+            // active users message
+            Message::receive_framed_msg(&mut stream, framing)
+                .await
+                .unwrap();
+            (stream, framing)
+        };
+
+        let capable =
+            |name| AuthUser::new(name, "password").with_compression(vec![Algorithm::Zstd]);
+        let (mut alice, alice_framing) =
+            login(capable("alice").with_connection_compression()).await;
+        let (mut bob, bob_framing) = login(capable("bob").with_connection_compression()).await;
+        let (mut carol, carol_framing) = login(capable("carol")).await;
+        assert!(matches!(alice_framing, Framing::Connection(_)));
+        assert!(matches!(carol_framing, Framing::PerMessage(_)));
+
+        let text = Message::new(MessagePayload::Text("hello ".repeat(50)));
+        Message::send_framed_msg(&text, &mut bob, bob_framing)
+            .await
+            .unwrap();
+
+        for (stream, framing) in [(&mut alice, alice_framing), (&mut carol, carol_framing)] {
+            let received = loop {
+                let message = Message::receive_framed_msg(stream, framing).await.unwrap();
+                if !matches!(message.data, MessagePayload::ServerInfo(_)) {
+                    break message;
+                }
+            };
+            assert_eq!(received.data, text.data);
+        }
+    }
+
+    #[tokio::test]
+    async fn status_request_reports_connection_count() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let _bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let request = Message::new(MessagePayload::StatusRequest);
+        Message::send_msg(&request, &mut alice).await.unwrap();
+
+        let response = Message::receive_msg(&mut alice).await.unwrap();
+        let MessagePayload::StatusResponse(status) = response.data else {
+            panic!("Expected status response, got {:?}", response.data);
+        };
+        assert_eq!(status.active_connections, 2);
+        assert!(status.db_reachable);
+    }
+
+    #[tokio::test]
+    async fn stale_login_is_rejected() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut stream = TcpStream::connect(server.address).await.unwrap();
+
+        let stale = AuthUser {
+            timestamp: chrono::Utc::now().timestamp() - 3600,
+            ..AuthUser::new("alice", "password")
+        };
+        let response = Message::handshake(&mut stream, stale).await.unwrap();
+        assert!(
+            matches!(response.data, MessagePayload::LoginResponse(ref auth) if !auth.is_success())
+        );
+
+        let fresh = AuthUser::new("alice", "password");
+        let response = Message::handshake(&mut stream, fresh).await.unwrap();
+        assert!(
+            matches!(response.data, MessagePayload::LoginResponse(ref auth) if auth.is_success())
+        );
+    }
+
+    #[tokio::test]
+    async fn message_that_fails_to_serialize_is_skipped() {
+        let settings = ChatSettings {
+            max_message_bytes: Some(200),
+            ..Default::default()
+        };
+        let server = TestServer::spawn(settings).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let large = Message::new(MessagePayload::Text("a".repeat(500)));
+        Message::send_msg(&large, &mut alice).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "Your message couldn't be sent, it is too large."
+        );
+
+        let small = Message::new(MessagePayload::Text("hi".into()));
+        Message::send_msg(&small, &mut alice).await.unwrap();
+        let received = Message::receive_msg(&mut bob).await.unwrap();
+        assert_eq!(received.data, MessagePayload::Text("hi".into()));
+    }
+
+    #[tokio::test]
+    async fn message_over_storage_limit_is_neither_stored_nor_relayed() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let max = StorageLimits::default().max_message_length;
+        let long = Message::new(MessagePayload::Text("a".repeat(max + 1)));
+        Message::send_msg(&long, &mut alice).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            format!(
+                "Your message couldn't be sent. Value of message is longer than {max} characters."
+            )
+        );
+
+        let small = Message::new(MessagePayload::Text("hi".into()));
+        Message::send_msg(&small, &mut alice).await.unwrap();
+        let received = Message::receive_msg(&mut bob).await.unwrap();
+        assert_eq!(received.data, MessagePayload::Text("hi".into()));
+        let stored = server.db.get_messages("alice", None, 50).await.unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rename_is_broadcast_to_connected_clients() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let rename = Message::new(MessagePayload::Rename("carol".into()));
+        Message::send_msg(&rename, &mut alice).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "alice is now known as carol"
+        );
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "alice is now known as carol"
+        );
+
+        let text = Message::new(MessagePayload::Text("hi".into()));
+        Message::send_msg(&text, &mut alice).await.unwrap();
+        let received = Message::receive_msg(&mut bob).await.unwrap();
+        assert_eq!(received.sender.as_deref(), Some("carol"));
+    }
+
+    #[tokio::test]
+    async fn half_read_frame_survives_events_of_other_users() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+        let bob_id = server.db.get_user("bob").await.unwrap().unwrap().id;
+
+        let mut frame = Vec::new();
+        let text = Message::new(MessagePayload::Text("hi".into()));
+        Message::send_msg(&text, &mut frame).await.unwrap();
+        let (head, tail) = frame.split_at(frame.len() / 2);
+        alice.write_all(head).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Rename is an event for every connection, it comes while the frame of alice is read
+        server
+            .bridge
+            .rename_user(server.db.as_ref(), &bob_id, "robert")
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "bob is now known as robert"
+        );
+        alice.write_all(tail).await.unwrap();
+
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "bob is now known as robert"
+        );
+        let received = receive_with_timeout(&mut bob).await.unwrap();
+        assert_eq!(received.sender.as_deref(), Some("alice"));
+        assert_eq!(received.data, MessagePayload::Text("hi".into()));
+    }
+
+    #[tokio::test]
+    async fn nick_changes_the_shown_name_only() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let nick = Message::new(MessagePayload::Nick("alice2".into()));
+        Message::send_msg(&nick, &mut alice).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "alice is now known as alice2"
+        );
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "alice is now known as alice2"
+        );
+
+        let text = Message::new(MessagePayload::Text("hi".into()));
+        Message::send_msg(&text, &mut alice).await.unwrap();
+        let received = Message::receive_msg(&mut bob).await.unwrap();
+        assert_eq!(received.sender.as_deref(), Some("alice2"));
+        assert!(server.db.get_user("alice").await.unwrap().is_some());
+        assert!(server.db.get_user("alice2").await.unwrap().is_none());
+
+        let list = Message::new(MessagePayload::ListUsers);
+        Message::send_msg(&list, &mut bob).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "Online: alice2 (alice), bob"
+        );
+    }
+
+    #[tokio::test]
+    async fn nick_of_another_connected_user_is_rejected() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let nick = Message::new(MessagePayload::Nick("bobby".into()));
+        Message::send_msg(&nick, &mut bob).await.unwrap();
+        _ = receive_server_info(&mut alice).await;
+        _ = receive_server_info(&mut bob).await;
+
+        for taken in ["bob", "bobby"] {
+            let nick = Message::new(MessagePayload::Nick(taken.into()));
+            Message::send_msg(&nick, &mut alice).await.unwrap();
+            assert_eq!(
+                receive_server_info(&mut alice).await,
+                format!("Nickname {taken} is already used by another user")
+            );
+        }
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn nick_of_registered_user_is_rejected_and_doesnt_get_their_dms() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        drop(server.connect_user("carol").await);
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        for (nick, reason) in [
+            (
+                "carol",
+                "Nickname carol is already used by another user".to_string(),
+            ),
+            (
+                "Carol",
+                "Nickname Carol is already used by another user".to_string(),
+            ),
+            (
+                "a\u{7}b",
+                format!(
+                    "Nickname is not valid. {}.",
+                    super::ServerError::InvalidUsername
+                ),
+            ),
+        ] {
+            let msg = Message::new(MessagePayload::Nick(nick.into()));
+            Message::send_msg(&msg, &mut alice).await.unwrap();
+            assert_eq!(receive_server_info(&mut alice).await, reason);
+        }
+
+        let msg = Message::new(MessagePayload::Nick("al".into()));
+        Message::send_msg(&msg, &mut alice).await.unwrap();
+        assert!(receive_server_info(&mut alice)
+            .await
+            .starts_with("Nickname is not valid."));
+        let msg = Message::new(MessagePayload::Nick("ally".into()));
+        Message::send_msg(&msg, &mut alice).await.unwrap();
+        _ = receive_server_info(&mut alice).await;
+        _ = receive_server_info(&mut bob).await;
+
+        // Private texts go to the username only
+        let dm = Message::new(MessagePayload::DirectMessage {
+            to: "ally".into(),
+            text: "hi".into(),
+        });
+        Message::send_msg(&dm, &mut bob).await.unwrap();
+        assert_eq!(
+            receive_server_info(&mut bob).await,
+            "User ally is offline, the message was not delivered."
+        );
+        assert!(receive_with_timeout(&mut alice).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rename_to_taken_username_is_rejected() {
+        let server = TestServer::spawn(ChatSettings::default()).await;
+        let mut alice = server.connect_user("alice").await;
+        let mut bob = server.connect_user("bob").await;
+        _ = receive_server_info(&mut alice).await;
+
+        let rename = Message::new(MessagePayload::Rename("bob".into()));
+        Message::send_msg(&rename, &mut alice).await.unwrap();
+
+        assert_eq!(
+            receive_server_info(&mut alice).await,
+            "Username bob is already taken"
+        );
+        assert!(receive_with_timeout(&mut bob).await.is_none());
+    }
 }
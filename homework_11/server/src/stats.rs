@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Aggregated statistics of a server run. Used to build a report when the server is shutting down.
+pub struct ServerStats {
+    started_at: Instant,
+    total_connections: AtomicU64,
+    active_connections: AtomicU64,
+    peak_connections: AtomicU64,
+    messages_relayed: AtomicU64,
+}
+
+/// Summary of the server run.
+#[derive(Debug, PartialEq)]
+pub struct ShutdownReport {
+    pub total_connections: u64,
+    pub messages_relayed: u64,
+    pub peak_connections: u64,
+    pub uptime: Duration,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            total_connections: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            peak_connections: AtomicU64::new(0),
+            messages_relayed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        let active = self.active_connections.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_connections.fetch_max(active, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn message_relayed(&self) {
+        self.messages_relayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn report(&self) -> ShutdownReport {
+        ShutdownReport {
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            messages_relayed: self.messages_relayed.load(Ordering::Relaxed),
+            peak_connections: self.peak_connections.load(Ordering::Relaxed),
+            uptime: self.started_at.elapsed(),
+        }
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownReport {
+    /// Writes the report to the tracing output.
+    pub fn log(&self) {
+        tracing::info!(
+            total_connections = self.total_connections,
+            messages_relayed = self.messages_relayed,
+            peak_connections = self.peak_connections,
+            uptime_seconds = self.uptime.as_secs(),
+            "Server shutdown report"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_reflects_simulated_run() {
+        let stats = ServerStats::new();
+
+        stats.connection_opened();
+        stats.connection_opened();
+        stats.message_relayed();
+        stats.connection_closed();
+        stats.connection_opened();
+        stats.connection_opened();
+        stats.message_relayed();
+        stats.message_relayed();
+        stats.connection_closed();
+        stats.connection_closed();
+
+        let report = stats.report();
+
+        assert_eq!(report.total_connections, 4);
+        assert_eq!(report.messages_relayed, 3);
+        assert_eq!(report.peak_connections, 3);
+    }
+}
@@ -0,0 +1,481 @@
+//! Helpers shared by the server tests. Server runs on a random port with an in-memory database.
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use shared::message::{AuthUser, Message, MessagePayload};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::{
+    admin::AdminAction,
+    api::ApiData,
+    bridge::ChatBridge,
+    configuration::ChatSettings,
+    db::{expiry, ChatDb, StorageLimits},
+    lockout::LoginLockout,
+    message_info::{MessageHistory, MessageInfo},
+    server_error::ServerError,
+    startup::run_server,
+    stats::ServerStats,
+    user::{DuplicateUserPolicy, ImportSummary, User, UserInfo},
+};
+
+/// `ChatDb` that keeps everything in memory.
+#[derive(Default)]
+pub struct InMemoryDb {
+    pub users: Mutex<Vec<User>>,
+    pub messages: Mutex<Vec<(Uuid, MessageInfo)>>,
+    /// Expiry of ephemeral messages by the stored message id.
+    pub expires: Mutex<HashMap<Uuid, DateTime<Utc>>>,
+    pub audit: Mutex<Vec<AdminAction>>,
+    /// Checked like by the postgres database, so the servers react to overlong values the same way.
+    pub limits: StorageLimits,
+}
+
+impl InMemoryDb {
+    fn is_expired(&self, id: &Uuid) -> bool {
+        self.expires
+            .lock()
+            .unwrap()
+            .get(id)
+            .is_some_and(|expires_at| *expires_at <= Utc::now())
+    }
+}
+
+#[async_trait]
+impl ChatDb for InMemoryDb {
+    async fn insert_message(&self, message: &Message, user_id: &Uuid) -> Result<(), ServerError> {
+        let username = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|u| u.id == *user_id)
+            .map(|u| u.username.clone())
+            .ok_or(ServerError::StoreMessage)?;
+        let text = MessagePayload::serialize_to_text(&message.data);
+        self.limits.check_message(&text)?;
+
+        let info = MessageInfo {
+            id: Uuid::new_v4(),
+            username,
+            text,
+            timestamp: Utc::now(),
+            room: message.room_name().to_string(),
+        };
+        if let Some(expires_at) = expiry(message.ttl_seconds) {
+            self.expires.lock().unwrap().insert(info.id, expires_at);
+        }
+        self.messages.lock().unwrap().push((*user_id, info));
+        Ok(())
+    }
+
+    async fn get_messages(
+        &self,
+        username: &str,
+        room: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<MessageInfo>, ServerError> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .iter()
+            .rev()
+            .filter(|(_, m)| m.username.starts_with(username) && !self.is_expired(&m.id))
+            .filter(|(_, m)| room.is_none_or(|room| m.room == room))
+            .take(limit as usize)
+            .map(|(_, m)| MessageInfo {
+                id: m.id,
+                username: m.username.clone(),
+                text: m.text.clone(),
+                timestamp: m.timestamp,
+                room: m.room.clone(),
+            })
+            .collect())
+    }
+
+    /// Edits replace the text, the history is not kept.
+    async fn edit_message(&self, id: &Uuid, text: &str) -> Result<bool, ServerError> {
+        let mut messages = self.messages.lock().unwrap();
+        let Some((_, message)) = messages.iter_mut().find(|(_, m)| m.id == *id) else {
+            return Ok(false);
+        };
+        message.text = text.to_string();
+        Ok(true)
+    }
+
+    async fn get_message_history(&self, id: &Uuid) -> Result<Option<MessageHistory>, ServerError> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .iter()
+            .find(|(_, m)| m.id == *id && !self.is_expired(&m.id))
+            .map(|(_, m)| MessageHistory {
+                current: MessageInfo {
+                    id: m.id,
+                    username: m.username.clone(),
+                    text: m.text.clone(),
+                    timestamp: m.timestamp,
+                    room: m.room.clone(),
+                },
+                edits: Vec::new(),
+            }))
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), ServerError> {
+        self.users.lock().unwrap().push(user.clone());
+        Ok(())
+    }
+
+    async fn get_user(&self, username: &str) -> Result<Option<User>, ServerError> {
+        let users = self.users.lock().unwrap();
+        Ok(users
+            .iter()
+            .find(|u| u.username.to_lowercase() == username.to_lowercase())
+            .cloned())
+    }
+
+    async fn get_users(&self) -> Result<Vec<UserInfo>, ServerError> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().cloned().map(UserInfo::from).collect())
+    }
+
+    async fn export_users(&self) -> Result<Vec<User>, ServerError> {
+        Ok(self.users.lock().unwrap().clone())
+    }
+
+    async fn import_users(
+        &self,
+        users: &[User],
+        on_duplicate: DuplicateUserPolicy,
+    ) -> Result<ImportSummary, ServerError> {
+        let mut existing = self.users.lock().unwrap();
+        let mut summary = ImportSummary::default();
+        for user in users {
+            let id_taken = existing.iter().any(|u| u.id == user.id);
+            let same_name = |u: &&mut User| u.username.eq_ignore_ascii_case(&user.username);
+            match existing.iter_mut().find(same_name) {
+                Some(_) if on_duplicate == DuplicateUserPolicy::Skip => {
+                    summary.skip(&user.username)
+                }
+                Some(current) => {
+                    current.password = user.password.clone();
+                    current.salt = user.salt.clone();
+                    summary.overwritten += 1;
+                }
+                None if id_taken => summary.skip(&user.username),
+                None => {
+                    existing.push(user.clone());
+                    summary.imported += 1;
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    async fn remove_user(&self, id: &Uuid) -> Result<u64, ServerError> {
+        let mut users = self.users.lock().unwrap();
+        let count = users.len();
+        users.retain(|u| u.id != *id);
+        self.messages
+            .lock()
+            .unwrap()
+            .retain(|(user_id, _)| user_id != id);
+        Ok((count - users.len()) as u64)
+    }
+
+    async fn rename_user(&self, id: &Uuid, new_name: &str) -> Result<Option<String>, ServerError> {
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|u| u.username == new_name && u.id != *id) {
+            return Err(ServerError::UsernameTaken(new_name.to_string()));
+        }
+        let Some(user) = users.iter_mut().find(|u| u.id == *id) else {
+            return Ok(None);
+        };
+        let previous = std::mem::replace(&mut user.username, new_name.to_string());
+        for (_, message) in self.messages.lock().unwrap().iter_mut() {
+            if message.username == previous {
+                message.username = new_name.to_string();
+            }
+        }
+        Ok(Some(previous))
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    async fn record_admin_action(
+        &self,
+        admin_id: &Uuid,
+        action: &str,
+        target: &str,
+        details: Option<&str>,
+    ) -> Result<(), ServerError> {
+        self.audit.lock().unwrap().push(AdminAction {
+            id: Uuid::new_v4(),
+            admin_id: *admin_id,
+            action: action.to_string(),
+            target: target.to_string(),
+            details: details.map(str::to_string),
+            timestamp: Utc::now(),
+        });
+        Ok(())
+    }
+
+    async fn get_admin_actions(&self) -> Result<Vec<AdminAction>, ServerError> {
+        Ok(self.audit.lock().unwrap().iter().rev().cloned().collect())
+    }
+}
+
+/// `ChatDb` whose every call fails as if the database was down. Counts the calls.
+#[derive(Default)]
+pub struct UnreachableDb {
+    pub calls: Arc<AtomicUsize>,
+}
+
+impl UnreachableDb {
+    fn fail<T>(&self) -> Result<T, ServerError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Err(ServerError::DatabaseUnreachable)
+    }
+}
+
+#[async_trait]
+impl ChatDb for UnreachableDb {
+    async fn insert_message(&self, _: &Message, _: &Uuid) -> Result<(), ServerError> {
+        self.fail()
+    }
+
+    async fn get_messages(
+        &self,
+        _: &str,
+        _: Option<&str>,
+        _: u32,
+    ) -> Result<Vec<MessageInfo>, ServerError> {
+        self.fail()
+    }
+
+    async fn edit_message(&self, _: &Uuid, _: &str) -> Result<bool, ServerError> {
+        self.fail()
+    }
+
+    async fn get_message_history(&self, _: &Uuid) -> Result<Option<MessageHistory>, ServerError> {
+        self.fail()
+    }
+
+    async fn insert_user(&self, _: &User) -> Result<(), ServerError> {
+        self.fail()
+    }
+
+    async fn get_user(&self, _: &str) -> Result<Option<User>, ServerError> {
+        self.fail()
+    }
+
+    async fn get_users(&self) -> Result<Vec<UserInfo>, ServerError> {
+        self.fail()
+    }
+
+    async fn export_users(&self) -> Result<Vec<User>, ServerError> {
+        self.fail()
+    }
+
+    async fn import_users(
+        &self,
+        _: &[User],
+        _: DuplicateUserPolicy,
+    ) -> Result<ImportSummary, ServerError> {
+        self.fail()
+    }
+
+    async fn remove_user(&self, _: &Uuid) -> Result<u64, ServerError> {
+        self.fail()
+    }
+
+    async fn rename_user(&self, _: &Uuid, _: &str) -> Result<Option<String>, ServerError> {
+        self.fail()
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        self.fail()
+    }
+
+    async fn record_admin_action(
+        &self,
+        _: &Uuid,
+        _: &str,
+        _: &str,
+        _: Option<&str>,
+    ) -> Result<(), ServerError> {
+        self.fail()
+    }
+
+    async fn get_admin_actions(&self) -> Result<Vec<AdminAction>, ServerError> {
+        self.fail()
+    }
+}
+
+/// Chat server running in a background task.
+pub struct TestServer {
+    pub address: SocketAddr,
+    pub db: Arc<InMemoryDb>,
+    pub bridge: Arc<ChatBridge>,
+    pub lockout: Arc<LoginLockout>,
+    settings: ChatSettings,
+    shutdown: Arc<Notify>,
+    task: JoinHandle<Result<(), ServerError>>,
+}
+
+impl TestServer {
+    pub async fn spawn(settings: ChatSettings) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let db = Arc::new(InMemoryDb::default());
+        let stats = Arc::new(ServerStats::new());
+        let bridge = Arc::new(ChatBridge::from_settings(&settings));
+        let lockout = Arc::new(LoginLockout::from_settings(&settings));
+        let shutdown = Arc::new(Notify::new());
+
+        let task = tokio::spawn(run_server(
+            listener,
+            db.clone(),
+            settings.clone(),
+            stats,
+            bridge.clone(),
+            lockout.clone(),
+            {
+                let shutdown = shutdown.clone();
+                async move { shutdown.notified().await }
+            },
+        ));
+
+        Self {
+            address,
+            db,
+            bridge,
+            lockout,
+            settings,
+            shutdown,
+            task,
+        }
+    }
+
+    /// Api over the database, the bridge and the lockout of the server, as it runs next to the chat server.
+    /// Mount it with `App::new().configure(|cfg| api.configure(cfg))`.
+    pub fn api(&self) -> ApiData<InMemoryDb> {
+        ApiData::new(
+            self.db.clone(),
+            &self.settings,
+            self.bridge.clone(),
+            self.lockout.clone(),
+        )
+    }
+
+    /// Shuts the server down as on Ctrl-C and waits until it stops.
+    pub async fn shutdown(self) -> Result<(), ServerError> {
+        self.shutdown.notify_one();
+        self.task.await.unwrap()
+    }
+
+    /// Connects a new client and logs it in. Returns the stream after the initial server messages were read.
+    pub async fn connect_user(&self, name: &str) -> TcpStream {
+        self.connect_user_with_token(name).await.0
+    }
+
+    /// Same as `connect_user` but also returns the session token from the login response.
+    pub async fn connect_user_with_token(&self, name: &str) -> (TcpStream, Uuid) {
+        let mut stream = TcpStream::connect(self.address).await.unwrap();
+        let response = login(&mut stream, name, "password").await;
+        let MessagePayload::LoginResponse(auth) = response.data else {
+            panic!("Expected login response, got {:?}", response.data);
+        };
+        assert!(auth.is_success());
+        // active users message
+        Message::receive_msg(&mut stream).await.unwrap();
+        (stream, auth.session_token().unwrap())
+    }
+}
+
+/// Sends the login message and returns the response from the server.
+pub async fn login(stream: &mut TcpStream, name: &str, password: &str) -> Message {
+    Message::handshake(stream, AuthUser::new(name, password))
+        .await
+        .unwrap()
+}
+
+/// Receives the next message and returns its text. Panics if it is not a server info message or nothing arrives in time.
+pub async fn receive_server_info(stream: &mut TcpStream) -> String {
+    let msg = receive_with_timeout(stream)
+        .await
+        .expect("No message received");
+    match msg.data {
+        MessagePayload::ServerInfo(text) => text,
+        other => panic!("Expected server info, got {:?}", other),
+    }
+}
+
+/// Receives the next message. Returns None if nothing arrives in a short time.
+pub async fn receive_with_timeout(stream: &mut TcpStream) -> Option<Message> {
+    tokio::time::timeout(Duration::from_millis(500), Message::receive_msg(stream))
+        .await
+        .ok()
+        .map(|msg| msg.unwrap())
+}
+
+/// Request received by the webhook from `spawn_webhook`.
+pub struct WebhookRequest {
+    pub body: serde_json::Value,
+    pub raw_body: Vec<u8>,
+    pub signature: Option<String>,
+}
+
+/// Runs an HTTP server that accepts webhook posts on the returned url. The first `failures` requests are answered
+/// with an error, the rest are sent to the receiver.
+pub fn spawn_webhook(failures: usize) -> (String, mpsc::UnboundedReceiver<WebhookRequest>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let failures = Arc::new(AtomicUsize::new(failures));
+
+    let server = HttpServer::new(move || {
+        let sender = sender.clone();
+        let failures = failures.clone();
+        App::new().route(
+            "/hook",
+            web::post().to(move |request: HttpRequest, body: web::Bytes| {
+                let sender = sender.clone();
+                let failures = failures.clone();
+                async move {
+                    let failing = failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    if failing {
+                        return HttpResponse::ServiceUnavailable().finish();
+                    }
+                    let signature = request
+                        .headers()
+                        .get("X-Webhook-Signature")
+                        .map(|value| value.to_str().unwrap().to_string());
+                    _ = sender.send(WebhookRequest {
+                        body: serde_json::from_slice(&body).unwrap(),
+                        raw_body: body.to_vec(),
+                        signature,
+                    });
+                    HttpResponse::Ok().finish()
+                }
+            }),
+        )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    tokio::spawn(server);
+
+    (url, receiver)
+}
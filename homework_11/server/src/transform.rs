@@ -0,0 +1,222 @@
+use shared::message::{Message, MessagePayload};
+
+use crate::attachment::{AttachmentCheck, AttachmentPolicy};
+use crate::bandwidth::BandwidthMeter;
+use crate::configuration::ChatSettings;
+use crate::rate_limit::MessageRateLimit;
+
+/// Outcome of a transform applied to a received message.
+#[derive(Debug)]
+pub enum TransformResult {
+    /// The message, possibly changed, continues to the next transform.
+    Continue(Message),
+    /// The message is silently dropped.
+    Drop,
+    /// The message is dropped and the sender is told why.
+    Reject(String),
+}
+
+/// Step of the server-side handling of received messages. Transforms are applied per connection, so they can keep state.
+pub trait MessageTransform: Send {
+    fn apply(&mut self, message: Message) -> TransformResult;
+}
+
+/// Transforms applied in order to every message received from a client.
+#[derive(Default)]
+pub struct Pipeline {
+    transforms: Vec<Box<dyn MessageTransform>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the pipeline for a connection from the chat settings.
+    pub fn from_settings(settings: &ChatSettings) -> Self {
+        let mut pipeline = Self::new();
+        if settings.trim_text {
+            pipeline = pipeline.with(TrimText);
+        }
+        pipeline
+            .with(MessageRateLimit::new(
+                settings.max_messages_per_second,
+                settings.message_burst,
+            ))
+            .with(BandwidthMeter::from_settings(settings))
+            .with(AttachmentPolicy::new(&settings.allowed_attachment_types))
+    }
+
+    pub fn with(mut self, transform: impl MessageTransform + 'static) -> Self {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    /// Applies the transforms until one of them drops or rejects the message.
+    pub fn apply(&mut self, mut message: Message) -> TransformResult {
+        for transform in self.transforms.iter_mut() {
+            match transform.apply(message) {
+                TransformResult::Continue(next) => message = next,
+                result => return result,
+            }
+        }
+        TransformResult::Continue(message)
+    }
+}
+
+/// Trims whitespace around text messages, messages without any text are dropped.
+pub struct TrimText;
+
+impl MessageTransform for TrimText {
+    fn apply(&mut self, mut message: Message) -> TransformResult {
+        if let MessagePayload::Text(text) = &mut message.data {
+            let trimmed = text.trim();
+            if trimmed.is_empty() {
+                return TransformResult::Drop;
+            }
+            *text = trimmed.to_string();
+        }
+        TransformResult::Continue(message)
+    }
+}
+
+impl MessageTransform for BandwidthMeter {
+    fn apply(&mut self, message: Message) -> TransformResult {
+        let size = message.data.size();
+        let consumed = match message.data {
+            MessagePayload::FileChunk { .. } => self.try_consume_chunk(size),
+            _ => self.try_consume(size),
+        };
+        if !consumed {
+            return TransformResult::Reject(
+                "You have exceeded the data limit. Try again later or send a smaller message."
+                    .to_string(),
+            );
+        }
+        TransformResult::Continue(message)
+    }
+}
+
+/// Messages written by the user count, and requests that are sent to everyone or load the database.
+/// Typing indicators, replies to key announcements and file chunks are sent by the client on its own,
+/// chunks are limited by the bandwidth instead.
+impl MessageTransform for MessageRateLimit {
+    fn apply(&mut self, message: Message) -> TransformResult {
+        let counted = matches!(
+            message.data,
+            MessagePayload::Text(_)
+                | MessagePayload::Image(_)
+                | MessagePayload::File(_, _)
+                | MessagePayload::DirectMessage { .. }
+                | MessagePayload::Nick(_)
+                | MessagePayload::Rename(_)
+                | MessagePayload::HistoryRequest(_)
+                | MessagePayload::StatusRequest
+                | MessagePayload::KeyAnnounce { reply: true, .. }
+        );
+        if counted && !self.try_send() {
+            return TransformResult::Reject("You are being rate limited".to_string());
+        }
+        TransformResult::Continue(message)
+    }
+}
+
+impl MessageTransform for AttachmentPolicy {
+    fn apply(&mut self, message: Message) -> TransformResult {
+        match self.check(&message.data) {
+            AttachmentCheck::Allowed => TransformResult::Continue(message),
+            AttachmentCheck::Denied => {
+                TransformResult::Reject("This type of attachment is not allowed.".to_string())
+            }
+            AttachmentCheck::UnknownTransfer => TransformResult::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn pipeline() -> Pipeline {
+        Pipeline::new()
+            .with(TrimText)
+            .with(AttachmentPolicy::new(&["png".to_string()]))
+    }
+
+    #[test]
+    fn transforms_are_applied_in_order() {
+        let mut pipeline = pipeline();
+
+        let result = pipeline.apply(Message::new(MessagePayload::Text("  hello ".into())));
+        assert!(
+            matches!(result, TransformResult::Continue(msg) if msg.data == MessagePayload::Text("hello".into()))
+        );
+
+        let result = pipeline.apply(Message::new(MessagePayload::Text("   ".into())));
+        assert!(matches!(result, TransformResult::Drop));
+
+        let pdf = MessagePayload::File("a.png".into(), b"%PDF-1.4".to_vec());
+        let result = pipeline.apply(Message::new(pdf));
+        assert!(matches!(result, TransformResult::Reject(_)));
+    }
+
+    #[test]
+    fn requests_to_everyone_or_the_database_are_rate_limited() {
+        let counted = || {
+            [
+                MessagePayload::Nick("ally".into()),
+                MessagePayload::Rename("alicia".into()),
+                MessagePayload::HistoryRequest(10),
+                MessagePayload::StatusRequest,
+                MessagePayload::KeyAnnounce {
+                    public_key: [1; 32],
+                    reply: true,
+                },
+            ]
+        };
+        for payload in counted() {
+            let mut limit = MessageRateLimit::new(Some(0.001), 1);
+            assert!(matches!(
+                limit.apply(Message::new(MessagePayload::Text("hi".into()))),
+                TransformResult::Continue(_)
+            ));
+            assert!(matches!(
+                limit.apply(Message::new(payload)),
+                TransformResult::Reject(_)
+            ));
+        }
+
+        let mut limit = MessageRateLimit::new(Some(0.001), 1);
+        limit.apply(Message::new(MessagePayload::Text("hi".into())));
+        let reply = MessagePayload::KeyAnnounce {
+            public_key: [1; 32],
+            reply: false,
+        };
+        assert!(matches!(
+            limit.apply(Message::new(reply)),
+            TransformResult::Continue(_)
+        ));
+    }
+
+    #[test]
+    fn small_file_chunks_are_limited_by_bandwidth() {
+        let mut meter = BandwidthMeter::new(Some(1_000), 1024);
+        let chunk = || MessagePayload::FileChunk {
+            transfer_id: Uuid::new_v4(),
+            name: "big.bin".into(),
+            seq: 0,
+            total: 2,
+            data: vec![0; 600],
+        };
+
+        assert!(matches!(
+            meter.apply(Message::new(chunk())),
+            TransformResult::Continue(_)
+        ));
+        assert!(matches!(
+            meter.apply(Message::new(chunk())),
+            TransformResult::Reject(_)
+        ));
+    }
+}
@@ -6,13 +6,13 @@ use ring::{
     rand::{SecureRandom, SystemRandom},
 };
 use secrecy::{ExposeSecret, Secret};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use shared::message::AuthUser;
 use uuid::Uuid;
 
 use crate::server_error::ServerError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
@@ -23,6 +23,24 @@ pub struct User {
 const CREDENTIAL_LEN: usize = digest::SHA512_OUTPUT_LEN;
 const N_ITER: Option<NonZeroU32> = NonZeroU32::new(100_000);
 
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+/// Checks the name of a new user and returns it without the surrounding whitespace. Only ASCII letters, digits,
+/// underscores and dashes are allowed, so nobody can register a name that only looks like another one.
+/// The case is kept for display, uniqueness and logins ignore it.
+pub fn validate_username(name: &str) -> Result<String, ServerError> {
+    let name = name.trim();
+    let valid_length = (MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&name.len());
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_length || !valid_chars {
+        return Err(ServerError::InvalidUsername);
+    }
+    Ok(name.to_string())
+}
+
 impl User {
     pub fn verify_user_password(&self, password_to_verify: &[u8]) -> Result<bool, ServerError> {
         let decoded_salt = general_purpose::STANDARD
@@ -82,6 +100,77 @@ impl TryFrom<AuthUser> for User {
     }
 }
 
+/// User account in an export. Password hash and salt are included only in a full backup,
+/// only such exports can be imported back.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportedUser {
+    pub id: Uuid,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+}
+
+impl ExportedUser {
+    pub fn new(user: User, include_passwords: bool) -> Self {
+        let (password, salt) = match include_passwords {
+            true => (Some(user.password.expose_secret().clone()), Some(user.salt)),
+            false => (None, None),
+        };
+        Self {
+            id: user.id,
+            username: user.username,
+            password,
+            salt,
+        }
+    }
+}
+
+impl TryFrom<ExportedUser> for User {
+    type Error = ServerError;
+
+    fn try_from(value: ExportedUser) -> Result<Self, Self::Error> {
+        let (Some(password), Some(salt)) = (value.password, value.salt) else {
+            return Err(ServerError::MissingPassword(value.username));
+        };
+        Ok(Self {
+            id: value.id,
+            username: value.username,
+            password: Secret::from(password),
+            salt,
+        })
+    }
+}
+
+/// What happens to an imported user whose username already exists.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateUserPolicy {
+    /// The existing user is kept.
+    #[default]
+    Skip,
+    /// The existing user gets the imported password, its id stays so its messages are kept.
+    Overwrite,
+}
+
+/// How many users the import inserted, overwrote and skipped.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+    /// Usernames of the skipped users. They already exist, their id is taken or the name is not valid.
+    pub skipped_users: Vec<String>,
+}
+
+impl ImportSummary {
+    pub fn skip(&mut self, username: &str) {
+        self.skipped += 1;
+        self.skipped_users.push(username.to_string());
+    }
+}
+
 #[derive(Serialize)]
 pub struct UserInfo {
     pub id: Uuid,
@@ -96,3 +185,43 @@ impl From<User> for UserInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_usernames_are_trimmed() {
+        for (name, expected) in [
+            ("alice", "alice"),
+            ("  Bob_42 ", "Bob_42"),
+            ("big-cat", "big-cat"),
+            (
+                &"a".repeat(MAX_USERNAME_LENGTH),
+                &"a".repeat(MAX_USERNAME_LENGTH),
+            ),
+        ] {
+            assert_eq!(validate_username(name).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_usernames_are_rejected() {
+        for name in [
+            "",
+            "   ",
+            "ab",
+            &"a".repeat(MAX_USERNAME_LENGTH + 1),
+            "al ice",
+            "alice\u{200b}",
+            "bo\u{7}b",
+            "\u{0430}lice",
+            "eve!",
+        ] {
+            assert!(
+                matches!(validate_username(name), Err(ServerError::InvalidUsername)),
+                "{name:?} was accepted"
+            );
+        }
+    }
+}
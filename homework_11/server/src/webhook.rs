@@ -0,0 +1,163 @@
+use base64::{engine::general_purpose, Engine};
+use chrono::Utc;
+use ring::hmac;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::configuration::WebhookSettings;
+
+/// Presence change posted to the webhook.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PresenceEvent {
+    pub event: PresenceKind,
+    pub username: String,
+    pub timestamp: i64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceKind {
+    Join,
+    Leave,
+}
+
+impl PresenceEvent {
+    pub fn new(event: PresenceKind, username: &str) -> Self {
+        Self {
+            event,
+            username: username.to_string(),
+            timestamp: Utc::now().timestamp(),
+        }
+    }
+}
+
+/// Posts presence events to the webhook from a background task. Events wait in a bounded queue,
+/// so a slow webhook never blocks the chat, it only loses events when the queue is full.
+pub struct PresenceWebhook {
+    sender: mpsc::Sender<PresenceEvent>,
+}
+
+impl PresenceWebhook {
+    /// Starts the delivery task, it has to be called inside the tokio runtime.
+    pub fn spawn(settings: WebhookSettings) -> Self {
+        let (sender, receiver) = mpsc::channel(settings.queue_size.max(1));
+        tokio::spawn(deliver_events(settings, receiver));
+        Self { sender }
+    }
+
+    pub fn notify(&self, event: PresenceEvent) {
+        if self.sender.try_send(event).is_err() {
+            tracing::warn!("Presence webhook queue is full, dropping the event.");
+        }
+    }
+}
+
+async fn deliver_events(settings: WebhookSettings, mut receiver: mpsc::Receiver<PresenceEvent>) {
+    // Without a timeout a webhook that never answers would hold the delivery of every later event.
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_millis(settings.timeout_ms))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!(
+                "Failed to create the presence webhook client, events are not posted. {e}"
+            );
+            return;
+        }
+    };
+    let key = settings
+        .secret
+        .as_ref()
+        .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.expose_secret().as_bytes()));
+
+    while let Some(event) = receiver.recv().await {
+        let Ok(body) = serde_json::to_vec(&event) else {
+            tracing::error!("Failed to serialize presence event {:?}", event);
+            continue;
+        };
+        let mut delay = Duration::from_millis(settings.retry_delay_ms);
+        for attempt in 0..=settings.max_retries {
+            let mut request = client
+                .post(&settings.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(key) = &key {
+                let signature = hmac::sign(key, &body);
+                request = request.header(
+                    "X-Webhook-Signature",
+                    general_purpose::STANDARD.encode(signature.as_ref()),
+                );
+            }
+
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => break,
+                Err(e) if attempt < settings.max_retries => {
+                    tracing::debug!("Presence webhook failed, retrying in {:?}. {e}", delay);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => tracing::warn!("Presence webhook failed, dropping the event. {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::spawn_webhook;
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried() {
+        let (url, mut received) = spawn_webhook(1);
+        let webhook = PresenceWebhook::spawn(WebhookSettings {
+            url,
+            secret: Some("secret".to_string().into()),
+            max_retries: 2,
+            retry_delay_ms: 10,
+            queue_size: 10,
+            timeout_ms: 5000,
+        });
+
+        webhook.notify(PresenceEvent::new(PresenceKind::Leave, "alice"));
+
+        let request = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body["event"], "leave");
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let signature = general_purpose::STANDARD
+            .decode(request.signature.unwrap())
+            .unwrap();
+        assert!(hmac::verify(&key, &request.raw_body, &signature).is_ok());
+    }
+
+    #[tokio::test]
+    async fn delivery_that_does_not_answer_is_retried_after_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook = PresenceWebhook::spawn(WebhookSettings {
+            url: format!("http://{}/hook", listener.local_addr().unwrap()),
+            secret: None,
+            max_retries: 1,
+            retry_delay_ms: 10,
+            queue_size: 10,
+            timeout_ms: 100,
+        });
+
+        webhook.notify(PresenceEvent::new(PresenceKind::Join, "alice"));
+
+        // Connections are accepted and never answered, the retry opens a second one
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let (connection, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            connections.push(connection);
+        }
+    }
+}
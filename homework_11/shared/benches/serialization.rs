@@ -0,0 +1,97 @@
+//! Compares serialization of messages with bincode (the wire format), JSON and the text stored in the database.
+//! Run with `cargo bench -p shared`.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde::{Deserialize, Serialize};
+use shared::message::{Message, MessagePayload, Priority};
+use uuid::Uuid;
+
+/// Fields of `Message` with the payload as JSON. `Message` encodes the payload as a bincode blob,
+/// in JSON it would be only an array of the blob bytes.
+#[derive(Serialize, Deserialize)]
+struct JsonMessage<P> {
+    id: Uuid,
+    sender: Option<String>,
+    timestamp: i64,
+    priority: Priority,
+    data: P,
+    ttl_seconds: Option<u64>,
+    room: Option<String>,
+}
+
+impl JsonMessage<&MessagePayload> {
+    fn new(message: &Message) -> JsonMessage<&MessagePayload> {
+        JsonMessage {
+            id: message.id,
+            sender: message.sender.clone(),
+            timestamp: message.timestamp,
+            priority: message.priority,
+            data: &message.data,
+            ttl_seconds: message.ttl_seconds,
+            room: message.room.clone(),
+        }
+    }
+}
+
+fn payloads() -> Vec<(&'static str, MessagePayload)> {
+    vec![
+        (
+            "short_text",
+            MessagePayload::Text("Hello, how are you?".to_string()),
+        ),
+        (
+            "long_text",
+            MessagePayload::Text("Lorem ipsum dolor sit amet. ".repeat(400)),
+        ),
+        (
+            "small_file",
+            MessagePayload::File("notes.txt".to_string(), vec![7; 4 * 1024]),
+        ),
+        (
+            "large_file",
+            MessagePayload::File("photo.png".to_string(), vec![7; 1024 * 1024]),
+        ),
+    ]
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for (name, payload) in payloads() {
+        let message = Message::new(payload);
+        group.throughput(Throughput::Bytes(message.data.size() as u64));
+
+        group.bench_with_input(BenchmarkId::new("bincode", name), &message, |b, m| {
+            b.iter(|| bincode::serialize(black_box(m)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("json", name), &message, |b, m| {
+            b.iter(|| serde_json::to_vec(&JsonMessage::new(black_box(m))).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("text", name), &message, |b, m| {
+            b.iter(|| MessagePayload::serialize_to_text(black_box(&m.data)))
+        });
+    }
+    group.finish();
+}
+
+/// The stored text can't be turned back into a message, so only bincode and JSON are compared here.
+fn deserialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deserialize");
+    for (name, payload) in payloads() {
+        let message = Message::new(payload);
+        group.throughput(Throughput::Bytes(message.data.size() as u64));
+
+        let bytes = bincode::serialize(&message).unwrap();
+        group.bench_with_input(BenchmarkId::new("bincode", name), &bytes, |b, bytes| {
+            b.iter(|| bincode::deserialize::<Message>(black_box(bytes)).unwrap())
+        });
+        let json = serde_json::to_vec(&JsonMessage::new(&message)).unwrap();
+        group.bench_with_input(BenchmarkId::new("json", name), &json, |b, json| {
+            b.iter(|| {
+                serde_json::from_slice::<JsonMessage<MessagePayload>>(black_box(json)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize, deserialize);
+criterion_main!(benches);
@@ -0,0 +1,215 @@
+use crate::errors::MessageError;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Compression algorithm of a frame. It is encoded in the flag byte of every frame,
+/// so the receiver can decompress the frame no matter what was negotiated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Algorithms this build can compress and decompress.
+pub const SUPPORTED_ALGORITHMS: &[Algorithm] = &[Algorithm::Zstd, Algorithm::Gzip, Algorithm::None];
+
+impl Algorithm {
+    pub fn flag(self) -> u8 {
+        match self {
+            Algorithm::None => 0,
+            Algorithm::Gzip => 1,
+            Algorithm::Zstd => 2,
+        }
+    }
+
+    pub fn from_flag(flag: u8) -> Result<Self, MessageError> {
+        match flag {
+            0 => Ok(Algorithm::None),
+            1 => Ok(Algorithm::Gzip),
+            2 => Ok(Algorithm::Zstd),
+            _ => Err(MessageError::UnknownCompression(flag)),
+        }
+    }
+
+    /// Picks the algorithm both sides support. The preferred algorithm is used if the peer supports it,
+    /// otherwise the first algorithm from the peer's list that is supported here. Falls back to no compression.
+    pub fn negotiate(preferred: Algorithm, peer_algorithms: &[Algorithm]) -> Algorithm {
+        if preferred == Algorithm::None {
+            return Algorithm::None;
+        }
+        if peer_algorithms.contains(&preferred) {
+            return preferred;
+        }
+        peer_algorithms
+            .iter()
+            .copied()
+            .find(|algorithm| SUPPORTED_ALGORITHMS.contains(algorithm))
+            .unwrap_or(Algorithm::None)
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Algorithm::None),
+            "gzip" => Ok(Algorithm::Gzip),
+            "zstd" => Ok(Algorithm::Zstd),
+            _ => Err(format!("Unknown compression algorithm {s}")),
+        }
+    }
+}
+
+/// Compression used when sending frames.
+/// level: zstd accepts 1-22, gzip 0-9 (higher values are clamped).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    pub level: i32,
+}
+
+impl Compression {
+    pub const NONE: Compression = Compression {
+        algorithm: Algorithm::None,
+        level: 0,
+    };
+
+    pub fn new(algorithm: Algorithm, level: i32) -> Self {
+        Self { algorithm, level }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, MessageError> {
+        match self.algorithm {
+            Algorithm::None => Ok(data.to_vec()),
+            Algorithm::Gzip => {
+                let level = flate2::Compression::new(self.level.clamp(0, 9) as u32);
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder
+                    .write_all(data)
+                    .map_err(MessageError::CompressionError)?;
+                encoder.finish().map_err(MessageError::CompressionError)
+            }
+            Algorithm::Zstd => {
+                zstd::encode_all(data, self.level).map_err(MessageError::CompressionError)
+            }
+        }
+    }
+}
+
+/// How the compression of frames is signalled on a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// Every frame has a flag byte with its compression algorithm.
+    PerMessage(Compression),
+    /// Compression was agreed for the whole connection at login, so frames don't have the flag byte.
+    /// Frames that are not compressed with the agreed algorithm are exceptions,
+    /// they have the highest bit of the length set and the flag byte follows the length.
+    Connection(Compression),
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::PerMessage(Compression::NONE)
+    }
+}
+
+impl Framing {
+    /// Connection compression is used only if both sides agreed on it and on an algorithm.
+    pub fn negotiated(compression: Compression, connection_compression: bool) -> Self {
+        match connection_compression && compression.algorithm != Algorithm::None {
+            true => Framing::Connection(compression),
+            false => Framing::PerMessage(compression),
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self {
+            Framing::PerMessage(compression) | Framing::Connection(compression) => *compression,
+        }
+    }
+}
+
+/// Decompresses the frame. Output larger than `limit` is rejected as soon as it is reached,
+/// so a small frame can't expand to gigabytes.
+pub fn decompress(
+    algorithm: Algorithm,
+    data: &[u8],
+    limit: usize,
+) -> Result<Vec<u8>, MessageError> {
+    let mut decompressed = Vec::new();
+    let read_limit = limit as u64 + 1;
+    match algorithm {
+        Algorithm::None => decompressed.extend_from_slice(data),
+        Algorithm::Gzip => {
+            GzDecoder::new(data)
+                .take(read_limit)
+                .read_to_end(&mut decompressed)
+                .map_err(MessageError::CompressionError)?;
+        }
+        Algorithm::Zstd => {
+            zstd::stream::read::Decoder::with_buffer(data)
+                .map_err(MessageError::CompressionError)?
+                .take(read_limit)
+                .read_to_end(&mut decompressed)
+                .map_err(MessageError::CompressionError)?;
+        }
+    }
+    if decompressed.len() > limit {
+        return Err(MessageError::DecompressedTooLarge { limit });
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_data_is_decompressed() {
+        let data = b"hello hello hello hello hello".repeat(10);
+
+        for algorithm in SUPPORTED_ALGORITHMS {
+            let compressed = Compression::new(*algorithm, 3).compress(&data).unwrap();
+            assert_eq!(
+                decompress(*algorithm, &compressed, data.len()).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn decompressed_data_over_limit_is_rejected() {
+        let data = vec![0u8; 1024 * 1024];
+
+        for algorithm in SUPPORTED_ALGORITHMS {
+            let compressed = Compression::new(*algorithm, 3).compress(&data).unwrap();
+            assert!(matches!(
+                decompress(*algorithm, &compressed, 1024),
+                Err(MessageError::DecompressedTooLarge { limit: 1024 })
+            ));
+        }
+    }
+
+    #[test]
+    fn unsupported_algorithm_falls_back_to_none() {
+        assert_eq!(
+            Algorithm::negotiate(Algorithm::Zstd, &[Algorithm::Gzip]),
+            Algorithm::Gzip
+        );
+        assert_eq!(Algorithm::negotiate(Algorithm::Zstd, &[]), Algorithm::None);
+        assert_eq!(
+            Algorithm::negotiate(Algorithm::None, &[Algorithm::Zstd]),
+            Algorithm::None
+        );
+        assert!(matches!(
+            Algorithm::from_flag(42),
+            Err(MessageError::UnknownCompression(42))
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::compression::Algorithm;
 use bincode::Error as BincodeError;
 use thiserror::Error;
 
@@ -11,6 +12,42 @@ pub enum MessageError {
     SendError(#[source] std::io::Error),
     #[error("Failed to receive message. {0}")]
     RecieveError(#[source] std::io::Error),
+    #[error("Failed to compress or decompress message. {0}")]
+    CompressionError(#[source] std::io::Error),
+    #[error("Unknown compression flag {0}")]
+    UnknownCompression(u8),
+    #[error("Frame of {0} bytes is too large to be sent")]
+    FrameTooLarge(usize),
+    #[error("Received message has {size} bytes, at most {limit} bytes are accepted")]
+    MessageTooLarge { size: usize, limit: usize },
+    #[error("Received message decompresses to more than {limit} bytes")]
+    DecompressedTooLarge { limit: usize },
+    #[error("Received message is compressed with {0:?}, which was not negotiated")]
+    UnexpectedCompression(Algorithm),
+}
+
+impl MessageError {
+    /// Whether the error happened before anything was written, so the stream can still be used for other messages.
+    pub fn is_serialization(&self) -> bool {
+        matches!(
+            self,
+            MessageError::SerializeError(_)
+                | MessageError::CompressionError(_)
+                | MessageError::FrameTooLarge(_)
+        )
+    }
+
+    /// Whether the peer sent a frame that can't be read as a message, as opposed to the connection failing.
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            MessageError::DeserializeError(_)
+                | MessageError::CompressionError(_)
+                | MessageError::UnknownCompression(_)
+                | MessageError::DecompressedTooLarge { .. }
+                | MessageError::UnexpectedCompression(_)
+        )
+    }
 }
 
 #[derive(Debug, Error)]
@@ -19,6 +56,8 @@ pub enum TracingErrors {
     CreateDirError(#[source] std::io::Error),
     #[error("Failed to create a log file. {0}")]
     CreateLogFileError(#[source] std::io::Error),
+    #[error("Failed to create a rolling log file. {0}")]
+    CreateRollingLogError(#[source] tracing_appender::rolling::InitError),
     #[error("Failed to setup tracing for client. {0}")]
     SetupTracingError(String),
 }
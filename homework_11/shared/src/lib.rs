@@ -1,3 +1,4 @@
+pub mod compression;
 pub mod errors;
 pub mod message;
 pub mod tracing;
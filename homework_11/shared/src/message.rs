@@ -1,18 +1,53 @@
+use crate::compression::{self, Algorithm, Compression, Framing};
 use crate::errors::MessageError;
+use bincode::Options;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Version of the message protocol, it changes when the wire format changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Room of the messages that don't say otherwise, every user starts in it.
+pub const DEFAULT_ROOM: &str = "general";
+
+/// Length of the frame body is sent as u32, so larger messages can't be sent.
+pub const MAX_FRAME_SIZE: u64 = u32::MAX as u64;
+
+/// Received messages larger than this are rejected before their buffer is allocated. Larger files are sent in chunks.
+pub const MAX_MESSAGE_SIZE: usize = 50 * 1024 * 1024;
+
+/// With connection compression the highest bit of the length marks frames that carry their own flag byte.
+const EXCEPTION_BIT: u32 = 1 << 31;
 
 /// Main message struct that wraps the data and other metadata fields.
+/// id: unique id of the message, it stays the same on the way from the sender to the receivers
 /// sender: the username of the sender
 /// timestamp: when msg was created, not used at the moment but it will be useful for the frontend
-/// data: the actual payload of the message
+/// priority: messages with higher priority are delivered first when the client has more messages waiting
+/// data: the actual payload of the message, it is encoded so that payload types added later can be recognized as unknown
+/// ttl_seconds: ephemeral messages disappear from the history this many seconds after they were sent
+/// room: chat room of the message, set by the server to the room of the sender. Server messages without a room go to all rooms
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message {
+    pub id: Uuid,
     pub sender: Option<String>,
     pub timestamp: i64,
+    pub priority: Priority,
+    #[serde(with = "payload_codec")]
     pub data: MessagePayload,
+    pub ttl_seconds: Option<u64>,
+    pub room: Option<String>,
+}
+
+/// Delivery priority of the message. System messages (server info) are high, user messages normal.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
 }
 
 impl Message {
@@ -20,28 +55,78 @@ impl Message {
     pub fn new(data: MessagePayload) -> Self {
         let now = Utc::now();
         Message {
+            id: Uuid::new_v4(),
             sender: None,
             timestamp: now.timestamp(),
+            priority: data.priority(),
             data,
+            ttl_seconds: None,
+            room: None,
         }
     }
 
+    /// Makes the message ephemeral.
+    pub fn with_ttl(mut self, ttl_seconds: u64) -> Self {
+        self.ttl_seconds = Some(ttl_seconds);
+        self
+    }
+
+    /// Epoch seconds when the ephemeral message expires, None for messages that don't expire.
+    pub fn expires_at(&self) -> Option<i64> {
+        self.ttl_seconds.map(|ttl| {
+            self.timestamp
+                .saturating_add(ttl.try_into().unwrap_or(i64::MAX))
+        })
+    }
+
+    /// Whether the ephemeral message expired at `now` (epoch seconds).
+    pub fn is_expired_at(&self, now: i64) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
     /// Creates a new server info message with the given text.
     pub fn new_server_msg(text: &str) -> Self {
         let now = Utc::now();
         Message {
+            id: Uuid::new_v4(),
             data: MessagePayload::ServerInfo(text.to_owned()),
             sender: None,
             timestamp: now.timestamp(),
+            priority: Priority::High,
+            ttl_seconds: None,
+            room: None,
         }
     }
 
+    /// Room of the message, `DEFAULT_ROOM` when it has none.
+    pub fn room_name(&self) -> &str {
+        self.room.as_deref().unwrap_or(DEFAULT_ROOM)
+    }
+
     pub fn set_from_user(&mut self, sender: &str) {
         self.sender = Some(sender.to_owned())
     }
 
     fn serialize(message: &Message) -> Result<Vec<u8>, MessageError> {
-        bincode::serialize(message).map_err(MessageError::SerializeError)
+        Message::encoding(MAX_FRAME_SIZE)
+            .serialize(message)
+            .map_err(MessageError::SerializeError)
+    }
+
+    /// Returns the size of the serialized message. Fails the same way as sending would, or if the message is larger than `limit`.
+    pub fn serialized_size(message: &Message, limit: u64) -> Result<u64, MessageError> {
+        Message::encoding(limit.min(MAX_FRAME_SIZE))
+            .serialized_size(message)
+            .map_err(MessageError::SerializeError)
+    }
+
+    /// Same encoding as `bincode::serialize`, with a limit on the size.
+    fn encoding(limit: u64) -> impl Options {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(limit)
     }
 
     fn deserialize(data: &[u8]) -> Result<Message, MessageError> {
@@ -49,16 +134,67 @@ impl Message {
     }
 
     /// Sends the message to the given stream.
-    #[tracing::instrument(name = "Sending message", skip(message, stream))]
     pub async fn send_msg<T>(message: &Message, stream: &mut T) -> Result<(), MessageError>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        Message::send_compressed_msg(message, stream, Compression::NONE).await
+    }
+
+    /// Sends the message to the given stream. The frame is the length of the body, a flag byte with the compression algorithm and the body.
+    pub async fn send_compressed_msg<T>(
+        message: &Message,
+        stream: &mut T,
+        compression: Compression,
+    ) -> Result<(), MessageError>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        Message::send_framed_msg(message, stream, Framing::PerMessage(compression)).await
+    }
+
+    /// Sends the message framed the way the connection agreed on. With connection compression the frame
+    /// is the length and the body, frames that don't get smaller by compressing are sent uncompressed as exceptions.
+    #[tracing::instrument(name = "Sending message", skip(message, stream))]
+    pub async fn send_framed_msg<T>(
+        message: &Message,
+        stream: &mut T,
+        framing: Framing,
+    ) -> Result<(), MessageError>
     where
         T: AsyncWrite + Unpin,
     {
         let serialized = Message::serialize(message)?;
-        let length = serialized.len() as u32;
+        let (serialized, header) = match framing {
+            Framing::PerMessage(compression) => {
+                let serialized = compression.compress(&serialized)?;
+                let mut header = (serialized.len() as u32).to_be_bytes().to_vec();
+                header.push(compression.algorithm.flag());
+                (serialized, header)
+            }
+            Framing::Connection(compression) => {
+                let compressed = compression.compress(&serialized)?;
+                let (body, exception) = match compressed.len() < serialized.len() {
+                    true => (compressed, None),
+                    false => (serialized, Some(Algorithm::None)),
+                };
+                let length = u32::try_from(body.len())
+                    .ok()
+                    .filter(|length| length & EXCEPTION_BIT == 0)
+                    .ok_or(MessageError::FrameTooLarge(body.len()))?;
+                let mut header = match exception {
+                    Some(_) => length | EXCEPTION_BIT,
+                    None => length,
+                }
+                .to_be_bytes()
+                .to_vec();
+                header.extend(exception.map(Algorithm::flag));
+                (body, header)
+            }
+        };
 
-        _ = stream
-            .write(&length.to_be_bytes())
+        stream
+            .write_all(&header)
             .await
             .map_err(MessageError::SendError)?;
 
@@ -74,13 +210,56 @@ impl Message {
     where
         T: AsyncRead + Unpin,
     {
-        let mut len_bytes = [0u8; 4];
+        Message::receive_framed_msg(stream, Framing::default()).await
+    }
+
+    /// Receives a message framed the way the connection agreed on, at most `MAX_MESSAGE_SIZE` bytes long.
+    pub async fn receive_framed_msg<T>(
+        stream: &mut T,
+        framing: Framing,
+    ) -> Result<Message, MessageError>
+    where
+        T: AsyncRead + Unpin,
+    {
+        Message::receive_framed_msg_with_limit(stream, framing, MAX_MESSAGE_SIZE).await
+    }
+
+    /// Receives a message framed the way the connection agreed on. Frames longer than `limit` bytes are rejected
+    /// before their buffer is allocated, so a wrong length can't exhaust the memory. The same limit applies to the
+    /// decompressed message, and frames compressed with another algorithm than the negotiated one are rejected.
+    pub async fn receive_framed_msg_with_limit<T>(
+        stream: &mut T,
+        framing: Framing,
+        limit: usize,
+    ) -> Result<Message, MessageError>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut length = [0u8; 4];
         stream
-            .read_exact(&mut len_bytes)
+            .read_exact(&mut length)
             .await
             .map_err(MessageError::RecieveError)?;
+        let length = u32::from_be_bytes(length);
 
-        let len = u32::from_be_bytes(len_bytes) as usize;
+        let (len, has_flag) = match framing {
+            Framing::PerMessage(_) => (length, true),
+            Framing::Connection(_) => (length & !EXCEPTION_BIT, length & EXCEPTION_BIT != 0),
+        };
+        let algorithm = match has_flag {
+            true => {
+                let flag = stream.read_u8().await.map_err(MessageError::RecieveError)?;
+                Algorithm::from_flag(flag)?
+            }
+            false => framing.compression().algorithm,
+        };
+        if algorithm != Algorithm::None && algorithm != framing.compression().algorithm {
+            return Err(MessageError::UnexpectedCompression(algorithm));
+        }
+        let len = len as usize;
+        if len > limit {
+            return Err(MessageError::MessageTooLarge { size: len, limit });
+        }
 
         let mut buffer = vec![0u8; len];
 
@@ -89,23 +268,11 @@ impl Message {
             .await
             .map_err(MessageError::RecieveError)?;
 
-        let message = Message::deserialize(&buffer)?;
+        let message = Message::deserialize(&compression::decompress(algorithm, &buffer, limit)?)?;
 
         Ok(message)
     }
 
-    pub async fn send_active_users_msg<T>(
-        stream: &mut T,
-        active_users: usize,
-    ) -> Result<(), MessageError>
-    where
-        T: AsyncWrite + Unpin,
-    {
-        let msg = Self::new_server_msg(&format!("Active users: {}", active_users));
-        Message::send_msg(&msg, stream).await?;
-        Ok(())
-    }
-
     pub async fn handshake<T>(stream: &mut T, user: AuthUser) -> Result<Message, MessageError>
     where
         T: AsyncWrite + AsyncRead + Unpin,
@@ -127,19 +294,237 @@ pub enum MessagePayload {
     Image(Vec<u8>),
     File(String, Vec<u8>),
     ServerInfo(String),
+    /// Number of users connected to the server.
+    ActiveUsers(usize),
     Login(AuthUser),
     LoginResponse(AuthPayload),
+    /// Keepalive sent by an idle client, the server doesn't relay it.
+    Ping,
+    /// Part of a large file. Chunks with the same `transfer_id` are joined by the receiver.
+    FileChunk {
+        transfer_id: Uuid,
+        name: String,
+        seq: u32,
+        total: u32,
+        data: Vec<u8>,
+    },
+    /// The sender stopped the transfer, the receiver discards what it got so far.
+    FileCancel {
+        id: Uuid,
+    },
+    /// Asks the server for its status, the server answers with `StatusResponse`.
+    StatusRequest,
+    StatusResponse(ServerStatus),
+    /// Asks the server to change the username of the connected user. Everybody is told about the new name.
+    Rename(String),
+    /// Asks the server for the last messages of the chat, the server answers only to the sender with `History`.
+    HistoryRequest(u32),
+    /// Messages from the history, the oldest first.
+    History(Vec<HistoryEntry>),
+    /// Moves the user to another room, messages are then exchanged only with users in the same room.
+    JoinRoom(String),
+    /// Private message, the server delivers it only to the connections of the user `to` and back to the sender.
+    DirectMessage {
+        to: String,
+        text: String,
+    },
+    /// Public X25519 key of the sender for the end-to-end encryption, it is new for every run of the client.
+    /// Receivers with `reply` set answer with their own key, so a user that joins later gets the keys of the room.
+    KeyAnnounce {
+        public_key: [u8; 32],
+        reply: bool,
+    },
+    /// Asks the server who is connected, the server answers only to the sender with a `ServerInfo`.
+    ListUsers,
+    /// Changes the name shown with the messages of the connection, the account keeps its username.
+    Nick(String),
+    /// The sender is writing a message. It is relayed to the room, but never stored.
+    Typing,
+    /// Payload type added in a newer version of the protocol, with its variant index. It can't be sent.
+    #[serde(skip)]
+    Unknown(u32),
+}
+
+/// Encodes the payload as a length-prefixed blob, so a receiver can skip payload types it doesn't know
+/// instead of failing to read the whole message.
+mod payload_codec {
+    use super::MessagePayload;
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        payload: &MessagePayload,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let blob = bincode::serialize(payload).map_err(ser::Error::custom)?;
+        serializer.serialize_bytes(&blob)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<MessagePayload, D::Error> {
+        let blob = Vec::<u8>::deserialize(deserializer)?;
+        decode(&blob).map_err(de::Error::custom)
+    }
+
+    /// The blob starts with the variant index, indexes past the known variants are decoded as `Unknown`.
+    pub(super) fn decode(blob: &[u8]) -> Result<MessagePayload, bincode::Error> {
+        match blob.get(..4) {
+            Some(index) => {
+                let index = u32::from_le_bytes(index.try_into().expect("slice has 4 bytes"));
+                if index >= MessagePayload::KNOWN_VARIANTS {
+                    return Ok(MessagePayload::Unknown(index));
+                }
+                bincode::deserialize(blob)
+            }
+            None => bincode::deserialize(blob),
+        }
+    }
+}
+
+/// Message from the history of the chat.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HistoryEntry {
+    pub sender: String,
+    pub text: String,
+    /// Epoch seconds when the message was sent.
+    pub timestamp: i64,
+}
+
+/// Status of the server reported to clients.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ServerStatus {
+    pub active_connections: usize,
+    pub uptime_seconds: u64,
+    pub db_reachable: bool,
 }
 
 impl MessagePayload {
+    /// Number of payload types this version knows, `Unknown` excluded. It has to grow with every new variant.
+    pub const KNOWN_VARIANTS: u32 = 21;
+
     pub fn serialize_to_text(data: &MessagePayload) -> String {
         match data {
             MessagePayload::Text(text) => text.to_owned(),
             MessagePayload::Image(_) => "img sent".to_string(),
             MessagePayload::File(name, _) => format!("file sent: {name}"),
             MessagePayload::ServerInfo(_) => "".to_string(),
+            MessagePayload::ActiveUsers(_) => "".to_string(),
             MessagePayload::Login(_) => "".to_string(),
             MessagePayload::LoginResponse(_) => "".to_string(),
+            MessagePayload::Ping => "".to_string(),
+            MessagePayload::FileChunk { name, .. } => format!("file sent: {name}"),
+            MessagePayload::FileCancel { .. } => "".to_string(),
+            MessagePayload::StatusRequest => "".to_string(),
+            MessagePayload::StatusResponse(_) => "".to_string(),
+            MessagePayload::Rename(_) => "".to_string(),
+            MessagePayload::HistoryRequest(_) => "".to_string(),
+            MessagePayload::History(_) => "".to_string(),
+            MessagePayload::JoinRoom(_) => "".to_string(),
+            MessagePayload::DirectMessage { .. } => "".to_string(),
+            MessagePayload::KeyAnnounce { .. } => "".to_string(),
+            MessagePayload::ListUsers => "".to_string(),
+            MessagePayload::Nick(_) => "".to_string(),
+            MessagePayload::Typing => "".to_string(),
+            MessagePayload::Unknown(_) => "".to_string(),
+        }
+    }
+
+    /// Returns false for payloads that are not worth keeping in the message history,
+    /// e.g. only the first chunk of a file is stored.
+    pub fn is_stored(&self) -> bool {
+        match self {
+            MessagePayload::FileChunk { seq, .. } => *seq == 0,
+            MessagePayload::Ping
+            | MessagePayload::ServerInfo(_)
+            | MessagePayload::FileCancel { .. }
+            | MessagePayload::StatusRequest
+            | MessagePayload::StatusResponse(_)
+            | MessagePayload::Rename(_)
+            | MessagePayload::HistoryRequest(_)
+            | MessagePayload::History(_)
+            | MessagePayload::JoinRoom(_)
+            | MessagePayload::DirectMessage { .. }
+            | MessagePayload::KeyAnnounce { .. }
+            | MessagePayload::ListUsers
+            | MessagePayload::Nick(_)
+            | MessagePayload::Typing
+            | MessagePayload::Unknown(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Returns true for payloads that matter only when they are sent, e.g. typing indicators.
+    /// They are not stored and not kept for users that poll or reconnect later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, MessagePayload::Typing)
+    }
+
+    /// Returns the delivery priority for the payload type.
+    pub fn priority(&self) -> Priority {
+        match self {
+            MessagePayload::ServerInfo(_)
+            | MessagePayload::ActiveUsers(_)
+            | MessagePayload::StatusResponse(_) => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+
+    /// Returns the name of the payload type, useful for logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessagePayload::Text(_) => "text",
+            MessagePayload::Image(_) => "image",
+            MessagePayload::File(_, _) => "file",
+            MessagePayload::ServerInfo(_) => "server_info",
+            MessagePayload::ActiveUsers(_) => "active_users",
+            MessagePayload::Login(_) => "login",
+            MessagePayload::LoginResponse(_) => "login_response",
+            MessagePayload::Ping => "ping",
+            MessagePayload::FileChunk { .. } => "file_chunk",
+            MessagePayload::FileCancel { .. } => "file_cancel",
+            MessagePayload::StatusRequest => "status_request",
+            MessagePayload::StatusResponse(_) => "status_response",
+            MessagePayload::Rename(_) => "rename",
+            MessagePayload::HistoryRequest(_) => "history_request",
+            MessagePayload::History(_) => "history",
+            MessagePayload::JoinRoom(_) => "join_room",
+            MessagePayload::DirectMessage { .. } => "direct_message",
+            MessagePayload::KeyAnnounce { .. } => "key_announce",
+            MessagePayload::ListUsers => "list_users",
+            MessagePayload::Nick(_) => "nick",
+            MessagePayload::Typing => "typing",
+            MessagePayload::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Returns the size of the carried data in bytes, without the serialization overhead.
+    pub fn size(&self) -> usize {
+        match self {
+            MessagePayload::Text(text) | MessagePayload::ServerInfo(text) => text.len(),
+            MessagePayload::DirectMessage { to, text } => to.len() + text.len(),
+            MessagePayload::Image(data) => data.len(),
+            MessagePayload::KeyAnnounce { public_key, .. } => public_key.len(),
+            MessagePayload::History(entries) => entries
+                .iter()
+                .map(|entry| entry.sender.len() + entry.text.len())
+                .sum(),
+            MessagePayload::File(name, data) | MessagePayload::FileChunk { name, data, .. } => {
+                name.len() + data.len()
+            }
+            MessagePayload::ActiveUsers(_)
+            | MessagePayload::Login(_)
+            | MessagePayload::LoginResponse(_)
+            | MessagePayload::Ping
+            | MessagePayload::FileCancel { .. }
+            | MessagePayload::StatusRequest
+            | MessagePayload::StatusResponse(_)
+            | MessagePayload::Rename(_)
+            | MessagePayload::HistoryRequest(_)
+            | MessagePayload::JoinRoom(_)
+            | MessagePayload::ListUsers
+            | MessagePayload::Nick(_)
+            | MessagePayload::Typing
+            | MessagePayload::Unknown(_) => 0,
         }
     }
 }
@@ -166,25 +551,109 @@ impl Display for Message {
                 filename
             )?,
             MessagePayload::ServerInfo(text) => writeln!(f, "--      {}      --", text)?,
+            MessagePayload::ActiveUsers(count) => {
+                writeln!(f, "--      Active users: {}      --", count)?
+            }
             MessagePayload::Login(_) => writeln!(f, "Login payload")?, //This won't be ever displayed in the client output
             MessagePayload::LoginResponse(data) => writeln!(f, "{}", data)?,
+            MessagePayload::Ping => {} // Keepalive is never displayed
+            MessagePayload::FileChunk { name, seq: 0, .. } => writeln!(
+                f,
+                "{} is sending a file {}",
+                self.sender.as_ref().unwrap_or(&ANONYMOUS.to_string()),
+                name
+            )?,
+            MessagePayload::FileChunk { .. } => {}
+            MessagePayload::FileCancel { .. } => writeln!(
+                f,
+                "{} cancelled a file transfer",
+                self.sender.as_ref().unwrap_or(&ANONYMOUS.to_string()),
+            )?,
+            MessagePayload::StatusRequest => writeln!(f, "Status request")?, //This won't be ever displayed in the client output
+            MessagePayload::StatusResponse(status) => writeln!(
+                f,
+                "--      Active connections: {}, uptime: {}s, database: {}      --",
+                status.active_connections,
+                status.uptime_seconds,
+                if status.db_reachable {
+                    "reachable"
+                } else {
+                    "unreachable"
+                }
+            )?,
+            MessagePayload::Rename(_) => writeln!(f, "Rename request")?, //This won't be ever displayed in the client output
+            MessagePayload::HistoryRequest(_) => writeln!(f, "History request")?, //This won't be ever displayed in the client output
+            MessagePayload::JoinRoom(_) => writeln!(f, "Join room request")?, //This won't be ever displayed in the client output
+            MessagePayload::ListUsers => writeln!(f, "List users request")?, //This won't be ever displayed in the client output
+            MessagePayload::Nick(_) => writeln!(f, "Nickname request")?, //This won't be ever displayed in the client output
+            MessagePayload::Typing => writeln!(
+                f,
+                "{} is typing...",
+                self.sender.as_ref().unwrap_or(&ANONYMOUS.to_string()),
+            )?,
+            MessagePayload::DirectMessage { to, text } => writeln!(
+                f,
+                "{} -> {} (private): {}",
+                self.sender.as_ref().unwrap_or(&ANONYMOUS.to_string()),
+                to,
+                text
+            )?,
+            MessagePayload::KeyAnnounce { .. } => {} // Keys are handled by the client, never displayed
+            MessagePayload::History(entries) => {
+                writeln!(f, "--      Last {} messages      --", entries.len())?;
+                for entry in entries {
+                    writeln!(f, "(history) {}: {}", entry.sender, entry.text)?;
+                }
+            }
+            MessagePayload::Unknown(_) => {} // Receivers decide whether to report it
         }
         Ok(())
     }
 }
 
+/// Login request.
+/// session_token: token from the previous login, it is sent when the client reconnects.
+/// compression: algorithms the client supports, in the order of preference.
+/// connection_compression: the client can compress the whole connection, so frames don't need the flag byte.
+/// timestamp and nonce: protect against replaying a captured login, every login needs a new nonce.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct AuthUser {
     pub name: String,
     pub password: String,
+    pub session_token: Option<Uuid>,
+    pub compression: Vec<Algorithm>,
+    pub connection_compression: bool,
+    pub timestamp: i64,
+    pub nonce: Uuid,
 }
 impl AuthUser {
     pub fn new(name: &str, password: &str) -> Self {
         Self {
             name: name.to_owned(),
             password: password.to_owned(),
+            session_token: None,
+            compression: Vec::new(),
+            connection_compression: false,
+            timestamp: Utc::now().timestamp(),
+            nonce: Uuid::new_v4(),
         }
     }
+
+    pub fn with_session_token(mut self, session_token: Uuid) -> Self {
+        self.session_token = Some(session_token);
+        self
+    }
+
+    pub fn with_compression(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.compression = algorithms;
+        self
+    }
+
+    /// Offers to compress the whole connection with the negotiated algorithm.
+    pub fn with_connection_compression(mut self) -> Self {
+        self.connection_compression = true;
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -192,39 +661,116 @@ pub struct AuthPayload {
     is_ok: bool,
     message: Option<AuthMessage>,
     err: Option<AuthError>,
+    session_token: Option<Uuid>,
+    compression: Algorithm,
+    connection_compression: bool,
+    /// When a failed login can be tried again, e.g. after a lockout.
+    retry_after_seconds: Option<u64>,
 }
 
 impl AuthPayload {
-    pub fn new_login() -> Self {
+    pub fn new_login(session_token: Uuid) -> Self {
         Self {
             is_ok: true,
             message: Some(AuthMessage::LoginSuccessful),
             err: None,
+            session_token: Some(session_token),
+            compression: Algorithm::None,
+            connection_compression: false,
+            retry_after_seconds: None,
         }
     }
 
+    /// Sets the compression algorithm the server picked for the connection.
+    pub fn with_compression(mut self, algorithm: Algorithm) -> Self {
+        self.compression = algorithm;
+        self
+    }
+
+    /// Sets whether the whole connection is compressed with the picked algorithm after the login.
+    pub fn with_connection_compression(mut self, enabled: bool) -> Self {
+        self.connection_compression = enabled;
+        self
+    }
+
     pub fn new_error() -> Self {
+        Self::new_auth_error(AuthError::IncorrectPassword)
+    }
+
+    pub fn new_auth_error(err: AuthError) -> Self {
         Self {
             is_ok: false,
             message: None,
-            err: Some(AuthError::IncorrectPassword),
+            err: Some(err),
+            session_token: None,
+            compression: Algorithm::None,
+            connection_compression: false,
+            retry_after_seconds: None,
         }
     }
+
+    /// Tells the client after how many seconds the login can be tried again.
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after_seconds = Some(seconds);
+        self
+    }
 }
 
 impl AuthPayload {
     pub fn is_success(&self) -> bool {
         self.is_ok
     }
+
+    /// Token identifying the session. Client can send it back when reconnecting.
+    pub fn session_token(&self) -> Option<Uuid> {
+        self.session_token
+    }
+
+    /// Compression algorithm both sides use for sending messages.
+    pub fn compression(&self) -> Algorithm {
+        self.compression
+    }
+
+    pub fn connection_compression(&self) -> bool {
+        self.connection_compression
+    }
+
+    /// Reason of a failed login.
+    pub fn error(&self) -> Option<&AuthError> {
+        self.err.as_ref()
+    }
+
+    pub fn retry_after_seconds(&self) -> Option<u64> {
+        self.retry_after_seconds
+    }
 }
 impl Display for AuthPayload {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.is_ok {
-            writeln!(f, "Login was successful.")?;
-        } else {
-            writeln!(f, "Login failed, incorrect password.")?
+        match (self.is_ok, &self.err) {
+            (true, _) => write!(f, "Login was successful.")?,
+            (false, Some(AuthError::AlreadyConnected)) => {
+                write!(f, "Login failed, user is already connected.")?
+            }
+            (false, Some(AuthError::TooManyConnections)) => {
+                write!(f, "Login failed, the user has too many open connections.")?
+            }
+            (false, Some(AuthError::ReplayedLogin)) => write!(
+                f,
+                "Login failed, the request is expired or was already used. Check your clock."
+            )?,
+            (false, Some(AuthError::LockedOut)) => write!(
+                f,
+                "Login failed, the user is locked after too many failed attempts."
+            )?,
+            (false, Some(AuthError::InvalidUsername(reason))) => {
+                write!(f, "Login failed. {reason}.")?
+            }
+            (false, _) => write!(f, "Login failed, incorrect password.")?,
         }
-        Ok(())
+        if let Some(seconds) = self.retry_after_seconds {
+            write!(f, " Try again in {seconds}s.")?;
+        }
+        writeln!(f)
     }
 }
 
@@ -236,4 +782,148 @@ pub enum AuthMessage {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum AuthError {
     IncorrectPassword,
+    AlreadyConnected,
+    ReplayedLogin,
+    /// The user has the maximum number of connections open.
+    TooManyConnections,
+    /// Too many failed logins, the response says when the login can be tried again.
+    LockedOut,
+    /// The name can't be registered, the reason is for the user.
+    InvalidUsername(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_variants_match_the_last_variant() {
+        let blob = bincode::serialize(&MessagePayload::Typing).unwrap();
+
+        assert_eq!(
+            u32::from_le_bytes(blob[..4].try_into().unwrap()),
+            MessagePayload::KNOWN_VARIANTS - 1
+        );
+    }
+
+    #[test]
+    fn login_response_of_version_6_is_not_decoded() {
+        let payload = MessagePayload::LoginResponse(AuthPayload::new_error());
+        let blob = bincode::serialize(&payload).unwrap();
+        assert_eq!(payload_codec::decode(&blob).unwrap(), payload);
+
+        // Version 6 didn't send `retry_after_seconds`, the last byte is its `None`
+        assert!(payload_codec::decode(&blob[..blob.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_message_is_rejected_before_reading_it() {
+        // Only the header is sent, a buffer of that size must not be allocated
+        let mut header = (u32::MAX >> 1).to_be_bytes().to_vec();
+        header.push(Algorithm::None.flag());
+        let mut stream = header.as_slice();
+
+        let result =
+            Message::receive_framed_msg_with_limit(&mut stream, Framing::default(), 1024).await;
+
+        assert!(matches!(
+            result,
+            Err(MessageError::MessageTooLarge { size, limit: 1024 }) if size == (u32::MAX >> 1) as usize
+        ));
+    }
+
+    #[tokio::test]
+    async fn frame_with_unnegotiated_compression_is_rejected() {
+        let message = Message::new(MessagePayload::Text("hello".into()));
+        let zstd = Framing::PerMessage(Compression::new(Algorithm::Zstd, 3));
+        let mut frame = Vec::new();
+        Message::send_framed_msg(&message, &mut frame, zstd)
+            .await
+            .unwrap();
+
+        let result = Message::receive_framed_msg(&mut frame.as_slice(), Framing::default()).await;
+        assert!(matches!(
+            result,
+            Err(MessageError::UnexpectedCompression(Algorithm::Zstd))
+        ));
+        let received = Message::receive_framed_msg(&mut frame.as_slice(), zstd)
+            .await
+            .unwrap();
+        assert_eq!(received.data, message.data);
+    }
+
+    /// Message as a newer version would send it, with a payload type this version doesn't know.
+    #[derive(Serialize)]
+    struct FutureMessage {
+        id: Uuid,
+        sender: Option<String>,
+        timestamp: i64,
+        priority: Priority,
+        data: Vec<u8>,
+        ttl_seconds: Option<u64>,
+        room: Option<String>,
+    }
+
+    #[tokio::test]
+    async fn unknown_variant_is_decoded_without_breaking_the_stream() {
+        let mut data = bincode::serialize(&MessagePayload::Text("from the future".into())).unwrap();
+        data[..4].copy_from_slice(&42u32.to_le_bytes());
+        let body = bincode::serialize(&FutureMessage {
+            id: Uuid::new_v4(),
+            sender: Some("alice".into()),
+            timestamp: 0,
+            priority: Priority::Normal,
+            data,
+            ttl_seconds: None,
+            room: None,
+        })
+        .unwrap();
+
+        let mut stream = (body.len() as u32).to_be_bytes().to_vec();
+        stream.push(Algorithm::None.flag());
+        stream.extend(body);
+        Message::send_msg(&Message::new(MessagePayload::Ping), &mut stream)
+            .await
+            .unwrap();
+
+        let mut stream = stream.as_slice();
+        let unknown = Message::receive_msg(&mut stream).await.unwrap();
+        assert_eq!(unknown.data, MessagePayload::Unknown(42));
+        assert_eq!(unknown.sender.as_deref(), Some("alice"));
+        let next = Message::receive_msg(&mut stream).await.unwrap();
+        assert_eq!(next.data, MessagePayload::Ping);
+    }
+
+    #[tokio::test]
+    async fn incompressible_frame_is_sent_as_exception_with_connection_compression() {
+        let framing = Framing::Connection(Compression::new(Algorithm::Zstd, 3));
+        let text = Message::new(MessagePayload::Text("hello ".repeat(100)));
+        // Random bytes don't get smaller by compressing
+        let noise: Vec<u8> = (0..64).flat_map(|_| *Uuid::new_v4().as_bytes()).collect();
+        let image = Message::new(MessagePayload::Image(noise.clone()));
+
+        let mut stream = Vec::new();
+        Message::send_framed_msg(&text, &mut stream, framing)
+            .await
+            .unwrap();
+        let text_frame = stream.len();
+        Message::send_framed_msg(&image, &mut stream, framing)
+            .await
+            .unwrap();
+
+        assert_eq!(stream[0] & 0x80, 0);
+        assert_eq!(stream[text_frame] & 0x80, 0x80);
+        assert_eq!(stream[text_frame + 4], Algorithm::None.flag());
+
+        let mut stream = stream.as_slice();
+        let received = Message::receive_framed_msg(&mut stream, framing)
+            .await
+            .unwrap();
+        assert_eq!(received.data, text.data);
+        let received = Message::receive_framed_msg(&mut stream, framing)
+            .await
+            .unwrap();
+        assert_eq!(received.data, MessagePayload::Image(noise));
+        assert!(stream.is_empty());
+    }
 }
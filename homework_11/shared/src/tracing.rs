@@ -2,11 +2,16 @@ use chrono::Utc;
 use std::{
     fs::{self, File},
     path::PathBuf,
+    str::FromStr,
 };
 use tracing::{subscriber::set_global_default, Subscriber};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{
+    fmt::writer::MakeWriterExt, fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry,
+};
 
 use crate::errors::TracingErrors;
 
@@ -33,6 +38,21 @@ where
         .with(formatting_layer)
 }
 
+/// Same as `get_subscriber`, but every log line is written to both sinks, e.g. to a file to keep and to stderr to watch.
+/// Events are formatted once, so both sinks get the same lines.
+pub fn get_subscriber_tee<A, B>(
+    name: String,
+    env_filter: String,
+    first: A,
+    second: B,
+) -> impl Subscriber + Sync + Send
+where
+    A: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    B: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    get_subscriber(name, env_filter, first.and(second))
+}
+
 /// Initializes the global log subscriber with the given `subscriber`.
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) -> Result<(), TracingErrors> {
     LogTracer::init()
@@ -42,6 +62,22 @@ pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) -> Result<(),
     Ok(())
 }
 
+/// Same as `init_subscriber`, but the failure is not fatal. The error is printed to stderr and the program
+/// runs on without tracing, events then go nowhere. Returns whether tracing was set up.
+pub fn init_subscriber_or_warn(subscriber: impl Subscriber + Send + Sync) -> bool {
+    warn_on_failure(init_subscriber(subscriber))
+}
+
+fn warn_on_failure(setup: Result<(), TracingErrors>) -> bool {
+    match setup {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Tracing couldn't be initialized, running without it. {e}");
+            false
+        }
+    }
+}
+
 /// Creates a log file in the `logs_dir` directory with the name `file_prefix-<timestamp>.log`
 /// If the directory does not exist it will be created.
 /// Returns the file to write to.
@@ -59,3 +95,134 @@ pub fn create_log_file(logs_dir: &str, file_prefix: &str) -> Result<File, Tracin
     let file = File::create(path).map_err(TracingErrors::CreateLogFileError)?;
     Ok(file)
 }
+
+/// How often a rolling log file is replaced by a new one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(LogRotation::Hourly),
+            "daily" => Ok(LogRotation::Daily),
+            _ => Err(format!("Unknown log rotation {s}, use hourly or daily")),
+        }
+    }
+}
+
+/// Creates a log writer in the `logs_dir` directory. With a `rotation` a new file `file_prefix.<date>.log` is started
+/// every hour or day, so a long running process doesn't write one huge file. Without it there is one file per start,
+/// see `create_log_file`. If the directory does not exist it will be created.
+///
+/// The lines are written to the file by a background thread. It stops when the returned guard is dropped,
+/// the lines still waiting are written then. Keep the guard until the program ends, e.g. `let _guard = ...` in `main`.
+/// Dropping it early, also with `let _ = ...`, silently stops the logging.
+pub fn create_log_writer(
+    logs_dir: &str,
+    file_prefix: &str,
+    rotation: Option<LogRotation>,
+) -> Result<(NonBlocking, WorkerGuard), TracingErrors> {
+    let rotation = match rotation {
+        None => {
+            return Ok(tracing_appender::non_blocking(create_log_file(
+                logs_dir,
+                file_prefix,
+            )?))
+        }
+        Some(LogRotation::Hourly) => Rotation::HOURLY,
+        Some(LogRotation::Daily) => Rotation::DAILY,
+    };
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_prefix)
+        .filename_suffix("log")
+        .build(logs_dir)
+        .map_err(TracingErrors::CreateRollingLogError)?;
+    Ok(tracing_appender::non_blocking(appender))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        /// Writer of the sink, every event gets a clone writing to the same buffer.
+        fn into_make(self) -> impl Fn() -> Buffer {
+            move || self.clone()
+        }
+    }
+
+    #[test]
+    fn rolling_log_is_written_when_the_guard_is_dropped() {
+        let logs_dir = std::env::temp_dir().join(format!("logs-{}", uuid::Uuid::new_v4()));
+        let logs_dir = logs_dir.to_str().unwrap();
+        let (writer, guard) =
+            create_log_writer(logs_dir, "test", Some(LogRotation::Daily)).unwrap();
+        let subscriber = get_subscriber("test".into(), "info".into(), writer);
+
+        tracing::subscriber::with_default(subscriber, || tracing::info!("rolled"));
+        drop(guard);
+
+        let files: Vec<_> = fs::read_dir(logs_dir)
+            .unwrap()
+            .map(|f| f.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert!(
+            name.starts_with("test.") && name.ends_with(".log"),
+            "{name}"
+        );
+        assert!(fs::read_to_string(&files[0]).unwrap().contains("rolled"));
+        fs::remove_dir_all(logs_dir).unwrap();
+    }
+
+    #[test]
+    fn tee_subscriber_writes_to_both_sinks() {
+        let (first, second) = (Buffer::default(), Buffer::default());
+        let subscriber = get_subscriber_tee(
+            "test".into(),
+            "info".into(),
+            first.clone().into_make(),
+            second.clone().into_make(),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("hello from both");
+            tracing::debug!("filtered out");
+        });
+
+        for sink in [first, second] {
+            let logs = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+            assert_eq!(logs.lines().count(), 1);
+            assert!(logs.contains("hello from both"));
+        }
+    }
+
+    #[test]
+    fn failed_setup_is_not_fatal() {
+        assert!(warn_on_failure(Ok(())));
+        let failed = TracingErrors::SetupTracingError("Failed to set subscriber".to_string());
+        assert!(!warn_on_failure(Err(failed)));
+    }
+}
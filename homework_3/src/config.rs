@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use ::config::{ConfigError, Environment, File};
+use serde::Deserialize;
+
+/// File with the defaults, it is looked up in the current directory.
+pub const CONFIG_FILE: &str = "formatter.toml";
+
+/// Prefix of the environment variables overriding the file, e.g. `FORMATTER_OPERATION=uppercase`.
+const ENV_PREFIX: &str = "FORMATTER";
+
+/// Defaults of the homework_3 CLI, so the same operation can be scripted without repeating it.
+/// They are read from `formatter.toml` when it exists and environment variables override them.
+/// The interactive homework_4 tool doesn't read them.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    /// Operation used when none is given as an argument.
+    pub operation: Option<String>,
+    /// Removes whitespace around the input text before the operation is applied. Csv files are not trimmed.
+    pub trim: bool,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(Path::new(CONFIG_FILE), ENV_PREFIX)
+    }
+
+    fn load_from(path: &Path, env_prefix: &str) -> Result<Self, ConfigError> {
+        ::config::Config::builder()
+            .add_source(File::from(path).required(false))
+            .add_source(Environment::with_prefix(env_prefix))
+            .build()?
+            .try_deserialize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_overrides_operation_from_file() {
+        let path = std::env::temp_dir().join("homework_3_formatter.toml");
+        std::fs::write(&path, "operation = \"lowercase\"\ntrim = true\n").unwrap();
+
+        let config = Config::load_from(&path, "HOMEWORK_3_TEST").unwrap();
+        assert_eq!(config.operation.as_deref(), Some("lowercase"));
+
+        std::env::set_var("HOMEWORK_3_TEST_OPERATION", "uppercase");
+        let config = Config::load_from(&path, "HOMEWORK_3_TEST").unwrap();
+        assert_eq!(
+            config,
+            Config {
+                operation: Some("uppercase".to_string()),
+                trim: true,
+            }
+        );
+    }
+
+    #[test]
+    fn missing_file_gives_defaults() {
+        let config = Config::load_from(Path::new("does/not/exist.toml"), "HOMEWORK_3_NONE");
+
+        assert_eq!(config.unwrap(), Config::default());
+    }
+}
@@ -1,38 +1,226 @@
 use std::{
     error::Error,
-    io::{self, Read},
+    fs,
+    io::{self, BufRead},
 };
 
-use crate::operation::Operation;
+use crate::operation::{Csv, CsvRenderOptions, Operation};
 
+pub use crate::config::Config;
+
+mod config;
 mod operation;
 
-pub fn run(arg: &str) -> Result<String, Box<dyn Error>> {
-    let operation = Operation::try_from(arg)?;
+/// Operation of one run and the file it is applied to, `None` for stdin.
+#[derive(Debug, PartialEq)]
+pub struct Invocation<'a> {
+    pub operation: &'a str,
+    pub path: Option<&'a str>,
+}
+
+/// Reads the `[operation] [path]` arguments, without the program name. The operation from the arguments takes
+/// precedence over the one in the config. When the config has an operation, a single argument that isn't
+/// an operation is the path.
+pub fn parse_args<'a>(args: &'a [String], config: &'a Config) -> Result<Invocation<'a>, String> {
+    let default = config.operation.as_deref();
+    let (operation, path) = match args {
+        [] => (default, None),
+        [arg] if Operation::try_from(arg.as_str()).is_err() && default.is_some() => {
+            (default, Some(arg.as_str()))
+        }
+        [arg] => (Some(arg.as_str()), None),
+        [arg, path] => (Some(arg.as_str()), Some(path.as_str())),
+        _ => return Err("Incorrect number of arguments. Please provide an operation: lowercase, uppercase, no-spaces, slugify, random, alternating, csv, reverse, count, freq, freq-words, json. Optionally followed by a path to the input file.".to_string()),
+    };
+    let operation = operation.ok_or("No operation given as an argument or in the config.")?;
+    Ok(Invocation { operation, path })
+}
+
+/// Applies the operation to the contents of the file at `path`, or to the text from stdin when there is no path.
+/// Returns the formatted text, or `None` when it was already written to stdout.
+pub fn run(invocation: &Invocation, config: &Config) -> Result<Option<String>, Box<dyn Error>> {
+    run_with_stdin(invocation, config, io::stdin().lock())
+}
 
-    let input_data = read_text(&operation)?;
+/// Same as `run`, with the text read from `stdin` instead of the standard input.
+fn run_with_stdin(
+    invocation: &Invocation,
+    config: &Config,
+    stdin: impl BufRead,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let operation = Operation::try_from(invocation.operation)?;
+    let path = invocation.path;
+
+    // Csv input can be large, so it is rendered straight to stdout without loading all rows.
+    if let Operation::Csv = operation {
+        let options = CsvRenderOptions::default();
+        match path {
+            Some(path) => Csv::render_file(path, &mut io::stdout().lock(), options)?,
+            None => {
+                println!("Insert text:");
+                Csv::render_streaming(stdin, &mut io::stdout().lock(), options)?;
+            }
+        }
+        return Ok(None);
+    }
+
+    let input_data = match path {
+        Some(path) => read_from_file(path)?,
+        None => read_text(&operation, stdin)?,
+    };
+
+    let formatted = match config.trim {
+        true => operation.format(input_data.trim())?,
+        false => operation.format(&input_data)?,
+    };
+    Ok(Some(formatted))
+}
 
-    operation.format(&input_data)
+fn read_from_file(path: &str) -> Result<String, Box<dyn Error>> {
+    fs::read_to_string(path).map_err(|e| format!("Cannot read file {path}. {e}").into())
 }
 
-fn read_text(operation: &Operation) -> Result<String, Box<dyn Error>> {
+/// Operations over a whole text read everything until the end of the input, the others a single line.
+fn read_text(operation: &Operation, mut stdin: impl BufRead) -> Result<String, Box<dyn Error>> {
     println!("Insert text:");
 
     let text = match operation {
-        Operation::Csv => {
+        Operation::Json | Operation::Count | Operation::Freq | Operation::FreqWords => {
             let mut data: Vec<_> = vec![];
-            io::stdin().read_to_end(&mut data)?;
+            stdin.read_to_end(&mut data)?;
             String::from_utf8(data)?
         }
         _ => {
             let mut data = String::new();
-            io::stdin().read_line(&mut data)?;
+            stdin.read_line(&mut data)?;
             data
         }
     };
     Ok(text)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn operation_is_applied_to_file_contents() {
+        let path = std::env::temp_dir().join("homework_3_input.txt");
+        fs::write(&path, "hello world").unwrap();
+        let invocation = Invocation {
+            operation: "uppercase",
+            path: path.to_str(),
+        };
+
+        let result = run(&invocation, &Config::default());
+        assert_eq!(result.unwrap().as_deref(), Some("HELLO WORLD"));
+    }
+
+    #[test]
+    fn argument_takes_precedence_over_config() {
+        let path = std::env::temp_dir().join("homework_3_config_input.txt");
+        fs::write(&path, "  Hello World\n").unwrap();
+        let path = path.to_str().unwrap();
+        let config = Config {
+            operation: Some("uppercase".to_string()),
+            trim: true,
+        };
+
+        let only_path = args(&[path]);
+        let invocation = parse_args(&only_path, &config).unwrap();
+        assert_eq!(
+            run(&invocation, &config).unwrap().as_deref(),
+            Some("HELLO WORLD")
+        );
+        let both = args(&["lowercase", path]);
+        let invocation = parse_args(&both, &config).unwrap();
+        assert_eq!(
+            run(&invocation, &config).unwrap().as_deref(),
+            Some("hello world")
+        );
+        let no_config = Config::default();
+        let invocation = parse_args(&only_path, &no_config).unwrap();
+        assert!(run(&invocation, &no_config).is_err());
+    }
+
+    #[test]
+    fn args_are_parsed_with_the_config_operation() {
+        let config = Config {
+            operation: Some("uppercase".to_string()),
+            trim: false,
+        };
+        let cases = [
+            (args(&[]), "uppercase", None),
+            (args(&["lowercase"]), "lowercase", None),
+            (args(&["input.txt"]), "uppercase", Some("input.txt")),
+            (
+                args(&["lowercase", "input.txt"]),
+                "lowercase",
+                Some("input.txt"),
+            ),
+        ];
+        for (args, operation, path) in &cases {
+            assert_eq!(
+                parse_args(args, &config),
+                Ok(Invocation {
+                    operation,
+                    path: *path
+                })
+            );
+        }
+
+        let no_config = Config::default();
+        assert!(parse_args(&args(&[]), &no_config).is_err());
+        assert_eq!(
+            parse_args(&args(&["input.txt"]), &no_config)
+                .unwrap()
+                .operation,
+            "input.txt"
+        );
+        assert!(parse_args(&args(&["a", "b", "c"]), &config).is_err());
+    }
+
+    #[test]
+    fn counts_are_over_all_lines_of_stdin() {
+        let invocation = Invocation {
+            operation: "count",
+            path: None,
+        };
+        let stdin = io::Cursor::new("a b\nc d\ne\n");
+
+        let result = run_with_stdin(&invocation, &Config::default(), stdin);
+        assert_eq!(
+            result.unwrap().as_deref(),
+            Some("lines: 3, words: 5, chars: 10")
+        );
+
+        let invocation = Invocation {
+            operation: "uppercase",
+            path: None,
+        };
+        let stdin = io::Cursor::new("a b\nc d\n");
+        let result = run_with_stdin(&invocation, &Config::default(), stdin);
+        assert_eq!(result.unwrap().as_deref(), Some("A B\n"));
+    }
+
+    #[test]
+    fn missing_file_returns_error() {
+        let invocation = Invocation {
+            operation: "uppercase",
+            path: Some("does/not/exist.txt"),
+        };
+        let result = run(&invocation, &Config::default());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Cannot read file does/not/exist.txt."));
+    }
+}
+
 /*
 pub fn run<R, W>(mut reader: R, mut writer: W, args: &[String]) -> Result<String, Box<dyn Error>>
 where
@@ -1,19 +1,37 @@
 use std::env;
 
-use homework_3::run;
+use homework_3::{parse_args, run, Config};
 
-/// Run the program with 1 argument: lowercase, uppercase, no-spaces, slugify, random, alternating or csv
-/// Then insert one line to std input. In case of csv you can pass multiple lines
+/// Run the program with 1 argument: lowercase, uppercase, no-spaces, slugify, random, alternating, csv, reverse, count, freq, freq-words or json
+/// Then insert one line to std input. In case of csv, json, count, freq and freq-words you can pass multiple lines
+/// Optional second argument is a path to a file, then the operation is applied to the file contents instead of std input.
+/// The operation can be left out when `formatter.toml` or `FORMATTER_OPERATION` sets one, then the only argument is the path.
+/// `trim = true` (or `FORMATTER_TRIM`) removes whitespace around the input.
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Incorrect number of arguments. Please provide exactly one argument: lowercase, uppercase, no-spaces, slugify, random, alternating, csv.");
-    }
-    let arg = &args[1];
-    let result = run(arg);
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Error while reading the config. Error: {error}");
+            return;
+        }
+    };
+    let invocation = match parse_args(&args[1..], &config) {
+        Ok(invocation) => invocation,
+        Err(error) => {
+            eprintln!("{error}");
+            return;
+        }
+    };
 
-    match result {
-        Ok(value) => println!("{value}"),
-        Err(error) => eprintln!("Error while using operation: {arg}. Error: {error}"),
+    match run(&invocation, &config) {
+        // Output that already ends with a line break, e.g. a table, is printed as it is
+        Ok(Some(value)) if value.ends_with('\n') => print!("{value}"),
+        Ok(Some(value)) => println!("{value}"),
+        Ok(None) => {}
+        Err(error) => eprintln!(
+            "Error while using operation: {}. Error: {error}",
+            invocation.operation
+        ),
     }
 }
@@ -1,8 +1,18 @@
-use std::{error::Error, fmt::Display};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use convert_case::{Case, Casing};
-use csv::{Reader, StringRecord};
+use csv::{Reader, ReaderBuilder, StringRecord};
+use serde_json::{Map, Number, Value};
 use slug::slugify;
+use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug, PartialEq)]
 pub enum Operation {
@@ -13,6 +23,11 @@ pub enum Operation {
     Random,
     Alternating,
     Csv,
+    Reverse,
+    Count,
+    Freq,
+    FreqWords,
+    Json,
 }
 
 impl Operation {
@@ -25,6 +40,11 @@ impl Operation {
             Self::Random => Self::to_random_case(text),
             Self::Alternating => Self::to_alternating_case(text),
             Self::Csv => Self::to_csv(text),
+            Self::Reverse => Self::reverse(text),
+            Self::Count => Self::count(text),
+            Self::Freq => Self::char_frequency(text),
+            Self::FreqWords => Self::word_frequency(text),
+            Self::Json => Self::to_json(text),
         }
     }
     // Extrahoval jsem tyto funkce jak bylo v zadani, ale prijde mi ze to je k nicemu.
@@ -53,9 +73,71 @@ impl Operation {
     }
 
     fn to_csv(text: &str) -> Result<String, Box<dyn Error>> {
-        let csv = Csv::from_string(text)?;
+        let csv = Csv::from_string(text, CsvRenderOptions::default())?;
         Ok(csv.to_string())
     }
+
+    // Reversed by graphemes, so emoji and accented characters stay intact. The line break at the end stays at the end.
+    fn reverse(text: &str) -> Result<String, Box<dyn Error>> {
+        let line = text.trim_end_matches(['\r', '\n']);
+        let mut reversed: String = line.graphemes(true).rev().collect();
+        reversed.push_str(&text[line.len()..]);
+        Ok(reversed)
+    }
+
+    fn count(text: &str) -> Result<String, Box<dyn Error>> {
+        Ok(format!(
+            "lines: {}, words: {}, chars: {}",
+            text.lines().count(),
+            text.split_whitespace().count(),
+            text.chars().count()
+        ))
+    }
+
+    fn to_json(text: &str) -> Result<String, Box<dyn Error>> {
+        let csv = Csv::from_string(text, CsvRenderOptions::default())?;
+        Ok(serde_json::to_string(&csv.to_json())?)
+    }
+
+    // Whitespace is not counted, `freq` skips whitespace characters and `freq-words` splits words by any whitespace.
+    fn char_frequency(text: &str) -> Result<String, Box<dyn Error>> {
+        let chars = text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(String::from);
+        Self::to_frequency_table("char", chars)
+    }
+
+    fn word_frequency(text: &str) -> Result<String, Box<dyn Error>> {
+        Self::to_frequency_table("word", text.split_whitespace().map(String::from))
+    }
+
+    fn to_frequency_table(
+        header: &str,
+        items: impl Iterator<Item = String>,
+    ) -> Result<String, Box<dyn Error>> {
+        let data = frequencies(items)
+            .into_iter()
+            .map(|(item, count)| StringRecord::from(vec![item, count.to_string()]))
+            .collect();
+        let csv = Csv {
+            headers: StringRecord::from(vec![header, "count"]),
+            data,
+            options: CsvRenderOptions::default(),
+        };
+        Ok(csv.to_string())
+    }
+}
+
+/// Counts of the items sorted by count descending, items with the same count are sorted alphabetically.
+fn frequencies(items: impl Iterator<Item = String>) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for item in items {
+        *counts.entry(item).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
 }
 
 impl TryFrom<&str> for Operation {
@@ -70,19 +152,51 @@ impl TryFrom<&str> for Operation {
             "random" => Ok(Self::Random),
             "alternating" => Ok(Self::Alternating),
             "csv"=> Ok(Self::Csv),
-            _ => Err("Invalid argument. Please use one of: lowercase, uppercase, no-spaces, slugify, random, alternating, csv".to_string()),
+            "reverse" => Ok(Self::Reverse),
+            "count" => Ok(Self::Count),
+            "freq" => Ok(Self::Freq),
+            "freq-words" => Ok(Self::FreqWords),
+            "json" => Ok(Self::Json),
+            _ => Err("Invalid argument. Please use one of: lowercase, uppercase, no-spaces, slugify, random, alternating, csv, reverse, count, freq, freq-words, json".to_string()),
         }
     }
 }
 
-struct Csv {
+pub(crate) struct Csv {
     headers: StringRecord,
     data: Vec<StringRecord>,
+    options: CsvRenderOptions,
+}
+
+/// How the csv is parsed and rendered. The default is comma separated and all columns left-aligned.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CsvRenderOptions {
+    /// Separator of the cells in the input, e.g. `b','`, `b';'` or `b'\t'`.
+    pub delimiter: u8,
+    /// Right-align the columns where every non-empty cell is a number.
+    pub right_align_numbers: bool,
+}
+
+impl Default for CsvRenderOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            right_align_numbers: false,
+        }
+    }
+}
+
+impl CsvRenderOptions {
+    fn reader<R: Read>(&self, input: R) -> Reader<R> {
+        ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .from_reader(input)
+    }
 }
 
 impl Csv {
-    fn from_string(input_str: &str) -> Result<Self, Box<dyn Error>> {
-        let mut reader = Reader::from_reader(input_str.as_bytes());
+    fn from_string(input_str: &str, options: CsvRenderOptions) -> Result<Self, Box<dyn Error>> {
+        let mut reader = options.reader(input_str.as_bytes());
         let headers = reader.headers()?.clone();
         let mut data = vec![];
 
@@ -90,12 +204,96 @@ impl Csv {
             data.push(result?);
         }
 
-        let csv = Csv { headers, data };
+        let csv = Csv {
+            headers,
+            data,
+            options,
+        };
 
         Ok(csv)
     }
 }
 
+impl Csv {
+    /// Array of objects keyed by the headers. Empty cells are null and numbers are JSON numbers.
+    fn to_json(&self) -> Value {
+        self.data
+            .iter()
+            .map(|row| {
+                let object = self
+                    .headers
+                    .iter()
+                    .zip(row.iter())
+                    .map(|(header, cell)| (header.to_string(), json_value(cell)))
+                    .collect::<Map<_, _>>();
+                Value::Object(object)
+            })
+            .collect()
+    }
+
+    /// Renders the table of the file at `path` to `output` like `Display`, but without keeping the rows in memory.
+    /// The rows are read twice, first to find the column widths and then to write them, so only the widths
+    /// and one row are held at a time. It is slower than `from_string` with `Display`, which is fine for small inputs.
+    pub(crate) fn render_file<W: Write>(
+        path: &str,
+        output: &mut W,
+        options: CsvRenderOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let open = || {
+            File::open(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("Cannot read file {path}. {e}")))
+        };
+        Self::render_twice(BufReader::new(open()?), open, output, options)
+    }
+
+    /// Same as `render_file` for an input that can be read only once, like stdin. The first pass copies it
+    /// to a new temporary file that the second pass reads.
+    pub(crate) fn render_streaming<R: Read, W: Write>(
+        input: R,
+        output: &mut W,
+        options: CsvRenderOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let (spool, file) = create_spool()?;
+        let input = Tee {
+            input,
+            copy: BufWriter::new(file),
+        };
+        let rendered = Self::render_twice(input, || File::open(&spool), output, options);
+        _ = fs::remove_file(&spool);
+        rendered
+    }
+
+    /// Finds the column widths in `first` and writes the rows of `second`, which has to read the same data.
+    fn render_twice<R: Read, S: Read, W: Write>(
+        first: R,
+        second: impl FnOnce() -> io::Result<S>,
+        output: &mut W,
+        options: CsvRenderOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut reader = options.reader(first);
+        let mut layout = Layout::new(reader.headers()?, options);
+        let mut record = StringRecord::new();
+        while reader.read_record(&mut record)? {
+            layout.fit(&record);
+        }
+        drop(reader);
+        if layout.widths.is_empty() {
+            return Ok(());
+        }
+
+        let mut reader = options.reader(BufReader::new(second()?));
+        let separator = layout.separator();
+        writeln!(output, "{}", separator)?;
+        writeln!(output, "{}", layout.format_row(reader.headers()?))?;
+        writeln!(output, "{}", separator)?;
+        while reader.read_record(&mut record)? {
+            writeln!(output, "{}", layout.format_row(&record))?;
+        }
+        writeln!(output, "{}", separator)?;
+        Ok(())
+    }
+}
+
 impl Display for Csv {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if self.headers.is_empty() {
@@ -103,42 +301,20 @@ impl Display for Csv {
         }
 
         // Set column width based on the widest cell
-        let mut widths = Vec::with_capacity(self.headers.len());
-        for header in self.headers.iter() {
-            widths.push(header.len());
-        }
+        let mut layout = Layout::new(&self.headers, self.options);
         for row in &self.data {
-            for (i, cell) in row.iter().enumerate() {
-                widths[i] = widths[i].max(cell.len());
-            }
+            layout.fit(row);
         }
 
         // Format headers and separator
-        let mut separator = "|".to_string();
-        let header_str = self
-            .headers
-            .iter()
-            .enumerate()
-            .map(|(i, header)| {
-                separator.push_str(&"-".repeat(widths[i] + 3));
-                format!("| {: <width$} ", header, width = widths[i])
-            })
-            .collect::<Vec<_>>()
-            .join("");
-        separator.push('|');
+        let separator = layout.separator();
         writeln!(f, "{}", separator)?;
-        writeln!(f, "{} |", header_str)?;
+        writeln!(f, "{}", layout.format_row(&self.headers))?;
 
         // Format data
         writeln!(f, "{}", separator)?;
         for row in &self.data {
-            let row_str = row
-                .iter()
-                .enumerate()
-                .map(|(i, cell)| format!("| {: <width$} ", cell, width = widths[i]))
-                .collect::<Vec<_>>()
-                .join("");
-            writeln!(f, "{} |", row_str)?;
+            writeln!(f, "{}", layout.format_row(row))?;
         }
         writeln!(f, "{}", separator)?;
 
@@ -146,6 +322,108 @@ impl Display for Csv {
     }
 }
 
+/// Creates the temporary file for the copy of a streamed csv. The file has to be new, so a file or a symlink
+/// that someone else put at the path in the shared temporary directory is never written to.
+fn create_spool() -> io::Result<(PathBuf, File)> {
+    static SPOOLS: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let spool = SPOOLS.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("homework_3_{}_{spool}.csv", std::process::id()));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads from `input` and writes everything read to `copy`. The copy is flushed at the end of the input.
+struct Tee<R, W> {
+    input: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.input.read(buf)?;
+        self.copy.write_all(&buf[..read])?;
+        if read == 0 && !buf.is_empty() {
+            self.copy.flush()?;
+        }
+        Ok(read)
+    }
+}
+
+/// A cell is a number only when the number is written back the same, so codes like `007`, `1.50` or `+1`
+/// stay strings and no data is lost.
+fn json_value(cell: &str) -> Value {
+    if cell.is_empty() {
+        return Value::Null;
+    }
+    let number = match cell.parse::<i64>() {
+        Ok(number) => Some(Number::from(number)),
+        Err(_) => cell.parse::<f64>().ok().and_then(Number::from_f64),
+    };
+    match number {
+        Some(number) if number.to_string() == cell => Value::Number(number),
+        _ => Value::String(cell.to_string()),
+    }
+}
+
+/// Widths and alignment of the table columns, found from the cells of all rows.
+struct Layout {
+    widths: Vec<usize>,
+    /// None until the column has a non-empty cell, then whether all its non-empty cells are numbers.
+    numeric: Vec<Option<bool>>,
+    right_align_numbers: bool,
+}
+
+impl Layout {
+    fn new(headers: &StringRecord, options: CsvRenderOptions) -> Self {
+        Self {
+            widths: headers.iter().map(|header| header.len()).collect(),
+            numeric: vec![None; headers.len()],
+            right_align_numbers: options.right_align_numbers,
+        }
+    }
+
+    fn fit(&mut self, row: &StringRecord) {
+        for (i, cell) in row.iter().enumerate() {
+            self.widths[i] = self.widths[i].max(cell.len());
+            let cell = cell.trim();
+            if !cell.is_empty() {
+                let is_number = cell.parse::<f64>().is_ok();
+                self.numeric[i] = Some(self.numeric[i].unwrap_or(true) && is_number);
+            }
+        }
+    }
+
+    fn separator(&self) -> String {
+        let dashes: String = self
+            .widths
+            .iter()
+            .map(|width| "-".repeat(width + 3))
+            .collect();
+        format!("|{dashes}|")
+    }
+
+    fn format_row(&self, row: &StringRecord) -> String {
+        let cells: String = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                let width = self.widths[i];
+                match self.right_align_numbers && self.numeric[i] == Some(true) {
+                    true => format!("| {: >width$} ", cell),
+                    false => format!("| {: <width$} ", cell),
+                }
+            })
+            .collect();
+        format!("{cells} |")
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -163,7 +441,7 @@ test,some looooooong string,11111111111111111111
 Paris,,1
 ,Greenland,";
 
-        let csv = Csv::from_string(data);
+        let csv = Csv::from_string(data, CsvRenderOptions::default());
         assert!(csv.is_ok());
         println!("{}", csv.unwrap());
     }
@@ -178,7 +456,7 @@ johnson81,4081,30no86,cj4081,Craig,Johnson,Depot,London
 jenkins46,9346,,mj9346,Mary,Jenkins,Engineering,Manchester
 smith79,5079,09ja61,js5079,Jamie,Smith,Engineering,";
 
-        let csv = Csv::from_string(data);
+        let csv = Csv::from_string(data, CsvRenderOptions::default());
         assert!(csv.is_ok());
         println!("{}", csv.unwrap());
     }
@@ -189,7 +467,7 @@ smith79,5079,09ja61,js5079,Jamie,Smith,Engineering,";
 city,country,pop
 Boston,";
 
-        let csv = Csv::from_string(data);
+        let csv = Csv::from_string(data, CsvRenderOptions::default());
         assert!(csv.is_err());
     }
 
@@ -203,6 +481,11 @@ Boston,";
             ("random", Operation::Random),
             ("alternating", Operation::Alternating),
             ("csv", Operation::Csv),
+            ("reverse", Operation::Reverse),
+            ("count", Operation::Count),
+            ("freq", Operation::Freq),
+            ("freq-words", Operation::FreqWords),
+            ("json", Operation::Json),
         ];
 
         for (op_string, expected) in operations {
@@ -215,7 +498,7 @@ Boston,";
     fn invalid_op_should_return_error() {
         let arg: Result<Operation, String> = "sth".try_into();
         assert!(arg.is_err());
-        assert_eq!(arg.unwrap_err(), "Invalid argument. Please use one of: lowercase, uppercase, no-spaces, slugify, random, alternating, csv".to_string());
+        assert_eq!(arg.unwrap_err(), "Invalid argument. Please use one of: lowercase, uppercase, no-spaces, slugify, random, alternating, csv, reverse, count, freq, freq-words, json".to_string());
     }
 
     #[test]
@@ -233,6 +516,10 @@ Boston,";
                 Operation::Slugify,
                 "lorem-ipsum-dolor-sit-amet-consectetur-adipiscing-elit-sed".to_string(),
             ),
+            (
+                Operation::Reverse,
+                "des ,tile gnicsipida rutetcesnoc ,tema tis rolod muspi meroL".to_string(),
+            ),
         ];
 
         for (operation, expected) in test_data {
@@ -241,4 +528,130 @@ Boston,";
             assert_eq!(formatted.unwrap(), expected);
         }
     }
+
+    #[test]
+    fn reverse_keeps_graphemes() {
+        let formatted = Operation::Reverse.format("He\u{301}llo 👋");
+        assert_eq!(formatted.unwrap(), "👋 olle\u{301}H");
+    }
+
+    #[test]
+    fn reverse_keeps_trailing_newline() {
+        assert_eq!(Operation::Reverse.format("abc\n").unwrap(), "cba\n");
+        assert_eq!(Operation::Reverse.format("abc\r\n").unwrap(), "cba\r\n");
+        assert_eq!(Operation::Reverse.format("ab\ncd\n").unwrap(), "dc\nba\n");
+    }
+
+    #[test]
+    fn count_reports_lines_words_and_chars() {
+        let text = "Příliš  žluťoučký kůň\n\núpěl   ďábelské ódy\n";
+
+        let formatted = Operation::Count.format(text);
+        assert_eq!(formatted.unwrap(), "lines: 3, words: 6, chars: 43");
+    }
+
+    #[test]
+    fn frequencies_are_sorted_by_count() {
+        let chars = "hello world"
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(String::from);
+        let expected: Vec<(String, usize)> = [
+            ("l", 3),
+            ("o", 2),
+            ("d", 1),
+            ("e", 1),
+            ("h", 1),
+            ("r", 1),
+            ("w", 1),
+        ]
+        .into_iter()
+        .map(|(c, n)| (c.to_string(), n))
+        .collect();
+        assert_eq!(frequencies(chars), expected);
+
+        let table = Operation::FreqWords.format("to be or not to be").unwrap();
+        assert!(table.contains("| word | count  |"));
+        assert!(table.contains("| be   | 2      |"));
+    }
+
+    #[test]
+    fn streaming_render_matches_display() {
+        let data = "\
+city,country,pop
+Boston,United States,4628910
+Prague,Czech Republic,123456
+,Greenland,";
+
+        let mut output = Vec::new();
+        let options = CsvRenderOptions {
+            delimiter: b',',
+            right_align_numbers: true,
+        };
+        // Stdin can be read only once, like the slice
+        Csv::render_streaming(data.as_bytes(), &mut output, options).unwrap();
+
+        let csv = Csv::from_string(data, options).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), csv.to_string());
+    }
+
+    #[test]
+    fn file_is_rendered_by_reading_it_twice() {
+        let data = "city,pop\nBoston,4628910\nPrague,123456\n";
+        let path = std::env::temp_dir().join("homework_3_render_file.csv");
+        fs::write(&path, data).unwrap();
+
+        let mut output = Vec::new();
+        let options = CsvRenderOptions::default();
+        Csv::render_file(path.to_str().unwrap(), &mut output, options).unwrap();
+
+        let csv = Csv::from_string(data, options).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), csv.to_string());
+        assert!(
+            Csv::render_file("does/not/exist.csv", &mut Vec::new(), options)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Cannot read file does/not/exist.csv.")
+        );
+    }
+
+    #[test]
+    fn numeric_columns_are_right_aligned() {
+        let data = "name;pop;code\nBoston;4628910;US\nPrague;;12\nOslo;700000;\n";
+        let options = CsvRenderOptions {
+            delimiter: b';',
+            right_align_numbers: true,
+        };
+
+        let csv = Csv::from_string(data, options).unwrap().to_string();
+
+        // pop has only numbers and an empty cell, code mixes text and numbers
+        assert!(csv.contains("| name   |     pop | code  |"));
+        assert!(csv.contains("| Boston | 4628910 | US    |"));
+        assert!(csv.contains("| Prague |         | 12    |"));
+        assert!(csv.contains("| Oslo   |  700000 |       |"));
+    }
+
+    #[test]
+    fn default_options_keep_left_alignment() {
+        let csv = Csv::from_string("n,x\n1,a\n", CsvRenderOptions::default()).unwrap();
+        assert!(csv.to_string().contains("| 1 | a  |"));
+    }
+
+    #[test]
+    fn csv_is_converted_to_json() {
+        let json = Operation::Json.format("a,b\n1,x").unwrap();
+        assert_eq!(json, r#"[{"a":1,"b":"x"}]"#);
+
+        let json = Operation::Json.format("a,b,c\n1.5,,007x\n").unwrap();
+        assert_eq!(json, r#"[{"a":1.5,"b":null,"c":"007x"}]"#);
+
+        let json = Operation::Json
+            .format("zip,price,sign,big,neg\n01234,1.50,+1,18446744073709551616,-2\n")
+            .unwrap();
+        assert_eq!(
+            json,
+            r#"[{"zip":"01234","price":"1.50","sign":"+1","big":"18446744073709551616","neg":-2}]"#
+        );
+    }
 }
@@ -3,10 +3,13 @@ use program_type::{Interactive, OneShot};
 pub mod operation;
 pub mod program_type;
 
+/// Keeps the whitespace around the input text, only the line ending is removed.
+const NO_TRIM: &str = "--no-trim";
+
 pub fn process(args: &[String]) {
-    if args.is_empty() {
-        Interactive::start()
-    } else {
-        OneShot::start(&args[0])
+    let trim = !args.iter().any(|arg| arg == NO_TRIM);
+    match args.iter().find(|arg| *arg != NO_TRIM) {
+        None => Interactive::start(trim),
+        Some(arg) => OneShot::start(arg, trim),
     }
 }
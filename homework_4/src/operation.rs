@@ -4,7 +4,7 @@ use convert_case::{Case, Casing};
 use csv::{Reader, StringRecord};
 use slug::slugify;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Operation {
     Lowercase,
     Uppercase,
@@ -16,6 +16,50 @@ pub enum Operation {
 }
 
 impl Operation {
+    pub const ALL: [Operation; 7] = [
+        Self::Lowercase,
+        Self::Uppercase,
+        Self::NoSpace,
+        Self::Slugify,
+        Self::Random,
+        Self::Alternating,
+        Self::Csv,
+    ];
+
+    /// Name of the operation on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Lowercase => "lowercase",
+            Self::Uppercase => "uppercase",
+            Self::NoSpace => "no-spaces",
+            Self::Slugify => "slugify",
+            Self::Random => "random",
+            Self::Alternating => "alternating",
+            Self::Csv => "csv",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Lowercase => "converts the text to lowercase",
+            Self::Uppercase => "converts the text to uppercase",
+            Self::NoSpace => "removes all spaces",
+            Self::Slugify => "converts the text to a slug usable in urls",
+            Self::Random => "changes the case of every letter randomly",
+            Self::Alternating => "alternates lowercase and uppercase letters",
+            Self::Csv => "prints the csv file at the given path as a table",
+        }
+    }
+
+    /// One line with the name and description of every operation.
+    pub fn help() -> String {
+        Self::ALL
+            .iter()
+            .map(|operation| format!("{:<12} {}", operation.name(), operation.description()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
     pub fn format(&self, text: &str) -> Result<String, Box<dyn Error>> {
         match self {
             Self::Lowercase => Self::to_lowercase(text),
@@ -252,6 +296,17 @@ Boston,";
         }
     }
 
+    #[test]
+    fn help_lists_every_operation() {
+        let help = Operation::help();
+
+        assert_eq!(help.lines().count(), Operation::ALL.len());
+        for operation in Operation::ALL {
+            assert_eq!(Operation::try_from(operation.name()), Ok(operation));
+        }
+        assert!(help.contains("no-spaces    removes all spaces"));
+    }
+
     #[test]
     fn invalid_op_should_return_error() {
         let arg: Result<Operation, String> = "sth".try_into();
@@ -10,18 +10,56 @@ use crate::operation::Operation;
 ///Represents operation and data to process
 type OperationData = (Operation, String);
 
+/// Line entered in the interactive mode.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Quit,
+    Help,
+    Format(OperationData),
+    /// Following lines are all formatted with the operation.
+    BatchStarted(Operation),
+    BatchEnded,
+}
+
+/// State of the interactive mode. A line with just an operation starts a batch, then every line is formatted
+/// with that operation until an empty line or `done`. Another operation name switches the batch to it.
+#[derive(Default)]
+struct InteractiveState {
+    batch: Option<Operation>,
+}
+
+impl InteractiveState {
+    fn next_command(&mut self, input: &str) -> Result<Command, Box<dyn Error>> {
+        if input == "q" {
+            return Ok(Command::Quit);
+        }
+        if let Ok(operation) = Operation::try_from(input) {
+            self.batch = Some(operation);
+            return Ok(Command::BatchStarted(operation));
+        }
+        match self.batch {
+            Some(_) if input.is_empty() || input == "done" => {
+                self.batch = None;
+                Ok(Command::BatchEnded)
+            }
+            Some(operation) => Ok(Command::Format((operation, input.to_string()))),
+            None => Interactive::parse_command(input),
+        }
+    }
+}
+
 pub struct OneShot;
 
 impl OneShot {
-    pub fn start(arg: &str) {
-        let result = OneShot::init_one_shot(arg);
+    pub fn start(arg: &str, trim: bool) {
+        let result = OneShot::init_one_shot(arg, trim);
         match &result {
             Ok((operation, data)) => format_data(operation, data),
             Err(error) => eprint!("{}", error),
         }
     }
 
-    fn init_one_shot(arg: &str) -> Result<OperationData, Box<dyn Error>> {
+    fn init_one_shot(arg: &str, trim: bool) -> Result<OperationData, Box<dyn Error>> {
         let operation = Operation::try_from(arg)?;
         match &operation {
             Operation::Csv => println!("Insert path to a csv file:"),
@@ -30,7 +68,7 @@ impl OneShot {
         let mut data = String::new();
         io::stdin().read_line(&mut data)?;
 
-        let data = data.trim().to_string();
+        let data = strip_line(&data, trim).to_string();
 
         Ok((operation, data))
     }
@@ -39,11 +77,11 @@ impl OneShot {
 pub struct Interactive;
 
 impl Interactive {
-    pub fn start() {
+    pub fn start(trim: bool) {
         let (sender, receiver) = std::sync::mpsc::channel();
 
         let handle: JoinHandle<Result<(), String>> =
-            thread::spawn(move || Self::interactive_thread(sender));
+            thread::spawn(move || Self::interactive_thread(sender, trim));
 
         while let Ok((operation, data)) = receiver.recv() {
             format_data(&operation, &data)
@@ -54,8 +92,10 @@ impl Interactive {
         }
     }
 
-    fn interactive_thread(sender: Sender<(Operation, String)>) -> Result<(), String> {
-        println!("Enter <command> <text> to format the data or 'q' to quit the program.");
+    fn interactive_thread(sender: Sender<(Operation, String)>, trim: bool) -> Result<(), String> {
+        println!("Enter <command> <text> to format the data, 'help' to list the commands or 'q' to quit the program.");
+        println!("Enter just <command> to format every following line with it, until an empty line or 'done'.");
+        let mut state = InteractiveState::default();
         loop {
             let mut input = String::new();
             if std::io::stdin().read_line(&mut input).is_err() {
@@ -63,14 +103,26 @@ impl Interactive {
                 continue;
             }
 
-            let input = input.trim();
+            let input = strip_line(&input, trim);
 
-            if input == "q" {
-                break;
-            }
-
-            let (operation, input_text) = match Self::parse_params(input) {
-                Ok(result) => result,
+            let (operation, input_text) = match state.next_command(input) {
+                Ok(Command::Quit) => break,
+                Ok(Command::Help) => {
+                    println!("{}", Operation::help());
+                    continue;
+                }
+                Ok(Command::BatchStarted(operation)) => {
+                    println!(
+                        "Every line is formatted with {}, enter an empty line or 'done' to stop.",
+                        operation.name()
+                    );
+                    continue;
+                }
+                Ok(Command::BatchEnded) => {
+                    println!("Batch finished.");
+                    continue;
+                }
+                Ok(Command::Format(result)) => result,
                 Err(error) => {
                     eprintln!("{}", error);
                     continue;
@@ -84,6 +136,14 @@ impl Interactive {
         Ok(())
     }
 
+    fn parse_command(input: &str) -> Result<Command, Box<dyn Error>> {
+        match input {
+            "q" => Ok(Command::Quit),
+            "help" | "?" => Ok(Command::Help),
+            _ => Self::parse_params(input).map(Command::Format),
+        }
+    }
+
     fn parse_params(args: &str) -> Result<(Operation, String), Box<dyn Error>> {
         let mut parts = args.splitn(2, ' ');
         let first_arg = parts.next().unwrap_or("");
@@ -95,6 +155,14 @@ impl Interactive {
     }
 }
 
+/// Removes the whitespace around the line, or without `trim` only the line ending.
+fn strip_line(line: &str, trim: bool) -> &str {
+    match trim {
+        true => line.trim(),
+        false => line.trim_end_matches(['\n', '\r']),
+    }
+}
+
 fn format_data(operation: &Operation, data: &str) {
     let result = operation.format(data);
     match result {
@@ -135,6 +203,57 @@ mod test {
         );
     }
 
+    #[test]
+    fn should_recognize_help_and_quit() {
+        for input in ["help", "?"] {
+            assert_eq!(Interactive::parse_command(input).unwrap(), Command::Help);
+        }
+        assert_eq!(Interactive::parse_command("q").unwrap(), Command::Quit);
+        assert_eq!(
+            Interactive::parse_command("uppercase help").unwrap(),
+            Command::Format((Operation::Uppercase, "help".to_string()))
+        );
+    }
+
+    #[test]
+    fn should_format_every_line_of_batch() {
+        let mut state = InteractiveState::default();
+        let format = |operation, text: &str| Command::Format((operation, text.to_string()));
+        let expected = [
+            ("slugify", Command::BatchStarted(Operation::Slugify)),
+            ("First Title", format(Operation::Slugify, "First Title")),
+            (
+                "lowercase text",
+                format(Operation::Slugify, "lowercase text"),
+            ),
+            ("uppercase", Command::BatchStarted(Operation::Uppercase)),
+            ("second", format(Operation::Uppercase, "second")),
+            ("done", Command::BatchEnded),
+            ("lowercase TEXT", format(Operation::Lowercase, "TEXT")),
+            ("no-spaces", Command::BatchStarted(Operation::NoSpace)),
+            ("", Command::BatchEnded),
+            ("csv", Command::BatchStarted(Operation::Csv)),
+            ("q", Command::Quit),
+        ];
+
+        for (input, command) in expected {
+            assert_eq!(state.next_command(input).unwrap(), command, "{input:?}");
+        }
+        assert!(InteractiveState::default().next_command("done").is_err());
+    }
+
+    #[test]
+    fn should_keep_spaces_without_trim() {
+        let line = strip_line("uppercase   hi  \r\n", false);
+        let (operation, text) = Interactive::parse_params(line).unwrap();
+
+        assert_eq!(operation.format(&text).unwrap(), "  HI  ");
+
+        let line = strip_line("  uppercase hi  \n", true);
+        let (operation, text) = Interactive::parse_params(line).unwrap();
+        assert_eq!(operation.format(&text).unwrap(), "HI");
+    }
+
     #[test]
     fn should_return_error_for_invalid_operation() {
         let input = "tsdfsd input.csv";
@@ -0,0 +1,3 @@
+-- Existing hashes were derived with the former hardcoded count, new users always store theirs
+ALTER TABLE users ADD COLUMN iterations INTEGER NOT NULL DEFAULT 100000;
+ALTER TABLE users ALTER COLUMN iterations DROP DEFAULT;
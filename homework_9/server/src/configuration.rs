@@ -1,7 +1,12 @@
 use secrecy::{ExposeSecret, Secret};
+use serde::de::Error;
+use serde::Deserializer;
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
+use std::num::NonZeroU32;
+
+use crate::user::DEFAULT_ITERATIONS;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
@@ -46,6 +51,32 @@ pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: std::net::Ipv4Addr,
+    /// PBKDF2 iterations used to hash passwords of new users. Existing users keep the count they were created with.
+    #[serde(
+        default = "default_password_iterations",
+        deserialize_with = "deserialize_password_iterations"
+    )]
+    pub password_iterations: NonZeroU32,
+}
+
+fn default_password_iterations() -> NonZeroU32 {
+    DEFAULT_ITERATIONS
+}
+
+/// The count is stored with the user in an INTEGER column, so it has to be 1 to `i32::MAX`.
+fn deserialize_password_iterations<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<NonZeroU32, D::Error> {
+    let iterations: u32 = deserialize_number_from_string(deserializer)?;
+    i32::try_from(iterations)
+        .ok()
+        .and_then(|_| NonZeroU32::new(iterations))
+        .ok_or_else(|| {
+            D::Error::custom(format!(
+                "password_iterations has to be 1 to {}, got {iterations}",
+                i32::MAX
+            ))
+        })
 }
 
 pub enum Environment {
@@ -101,3 +132,27 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
 
     settings.try_deserialize::<Settings>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, File, FileFormat};
+
+    fn application(password_iterations: &str) -> Result<ApplicationSettings, config::ConfigError> {
+        let yaml =
+            format!("port: 11111\nhost: 127.0.0.1\npassword_iterations: {password_iterations}");
+        Config::builder()
+            .add_source(File::from_str(&yaml, FileFormat::Yaml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn password_iterations_have_to_fit_the_column() {
+        let settings = application("2147483647").unwrap();
+        assert_eq!(settings.password_iterations.get(), i32::MAX as u32);
+
+        assert!(application("2147483648").is_err());
+        assert!(application("0").is_err());
+    }
+}
@@ -58,13 +58,14 @@ impl ChatDb for ChatPostgresDb {
     async fn insert_user(&self, user: &User) -> Result<(), ServerError> {
         sqlx::query!(
             r#"
-            INSERT INTO users(id,password,username,salt,last_login)
-            VALUES ($1,$2,$3,$4,$5)
+            INSERT INTO users(id,password,username,salt,iterations,last_login)
+            VALUES ($1,$2,$3,$4,$5,$6)
             "#,
             user.id,
             user.password.expose_secret(),
             user.username,
             user.salt,
+            user.iterations,
             Utc::now(),
         )
         .execute(&self.db_pool)
@@ -80,7 +81,7 @@ impl ChatDb for ChatPostgresDb {
     async fn get_user(&self, username: &str) -> Result<Option<User>, ServerError> {
         let user = sqlx::query_as!(
             User,
-            "SELECT id, password, username, salt FROM users WHERE username = $1",
+            "SELECT id, password, username, salt, iterations FROM users WHERE username = $1",
             username
         )
         .fetch_optional(&self.db_pool)
@@ -21,4 +21,6 @@ pub enum ServerError {
     PasswordDecode,
     #[error("Failed to create user")]
     CreateUser,
+    #[error("Password is too weak")]
+    WeakPassword,
 }
@@ -2,20 +2,22 @@ use configuration::Settings;
 use flume::{Receiver, Sender};
 use futures::stream::{self, StreamExt};
 use server_error::ServerError;
-use shared::message::{AuthPayload, Message, MessagePayload};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use shared::message::{AuthError, AuthPayload, Message, MessagePayload};
+use std::{collections::HashMap, net::SocketAddr, num::NonZeroU32, sync::Arc};
 use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use crate::db::{ChatDb, ChatPostgresDb};
+use crate::user::User;
 use crate::{configuration, server_error};
 
 /// Starts the server. It will listen for incoming connections and spawn a new thread for each connection.
 /// In a separate thread runs a broadcasting function that will send messages to all connected clients.
 pub async fn start(config: Settings) -> Result<(), ServerError> {
     let db = Arc::new(ChatPostgresDb::new(&config.database));
+    let password_iterations = config.application.password_iterations;
 
     let server = format!("{}:{}", config.application.host, config.application.port);
     tracing::info!("Starting server on address {server}...");
@@ -39,7 +41,10 @@ pub async fn start(config: Settings) -> Result<(), ServerError> {
                 let clients = Arc::clone(&clients);
                 let db = Arc::clone(&db);
                 tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream, address, sender, clients, db).await {
+                    if let Err(e) =
+                        handle_connection(stream, address, sender, clients, db, password_iterations)
+                            .await
+                    {
                         tracing::error!("Error while handling connection: {}", e);
                     }
                 });
@@ -57,9 +62,11 @@ async fn handle_connection(
     sender: Sender<(SocketAddr, Message)>,
     clients: Arc<Mutex<HashMap<SocketAddr, OwnedWriteHalf>>>,
     db: Arc<impl ChatDb>,
+    password_iterations: NonZeroU32,
 ) -> Result<(), ServerError> {
     tracing::info!("New connection from: {address}. Authenticating...");
-    let (current_user_id, current_user_name) = authenticate_user(&mut stream, db.clone()).await?;
+    let (current_user_id, current_user_name) =
+        authenticate_user(&mut stream, db.clone(), password_iterations).await?;
 
     let clients_count = clients.lock().await.len();
 
@@ -144,6 +151,7 @@ async fn remove_client(
 async fn authenticate_user(
     mut stream: &mut TcpStream,
     db: Arc<impl ChatDb>,
+    password_iterations: NonZeroU32,
 ) -> Result<(Uuid, String), ServerError> {
     loop {
         // wait for username and password from client
@@ -187,14 +195,30 @@ async fn authenticate_user(
                     None => {
                         tracing::debug!("Registering new user.");
 
-                        let Ok(user) = auth_user.try_into() else {
-                            let payload = MessagePayload::LoginResponse(AuthPayload::new_error());
-
-                            let msg = Message::new(payload);
-                            Message::send_msg(&msg, stream)
-                                .await
-                                .map_err(ServerError::SendMessage)?;
-                            continue;
+                        let user = match User::new(auth_user, password_iterations) {
+                            Ok(user) => user,
+                            Err(ServerError::WeakPassword) => {
+                                tracing::debug!("Rejected weak password of a new user.");
+                                let payload = MessagePayload::LoginResponse(
+                                    AuthPayload::new_auth_error(AuthError::WeakPassword),
+                                );
+
+                                let msg = Message::new(payload);
+                                Message::send_msg(&msg, stream)
+                                    .await
+                                    .map_err(ServerError::SendMessage)?;
+                                continue;
+                            }
+                            Err(_) => {
+                                let payload =
+                                    MessagePayload::LoginResponse(AuthPayload::new_error());
+
+                                let msg = Message::new(payload);
+                                Message::send_msg(&msg, stream)
+                                    .await
+                                    .map_err(ServerError::SendMessage)?;
+                                continue;
+                            }
                         };
 
                         if db.insert_user(&user).await.is_err() {
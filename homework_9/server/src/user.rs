@@ -11,18 +11,87 @@ use uuid::Uuid;
 
 use crate::server_error::ServerError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct User {
     pub id: Uuid,
     pub password: Secret<String>,
     pub username: String,
     pub salt: String,
+    /// PBKDF2 iterations the password was hashed with, so the count can be raised without breaking older accounts.
+    pub iterations: i32,
 }
 
 const CREDENTIAL_LEN: usize = digest::SHA512_OUTPUT_LEN;
-const N_ITER: Option<NonZeroU32> = NonZeroU32::new(100_000);
+/// PBKDF2 iterations of new users when the configuration doesn't set them.
+pub const DEFAULT_ITERATIONS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Passwords that are tried first when guessing, they are rejected regardless of their length.
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "password1",
+    "password123",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "qwertyuiop",
+    "qwerty123",
+    "1q2w3e4r",
+    "iloveyou",
+    "sunshine",
+    "princess",
+    "football",
+    "baseball",
+    "welcome1",
+    "letmein123",
+    "abc12345",
+    "11111111",
+    "00000000",
+    "trustno1",
+];
+
+/// Checks that the password of a new user is long enough and is not a common one.
+pub fn check_password_strength(password: &str) -> Result<(), ServerError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH
+        || COMMON_PASSWORDS.contains(&password.to_lowercase().as_str())
+    {
+        return Err(ServerError::WeakPassword);
+    }
+    Ok(())
+}
 
 impl User {
+    /// Creates a new user with the password hashed with the given number of iterations.
+    pub fn new(value: AuthUser, iterations: NonZeroU32) -> Result<Self, ServerError> {
+        check_password_strength(&value.password)?;
+
+        let mut salt = [0u8; CREDENTIAL_LEN];
+        let rng = SystemRandom::new();
+
+        rng.fill(&mut salt).map_err(|_| ServerError::CreateUser)?;
+
+        let mut pwd_hash = [0u8; CREDENTIAL_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA512,
+            iterations,
+            &salt,
+            value.password.as_bytes(),
+            &mut pwd_hash,
+        );
+
+        let encoded_pwd = general_purpose::STANDARD.encode(pwd_hash);
+        let encoded_salt = general_purpose::STANDARD.encode(salt);
+
+        Ok(Self {
+            id: Uuid::new_v4(),
+            password: Secret::from(encoded_pwd),
+            username: value.name,
+            salt: encoded_salt,
+            iterations: i32::try_from(iterations.get()).map_err(|_| ServerError::CreateUser)?,
+        })
+    }
+
     pub fn verify_user_password(&self, password_to_verify: &[u8]) -> Result<bool, ServerError> {
         let decoded_salt = general_purpose::STANDARD
             .decode(self.salt.as_bytes())
@@ -32,17 +101,28 @@ impl User {
             .decode(self.password.expose_secret().as_bytes())
             .map_err(|_| ServerError::PasswordDecode)?;
 
+        let iterations = u32::try_from(self.iterations)
+            .ok()
+            .and_then(NonZeroU32::new)
+            .ok_or(ServerError::PasswordDecode)?;
+
         Ok(Self::verify(
             &decoded_pwd,
             &decoded_salt,
+            iterations,
             password_to_verify,
         ))
     }
 
-    fn verify(secret: &[u8], salt: &[u8], password_to_verify: &[u8]) -> bool {
+    fn verify(
+        secret: &[u8],
+        salt: &[u8],
+        iterations: NonZeroU32,
+        password_to_verify: &[u8],
+    ) -> bool {
         pbkdf2::verify(
             pbkdf2::PBKDF2_HMAC_SHA512,
-            N_ITER.unwrap(),
+            iterations,
             salt,
             password_to_verify,
             secret,
@@ -51,32 +131,48 @@ impl User {
     }
 }
 
-impl TryFrom<AuthUser> for User {
-    type Error = ServerError;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn try_from(value: AuthUser) -> Result<Self, Self::Error> {
-        let mut salt = [0u8; CREDENTIAL_LEN];
-        let rng = SystemRandom::new();
+    #[test]
+    fn short_password_is_rejected() {
+        let result = User::new(AuthUser::new("alice", "k3#fZ9q"), DEFAULT_ITERATIONS);
 
-        rng.fill(&mut salt).map_err(|_| ServerError::CreateUser)?;
+        assert!(matches!(result, Err(ServerError::WeakPassword)));
+    }
 
-        let mut pwd_hash = [0u8; CREDENTIAL_LEN];
-        pbkdf2::derive(
-            pbkdf2::PBKDF2_HMAC_SHA512,
-            N_ITER.unwrap(),
-            &salt,
-            value.password.as_bytes(),
-            &mut pwd_hash,
-        );
+    #[test]
+    fn common_password_is_rejected() {
+        let result = User::new(AuthUser::new("alice", "Password123"), DEFAULT_ITERATIONS);
 
-        let encoded_pwd = general_purpose::STANDARD.encode(pwd_hash);
-        let encoded_salt = general_purpose::STANDARD.encode(salt);
+        assert!(matches!(result, Err(ServerError::WeakPassword)));
+    }
 
-        Ok(Self {
-            id: Uuid::new_v4(),
-            password: Secret::from(encoded_pwd),
-            username: value.name,
-            salt: encoded_salt,
-        })
+    #[test]
+    fn strong_password_is_hashed() {
+        let user = User::new(
+            AuthUser::new("alice", "correct horse battery"),
+            DEFAULT_ITERATIONS,
+        )
+        .unwrap();
+
+        assert!(user.verify_user_password(b"correct horse battery").unwrap());
+        assert!(!user.verify_user_password(b"wrong").unwrap());
+    }
+
+    #[test]
+    fn password_is_verified_with_stored_iterations() {
+        let iterations = NonZeroU32::new(1_000).unwrap();
+        let user = User::new(AuthUser::new("alice", "correct horse battery"), iterations).unwrap();
+
+        assert_eq!(user.iterations, 1_000);
+        assert!(user.verify_user_password(b"correct horse battery").unwrap());
+
+        let mut wrong_count = user.clone();
+        wrong_count.iterations = DEFAULT_ITERATIONS.get() as i32;
+        assert!(!wrong_count
+            .verify_user_password(b"correct horse battery")
+            .unwrap());
     }
 }
@@ -211,10 +211,14 @@ impl AuthPayload {
         }
     }
     pub fn new_error() -> Self {
+        Self::new_auth_error(AuthError::IncorrectPassword)
+    }
+
+    pub fn new_auth_error(err: AuthError) -> Self {
         Self {
             is_ok: false,
             message: None,
-            err: Some(AuthError::IncorrectPassword),
+            err: Some(err),
         }
     }
 }
@@ -236,7 +240,13 @@ impl Display for AuthPayload {
                 AuthMessage::UserRegistered => writeln!(f, "You were successfully registered.")?,
             }
         } else {
-            writeln!(f, "Login failed, incorrect password.")?
+            match self.err {
+                Some(AuthError::WeakPassword) => writeln!(
+                    f,
+                    "Registration failed, use a password of at least 8 characters that is not a common one."
+                )?,
+                _ => writeln!(f, "Login failed, incorrect password.")?,
+            }
         }
         Ok(())
     }
@@ -251,4 +261,6 @@ pub enum AuthMessage {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum AuthError {
     IncorrectPassword,
+    /// The password of a new user is too short or too common.
+    WeakPassword,
 }